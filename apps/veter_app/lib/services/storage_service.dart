@@ -5,6 +5,16 @@ import '../models/message.dart';
 
 /// Storage service for local database operations
 class StorageService extends ChangeNotifier {
+  /// Schema migrations, in order. The migration at index `i` upgrades the
+  /// database from version `i + 1` to `i + 2`; version 1 is the schema
+  /// created by [_createTables]. Released migrations are never edited,
+  /// schema changes are made by appending a new one and updating
+  /// [_createTables] to match.
+  static const List<List<String>> _migrations = [];
+
+  /// Schema version this build creates and understands
+  static const int schemaVersion = 1 + _migrations.length;
+
   Database? _database;
   bool _isInitialized = false;
 
@@ -17,9 +27,10 @@ class StorageService extends ChangeNotifier {
 
       _database = await openDatabase(
         path,
-        version: 1,
+        version: schemaVersion,
         onCreate: _createTables,
         onUpgrade: _upgradeDatabase,
+        onDowngrade: _refuseDowngrade,
       );
 
       _isInitialized = true;
@@ -94,8 +105,23 @@ class StorageService extends ChangeNotifier {
     ''');
   }
 
+  /// Apply the migrations between the two versions in order. sqflite runs
+  /// this inside a transaction, so a failed migration leaves the database
+  /// at [oldVersion].
   Future<void> _upgradeDatabase(Database db, int oldVersion, int newVersion) async {
-    // Handle database upgrades here
+    for (var version = oldVersion; version < newVersion; version++) {
+      for (final statement in _migrations[version - 1]) {
+        await db.execute(statement);
+      }
+    }
+  }
+
+  /// A database written by a newer build may have a schema this one would
+  /// corrupt, so it is not opened
+  Future<void> _refuseDowngrade(Database db, int oldVersion, int newVersion) async {
+    throw StateError(
+      'Database schema version $oldVersion is newer than the supported version $newVersion',
+    );
   }
 
   /// Store a message
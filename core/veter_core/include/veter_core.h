@@ -0,0 +1,133 @@
+/*
+ * C interface of the Veter core, see src/ffi.rs for the conventions.
+ *
+ * Functions return a VeterStatus; on failure, veter_last_error_message()
+ * gives the message on the same thread. Models are passed as UTF-8 JSON,
+ * IDs as UUID strings. Strings and buffers returned through out parameters
+ * are freed with veter_string_free() and veter_buffer_free().
+ */
+
+#ifndef VETER_CORE_H
+#define VETER_CORE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum VeterStatus {
+    VETER_STATUS_OK = 0,
+    VETER_STATUS_CRYPTO = 1,
+    VETER_STATUS_INVALID_ENVELOPE = 2,
+    VETER_STATUS_DATABASE = 3,
+    VETER_STATUS_NETWORK = 4,
+    VETER_STATUS_SERIALIZATION = 5,
+    VETER_STATUS_KEY_MANAGEMENT = 6,
+    VETER_STATUS_AUTHENTICATION = 7,
+    VETER_STATUS_STORAGE = 8,
+    VETER_STATUS_INVALID_INPUT = 9,
+    VETER_STATUS_INTERNAL = 10,
+} VeterStatus;
+
+typedef struct VeterBuffer {
+    uint8_t *data;
+    size_t len;
+} VeterBuffer;
+
+typedef struct VeterStorage VeterStorage;
+typedef struct VeterCrypto VeterCrypto;
+typedef struct VeterNetwork VeterNetwork;
+typedef struct VeterSubscription VeterSubscription;
+
+/* json is only valid during the call; it is the error message unless status is OK */
+typedef void (*VeterEventCallback)(void *user_data, VeterStatus status, const char *json);
+
+/* Core */
+
+VeterStatus veter_init(void);
+VeterStatus veter_cleanup(void);
+char *veter_last_error_message(void);
+void veter_string_free(char *string);
+void veter_buffer_free(VeterBuffer buffer);
+
+/* Storage */
+
+VeterStatus veter_storage_open(const char *path, const char *password, VeterStorage **out_storage);
+void veter_storage_free(VeterStorage *storage);
+VeterStatus veter_storage_change_password(const VeterStorage *storage, const char *current_password, const char *new_password);
+VeterStatus veter_storage_store_user(const VeterStorage *storage, const char *user_json);
+VeterStatus veter_storage_store_device(const VeterStorage *storage, const char *device_json);
+VeterStatus veter_storage_store_room(const VeterStorage *storage, const char *room_json);
+VeterStatus veter_storage_store_message(const VeterStorage *storage, const char *message_json);
+VeterStatus veter_storage_get_messages(const VeterStorage *storage, const char *room_id, const char *query_json,
+                                       char **out_page_json);
+VeterStatus veter_storage_get_thread(const VeterStorage *storage, const char *root_id, const char *query_json,
+                                     char **out_page_json);
+VeterStatus veter_storage_get_room_threads(const VeterStorage *storage, const char *user_id, const char *room_id,
+                                           uint32_t limit, char **out_threads_json);
+VeterStatus veter_storage_mark_thread_read(const VeterStorage *storage, const char *root_id, const char *message_id);
+VeterStatus veter_storage_set_local_user(const VeterStorage *storage, const char *user_id);
+VeterStatus veter_storage_get_room_summaries(const VeterStorage *storage, char **out_rooms_json);
+VeterStatus veter_storage_get_receipts(const VeterStorage *storage, const char *room_id, char **out_receipts_json);
+VeterStatus veter_storage_get_reactions(const VeterStorage *storage, const char *user_id, const char *message_ids_json,
+                                        char **out_reactions_json);
+VeterStatus veter_storage_get_message_history(const VeterStorage *storage, const char *message_id,
+                                             char **out_versions_json);
+/* room_id may be NULL to search all rooms */
+VeterStatus veter_storage_search_messages(const VeterStorage *storage, const char *query, const char *room_id,
+                                          int64_t limit, char **out_results_json);
+
+/* Crypto */
+
+VeterStatus veter_crypto_generate_identity_keypair(VeterBuffer *out_private_key, VeterBuffer *out_public_key);
+VeterStatus veter_crypto_new(const uint8_t *identity_key, size_t identity_key_len, const char *device_id,
+                             VeterCrypto **out_crypto);
+void veter_crypto_free(VeterCrypto *crypto);
+VeterStatus veter_crypto_identity_public_key(const VeterCrypto *crypto, VeterBuffer *out_key);
+VeterStatus veter_crypto_generate_key_material(const VeterCrypto *crypto, size_t one_time_prekeys,
+                                               char **out_key_material_json);
+VeterStatus veter_crypto_start_session(const VeterCrypto *crypto, const char *room_id, const char *device_id,
+                                       const char *bundle_json);
+VeterStatus veter_crypto_update_room(const VeterCrypto *crypto, const char *room_json,
+                                     const char *devices_json, char **out_removed_json);
+VeterStatus veter_crypto_encrypt_message(const VeterCrypto *crypto, const uint8_t *content, size_t content_len,
+                                         const char *room_id, const char *message_id, VeterBuffer *out_payload);
+VeterStatus veter_crypto_decrypt_message(const VeterCrypto *crypto, const uint8_t *payload, size_t payload_len,
+                                         const char *room_id, const char *sender_device_id, const char *message_id,
+                                         VeterBuffer *out_content);
+VeterStatus veter_crypto_encrypt_file(const VeterCrypto *crypto, const uint8_t *content, size_t content_len,
+                                      VeterBuffer *out_key, VeterBuffer *out_blob);
+VeterStatus veter_crypto_decrypt_file(const VeterCrypto *crypto, const uint8_t *key, size_t key_len,
+                                      const uint8_t *blob, size_t blob_len, VeterBuffer *out_content);
+
+/* Network */
+
+VeterStatus veter_network_new(VeterNetwork **out_network);
+void veter_network_free(VeterNetwork *network);
+VeterStatus veter_network_connect_relay(const VeterNetwork *network, const char *endpoint);
+VeterStatus veter_network_connect_directory(const VeterNetwork *network, const char *endpoint);
+VeterStatus veter_network_register_device(const VeterNetwork *network, const char *device_json);
+VeterStatus veter_network_get_user_directory(const VeterNetwork *network, const char *user_id,
+                                             char **out_devices_json);
+VeterStatus veter_network_send_messages(const VeterNetwork *network, const char *messages_json,
+                                        char **out_accepted_ids_json);
+VeterStatus veter_network_receive_messages(const VeterNetwork *network, const char *device_id, uint32_t max_items,
+                                           char **out_messages_json);
+VeterStatus veter_network_acknowledge_messages(const VeterNetwork *network, const char *device_id,
+                                               const char *message_ids_json);
+VeterStatus veter_network_sync(const VeterNetwork *network, const VeterStorage *storage, const VeterCrypto *crypto,
+                               const char *device_id, uint32_t max_items, char **out_report_json);
+/* The callback runs on a core thread and must not call back into the core */
+VeterStatus veter_network_subscribe_messages(const VeterNetwork *network, const char *device_id, uint32_t credits,
+                                             VeterEventCallback callback, void *user_data,
+                                             VeterSubscription **out_subscription);
+/* Waits for a running callback to return */
+VeterStatus veter_subscription_cancel(VeterSubscription *subscription);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* VETER_CORE_H */
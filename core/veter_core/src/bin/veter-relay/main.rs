@@ -0,0 +1,275 @@
+//! Reference implementation of the Relay service
+//!
+//! Stores encrypted messages in per-device SQLite queues until the recipient
+//! acknowledges them. For development and end-to-end tests; it does no
+//! authentication.
+//!
+//! With a TLS certificate, serves gRPC over QUIC on the UDP port and HTTP/2
+//! over TLS on the TCP port of the listen address; otherwise plaintext HTTP/2.
+//!
+//! Usage: `veter-relay [--listen ADDR] [--database PATH] [--lease SECONDS]
+//! [--tls-cert PEM --tls-key PEM]`
+
+mod queue;
+
+use queue::QueueStore;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{Notify, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use veter_core::VeterError;
+use veter_core::networking::transport;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+
+const DEFAULT_LISTEN: &str = "127.0.0.1:50051";
+const DEFAULT_DATABASE: &str = "veter-relay.db";
+const DEFAULT_LEASE_SECONDS: u64 = 30;
+
+const USAGE: &str = "Usage: veter-relay [--listen ADDR] [--database PATH] [--lease SECONDS] [--tls-cert PEM --tls-key PEM]";
+
+/// Deliveries buffered per subscription before pushing waits for the client
+const SUBSCRIPTION_BUFFER: usize = 16;
+
+/// Relay service over the queue store
+struct RelayService {
+    queues: Arc<QueueStore>,
+    notifier: Arc<Notifier>,
+}
+
+/// Wakes the subscriptions of a device when it has new messages or credit
+#[derive(Default)]
+struct Notifier {
+    devices: Mutex<HashMap<Vec<u8>, Arc<Notify>>>,
+}
+
+impl Notifier {
+    fn device(&self, device_id: &[u8]) -> Arc<Notify> {
+        let mut devices = self.devices.lock().unwrap_or_else(|e| e.into_inner());
+        devices.entry(device_id.to_vec()).or_default().clone()
+    }
+
+    fn notify(&self, device_id: &[u8]) {
+        let devices = self.devices.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(notify) = devices.get(device_id) {
+            notify.notify_waiters();
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Relay for RelayService {
+    async fn enqueue(&self, request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        let mut accepted_ids = Vec::new();
+        for message in request.into_inner().messages {
+            // Messages that cannot be routed are left out of the accepted IDs
+            if !is_id(&message.id) || message.recipient_device_ids.is_empty()
+                || !message.recipient_device_ids.iter().all(|id| is_id(id)) {
+                continue;
+            }
+
+            self.queues.enqueue(&message).await.map_err(internal)?;
+            for device_id in &message.recipient_device_ids {
+                self.notifier.notify(device_id);
+            }
+            accepted_ids.push(message.id);
+        }
+
+        Ok(Response::new(EnqueueResponse { accepted_ids }))
+    }
+
+    async fn dequeue(&self, request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        let request = request.into_inner();
+        if !is_id(&request.device_id) {
+            return Err(Status::invalid_argument("Invalid device ID"));
+        }
+
+        let messages = self.queues.dequeue(&request.device_id, request.max_items, request.credits)
+            .await
+            .map_err(internal)?
+            .into_iter()
+            .map(|queued| queued.message)
+            .collect();
+
+        Ok(Response::new(DequeueResponse { messages }))
+    }
+
+    async fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        let request = request.into_inner();
+        if !is_id(&request.device_id) {
+            return Err(Status::invalid_argument("Invalid device ID"));
+        }
+
+        self.queues.ack(&request.device_id, &request.ids).await.map_err(internal)?;
+        // Acknowledging frees credit for pushing more
+        self.notifier.notify(&request.device_id);
+
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Delivery, Status>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        if !is_id(&request.device_id) {
+            return Err(Status::invalid_argument("Invalid device ID"));
+        }
+        let cursor = if request.cursor.is_empty() {
+            0
+        } else {
+            let bytes = request.cursor.try_into().map_err(|_| Status::invalid_argument("Invalid cursor"))?;
+            i64::from_be_bytes(bytes)
+        };
+
+        // Messages pushed after the cursor never reached the client
+        self.queues.release_after(&request.device_id, cursor).await.map_err(internal)?;
+
+        let (sender, receiver) = mpsc::channel(SUBSCRIPTION_BUFFER);
+        tokio::spawn(push_messages(
+            self.queues.clone(),
+            self.notifier.device(&request.device_id),
+            request.device_id,
+            request.credits,
+            sender,
+        ));
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Push a device's messages to a subscription as long as it has credit,
+/// until the client goes away
+async fn push_messages(
+    queues: Arc<QueueStore>,
+    notify: Arc<Notify>,
+    device_id: Vec<u8>,
+    credits: u32,
+    sender: mpsc::Sender<Result<Delivery, Status>>,
+) {
+    loop {
+        // Register before dequeuing so that no notification is missed
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let batch = match queues.dequeue(&device_id, credits, credits).await {
+            Ok(batch) => batch,
+            Err(e) => {
+                let _ = sender.send(Err(internal(e))).await;
+                return;
+            }
+        };
+
+        if batch.is_empty() {
+            tokio::select! {
+                _ = &mut notified => {}
+                // Leases may have run out
+                _ = tokio::time::sleep(queues.lease()) => {}
+                _ = sender.closed() => return,
+            }
+            continue;
+        }
+
+        for queued in batch {
+            let delivery = Delivery {
+                message: Some(queued.message),
+                cursor: queued.seq.to_be_bytes().to_vec(),
+            };
+            if sender.send(Ok(delivery)).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// IDs on the wire are raw UUIDs
+fn is_id(bytes: &[u8]) -> bool {
+    bytes.len() == 16
+}
+
+fn internal(error: VeterError) -> Status {
+    eprintln!("veter-relay: {}", error);
+    Status::internal("Relay storage error")
+}
+
+struct Config {
+    listen: String,
+    database: PathBuf,
+    lease: Duration,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Config, String> {
+    let mut config = Config {
+        listen: DEFAULT_LISTEN.to_string(),
+        database: PathBuf::from(DEFAULT_DATABASE),
+        lease: Duration::from_secs(DEFAULT_LEASE_SECONDS),
+        tls_cert: None,
+        tls_key: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "--listen" => config.listen = value()?,
+            "--database" => config.database = PathBuf::from(value()?),
+            "--lease" => {
+                let seconds = value()?;
+                let seconds: f64 = seconds.parse()
+                    .map_err(|_| format!("Invalid lease duration {}", seconds))?;
+                config.lease = Duration::try_from_secs_f64(seconds)
+                    .map_err(|_| format!("Invalid lease duration {}", seconds))?;
+            }
+            "--tls-cert" => config.tls_cert = Some(PathBuf::from(value()?)),
+            "--tls-key" => config.tls_key = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    if config.tls_cert.is_some() != config.tls_key.is_some() {
+        return Err("--tls-cert and --tls-key go together".to_string());
+    }
+
+    Ok(config)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let config = parse_args().inspect_err(|_| eprintln!("{}", USAGE))?;
+    let address = config.listen.parse()?;
+    let queues = Arc::new(QueueStore::open(&config.database, config.lease).await?);
+    let relay = Arc::new(RelayService {
+        queues,
+        notifier: Arc::new(Notifier::default()),
+    });
+
+    let (Some(tls_cert), Some(tls_key)) = (&config.tls_cert, &config.tls_key) else {
+        eprintln!("veter-relay listening on {}", address);
+        Server::builder()
+            .add_service(RelayServer::from_arc(relay))
+            .serve(address)
+            .await?;
+        return Ok(());
+    };
+
+    let certificate = std::fs::read(tls_cert)?;
+    let key = std::fs::read(tls_key)?;
+    let quic = quinn::Endpoint::server(transport::quic_server_config(&certificate, &key)?, address)?;
+
+    eprintln!("veter-relay listening on {} (QUIC and TLS)", address);
+    let tls = Server::builder()
+        .tls_config(ServerTlsConfig::new().identity(Identity::from_pem(&certificate, &key)))?
+        .add_service(RelayServer::from_arc(relay.clone()))
+        .serve(address);
+    let quic = Server::builder()
+        .add_service(RelayServer::from_arc(relay))
+        .serve_with_incoming(transport::quic_incoming(quic));
+    tokio::try_join!(tls, quic)?;
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+//! Per-device message queues in SQLite
+//!
+//! A message is stored once per recipient device. Dequeuing leases the
+//! returned messages to the device until a deadline; a message that is not
+//! acknowledged by then is handed out again, so delivery is at least once.
+
+use prost::Message as _;
+use sqlx::{Row, SqlitePool};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use std::path::Path;
+use std::time::Duration;
+use veter_core::networking::relay::proto::Ciphertext;
+use veter_core::{VeterError, Result};
+
+/// Most messages returned by one dequeue, whatever the client asks for
+const MAX_BATCH: u32 = 256;
+
+/// A leased message with its position in the device's queue
+pub struct QueuedMessage {
+    pub seq: i64,
+    pub message: Ciphertext,
+}
+
+/// Persistent queues of all devices
+pub struct QueueStore {
+    pool: SqlitePool,
+    lease: Duration,
+}
+
+impl QueueStore {
+    /// Open or create the queue database. Unacknowledged messages are
+    /// redelivered `lease` after they were handed out.
+    pub async fn open(path: &Path, lease: Duration) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        // A single connection serializes dequeues, so a message is never
+        // leased twice at once
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect_with(options)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to open queue database: {}", e)))?;
+
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS queued_messages (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id BLOB NOT NULL,
+                message_id BLOB NOT NULL,
+                ciphertext BLOB NOT NULL,
+                leased_until INTEGER,
+                UNIQUE (device_id, message_id)
+            )
+            "#
+        )
+        .execute(&pool)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to create queue table: {}", e)))?;
+
+        Ok(Self { pool, lease })
+    }
+
+    /// Queue a message for each of its recipients. Enqueuing a message again
+    /// is a no-op, so senders can safely retry.
+    pub async fn enqueue(&self, message: &Ciphertext) -> Result<()> {
+        let stored = Ciphertext {
+            recipient_device_ids: Vec::new(),
+            ..message.clone()
+        }
+        .encode_to_vec();
+
+        let mut tx = self.pool.begin()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to begin transaction: {}", e)))?;
+        for device_id in &message.recipient_device_ids {
+            sqlx::query("INSERT OR IGNORE INTO queued_messages (device_id, message_id, ciphertext) VALUES (?, ?, ?)")
+                .bind(device_id)
+                .bind(&message.id)
+                .bind(&stored)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| VeterError::Database(format!("Failed to enqueue message: {}", e)))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to commit transaction: {}", e)))
+    }
+
+    /// Lease the oldest available messages of a device.
+    ///
+    /// Returns at most `max_items` messages, and only as many as keep the
+    /// device's unacknowledged messages within `credits`.
+    pub async fn dequeue(&self, device_id: &[u8], max_items: u32, credits: u32) -> Result<Vec<QueuedMessage>> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut tx = self.pool.begin()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to begin transaction: {}", e)))?;
+
+        let outstanding: i64 = sqlx::query("SELECT count(*) AS outstanding FROM queued_messages WHERE device_id = ? AND leased_until > ?")
+            .bind(device_id)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to count leased messages: {}", e)))?
+            .get("outstanding");
+        let window = i64::from(credits).saturating_sub(outstanding).max(0);
+        let limit = window.min(i64::from(max_items.min(MAX_BATCH)));
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        let rows = sqlx::query(
+            r#"
+            SELECT seq, ciphertext FROM queued_messages
+            WHERE device_id = ? AND (leased_until IS NULL OR leased_until <= ?)
+            ORDER BY seq
+            LIMIT ?
+            "#
+        )
+        .bind(device_id)
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to dequeue messages: {}", e)))?;
+
+        let leased_until = now + self.lease.as_millis() as i64;
+        let mut messages = Vec::with_capacity(rows.len());
+        for row in rows {
+            sqlx::query("UPDATE queued_messages SET leased_until = ? WHERE seq = ?")
+                .bind(leased_until)
+                .bind(row.get::<i64, _>("seq"))
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| VeterError::Database(format!("Failed to lease message: {}", e)))?;
+
+            let ciphertext: Vec<u8> = row.get("ciphertext");
+            messages.push(QueuedMessage {
+                seq: row.get("seq"),
+                message: Ciphertext::decode(ciphertext.as_slice())
+                    .map_err(|e| VeterError::Serialization(format!("Corrupt queued message: {}", e)))?,
+            });
+        }
+        tx.commit()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to commit transaction: {}", e)))?;
+
+        Ok(messages)
+    }
+
+    /// Make the messages of a device after `seq` available again, e.g. when
+    /// they were leased to a subscription that broke before delivering them
+    pub async fn release_after(&self, device_id: &[u8], seq: i64) -> Result<()> {
+        sqlx::query("UPDATE queued_messages SET leased_until = NULL WHERE device_id = ? AND seq > ?")
+            .bind(device_id)
+            .bind(seq)
+            .execute(&self.pool)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to release messages: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// How long a dequeued message stays leased
+    pub fn lease(&self) -> Duration {
+        self.lease
+    }
+
+    /// Remove delivered messages from a device's queue
+    pub async fn ack(&self, device_id: &[u8], message_ids: &[Vec<u8>]) -> Result<()> {
+        let mut tx = self.pool.begin()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to begin transaction: {}", e)))?;
+        for message_id in message_ids {
+            sqlx::query("DELETE FROM queued_messages WHERE device_id = ? AND message_id = ?")
+                .bind(device_id)
+                .bind(message_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(|e| VeterError::Database(format!("Failed to acknowledge message: {}", e)))?;
+        }
+        tx.commit()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to commit transaction: {}", e)))
+    }
+}
@@ -0,0 +1,328 @@
+//! Conflict-free replicated room state
+//!
+//! A room's name, description and members are changed by [`RoomOp`]s, which
+//! every device applies to its own [`RoomState`]. Devices that applied the
+//! same ops end up in the same state, whatever order the ops arrived in and
+//! however long a device was offline:
+//!
+//! - members are an observed-remove set: a removal cancels the additions the
+//!   removing device had seen, so a concurrent re-addition survives it
+//! - name and description are last-writer-wins registers, ordered by
+//!   [`Timestamp`]
+//! - every op names the ops its device had applied before, and is held back
+//!   until those are applied here too, so a removal never overtakes the
+//!   addition it cancels
+//!
+//! Ops travel as [`MessageContent::RoomEvent`] messages, encrypted like any
+//! other message, and are persisted by storage along with them and the state
+//! they were applied to.
+
+use crate::{VeterError, Result, models::*};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use uuid::Uuid;
+
+pub type OpId = Uuid;
+
+/// Hybrid logical clock time of an op: wall-clock milliseconds, a counter
+/// for ops that would not be later otherwise, and the device as the final
+/// tie-break, which makes the order total
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Timestamp {
+    pub millis: i64,
+    pub counter: u32,
+    pub device_id: DeviceId,
+}
+
+impl Timestamp {
+    /// Time of a new op by `device_id`, later than any op seen so far
+    fn next(latest: Option<Timestamp>, device_id: DeviceId) -> Self {
+        let now = Utc::now().timestamp_millis();
+        match latest {
+            Some(latest) if latest.millis >= now => Self {
+                millis: latest.millis,
+                counter: latest.counter + 1,
+                device_id,
+            },
+            _ => Self {
+                millis: now,
+                counter: 0,
+                device_id,
+            },
+        }
+    }
+}
+
+/// A change to a room's state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoomOp {
+    pub id: OpId,
+    pub room_id: RoomId,
+    pub device_id: DeviceId,
+    pub timestamp: Timestamp,
+    /// Latest ops applied by the device when it made this one
+    pub deps: Vec<OpId>,
+    pub kind: RoomOpKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomOpKind {
+    /// Add a member, tagged with the op's ID
+    AddMember(UserId),
+    /// Remove a member by cancelling the additions with these tags
+    RemoveMember { user_id: UserId, observed: Vec<OpId> },
+    SetName(String),
+    SetDescription(Option<String>),
+}
+
+impl RoomOp {
+    /// Message carrying the op, with the op's ID, to encrypt and send to the
+    /// room
+    pub fn to_message(&self, sender_id: UserId) -> Message {
+        Message {
+            id: self.id,
+            room_id: self.room_id,
+            sender_id,
+            sender_device_id: self.device_id,
+            content: MessageContent::RoomEvent(self.clone()),
+            created_at: chrono::DateTime::from_timestamp_millis(self.timestamp.millis).unwrap_or_else(Utc::now),
+            edited_at: None,
+            reply_to: None,
+        }
+    }
+
+    /// Check that the op is what the message carrying it says
+    pub fn matches(&self, message: &Message) -> bool {
+        self.id == message.id && self.room_id == message.room_id && self.device_id == message.sender_device_id
+    }
+}
+
+/// Last-writer-wins register
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct LwwRegister<T> {
+    value: Option<T>,
+    timestamp: Option<Timestamp>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        Self {
+            value: None,
+            timestamp: None,
+        }
+    }
+}
+
+impl<T> LwwRegister<T> {
+    fn set(&mut self, value: T, timestamp: Timestamp) {
+        if self.timestamp < Some(timestamp) {
+            self.value = Some(value);
+            self.timestamp = Some(timestamp);
+        }
+    }
+}
+
+/// Observed-remove set, each element with the tags of its live additions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound(deserialize = "T: Ord + Deserialize<'de>"))]
+struct OrSet<T> {
+    entries: BTreeMap<T, BTreeMap<OpId, Timestamp>>,
+}
+
+impl<T> Default for OrSet<T> {
+    fn default() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Ord + Copy> OrSet<T> {
+    fn add(&mut self, value: T, tag: OpId, timestamp: Timestamp) {
+        self.entries.entry(value).or_default().insert(tag, timestamp);
+    }
+
+    fn remove(&mut self, value: &T, observed: &[OpId]) {
+        if let Some(tags) = self.entries.get_mut(value) {
+            tags.retain(|tag, _| !observed.contains(tag));
+        }
+    }
+
+    fn tags(&self, value: &T) -> Vec<OpId> {
+        self.entries.get(value).map(|tags| tags.keys().copied().collect()).unwrap_or_default()
+    }
+
+    /// Elements in the order of their earliest live addition
+    fn values(&self) -> Vec<T> {
+        let mut values: Vec<_> = self.entries.iter()
+            .filter_map(|(value, tags)| tags.values().min().map(|added| (*added, *value)))
+            .collect();
+        values.sort();
+        values.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// Whether any element was ever added
+    fn is_touched(&self) -> bool {
+        !self.entries.is_empty()
+    }
+}
+
+/// Replicated state of one room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomState {
+    room_id: RoomId,
+    name: LwwRegister<String>,
+    description: LwwRegister<Option<String>>,
+    members: OrSet<UserId>,
+    applied: HashSet<OpId>,
+    /// Applied ops no other applied op depends on
+    heads: BTreeSet<OpId>,
+    /// Received ops waiting for their dependencies
+    pending: Vec<RoomOp>,
+    latest: Option<Timestamp>,
+}
+
+impl RoomState {
+    /// State of a room no op has been applied to
+    pub fn new(room_id: RoomId) -> Self {
+        Self {
+            room_id,
+            name: LwwRegister::default(),
+            description: LwwRegister::default(),
+            members: OrSet::default(),
+            applied: HashSet::new(),
+            heads: BTreeSet::new(),
+            pending: Vec::new(),
+            latest: None,
+        }
+    }
+
+    /// State of a new room as set up by its creator, with the ops to send
+    /// to the other members
+    pub fn create(room: &Room, device_id: DeviceId) -> (Self, Vec<RoomOp>) {
+        let mut state = Self::new(room.id);
+        let mut ops = vec![
+            state.set_name(device_id, &room.name),
+            state.set_description(device_id, room.description.clone()),
+        ];
+        ops.extend(room.members.iter().map(|member| state.add_member(device_id, *member)));
+        (state, ops)
+    }
+
+    pub fn room_id(&self) -> RoomId {
+        self.room_id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.value.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.value.as_ref().and_then(|description| description.as_deref())
+    }
+
+    /// Current members, in the order they were added
+    pub fn members(&self) -> Vec<UserId> {
+        self.members.values()
+    }
+
+    pub fn is_member(&self, user_id: &UserId) -> bool {
+        !self.members.tags(user_id).is_empty()
+    }
+
+    /// Received ops still waiting for ops they depend on
+    pub fn pending(&self) -> &[RoomOp] {
+        &self.pending
+    }
+
+    /// Apply an op, or hold it back until the ops it depends on are
+    /// applied. Ops applied before are ignored.
+    pub fn apply(&mut self, op: RoomOp) -> Result<()> {
+        if op.room_id != self.room_id {
+            return Err(VeterError::InvalidInput(format!("Op {} is for room {}, not {}", op.id, op.room_id, self.room_id)));
+        }
+        if self.applied.contains(&op.id) || self.pending.iter().any(|pending| pending.id == op.id) {
+            return Ok(());
+        }
+        self.pending.push(op);
+
+        // Applying one op may unblock others
+        while let Some(index) = self.pending.iter().position(|op| op.deps.iter().all(|dep| self.applied.contains(dep))) {
+            let op = self.pending.remove(index);
+            self.apply_ready(op);
+        }
+        Ok(())
+    }
+
+    /// Change the name, returning the op to send
+    pub fn set_name(&mut self, device_id: DeviceId, name: &str) -> RoomOp {
+        self.local_op(device_id, RoomOpKind::SetName(name.to_string()))
+    }
+
+    /// Change the description, returning the op to send
+    pub fn set_description(&mut self, device_id: DeviceId, description: Option<String>) -> RoomOp {
+        self.local_op(device_id, RoomOpKind::SetDescription(description))
+    }
+
+    /// Add a member, returning the op to send
+    pub fn add_member(&mut self, device_id: DeviceId, user_id: UserId) -> RoomOp {
+        self.local_op(device_id, RoomOpKind::AddMember(user_id))
+    }
+
+    /// Remove a member, returning the op to send, or `None` if they are not
+    /// a member
+    pub fn remove_member(&mut self, device_id: DeviceId, user_id: UserId) -> Option<RoomOp> {
+        let observed = self.members.tags(&user_id);
+        if observed.is_empty() {
+            return None;
+        }
+        Some(self.local_op(device_id, RoomOpKind::RemoveMember { user_id, observed }))
+    }
+
+    /// Bring a room's name, description and members up to this state. Parts
+    /// no op has set yet are left alone.
+    pub fn apply_to(&self, room: &mut Room) {
+        if let Some(name) = &self.name.value {
+            room.name = name.clone();
+        }
+        if let Some(description) = &self.description.value {
+            room.description = description.clone();
+        }
+        if self.members.is_touched() {
+            room.members = self.members();
+        }
+        if let Some(latest) = self.latest.and_then(|latest| chrono::DateTime::from_timestamp_millis(latest.millis)) {
+            room.updated_at = room.updated_at.max(latest);
+        }
+    }
+
+    fn local_op(&mut self, device_id: DeviceId, kind: RoomOpKind) -> RoomOp {
+        let op = RoomOp {
+            id: Uuid::new_v4(),
+            room_id: self.room_id,
+            device_id,
+            timestamp: Timestamp::next(self.latest, device_id),
+            deps: self.heads.iter().copied().collect(),
+            kind,
+        };
+        self.apply_ready(op.clone());
+        op
+    }
+
+    fn apply_ready(&mut self, op: RoomOp) {
+        match op.kind {
+            RoomOpKind::AddMember(user_id) => self.members.add(user_id, op.id, op.timestamp),
+            RoomOpKind::RemoveMember { user_id, observed } => self.members.remove(&user_id, &observed),
+            RoomOpKind::SetName(name) => self.name.set(name, op.timestamp),
+            RoomOpKind::SetDescription(description) => self.description.set(description, op.timestamp),
+        }
+
+        for dep in &op.deps {
+            self.heads.remove(dep);
+        }
+        self.heads.insert(op.id);
+        self.applied.insert(op.id);
+        self.latest = self.latest.max(Some(op.timestamp));
+    }
+}
@@ -1,137 +1,726 @@
 //! Cryptographic operations for Veter
 
+pub mod envelope;
+pub mod escrow;
+pub mod fingerprint;
+pub mod mls;
+pub mod ratchet;
+pub mod sender_key;
+pub mod x3dh;
+
 use crate::{VeterError, Result, models::*};
-use aes_gcm::{Aes256Gcm, Key, Nonce, KeyInit};
-use aes_gcm::aead::Aead;
-use chacha20poly1305::{ChaCha20Poly1305, Key as ChaChaKey, Nonce as ChaChaNonce, KeyInit as ChaChaKeyInit};
-use rand::RngCore;
-use sha2::{Sha256, Digest};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 use hmac::{Hmac, Mac};
 use std::collections::HashMap;
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+use envelope::{Envelope, GroupEnvelope, MlsEnvelope, RecipientKey};
+use fingerprint::SafetyNumber;
+use mls::{MlsClient, MlsCommit, MlsHandshake};
+use ratchet::RatchetState;
+use sender_key::{GroupMessage, SenderKeyDistribution, SenderKeyRecord};
+use x3dh::{IdentityKeyPair, InitialMessage, OneTimePreKey, PreKeyBundle, SharedSecret, SignedPreKey};
+
+/// How long a replaced signed prekey is still accepted, for initial
+/// messages built from the bundle published before
+const SIGNED_PREKEY_GRACE_DAYS: i64 = 30;
 
 /// Cryptographic operations manager
 pub struct CryptoManager {
-    identity_key: Vec<u8>,
+    identity: IdentityKeyPair,
     device_id: DeviceId,
-    sessions: HashMap<RoomId, Session>,
+    signed_prekey: Option<SignedPreKey>,
+    /// The signed prekey replaced last, with when it was replaced
+    previous_signed_prekey: Option<(SignedPreKey, chrono::DateTime<chrono::Utc>)>,
+    one_time_prekeys: HashMap<[u8; 32], OneTimePreKey>,
+    sessions: HashMap<(RoomId, DeviceId), Session>,
+    sender_keys: HashMap<(RoomId, DeviceId), SenderKey>,
+    rooms: HashMap<RoomId, Room>,
+    mls: MlsClient,
 }
 
 impl CryptoManager {
-    /// Create a new crypto manager
-    pub fn new(identity_key: Vec<u8>, device_id: DeviceId) -> Self {
-        Self {
-            identity_key,
+    /// Create a new crypto manager from the device's identity private key
+    pub fn new(identity_key: Vec<u8>, device_id: DeviceId) -> Result<Self> {
+        let identity_key = Zeroizing::new(identity_key);
+        let identity = IdentityKeyPair::from_private_bytes(&identity_key)?;
+        let mls = MlsClient::new(&identity, device_id);
+
+        Ok(Self {
+            identity,
             device_id,
+            signed_prekey: None,
+            previous_signed_prekey: None,
+            one_time_prekeys: HashMap::new(),
             sessions: HashMap::new(),
-        }
+            sender_keys: HashMap::new(),
+            rooms: HashMap::new(),
+            mls,
+        })
     }
 
-    /// Generate a new identity key pair
+    /// Generate a new identity key pair, returned as (private, public)
     pub fn generate_identity_keypair() -> Result<(Vec<u8>, Vec<u8>)> {
-        // TODO: Use libsignal-protocol for proper key generation
-        // For now, generate random keys as placeholder
-        let mut private_key = vec![0u8; 32];
-        let mut public_key = vec![0u8; 32];
-        
-        rand::thread_rng().fill_bytes(&mut private_key);
-        rand::thread_rng().fill_bytes(&mut public_key);
-        
-        Ok((private_key, public_key))
+        let identity = IdentityKeyPair::generate();
+
+        Ok((identity.private_bytes().to_vec(), identity.public_key().to_vec()))
     }
 
-    /// Encrypt message content using AES-GCM
-    pub fn encrypt_message(&self, content: &[u8], room_id: RoomId) -> Result<Vec<u8>> {
-        // Generate random key and nonce for this message
-        let mut key_bytes = [0u8; 32];
-        let mut nonce_bytes = [0u8; 12];
-        
-        rand::thread_rng().fill_bytes(&mut key_bytes);
-        rand::thread_rng().fill_bytes(&mut nonce_bytes);
-        
-        let key = Key::from_slice(&key_bytes);
-        let nonce = Nonce::from_slice(&nonce_bytes);
-        
-        let cipher = Aes256Gcm::new(key);
-        let ciphertext = cipher.encrypt(nonce, content)
-            .map_err(|e| VeterError::Crypto(format!("Encryption failed: {}", e)))?;
-        
-        // TODO: Encrypt the key using libsignal session
-        // For now, prepend key and nonce (insecure!)
-        let mut result = Vec::new();
-        result.extend_from_slice(&key_bytes);
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
-        
-        Ok(result)
+    /// Generate a legal hold key pair, returned as (private, public), see
+    /// [`escrow::generate_keypair`]
+    pub fn generate_hold_keypair() -> (Vec<u8>, Vec<u8>) {
+        escrow::generate_keypair()
+    }
+
+    /// Public identity key of this device
+    pub fn identity_public_key(&self) -> Vec<u8> {
+        self.identity.public_key().to_vec()
     }
 
-    /// Decrypt message content using AES-GCM
-    pub fn decrypt_message(&self, encrypted: &[u8], room_id: RoomId) -> Result<Vec<u8>> {
-        if encrypted.len() < 44 { // 32 + 12 = key + nonce
-            return Err(VeterError::Crypto("Invalid encrypted message format".to_string()));
+    /// Safety number between this device's identity key and a peer device's,
+    /// for comparing digits or scanning QR codes in person
+    pub fn safety_number(&self, local_user_id: UserId, remote_user_id: UserId, remote_identity_key: &[u8]) -> Result<SafetyNumber> {
+        if remote_identity_key.len() != 32 {
+            return Err(VeterError::KeyManagement("Identity key must be 32 bytes".to_string()));
         }
-        
-        let key_bytes = &encrypted[0..32];
-        let nonce_bytes = &encrypted[32..44];
-        let ciphertext = &encrypted[44..];
-        
-        let key = Key::from_slice(key_bytes);
-        let nonce = Nonce::from_slice(nonce_bytes);
-        
-        let cipher = Aes256Gcm::new(key);
-        let plaintext = cipher.decrypt(nonce, ciphertext)
-            .map_err(|e| VeterError::Crypto(format!("Decryption failed: {}", e)))?;
-        
+
+        Ok(SafetyNumber::new(local_user_id, &self.identity.public_key(), remote_user_id, remote_identity_key))
+    }
+
+    /// Rotate the signed prekey and add one-time prekeys and as many MLS
+    /// KeyPackages, returning the key material to publish to the directory.
+    /// The replaced signed prekey is still accepted for
+    /// [`SIGNED_PREKEY_GRACE_DAYS`] days. The private keys are part of
+    /// [`CryptoManager::prekey_state`] and [`CryptoManager::mls_state`].
+    pub fn generate_key_material(&mut self, one_time_prekeys: usize) -> Result<KeyMaterial> {
+        let signed_prekey = SignedPreKey::generate(&self.identity);
+        let mut material = KeyMaterial {
+            identity_key: self.identity_public_key(),
+            signed_prekey: signed_prekey.public_key().to_vec(),
+            signed_prekey_signature: signed_prekey.signature().to_vec(),
+            one_time_prekeys: Vec::with_capacity(one_time_prekeys),
+            mls_key_packages: self.mls.generate_key_packages(one_time_prekeys)?,
+        };
+        if let Some(previous) = self.signed_prekey.replace(signed_prekey) {
+            self.previous_signed_prekey = Some((previous, chrono::Utc::now()));
+        }
+
+        for _ in 0..one_time_prekeys {
+            let prekey = OneTimePreKey::generate();
+            let public_key = prekey.public_key();
+            material.one_time_prekeys.push(public_key.to_vec());
+            self.one_time_prekeys.insert(public_key, prekey);
+        }
+
+        Ok(material)
+    }
+
+    /// Derive a shared session secret from a peer's prekey bundle.
+    ///
+    /// The returned [`InitialMessage`] must be delivered to the peer so it
+    /// can derive the same secret with [`CryptoManager::accept_key_agreement`].
+    pub fn initiate_key_agreement(&self, bundle: &KeyMaterial) -> Result<(SharedSecret, InitialMessage)> {
+        x3dh::initiate(&self.identity, &PreKeyBundle::try_from(bundle)?)
+    }
+
+    /// Derive the shared session secret from a peer's initial message,
+    /// consuming the one-time prekey it used
+    pub fn accept_key_agreement(&mut self, message: &InitialMessage) -> Result<SharedSecret> {
+        let signed_prekey = self.signed_prekey_for(message)?;
+        let one_time_prekey = self.one_time_prekey_for(message)?;
+
+        let secret = x3dh::respond(&self.identity, signed_prekey, one_time_prekey, message)?;
+        if let Some(public_key) = message.one_time_prekey {
+            self.one_time_prekeys.remove(&public_key);
+        }
+
+        Ok(secret)
+    }
+
+    /// Export the private keys of the signed prekeys still accepted and of
+    /// the unused one-time prekeys, to be persisted with
+    /// `StorageManager::store_prekey_state` after generating key material
+    /// and whenever a one-time prekey is consumed
+    pub fn prekey_state(&self) -> Result<Zeroizing<Vec<u8>>> {
+        self.prekey_state_without(None)
+    }
+
+    /// Prekey state without a one-time prekey about to be consumed
+    fn prekey_state_without(&self, consumed: Option<[u8; 32]>) -> Result<Zeroizing<Vec<u8>>> {
+        let state = PreKeyState {
+            signed_prekey: self.signed_prekey.as_ref().map(|prekey| StoredSignedPreKey::new(prekey, None)),
+            previous_signed_prekey: self.previous_signed_prekey.as_ref()
+                .filter(|(_, replaced_at)| in_grace_period(*replaced_at))
+                .map(|(prekey, replaced_at)| StoredSignedPreKey::new(prekey, Some(*replaced_at))),
+            one_time_prekeys: self.one_time_prekeys.iter()
+                .filter(|(public_key, _)| Some(**public_key) != consumed)
+                .map(|(_, prekey)| prekey.private_bytes())
+                .collect(),
+        };
+
+        Ok(Zeroizing::new(bincode::serialize(&state)?))
+    }
+
+    /// Restore prekeys, e.g. as loaded by `StorageManager::get_prekey_state`
+    pub fn init_prekey_state(&mut self, state_data: &[u8]) -> Result<()> {
+        let state: PreKeyState = bincode::deserialize(state_data)?;
+
+        self.signed_prekey = state.signed_prekey.as_ref().map(StoredSignedPreKey::restore).transpose()?;
+        self.previous_signed_prekey = match &state.previous_signed_prekey {
+            Some(stored) => Some((stored.restore()?, stored.replaced_at.unwrap_or_else(chrono::Utc::now))),
+            None => None,
+        };
+        self.one_time_prekeys = state.one_time_prekeys.iter()
+            .map(|secret| {
+                let prekey = OneTimePreKey::from_private_bytes(*secret);
+                (prekey.public_key(), prekey)
+            })
+            .collect();
+        Ok(())
+    }
+
+    /// Number of one-time prekeys not consumed yet
+    pub fn one_time_prekey_count(&self) -> usize {
+        self.one_time_prekeys.len()
+    }
+
+    /// The signed prekey an initial message was built against: the current
+    /// one or, within its grace period, the one it replaced
+    fn signed_prekey_for(&self, message: &InitialMessage) -> Result<&SignedPreKey> {
+        let current = self.signed_prekey.as_ref()
+            .ok_or_else(|| VeterError::KeyManagement("No signed prekey generated".to_string()))?;
+        if current.public_key() == message.signed_prekey {
+            return Ok(current);
+        }
+
+        match &self.previous_signed_prekey {
+            Some((previous, replaced_at)) if previous.public_key() == message.signed_prekey && in_grace_period(*replaced_at) => {
+                Ok(previous)
+            }
+            _ => Err(VeterError::KeyManagement("Unknown signed prekey".to_string())),
+        }
+    }
+
+    /// The one-time prekey an initial message used, if any
+    fn one_time_prekey_for(&self, message: &InitialMessage) -> Result<Option<&OneTimePreKey>> {
+        message.one_time_prekey
+            .map(|public_key| self.one_time_prekeys.get(&public_key)
+                .ok_or_else(|| VeterError::KeyManagement("Unknown one-time prekey".to_string())))
+            .transpose()
+    }
+
+    /// Start a Double Ratchet session with a peer device in a room from the
+    /// device's prekey bundle. The X3DH initial message is attached to every
+    /// envelope for that device until it answers.
+    pub fn start_session(&mut self, room_id: RoomId, device_id: DeviceId, bundle: &KeyMaterial) -> Result<()> {
+        let (secret, message) = self.initiate_key_agreement(bundle)?;
+        let state = PairwiseSession {
+            ratchet: RatchetState::init_initiator(&secret, message.signed_prekey)?,
+            initial_message: Some(message),
+        };
+
+        self.init_session(room_id, device_id, state.to_bytes()?)
+    }
+
+    /// Record a room's type and membership.
+    ///
+    /// Group and channel rooms in [`RoomEncryption::Signal`] mode are
+    /// encrypted with sender keys. `devices` are the known devices of the
+    /// room's past and present members: those whose user is no longer a
+    /// member lose their session and sender key, so they receive nothing
+    /// sent afterwards, and this device's sender key is rotated. The new key
+    /// must be persisted (see [`CryptoManager::get_sender_key`]); the
+    /// returned devices are the ones whose state should be deleted from
+    /// storage. Membership of MLS rooms only changes through commits.
+    pub fn update_room(&mut self, room: &Room, devices: &[Device]) -> Result<Vec<DeviceId>> {
+        if let Some(previous) = self.rooms.get(&room.id) {
+            if previous.encryption != room.encryption {
+                return Err(VeterError::InvalidInput("Room encryption mode cannot change".to_string()));
+            }
+        }
+        let member_left = self.rooms.get(&room.id)
+            .is_some_and(|previous| previous.members.iter().any(|member| !room.members.contains(member)));
+        let mut removed = devices.iter()
+            .filter(|device| !room.members.contains(&device.user_id))
+            .filter(|device| {
+                self.sessions.contains_key(&(room.id, device.id)) || self.sender_keys.contains_key(&(room.id, device.id))
+            })
+            .map(|device| device.id)
+            .collect::<Vec<_>>();
+        removed.sort();
+        removed.dedup();
+
+        self.rooms.insert(room.id, room.clone());
+        for device_id in &removed {
+            self.drop_room_device(room.id, *device_id);
+        }
+        if member_left || !removed.is_empty() {
+            self.rotate_sender_key(room.id)?;
+        }
+        Ok(removed)
+    }
+
+    /// Create the MLS group of a new room in [`RoomEncryption::Mls`] mode,
+    /// with this device as its only member
+    pub fn create_mls_room(&mut self, room: &Room) -> Result<()> {
+        if room.encryption != RoomEncryption::Mls {
+            return Err(VeterError::InvalidInput(format!("Room {} does not use MLS", room.id)));
+        }
+
+        self.mls.create_group(room.id)?;
+        self.rooms.insert(room.id, room.clone());
+        Ok(())
+    }
+
+    /// Add devices to an MLS room using a KeyPackage from their published
+    /// key material that was not used before. The commit goes to the current
+    /// members, the welcome to the added devices.
+    pub fn add_mls_members(&mut self, room_id: RoomId, members: &[KeyMaterial]) -> Result<MlsCommit> {
+        self.mls.add_members(room_id, members)
+    }
+
+    /// Remove devices from an MLS room; the commit goes to all members
+    pub fn remove_mls_members(&mut self, room_id: RoomId, device_ids: &[DeviceId]) -> Result<MlsCommit> {
+        self.mls.remove_members(room_id, device_ids)
+    }
+
+    /// Propose leaving an MLS room; the proposal is committed by another member
+    pub fn leave_mls_room(&mut self, room_id: RoomId) -> Result<Vec<u8>> {
+        self.mls.leave_group(room_id)
+    }
+
+    /// Commit the proposals received for an MLS room
+    pub fn commit_mls_proposals(&mut self, room_id: RoomId) -> Result<MlsCommit> {
+        self.mls.commit_pending_proposals(room_id)
+    }
+
+    /// Join an MLS room from a welcome, returning the room id
+    pub fn join_mls_room(&mut self, welcome: &[u8]) -> Result<RoomId> {
+        self.mls.join_group(welcome)
+    }
+
+    /// Process a commit or proposal sent to an MLS room
+    pub fn process_mls_handshake(&mut self, room_id: RoomId, message: &[u8]) -> Result<MlsHandshake> {
+        self.mls.process_handshake(room_id, message)
+    }
+
+    /// Current epoch of an MLS room
+    pub fn mls_epoch(&self, room_id: RoomId) -> Result<u64> {
+        self.mls.epoch(room_id)
+    }
+
+    /// Export the MLS KeyPackage private keys and the KeyPackages used to
+    /// add other devices, to be persisted with
+    /// `StorageManager::store_mls_state` after generating key material,
+    /// joining or adding members
+    pub fn mls_state(&self) -> Result<Vec<u8>> {
+        self.mls.to_bytes()
+    }
+
+    /// Restore MLS state, e.g. as loaded by `StorageManager::get_mls_state`
+    pub fn init_mls_state(&mut self, state_data: &[u8]) -> Result<()> {
+        self.mls.load(state_data)
+    }
+
+    /// Export the MLS group state and epoch secrets of a room, to be
+    /// persisted with `StorageManager::store_mls_group` after every MLS
+    /// operation on the room
+    pub fn mls_group_state(&self, room_id: RoomId) -> Result<Vec<u8>> {
+        self.mls.group_to_bytes(room_id)
+    }
+
+    /// Restore the MLS group of a room, e.g. as loaded by
+    /// `StorageManager::get_mls_groups`
+    pub fn init_mls_group_state(&mut self, room_id: RoomId, state_data: &[u8]) -> Result<()> {
+        self.mls.load_group_state(room_id, state_data)
+    }
+
+    /// Rooms with an MLS group on this device
+    pub fn mls_rooms(&self) -> impl Iterator<Item = RoomId> + '_ {
+        self.mls.group_ids()
+    }
+
+    /// Forget a device that is no longer part of a room: its session and
+    /// sender key are dropped and this device's sender key is rotated
+    pub fn remove_room_device(&mut self, room_id: RoomId, device_id: DeviceId) -> Result<()> {
+        self.drop_room_device(room_id, device_id);
+        self.rotate_sender_key(room_id)
+    }
+
+    /// Encrypt message content for the room.
+    ///
+    /// In direct rooms the content is encrypted once and its key is wrapped
+    /// per recipient session. Group and channel rooms produce a single
+    /// sender-key ciphertext, with distribution messages attached for devices
+    /// that have not received the current sender key yet, and MLS rooms a
+    /// single MLS application message. Room, sender device
+    /// and message id are bound as associated data, so the envelope cannot be
+    /// replayed under another identity.
+    pub fn encrypt_message(&mut self, content: &[u8], room_id: RoomId, message_id: MessageId) -> Result<Vec<u8>> {
+        let ad = envelope::associated_data(room_id, self.device_id, message_id);
+        if self.uses_mls(room_id)? {
+            let message = self.mls.encrypt(room_id, content, &ad)?;
+            return MlsEnvelope { message }.to_bytes();
+        }
+        if self.uses_sender_keys(room_id) {
+            return self.encrypt_group_message(content, room_id, &ad);
+        }
+
+        let recipients = self.session_devices(room_id);
+        if recipients.is_empty() {
+            return Err(VeterError::KeyManagement(format!("No sessions for room {}", room_id)));
+        }
+
+        let (key, nonce, ciphertext) = envelope::seal(content, &ad)?;
+        let mut envelope = Envelope {
+            recipients: Vec::with_capacity(recipients.len()),
+            nonce,
+            ciphertext,
+        };
+        for device_id in recipients {
+            envelope.recipients.push(self.wrap_for_device(room_id, device_id, key.as_ref(), &ad)?);
+        }
+
+        envelope.to_bytes()
+    }
+
+    /// Encrypt a copy of a message this device sent or received to an active
+    /// legal hold on its user, for upload to the compliance service
+    pub fn escrow_message(&self, hold: &LegalHold, message: &Message) -> Result<EscrowedMessage> {
+        escrow::seal(&self.identity, self.device_id, hold, message)
+    }
+
+    /// Decrypt an envelope sent by a peer device.
+    ///
+    /// Legacy or unauthenticated layouts are rejected with
+    /// [`VeterError::InvalidEnvelope`]. Session and sender key state is only
+    /// advanced if the message decrypts.
+    pub fn decrypt_message(
+        &mut self,
+        encrypted: &[u8],
+        room_id: RoomId,
+        sender_device_id: DeviceId,
+        message_id: MessageId,
+    ) -> Result<Vec<u8>> {
+        let (plaintext, keys) = self.open_message(encrypted, room_id, sender_device_id, message_id)?;
+        self.commit_keys(keys)?;
+
         Ok(plaintext)
     }
 
-    /// Encrypt file content using ChaCha20-Poly1305
-    pub fn encrypt_file(&self, content: &[u8]) -> Result<Vec<u8>> {
-        let mut key_bytes = [0u8; 32];
-        let mut nonce_bytes = [0u8; 12];
-        
-        rand::thread_rng().fill_bytes(&mut key_bytes);
-        rand::thread_rng().fill_bytes(&mut nonce_bytes);
-        
-        let key = ChaChaKey::from_slice(&key_bytes);
-        let nonce = ChaChaNonce::from_slice(&nonce_bytes);
-        
-        let cipher = ChaCha20Poly1305::new(key);
-        let ciphertext = cipher.encrypt(nonce, content)
-            .map_err(|e| VeterError::Crypto(format!("File encryption failed: {}", e)))?;
-        
-        // Prepend key and nonce (TODO: encrypt with envelope key)
-        let mut result = Vec::new();
-        result.extend_from_slice(&key_bytes);
-        result.extend_from_slice(&nonce_bytes);
-        result.extend_from_slice(&ciphertext);
-        
+    /// Decrypt an envelope like [`CryptoManager::decrypt_message`] without
+    /// changing any key state. The advanced state is returned, to be
+    /// persisted as [`CryptoManager::staged_key_state`] together with the
+    /// message and then applied with [`CryptoManager::commit_keys`]. If the
+    /// message is not kept, dropping the staged keys leaves everything as it
+    /// was.
+    pub fn open_message(
+        &self,
+        encrypted: &[u8],
+        room_id: RoomId,
+        sender_device_id: DeviceId,
+        message_id: MessageId,
+    ) -> Result<(Vec<u8>, StagedKeys)> {
+        let ad = envelope::associated_data(room_id, sender_device_id, message_id);
+        let mut keys = StagedKeys {
+            room_id,
+            device_id: sender_device_id,
+            session: None,
+            sender_key: None,
+            mls_secrets: None,
+        };
+
+        if MlsEnvelope::matches(encrypted) {
+            let envelope = MlsEnvelope::from_bytes(encrypted)?;
+            let (plaintext, secrets) = self.mls.decrypt(room_id, sender_device_id, &envelope.message, &ad)?;
+            keys.mls_secrets = Some(secrets);
+            return Ok((plaintext, keys));
+        }
+        if GroupEnvelope::matches(encrypted) {
+            let (plaintext, session, sender_key) = self.decrypt_group_message(encrypted, room_id, sender_device_id, &ad)?;
+            keys.session = session;
+            keys.sender_key = Some(sender_key);
+            return Ok((plaintext, keys));
+        }
+
+        let envelope = Envelope::from_bytes(encrypted)?;
+        let recipient = envelope.recipient(self.device_id)?;
+        let (plaintext, session) = self.unwrap_from_device(room_id, sender_device_id, recipient, &ad, |key| {
+            envelope::open(key, &envelope.nonce, &envelope.ciphertext, &ad)
+        })?;
+        keys.session = Some(session);
+
+        Ok((plaintext, keys))
+    }
+
+    /// Key state as it will be once `keys` are applied, to be stored with
+    /// the message they were staged for
+    pub fn staged_key_state(&self, keys: &StagedKeys) -> Result<KeyStateUpdate> {
+        let (room_id, device_id) = (keys.room_id, keys.device_id);
+        let now = chrono::Utc::now();
+
+        let session = match &keys.session {
+            Some(staged) => Some(Session {
+                room_id,
+                device_id,
+                session_data: staged.state.to_bytes()?,
+                created_at: self.sessions.get(&(room_id, device_id)).map_or(now, |session| session.created_at),
+                updated_at: now,
+            }),
+            None => None,
+        };
+        let sender_key = keys.sender_key.as_ref().map(|state_data| SenderKey {
+            room_id,
+            device_id,
+            state_data: state_data.clone(),
+            created_at: self.sender_keys.get(&(room_id, device_id)).map_or(now, |sender_key| sender_key.created_at),
+            updated_at: now,
+        });
+        let mls_group = match &keys.mls_secrets {
+            Some(secrets) => Some((room_id, self.mls.staged_group_to_bytes(secrets)?)),
+            None => None,
+        };
+        let consumed = keys.session.as_ref().and_then(|staged| staged.one_time_prekey);
+        let prekey_state = match consumed {
+            Some(public_key) => Some((self.device_id, self.prekey_state_without(Some(public_key))?.to_vec())),
+            None => None,
+        };
+
+        Ok(KeyStateUpdate { session, sender_key, mls_group, prekey_state })
+    }
+
+    /// Apply key state staged by [`CryptoManager::open_message`]
+    pub fn commit_keys(&mut self, mut keys: StagedKeys) -> Result<()> {
+        let (room_id, device_id) = (keys.room_id, keys.device_id);
+        if let Some(session) = keys.session.take() {
+            self.commit_session(room_id, device_id, session)?;
+        }
+        if let Some(sender_key) = keys.sender_key.take() {
+            self.init_sender_key(room_id, device_id, sender_key)?;
+        }
+        if let Some(secrets) = keys.mls_secrets.take() {
+            self.mls.commit_secrets(secrets)?;
+        }
+        Ok(())
+    }
+
+    fn encrypt_group_message(&mut self, content: &[u8], room_id: RoomId, ad: &[u8]) -> Result<Vec<u8>> {
+        let mut record = match self.sender_keys.get(&(room_id, self.device_id)) {
+            Some(sender_key) => SenderKeyRecord::from_bytes(&sender_key.state_data)?,
+            None => SenderKeyRecord::generate(),
+        };
+
+        let mut distributions = Vec::new();
+        let pending = record.undistributed(&self.session_devices(room_id));
+        if !pending.is_empty() {
+            let distribution = Zeroizing::new(bincode::serialize(&record.distribution())?);
+            for device_id in pending {
+                distributions.push(self.wrap_for_device(room_id, device_id, &distribution, ad)?);
+                record.mark_distributed(device_id);
+            }
+        }
+
+        let message = record.encrypt(content, ad)?;
+        self.init_sender_key(room_id, self.device_id, record.to_bytes()?)?;
+
+        GroupEnvelope {
+            distributions,
+            key_id: message.key_id,
+            iteration: message.iteration,
+            ciphertext: message.ciphertext,
+            signature: message.signature,
+        }.to_bytes()
+    }
+
+    /// Decrypt a group message. A distribution attached for this device is
+    /// applied to copies of the pairwise session and sender key, which are
+    /// returned with the advanced sender key for the caller to store.
+    fn decrypt_group_message(
+        &self,
+        encrypted: &[u8],
+        room_id: RoomId,
+        sender_device_id: DeviceId,
+        ad: &[u8],
+    ) -> Result<(Vec<u8>, Option<StagedSession>, Vec<u8>)> {
+        let envelope = GroupEnvelope::from_bytes(encrypted)?;
+        let mut record = self.sender_keys.get(&(room_id, sender_device_id))
+            .map(|sender_key| SenderKeyRecord::from_bytes(&sender_key.state_data))
+            .transpose()?;
+
+        let mut session = None;
+        if let Some(recipient) = envelope.distributions.iter().find(|recipient| recipient.device_id == self.device_id) {
+            let (distribution, staged) = self.unwrap_from_device(room_id, sender_device_id, recipient, ad, |payload| {
+                Ok(bincode::deserialize::<SenderKeyDistribution>(payload)?)
+            })?;
+            match &mut record {
+                Some(record) => record.add_distribution(&distribution),
+                None => record = Some(SenderKeyRecord::from_distribution(&distribution)),
+            }
+            session = Some(staged);
+        }
+
+        let mut record = record
+            .ok_or_else(|| VeterError::KeyManagement(format!("No sender key from device {}", sender_device_id)))?;
+        let message = GroupMessage {
+            key_id: envelope.key_id,
+            iteration: envelope.iteration,
+            ciphertext: envelope.ciphertext,
+            signature: envelope.signature,
+        };
+        let plaintext = record.decrypt(&message, ad).map_err(|e| match e {
+            VeterError::Crypto(e) => VeterError::InvalidEnvelope(format!("Group message rejected: {}", e)),
+            e => e,
+        })?;
+
+        Ok((plaintext, session, record.to_bytes()?))
+    }
+
+    fn drop_room_device(&mut self, room_id: RoomId, device_id: DeviceId) {
+        if let Some(mut session) = self.sessions.remove(&(room_id, device_id)) {
+            session.session_data.zeroize();
+        }
+        if let Some(mut sender_key) = self.sender_keys.remove(&(room_id, device_id)) {
+            sender_key.state_data.zeroize();
+        }
+    }
+
+    fn rotate_sender_key(&mut self, room_id: RoomId) -> Result<()> {
+        if self.sender_keys.contains_key(&(room_id, self.device_id)) {
+            self.init_sender_key(room_id, self.device_id, SenderKeyRecord::generate().to_bytes()?)?;
+        }
+        Ok(())
+    }
+
+    fn uses_sender_keys(&self, room_id: RoomId) -> bool {
+        self.rooms.get(&room_id).is_some_and(|room| {
+            room.encryption == RoomEncryption::Signal
+                && matches!(room.room_type, RoomType::Group | RoomType::Channel)
+        })
+    }
+
+    /// Whether a room is in MLS mode; rooms joined through a welcome count
+    /// even before their record arrives
+    fn uses_mls(&self, room_id: RoomId) -> Result<bool> {
+        match self.rooms.get(&room_id) {
+            Some(room) => Ok(room.encryption == RoomEncryption::Mls),
+            None => self.mls.has_group(room_id),
+        }
+    }
+
+    /// Peer devices this device has a session with in a room
+    fn session_devices(&self, room_id: RoomId) -> Vec<DeviceId> {
+        self.sessions.keys()
+            .filter(|(session_room, _)| *session_room == room_id)
+            .map(|(_, device_id)| *device_id)
+            .collect()
+    }
+
+    /// Encrypt a payload for one device over its pairwise session
+    fn wrap_for_device(&mut self, room_id: RoomId, device_id: DeviceId, payload: &[u8], ad: &[u8]) -> Result<RecipientKey> {
+        let (wrapped_key, initial_message) = self.with_session(room_id, device_id, |state| {
+            Ok((state.ratchet.encrypt(payload, ad)?, state.initial_message.clone()))
+        })?;
+
+        Ok(RecipientKey { device_id, initial_message, wrapped_key })
+    }
+
+    /// Decrypt a payload addressed to this device over the pairwise session
+    /// with the sender and run `op` on it. A session is set up from the
+    /// attached initial message if needed. Nothing is stored: the advanced
+    /// session is returned for [`CryptoManager::commit_session`].
+    fn unwrap_from_device<T>(
+        &self,
+        room_id: RoomId,
+        sender_device_id: DeviceId,
+        recipient: &RecipientKey,
+        ad: &[u8],
+        op: impl Fn(&[u8]) -> Result<T>,
+    ) -> Result<(T, StagedSession)> {
+        let open = |mut state: PairwiseSession| {
+            let payload = state.ratchet.decrypt(&recipient.wrapped_key, ad).map_err(|e| match e {
+                VeterError::Crypto(e) => VeterError::InvalidEnvelope(format!("Failed to unwrap key: {}", e)),
+                e => e,
+            })?;
+            let result = op(&Zeroizing::new(payload))?;
+            state.initial_message = None;
+            Ok((result, state))
+        };
+
+        let existing = match self.sessions.get(&(room_id, sender_device_id)) {
+            Some(session) => PairwiseSession::from_bytes(&session.session_data).and_then(open),
+            None => Err(VeterError::KeyManagement(format!("No session with device {}", sender_device_id))),
+        };
+
+        match (existing, &recipient.initial_message) {
+            (Ok((result, state)), _) => Ok((result, StagedSession { state, one_time_prekey: None })),
+            // The sender started a new session, e.g. after reinstalling
+            (Err(_), Some(initial_message)) => {
+                let (result, state) = open(self.accept_session(initial_message)?)?;
+                Ok((result, StagedSession { state, one_time_prekey: initial_message.one_time_prekey }))
+            }
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    /// Set up the responder side of a session from an X3DH initial message.
+    /// The one-time prekey is only consumed when the session is committed.
+    fn accept_session(&self, message: &InitialMessage) -> Result<PairwiseSession> {
+        let signed_prekey = self.signed_prekey_for(message)?;
+        let one_time_prekey = self.one_time_prekey_for(message)?;
+
+        let secret = x3dh::respond(&self.identity, signed_prekey, one_time_prekey, message)?;
+        Ok(PairwiseSession {
+            ratchet: RatchetState::init_responder(&secret, signed_prekey.secret())?,
+            initial_message: None,
+        })
+    }
+
+    /// Store a session returned by [`CryptoManager::unwrap_from_device`],
+    /// consuming the one-time prekey a new session was set up with
+    fn commit_session(&mut self, room_id: RoomId, device_id: DeviceId, staged: StagedSession) -> Result<()> {
+        if let Some(public_key) = staged.one_time_prekey {
+            self.one_time_prekeys.remove(&public_key);
+        }
+        self.init_session(room_id, device_id, staged.state.to_bytes()?)
+    }
+
+    /// Run an operation on a pairwise session and write the advanced state
+    /// back into `session_data` if it succeeds
+    fn with_session<T>(
+        &mut self,
+        room_id: RoomId,
+        device_id: DeviceId,
+        op: impl FnOnce(&mut PairwiseSession) -> Result<T>,
+    ) -> Result<T> {
+        let session = self.sessions.get_mut(&(room_id, device_id))
+            .ok_or_else(|| VeterError::KeyManagement(format!("No session with device {}", device_id)))?;
+
+        let mut state = PairwiseSession::from_bytes(&session.session_data)?;
+        let result = op(&mut state)?;
+        let session_data = state.to_bytes()?;
+        session.session_data.zeroize();
+        session.session_data = session_data;
+        session.updated_at = chrono::Utc::now();
+
         Ok(result)
     }
 
+    /// Encrypt file content using ChaCha20-Poly1305 under a fresh key.
+    ///
+    /// Returns (key, blob): the blob can be uploaded anywhere, the key must
+    /// only travel inside an encrypted message.
+    pub fn encrypt_file(&self, content: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+        envelope::seal_file(content)
+    }
+
     /// Decrypt file content using ChaCha20-Poly1305
-    pub fn decrypt_file(&self, encrypted: &[u8]) -> Result<Vec<u8>> {
-        if encrypted.len() < 44 {
-            return Err(VeterError::Crypto("Invalid encrypted file format".to_string()));
-        }
-        
-        let key_bytes = &encrypted[0..32];
-        let nonce_bytes = &encrypted[32..44];
-        let ciphertext = &encrypted[44..];
-        
-        let key = ChaChaKey::from_slice(key_bytes);
-        let nonce = ChaChaNonce::from_slice(nonce_bytes);
-        
-        let cipher = ChaCha20Poly1305::new(key);
-        let plaintext = cipher.decrypt(nonce, ciphertext)
-            .map_err(|e| VeterError::Crypto(format!("File decryption failed: {}", e)))?;
-        
-        Ok(plaintext)
+    pub fn decrypt_file(&self, key: &[u8], encrypted: &[u8]) -> Result<Vec<u8>> {
+        envelope::open_file(key, encrypted)
     }
 
     /// Generate HMAC for message authentication
     pub fn generate_hmac(&self, data: &[u8]) -> Result<Vec<u8>> {
-        let mut mac = Hmac::<Sha256>::new_from_slice(&self.identity_key)
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.identity.private_bytes())
             .map_err(|e| VeterError::Crypto(format!("HMAC creation failed: {}", e)))?;
         
         mac.update(data);
@@ -140,7 +729,7 @@ impl CryptoManager {
 
     /// Verify HMAC for message authentication
     pub fn verify_hmac(&self, data: &[u8], mac: &[u8]) -> Result<bool> {
-        let mut expected_mac = Hmac::<Sha256>::new_from_slice(&self.identity_key)
+        let mut expected_mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.identity.private_bytes())
             .map_err(|e| VeterError::Crypto(format!("HMAC creation failed: {}", e)))?;
         
         expected_mac.update(data);
@@ -149,22 +738,153 @@ impl CryptoManager {
         Ok(expected.as_slice() == mac)
     }
 
-    /// Initialize session for a room
-    pub fn init_session(&mut self, room_id: RoomId, session_data: Vec<u8>) -> Result<()> {
+    /// Initialize the session with a peer device in a room from serialized
+    /// session state, e.g. as loaded by `StorageManager::get_session`
+    pub fn init_session(&mut self, room_id: RoomId, device_id: DeviceId, session_data: Vec<u8>) -> Result<()> {
         let session = Session {
             room_id,
-            device_id: self.device_id,
+            device_id,
             session_data,
             created_at: chrono::Utc::now(),
             updated_at: chrono::Utc::now(),
         };
         
-        self.sessions.insert(room_id, session);
+        if let Some(mut replaced) = self.sessions.insert((room_id, device_id), session) {
+            replaced.session_data.zeroize();
+        }
         Ok(())
     }
 
-    /// Get session for a room
-    pub fn get_session(&self, room_id: RoomId) -> Option<&Session> {
-        self.sessions.get(&room_id)
+    /// Get the session with a peer device in a room
+    pub fn get_session(&self, room_id: RoomId, device_id: DeviceId) -> Option<&Session> {
+        self.sessions.get(&(room_id, device_id))
+    }
+
+    /// Initialize the sender key of a device in a room from serialized
+    /// state, e.g. as loaded by `StorageManager::get_sender_key`
+    pub fn init_sender_key(&mut self, room_id: RoomId, device_id: DeviceId, state_data: Vec<u8>) -> Result<()> {
+        let now = chrono::Utc::now();
+        let created_at = self.sender_keys.get(&(room_id, device_id))
+            .map_or(now, |sender_key| sender_key.created_at);
+
+        let replaced = self.sender_keys.insert((room_id, device_id), SenderKey {
+            room_id,
+            device_id,
+            state_data,
+            created_at,
+            updated_at: now,
+        });
+        if let Some(mut replaced) = replaced {
+            replaced.state_data.zeroize();
+        }
+        Ok(())
+    }
+
+    /// Get the sender key of a device in a room; this device's own key is
+    /// stored under its own device id
+    pub fn get_sender_key(&self, room_id: RoomId, device_id: DeviceId) -> Option<&SenderKey> {
+        self.sender_keys.get(&(room_id, device_id))
+    }
+
+    /// All pairwise sessions, e.g. to persist them
+    pub fn sessions(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    /// All sender keys, this device's own included
+    pub fn sender_keys(&self) -> impl Iterator<Item = &SenderKey> {
+        self.sender_keys.values()
+    }
+}
+
+impl Drop for CryptoManager {
+    /// Wipe the serialized session and sender key state, which holds chain
+    /// keys. The identity and prekeys wipe themselves.
+    fn drop(&mut self) {
+        for session in self.sessions.values_mut() {
+            session.session_data.zeroize();
+        }
+        for sender_key in self.sender_keys.values_mut() {
+            sender_key.state_data.zeroize();
+        }
+    }
+}
+
+/// Whether a signed prekey replaced at `replaced_at` is still accepted
+fn in_grace_period(replaced_at: chrono::DateTime<chrono::Utc>) -> bool {
+    chrono::Utc::now() - replaced_at < chrono::Duration::days(SIGNED_PREKEY_GRACE_DAYS)
+}
+
+/// Prekey private keys, as exported by [`CryptoManager::prekey_state`]
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct PreKeyState {
+    signed_prekey: Option<StoredSignedPreKey>,
+    previous_signed_prekey: Option<StoredSignedPreKey>,
+    one_time_prekeys: Vec<[u8; 32]>,
+}
+
+#[derive(Serialize, Deserialize, Zeroize)]
+struct StoredSignedPreKey {
+    secret: [u8; 32],
+    signature: Vec<u8>,
+    #[zeroize(skip)]
+    replaced_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl StoredSignedPreKey {
+    fn new(prekey: &SignedPreKey, replaced_at: Option<chrono::DateTime<chrono::Utc>>) -> Self {
+        Self {
+            secret: prekey.private_bytes(),
+            signature: prekey.signature().to_vec(),
+            replaced_at,
+        }
+    }
+
+    fn restore(&self) -> Result<SignedPreKey> {
+        let signature = self.signature.as_slice().try_into()
+            .map_err(|_| VeterError::KeyManagement("Signed prekey signature must be 64 bytes".to_string()))?;
+        Ok(SignedPreKey::from_parts(self.secret, signature))
+    }
+}
+
+/// Pairwise session state stored in `Session.session_data`
+#[derive(Serialize, Deserialize)]
+struct PairwiseSession {
+    ratchet: RatchetState,
+    initial_message: Option<InitialMessage>,
+}
+
+/// Key state advanced by decrypting a message, not applied yet, see
+/// [`CryptoManager::open_message`]
+pub struct StagedKeys {
+    room_id: RoomId,
+    device_id: DeviceId,
+    session: Option<StagedSession>,
+    sender_key: Option<Vec<u8>>,
+    mls_secrets: Option<mls::StagedSecrets>,
+}
+
+impl Drop for StagedKeys {
+    fn drop(&mut self) {
+        if let Some(sender_key) = &mut self.sender_key {
+            sender_key.zeroize();
+        }
+    }
+}
+
+/// Pairwise session advanced by a received payload, not stored yet
+struct StagedSession {
+    state: PairwiseSession,
+    /// One-time prekey used by a newly accepted session
+    one_time_prekey: Option<[u8; 32]>,
+}
+
+impl PairwiseSession {
+    fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(data)?)
+    }
+
+    fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
     }
 }
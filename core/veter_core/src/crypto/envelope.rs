@@ -0,0 +1,209 @@
+//! Versioned envelope formats for messages and files
+//!
+//! A message envelope starts with the `VTE` magic and a version byte,
+//! followed by the bincode-encoded [`Envelope`]. The content is encrypted
+//! once with a random content key, and that key is wrapped separately for
+//! every recipient device's Double Ratchet session. Group rooms use the
+//! `VTG` magic and a [`GroupEnvelope`] encrypted with the sender key, MLS
+//! rooms the `VTM` magic and an [`MlsEnvelope`] around an MLS message. File
+//! blobs use the `VTF` magic; their key travels inside the (already
+//! encrypted) message instead.
+
+use crate::{VeterError, Result, models::*};
+use super::x3dh::InitialMessage;
+use aes_gcm::{Aes256Gcm, Key, Nonce, KeyInit};
+use aes_gcm::aead::{Aead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key as ChaChaKey, Nonce as ChaChaNonce};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+/// Current envelope version
+pub const ENVELOPE_VERSION: u8 = 1;
+
+const MESSAGE_MAGIC: &[u8; 3] = b"VTE";
+const GROUP_MAGIC: &[u8; 3] = b"VTG";
+const MLS_MAGIC: &[u8; 3] = b"VTM";
+const FILE_MAGIC: &[u8; 3] = b"VTF";
+const AD_CONTEXT: &[u8] = b"Veter Envelope v1";
+
+/// Per-message content key, zeroized on drop
+pub type ContentKey = Zeroizing<[u8; 32]>;
+
+/// Encrypted message envelope
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope {
+    pub recipients: Vec<RecipientKey>,
+    pub nonce: [u8; 12],
+    pub ciphertext: Vec<u8>,
+}
+
+/// Content key wrapped for one recipient device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientKey {
+    pub device_id: DeviceId,
+    /// Present until the recipient has answered, so it can set up the session
+    pub initial_message: Option<InitialMessage>,
+    pub wrapped_key: Vec<u8>,
+}
+
+impl Envelope {
+    /// Encode the envelope with its magic and version prefix
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut result = MESSAGE_MAGIC.to_vec();
+        result.push(ENVELOPE_VERSION);
+        result.extend_from_slice(&bincode::serialize(self)?);
+        Ok(result)
+    }
+
+    /// Decode an envelope, rejecting unknown versions and legacy layouts
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let body = check_prefix(data, MESSAGE_MAGIC)?;
+        bincode::deserialize(body)
+            .map_err(|e| VeterError::InvalidEnvelope(format!("Malformed envelope: {}", e)))
+    }
+
+    /// Wrapped key addressed to a device
+    pub fn recipient(&self, device_id: DeviceId) -> Result<&RecipientKey> {
+        self.recipients.iter()
+            .find(|recipient| recipient.device_id == device_id)
+            .ok_or_else(|| VeterError::InvalidEnvelope("Message is not addressed to this device".to_string()))
+    }
+}
+
+/// Group message envelope: one ciphertext for the whole room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupEnvelope {
+    /// Sender key distribution messages for devices that lack the current key
+    pub distributions: Vec<RecipientKey>,
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl GroupEnvelope {
+    /// Encode the envelope with its magic and version prefix
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut result = GROUP_MAGIC.to_vec();
+        result.push(ENVELOPE_VERSION);
+        result.extend_from_slice(&bincode::serialize(self)?);
+        Ok(result)
+    }
+
+    /// Decode an envelope, rejecting unknown versions
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let body = check_prefix(data, GROUP_MAGIC)?;
+        bincode::deserialize(body)
+            .map_err(|e| VeterError::InvalidEnvelope(format!("Malformed envelope: {}", e)))
+    }
+
+    /// Whether the data is a group envelope
+    pub fn matches(data: &[u8]) -> bool {
+        data.starts_with(GROUP_MAGIC)
+    }
+}
+
+/// MLS message envelope: an MLS application message for the room's group
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MlsEnvelope {
+    pub message: Vec<u8>,
+}
+
+impl MlsEnvelope {
+    /// Encode the envelope with its magic and version prefix
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut result = MLS_MAGIC.to_vec();
+        result.push(ENVELOPE_VERSION);
+        result.extend_from_slice(&bincode::serialize(self)?);
+        Ok(result)
+    }
+
+    /// Decode an envelope, rejecting unknown versions
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        let body = check_prefix(data, MLS_MAGIC)?;
+        bincode::deserialize(body)
+            .map_err(|e| VeterError::InvalidEnvelope(format!("Malformed envelope: {}", e)))
+    }
+
+    /// Whether the data is an MLS envelope
+    pub fn matches(data: &[u8]) -> bool {
+        data.starts_with(MLS_MAGIC)
+    }
+}
+
+/// Associated data binding an envelope to its room, sender device and message
+pub fn associated_data(room_id: RoomId, sender_device_id: DeviceId, message_id: MessageId) -> Vec<u8> {
+    let mut ad = AD_CONTEXT.to_vec();
+    ad.push(ENVELOPE_VERSION);
+    ad.extend_from_slice(room_id.as_bytes());
+    ad.extend_from_slice(sender_device_id.as_bytes());
+    ad.extend_from_slice(message_id.as_bytes());
+    ad
+}
+
+/// Encrypt content under a fresh content key, returning (key, nonce, ciphertext)
+pub fn seal(content: &[u8], ad: &[u8]) -> Result<(ContentKey, [u8; 12], Vec<u8>)> {
+    let mut key = ContentKey::new([0u8; 32]);
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(key.as_mut());
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_ref()));
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: content, aad: ad })
+        .map_err(|e| VeterError::Crypto(format!("Encryption failed: {}", e)))?;
+
+    Ok((key, nonce, ciphertext))
+}
+
+/// Decrypt content with an unwrapped content key
+pub fn open(key: &[u8], nonce: &[u8; 12], ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+    if key.len() != 32 {
+        return Err(VeterError::InvalidEnvelope("Invalid content key".to_string()));
+    }
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key));
+    cipher.decrypt(Nonce::from_slice(nonce), Payload { msg: ciphertext, aad: ad })
+        .map_err(|_| VeterError::InvalidEnvelope("Message authentication failed".to_string()))
+}
+
+/// Encrypt a file blob under a fresh key, returning (key, blob)
+pub fn seal_file(content: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut key = vec![0u8; 32];
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut key);
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = ChaCha20Poly1305::new(ChaChaKey::from_slice(&key));
+    let ciphertext = cipher.encrypt(ChaChaNonce::from_slice(&nonce), Payload { msg: content, aad: FILE_MAGIC })
+        .map_err(|e| VeterError::Crypto(format!("File encryption failed: {}", e)))?;
+
+    let mut blob = FILE_MAGIC.to_vec();
+    blob.push(ENVELOPE_VERSION);
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&ciphertext);
+    Ok((key, blob))
+}
+
+/// Decrypt a file blob with the key taken from its message
+pub fn open_file(key: &[u8], blob: &[u8]) -> Result<Vec<u8>> {
+    let body = check_prefix(blob, FILE_MAGIC)?;
+    if key.len() != 32 || body.len() < 12 {
+        return Err(VeterError::InvalidEnvelope("Invalid encrypted file format".to_string()));
+    }
+
+    let cipher = ChaCha20Poly1305::new(ChaChaKey::from_slice(key));
+    cipher.decrypt(ChaChaNonce::from_slice(&body[..12]), Payload { msg: &body[12..], aad: FILE_MAGIC })
+        .map_err(|_| VeterError::InvalidEnvelope("File authentication failed".to_string()))
+}
+
+fn check_prefix<'a>(data: &'a [u8], magic: &[u8; 3]) -> Result<&'a [u8]> {
+    if data.len() < 4 || &data[..3] != magic {
+        return Err(VeterError::InvalidEnvelope("Unrecognized or legacy layout".to_string()));
+    }
+    if data[3] != ENVELOPE_VERSION {
+        return Err(VeterError::InvalidEnvelope(format!("Unsupported envelope version {}", data[3])));
+    }
+    Ok(&data[4..])
+}
@@ -0,0 +1,99 @@
+//! Safety numbers for identity key verification
+//!
+//! Each side of a conversation gets a 30-digit fingerprint derived from its
+//! user id and identity key by iterated SHA-512, as in Signal. The safety
+//! number is both fingerprints in a fixed order, so the two users see the
+//! same 60 digits. The QR payload carries the raw fingerprints so that one
+//! device can scan the other's screen instead of comparing digits.
+
+use crate::{VeterError, Result, models::UserId};
+use sha2::{Digest, Sha512};
+
+/// Version of the fingerprint derivation and QR payload
+pub const FINGERPRINT_VERSION: u8 = 1;
+
+const ITERATIONS: usize = 5200;
+const FINGERPRINT_LENGTH: usize = 30;
+
+/// Safety number of a pair of identity keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SafetyNumber {
+    local: [u8; FINGERPRINT_LENGTH],
+    remote: [u8; FINGERPRINT_LENGTH],
+}
+
+impl SafetyNumber {
+    /// Compute the safety number between our identity key and a peer's
+    pub fn new(local_user_id: UserId, local_identity_key: &[u8], remote_user_id: UserId, remote_identity_key: &[u8]) -> Self {
+        Self {
+            local: fingerprint(local_user_id, local_identity_key),
+            remote: fingerprint(remote_user_id, remote_identity_key),
+        }
+    }
+
+    /// 60 decimal digits, identical on both devices
+    pub fn displayable(&self) -> String {
+        let local = digits(&self.local);
+        let remote = digits(&self.remote);
+
+        if local <= remote {
+            local + &remote
+        } else {
+            remote + &local
+        }
+    }
+
+    /// Payload to show as a QR code: version, then our fingerprint and the
+    /// peer's fingerprint as seen by us
+    pub fn qr_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(1 + 2 * FINGERPRINT_LENGTH);
+        payload.push(FINGERPRINT_VERSION);
+        payload.extend_from_slice(&self.local);
+        payload.extend_from_slice(&self.remote);
+        payload
+    }
+
+    /// Check a QR payload scanned from the peer's screen. Returns `false`
+    /// if either identity key differs from the one the peer sees.
+    pub fn matches_qr(&self, payload: &[u8]) -> Result<bool> {
+        if payload.len() != 1 + 2 * FINGERPRINT_LENGTH {
+            return Err(VeterError::InvalidInput("Invalid safety number QR code".to_string()));
+        }
+        if payload[0] != FINGERPRINT_VERSION {
+            return Err(VeterError::InvalidInput(format!("Unsupported safety number version {}", payload[0])));
+        }
+
+        // The peer's local fingerprint is our remote one and vice versa
+        let (their_local, their_remote) = payload[1..].split_at(FINGERPRINT_LENGTH);
+        Ok(their_local == self.remote && their_remote == self.local)
+    }
+}
+
+/// Iterated hash over a user's identity key, truncated to 30 bytes
+fn fingerprint(user_id: UserId, identity_key: &[u8]) -> [u8; FINGERPRINT_LENGTH] {
+    let mut hash = Sha512::new()
+        .chain_update([0, FINGERPRINT_VERSION])
+        .chain_update(identity_key)
+        .chain_update(user_id.as_bytes())
+        .finalize();
+    for _ in 1..ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(identity_key)
+            .finalize();
+    }
+
+    let mut fingerprint = [0u8; FINGERPRINT_LENGTH];
+    fingerprint.copy_from_slice(&hash[..FINGERPRINT_LENGTH]);
+    fingerprint
+}
+
+/// Encode a fingerprint as six groups of five digits
+fn digits(fingerprint: &[u8; FINGERPRINT_LENGTH]) -> String {
+    fingerprint.chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
@@ -0,0 +1,590 @@
+//! MLS (RFC 9420) group backend
+//!
+//! Rooms created in [`RoomEncryption::Mls`] mode are backed by an MLS group
+//! whose group id is the room id. Members are identified by their device id
+//! in a basic credential and sign with their Ed25519 identity key, so a leaf
+//! can be checked against the identity key published in the directory.
+//! Every group has its own provider, so its state and epoch secrets can be
+//! rolled back and persisted on their own (see
+//! `StorageManager::store_mls_group`). Unused KeyPackages live in a separate
+//! provider, exported for `StorageManager::store_mls_state`.
+
+use crate::{VeterError, Result, models::*};
+use super::x3dh::IdentityKeyPair;
+use openmls::prelude::*;
+use openmls::prelude::tls_codec::{Deserialize as _, Serialize as _};
+use openmls_basic_credential::SignatureKeyPair;
+use openmls_rust_crypto::{MemoryStorage, RustCrypto};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{RwLockReadGuard, RwLockWriteGuard};
+use zeroize::Zeroize;
+
+/// Ciphersuite used for all MLS rooms
+const CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519;
+
+/// Storage label of the message secrets, the only group state that
+/// application messages advance
+const MESSAGE_SECRETS_LABEL: &[u8] = b"MessageSecrets";
+
+/// Number of KeyPackages used to add other devices that are remembered, so
+/// a stale copy of the directory cannot make us use one twice
+const MAX_USED_KEY_PACKAGES: usize = 1000;
+
+/// Handshake messages produced by a membership change
+#[derive(Debug, Clone)]
+pub struct MlsCommit {
+    /// Commit to deliver to the existing members of the room
+    pub commit: Vec<u8>,
+    /// Welcome to deliver to the added devices, if any
+    pub welcome: Option<Vec<u8>>,
+}
+
+/// Storage entries of a provider, as (key, value)
+type Entries = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Message secrets advanced by decrypting an application message, not
+/// applied to the group yet
+pub struct StagedSecrets {
+    room_id: RoomId,
+    entries: Entries,
+}
+
+impl Drop for StagedSecrets {
+    fn drop(&mut self) {
+        wipe(self.entries.iter_mut().map(|(_, value)| value));
+    }
+}
+
+/// Outcome of processing a handshake message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MlsHandshake {
+    /// A proposal was queued for the next commit
+    ProposalQueued,
+    /// A commit was merged and the group moved to a new epoch
+    CommitMerged { epoch: u64 },
+    /// A commit removed this device from the group
+    Removed,
+}
+
+/// OpenMLS provider with in-memory storage that can be exported
+#[derive(Default)]
+struct MlsProvider {
+    crypto: RustCrypto,
+    storage: MemoryStorage,
+}
+
+impl MlsProvider {
+    fn with_values(values: HashMap<Vec<u8>, Vec<u8>>) -> Self {
+        let provider = Self::default();
+        *provider.storage.values.write().unwrap_or_else(|e| e.into_inner()) = values;
+        provider
+    }
+
+    fn values(&self) -> Result<RwLockReadGuard<'_, HashMap<Vec<u8>, Vec<u8>>>> {
+        self.storage.values.read()
+            .map_err(|_| VeterError::Internal("MLS storage lock poisoned".to_string()))
+    }
+
+    fn values_mut(&self) -> Result<RwLockWriteGuard<'_, HashMap<Vec<u8>, Vec<u8>>>> {
+        self.storage.values.write()
+            .map_err(|_| VeterError::Internal("MLS storage lock poisoned".to_string()))
+    }
+}
+
+impl Drop for MlsProvider {
+    /// Wipe the stored private keys and epoch secrets
+    fn drop(&mut self) {
+        let values = self.storage.values.get_mut().unwrap_or_else(|e| e.into_inner());
+        wipe(values.values_mut());
+    }
+}
+
+/// Device-wide MLS state, as exported by [`MlsClient::to_bytes`]
+#[derive(Default, Serialize, Deserialize)]
+struct KeyPackageState {
+    key_packages: HashMap<Vec<u8>, Vec<u8>>,
+    used_key_packages: VecDeque<Vec<u8>>,
+}
+
+impl Drop for KeyPackageState {
+    fn drop(&mut self) {
+        wipe(self.key_packages.values_mut());
+    }
+}
+
+impl OpenMlsProvider for MlsProvider {
+    type CryptoProvider = RustCrypto;
+    type RandProvider = RustCrypto;
+    type StorageProvider = MemoryStorage;
+
+    fn storage(&self) -> &Self::StorageProvider {
+        &self.storage
+    }
+
+    fn crypto(&self) -> &Self::CryptoProvider {
+        &self.crypto
+    }
+
+    fn rand(&self) -> &Self::RandProvider {
+        &self.crypto
+    }
+}
+
+/// MLS client of this device
+pub struct MlsClient {
+    /// KeyPackages this device published and has not joined with yet
+    key_packages: MlsProvider,
+    groups: HashMap<RoomId, MlsProvider>,
+    /// Hash references of other devices' KeyPackages used to add them,
+    /// oldest first
+    used_key_packages: VecDeque<Vec<u8>>,
+    signer: SignatureKeyPair,
+    credential: CredentialWithKey,
+}
+
+impl MlsClient {
+    /// Create a client signing with the device's identity key
+    pub fn new(identity: &IdentityKeyPair, device_id: DeviceId) -> Self {
+        let signer = SignatureKeyPair::from_raw(
+            SignatureScheme::ED25519,
+            identity.private_bytes().to_vec(),
+            identity.public_key().to_vec(),
+        );
+        let credential = CredentialWithKey {
+            credential: BasicCredential::new(device_id.as_bytes().to_vec()).into(),
+            signature_key: signer.public().into(),
+        };
+
+        Self {
+            key_packages: MlsProvider::default(),
+            groups: HashMap::new(),
+            used_key_packages: VecDeque::new(),
+            signer,
+            credential,
+        }
+    }
+
+    /// Restore the device-wide state exported with [`MlsClient::to_bytes`]
+    pub fn load(&mut self, data: &[u8]) -> Result<()> {
+        let mut state: KeyPackageState = bincode::deserialize(data)?;
+        let mut key_packages = self.key_packages.values_mut()?;
+        wipe(key_packages.values_mut());
+        *key_packages = std::mem::take(&mut state.key_packages);
+        self.used_key_packages = std::mem::take(&mut state.used_key_packages);
+        Ok(())
+    }
+
+    /// Export the device-wide state: unused KeyPackages and the KeyPackages
+    /// used to add other devices
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let state = KeyPackageState {
+            key_packages: self.key_packages.values()?.clone(),
+            used_key_packages: self.used_key_packages.clone(),
+        };
+        Ok(bincode::serialize(&state)?)
+    }
+
+    /// Restore the state of a group exported with [`MlsClient::group_to_bytes`]
+    pub fn load_group_state(&mut self, room_id: RoomId, data: &[u8]) -> Result<()> {
+        let values = bincode::deserialize(data)?;
+        self.groups.insert(room_id, MlsProvider::with_values(values));
+        Ok(())
+    }
+
+    /// Export the state of a group: tree, epoch and message secrets
+    pub fn group_to_bytes(&self, room_id: RoomId) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(&*self.provider(room_id)?.values()?)?)
+    }
+
+    /// Rooms with a group on this device
+    pub fn group_ids(&self) -> impl Iterator<Item = RoomId> + '_ {
+        self.groups.keys().copied()
+    }
+
+    /// Generate KeyPackages to publish to the directory
+    pub fn generate_key_packages(&self, count: usize) -> Result<Vec<Vec<u8>>> {
+        (0..count)
+            .map(|_| {
+                let bundle = KeyPackage::builder()
+                    .build(CIPHERSUITE, &self.key_packages, &self.signer, self.credential.clone())
+                    .map_err(|e| VeterError::KeyManagement(format!("Failed to create KeyPackage: {}", e)))?;
+                bundle.key_package().tls_serialize_detached()
+                    .map_err(|e| VeterError::Serialization(format!("Failed to encode KeyPackage: {}", e)))
+            })
+            .collect()
+    }
+
+    /// Whether this device is a member of the room's group
+    pub fn has_group(&self, room_id: RoomId) -> Result<bool> {
+        match self.groups.get(&room_id) {
+            Some(provider) => Ok(load_group(provider, room_id)?.is_some()),
+            None => Ok(false),
+        }
+    }
+
+    /// Create the group for a new room with this device as its only member
+    pub fn create_group(&mut self, room_id: RoomId) -> Result<()> {
+        let config = MlsGroupCreateConfig::builder()
+            .ciphersuite(CIPHERSUITE)
+            .use_ratchet_tree_extension(true)
+            .build();
+
+        let provider = MlsProvider::default();
+        MlsGroup::new_with_group_id(
+            &provider,
+            &self.signer,
+            &config,
+            GroupId::from_slice(room_id.as_bytes()),
+            self.credential.clone(),
+        )
+        .map_err(|e| VeterError::Crypto(format!("Failed to create MLS group: {}", e)))?;
+        self.groups.insert(room_id, provider);
+        Ok(())
+    }
+
+    /// Add devices from their published KeyPackages and merge the commit.
+    ///
+    /// Each device is added with the first of its KeyPackages not used
+    /// before. The KeyPackage must be signed by the identity key it is
+    /// published with, so the directory cannot substitute its own key.
+    pub fn add_members(&mut self, room_id: RoomId, members: &[KeyMaterial]) -> Result<MlsCommit> {
+        let mut references = Vec::with_capacity(members.len());
+        let key_packages = members.iter()
+            .map(|material| {
+                let key_package = material.mls_key_packages.iter()
+                    .map(|key_package| self.validate_key_package(key_package, &material.identity_key))
+                    .find(|key_package| match key_package {
+                        Ok((_, reference)) => !self.used_key_packages.contains(reference),
+                        Err(_) => true,
+                    })
+                    .ok_or_else(|| VeterError::KeyManagement("No unused MLS KeyPackage published".to_string()))??;
+                references.push(key_package.1);
+                Ok(key_package.0)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let commit = transaction(self.provider(room_id)?, None, |provider| {
+            let mut group = group(provider, room_id)?;
+            let (commit, welcome, _) = group.add_members(provider, &self.signer, &key_packages)
+                .map_err(|e| VeterError::Crypto(format!("Failed to add MLS members: {}", e)))?;
+            merge_pending_commit(&mut group, provider)?;
+
+            Ok(MlsCommit {
+                commit: encode(&commit)?,
+                welcome: Some(encode(&welcome)?),
+            })
+        })?;
+
+        self.used_key_packages.extend(references);
+        while self.used_key_packages.len() > MAX_USED_KEY_PACKAGES {
+            self.used_key_packages.pop_front();
+        }
+        Ok(commit)
+    }
+
+    /// Remove devices from the group and merge the commit
+    pub fn remove_members(&self, room_id: RoomId, device_ids: &[DeviceId]) -> Result<MlsCommit> {
+        transaction(self.provider(room_id)?, None, |provider| {
+            let mut group = group(provider, room_id)?;
+            let leaves = device_ids.iter()
+                .map(|device_id| member_leaf(&group, *device_id))
+                .collect::<Result<Vec<_>>>()?;
+            let (commit, welcome, _) = group.remove_members(provider, &self.signer, &leaves)
+                .map_err(|e| VeterError::Crypto(format!("Failed to remove MLS members: {}", e)))?;
+            merge_pending_commit(&mut group, provider)?;
+
+            Ok(MlsCommit {
+                commit: encode(&commit)?,
+                welcome: welcome.map(|welcome| encode(&welcome)).transpose()?,
+            })
+        })
+    }
+
+    /// Propose removing this device from the group; another member has to
+    /// commit the proposal
+    pub fn leave_group(&self, room_id: RoomId) -> Result<Vec<u8>> {
+        transaction(self.provider(room_id)?, None, |provider| {
+            let mut group = group(provider, room_id)?;
+            let proposal = group.leave_group(provider, &self.signer)
+                .map_err(|e| VeterError::Crypto(format!("Failed to leave MLS group: {}", e)))?;
+            encode(&proposal)
+        })
+    }
+
+    /// Commit all queued proposals and merge the commit
+    pub fn commit_pending_proposals(&self, room_id: RoomId) -> Result<MlsCommit> {
+        transaction(self.provider(room_id)?, None, |provider| {
+            let mut group = group(provider, room_id)?;
+            let (commit, welcome, _) = group.commit_to_pending_proposals(provider, &self.signer)
+                .map_err(|e| VeterError::Crypto(format!("Failed to commit MLS proposals: {}", e)))?;
+            merge_pending_commit(&mut group, provider)?;
+
+            Ok(MlsCommit {
+                commit: encode(&commit)?,
+                welcome: welcome.map(|welcome| encode(&welcome)).transpose()?,
+            })
+        })
+    }
+
+    /// Join a group from a Welcome, returning its room id. The KeyPackage
+    /// the welcome was made for is consumed.
+    pub fn join_group(&mut self, welcome: &[u8]) -> Result<RoomId> {
+        let welcome = match decode(welcome)?.extract() {
+            MlsMessageBodyIn::Welcome(welcome) => welcome,
+            _ => return Err(VeterError::InvalidEnvelope("Expected an MLS Welcome".to_string())),
+        };
+        let config = MlsGroupJoinConfig::builder()
+            .use_ratchet_tree_extension(true)
+            .build();
+
+        // Join on a copy of the KeyPackages, so a failed join changes nothing
+        let provider = MlsProvider::with_values(self.key_packages.values()?.clone());
+        let group = StagedWelcome::new_from_welcome(&provider, &config, welcome, None)
+            .and_then(|staged| staged.into_group(&provider))
+            .map_err(|e| VeterError::KeyManagement(format!("Failed to join MLS group: {}", e)))?;
+        let room_id = room_id(group.group_id())?;
+
+        // KeyPackages left in the copy move back; the used one is gone
+        {
+            let mut values = provider.values_mut()?;
+            self.key_packages.values_mut()?.retain(|key, value| match values.remove(key) {
+                Some(mut copy) => {
+                    copy.zeroize();
+                    true
+                }
+                None => {
+                    value.zeroize();
+                    false
+                }
+            });
+        }
+        self.groups.insert(room_id, provider);
+        Ok(room_id)
+    }
+
+    /// Process a Commit or Proposal from another member
+    pub fn process_handshake(&self, room_id: RoomId, message: &[u8]) -> Result<MlsHandshake> {
+        let message = protocol_message(message)?;
+
+        transaction(self.provider(room_id)?, None, |provider| {
+            let mut group = group(provider, room_id)?;
+            let processed = group.process_message(provider, message)
+                .map_err(|e| VeterError::InvalidEnvelope(format!("MLS handshake rejected: {}", e)))?;
+
+            match processed.into_content() {
+                ProcessedMessageContent::ProposalMessage(proposal) => {
+                    group.store_pending_proposal(provider.storage(), *proposal)
+                        .map_err(|e| VeterError::Crypto(format!("Failed to queue MLS proposal: {}", e)))?;
+                    Ok(MlsHandshake::ProposalQueued)
+                }
+                ProcessedMessageContent::StagedCommitMessage(commit) => {
+                    let removed = commit.self_removed();
+                    group.merge_staged_commit(provider, *commit)
+                        .map_err(|e| VeterError::Crypto(format!("Failed to merge MLS commit: {}", e)))?;
+                    if removed {
+                        return Ok(MlsHandshake::Removed);
+                    }
+                    Ok(MlsHandshake::CommitMerged { epoch: group.epoch().as_u64() })
+                }
+                _ => Err(VeterError::InvalidEnvelope("Not an MLS handshake message".to_string())),
+            }
+        })
+    }
+
+    /// Encrypt an application message for the group, binding `ad`
+    pub fn encrypt(&self, room_id: RoomId, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        transaction(self.provider(room_id)?, Some(MESSAGE_SECRETS_LABEL), |provider| {
+            let mut group = group(provider, room_id)?;
+            group.set_aad(ad.to_vec());
+            let message = group.create_message(provider, &self.signer, plaintext)
+                .map_err(|e| VeterError::Crypto(format!("MLS encryption failed: {}", e)))?;
+            encode(&message)
+        })
+    }
+
+    /// Decrypt an application message from a device of the group without
+    /// changing the group. The message secrets it advances are returned, to
+    /// be applied with [`MlsClient::commit_secrets`] once the message is
+    /// stored.
+    pub fn decrypt(&self, room_id: RoomId, sender_device_id: DeviceId, message: &[u8], ad: &[u8]) -> Result<(Vec<u8>, StagedSecrets)> {
+        let message = protocol_message(message)?;
+
+        let (plaintext, entries) = stage(self.provider(room_id)?, MESSAGE_SECRETS_LABEL, |provider| {
+            let mut group = group(provider, room_id)?;
+            let processed = group.process_message(provider, message)
+                .map_err(|e| VeterError::InvalidEnvelope(format!("MLS message rejected: {}", e)))?;
+
+            if processed.aad() != ad {
+                return Err(VeterError::InvalidEnvelope("MLS message bound to another message".to_string()));
+            }
+            if credential_device(processed.credential())? != sender_device_id {
+                return Err(VeterError::InvalidEnvelope("MLS message from another device".to_string()));
+            }
+            match processed.into_content() {
+                ProcessedMessageContent::ApplicationMessage(message) => Ok(message.into_bytes()),
+                _ => Err(VeterError::InvalidEnvelope("Not an MLS application message".to_string())),
+            }
+        })?;
+        Ok((plaintext, StagedSecrets { room_id, entries }))
+    }
+
+    /// Apply the message secrets staged by [`MlsClient::decrypt`]
+    pub fn commit_secrets(&self, mut staged: StagedSecrets) -> Result<()> {
+        replace(self.provider(staged.room_id)?, MESSAGE_SECRETS_LABEL, std::mem::take(&mut staged.entries))
+    }
+
+    /// Export the state of a group as it will be once `staged` is applied
+    pub fn staged_group_to_bytes(&self, staged: &StagedSecrets) -> Result<Vec<u8>> {
+        let mut values = self.provider(staged.room_id)?.values()?.clone();
+        retain_wiped(&mut values, |key| !key.starts_with(MESSAGE_SECRETS_LABEL));
+        values.extend(staged.entries.iter().cloned());
+        let data = bincode::serialize(&values);
+        wipe(values.values_mut());
+        Ok(data?)
+    }
+
+    /// Current epoch of the room's group
+    pub fn epoch(&self, room_id: RoomId) -> Result<u64> {
+        Ok(group(self.provider(room_id)?, room_id)?.epoch().as_u64())
+    }
+
+    /// Device ids of the group's members
+    pub fn members(&self, room_id: RoomId) -> Result<Vec<DeviceId>> {
+        group(self.provider(room_id)?, room_id)?
+            .members()
+            .map(|member| credential_device(&member.credential))
+            .collect()
+    }
+
+    fn provider(&self, room_id: RoomId) -> Result<&MlsProvider> {
+        self.groups.get(&room_id)
+            .ok_or_else(|| VeterError::KeyManagement(format!("No MLS group for room {}", room_id)))
+    }
+
+    /// Validate a KeyPackage, returning it with its hash reference
+    fn validate_key_package(&self, key_package: &[u8], identity_key: &[u8]) -> Result<(KeyPackage, Vec<u8>)> {
+        let key_package = KeyPackageIn::tls_deserialize_exact(key_package)
+            .map_err(|e| VeterError::KeyManagement(format!("Malformed KeyPackage: {}", e)))?
+            .validate(self.key_packages.crypto(), ProtocolVersion::Mls10)
+            .map_err(|e| VeterError::KeyManagement(format!("Invalid KeyPackage: {}", e)))?;
+
+        if key_package.leaf_node().signature_key().as_slice() != identity_key {
+            return Err(VeterError::KeyManagement("KeyPackage is not signed by the identity key".to_string()));
+        }
+        let reference = key_package.hash_ref(self.key_packages.crypto())
+            .map_err(|e| VeterError::KeyManagement(format!("Failed to hash KeyPackage: {}", e)))?;
+        Ok((key_package, reference.as_slice().to_vec()))
+    }
+}
+
+/// Run an operation on a group's provider and roll its storage back if the
+/// operation fails, so rejected messages do not consume secrets. With a
+/// `label`, only the entries under it are saved beforehand: application
+/// messages advance nothing but the message secrets.
+fn transaction<T>(provider: &MlsProvider, label: Option<&[u8]>, op: impl FnOnce(&MlsProvider) -> Result<T>) -> Result<T> {
+    let label = label.unwrap_or_default();
+    let mut saved = entries(provider, label)?;
+
+    let result = op(provider);
+    if result.is_err() {
+        replace(provider, label, saved)?;
+    } else {
+        wipe(saved.iter_mut().map(|(_, value)| value));
+    }
+    result
+}
+
+/// Run an operation on a group's provider and always roll the entries
+/// under `label` back, returning them as the operation left them
+fn stage<T>(provider: &MlsProvider, label: &[u8], op: impl FnOnce(&MlsProvider) -> Result<T>) -> Result<(T, Entries)> {
+    let saved = entries(provider, label)?;
+
+    let result = op(provider);
+    let staged = entries(provider, label)?;
+    replace(provider, label, saved)?;
+    Ok((result?, staged))
+}
+
+/// Storage entries whose key starts with `label`; all for an empty label
+fn entries(provider: &MlsProvider, label: &[u8]) -> Result<Entries> {
+    Ok(provider.values()?.iter()
+        .filter(|(key, _)| key.starts_with(label))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect())
+}
+
+/// Replace the storage entries whose key starts with `label`
+fn replace(provider: &MlsProvider, label: &[u8], entries: Entries) -> Result<()> {
+    let mut values = provider.values_mut()?;
+    retain_wiped(&mut values, |key| !key.starts_with(label));
+    values.extend(entries);
+    Ok(())
+}
+
+/// Keep the storage entries whose key passes `keep`, wiping the others
+fn retain_wiped(values: &mut HashMap<Vec<u8>, Vec<u8>>, keep: impl Fn(&[u8]) -> bool) {
+    values.retain(|key, value| {
+        let kept = keep(key);
+        if !kept {
+            value.zeroize();
+        }
+        kept
+    });
+}
+
+/// Wipe storage values about to be dropped
+fn wipe<'a>(values: impl IntoIterator<Item = &'a mut Vec<u8>>) {
+    for value in values {
+        value.zeroize();
+    }
+}
+
+fn load_group(provider: &MlsProvider, room_id: RoomId) -> Result<Option<MlsGroup>> {
+    MlsGroup::load(provider.storage(), &GroupId::from_slice(room_id.as_bytes()))
+        .map_err(|e| VeterError::Crypto(format!("Failed to load MLS group: {}", e)))
+}
+
+fn group(provider: &MlsProvider, room_id: RoomId) -> Result<MlsGroup> {
+    load_group(provider, room_id)?
+        .ok_or_else(|| VeterError::KeyManagement(format!("No MLS group for room {}", room_id)))
+}
+
+fn merge_pending_commit(group: &mut MlsGroup, provider: &MlsProvider) -> Result<()> {
+    group.merge_pending_commit(provider)
+        .map_err(|e| VeterError::Crypto(format!("Failed to merge MLS commit: {}", e)))
+}
+
+fn member_leaf(group: &MlsGroup, device_id: DeviceId) -> Result<LeafNodeIndex> {
+    group.members()
+        .find(|member| credential_device(&member.credential).is_ok_and(|id| id == device_id))
+        .map(|member| member.index)
+        .ok_or_else(|| VeterError::KeyManagement(format!("Device {} is not an MLS group member", device_id)))
+}
+
+fn credential_device(credential: &Credential) -> Result<DeviceId> {
+    let credential = BasicCredential::try_from(credential.clone())
+        .map_err(|e| VeterError::KeyManagement(format!("Unsupported MLS credential: {}", e)))?;
+    DeviceId::from_slice(credential.identity())
+        .map_err(|e| VeterError::KeyManagement(format!("Invalid MLS credential identity: {}", e)))
+}
+
+fn room_id(group_id: &GroupId) -> Result<RoomId> {
+    RoomId::from_slice(group_id.as_slice())
+        .map_err(|e| VeterError::KeyManagement(format!("Invalid MLS group id: {}", e)))
+}
+
+fn encode(message: &MlsMessageOut) -> Result<Vec<u8>> {
+    message.to_bytes()
+        .map_err(|e| VeterError::Serialization(format!("Failed to encode MLS message: {}", e)))
+}
+
+fn decode(message: &[u8]) -> Result<MlsMessageIn> {
+    MlsMessageIn::tls_deserialize_exact(message)
+        .map_err(|e| VeterError::InvalidEnvelope(format!("Malformed MLS message: {}", e)))
+}
+
+fn protocol_message(message: &[u8]) -> Result<ProtocolMessage> {
+    decode(message)?.try_into_protocol_message()
+        .map_err(|e| VeterError::InvalidEnvelope(format!("Not an MLS group message: {}", e)))
+}
@@ -0,0 +1,365 @@
+//! Double Ratchet with header encryption
+//!
+//! Follows the Signal Double Ratchet specification (section 4, "Double
+//! Ratchet with header encryption"). Message keys are used with AES-256-GCM;
+//! the whole state serializes into `Session.session_data`.
+
+use crate::{VeterError, Result};
+use super::x3dh::SharedSecret;
+use aes_gcm::{Aes256Gcm, Key, Nonce, KeyInit};
+use aes_gcm::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Maximum number of message keys skipped within a single chain
+const MAX_SKIP: u32 = 1000;
+
+/// Maximum number of skipped message keys kept across all chains
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+const INIT_INFO: &[u8] = b"Veter Ratchet Init v1";
+const ROOT_INFO: &[u8] = b"Veter Ratchet Root v1";
+const MESSAGE_INFO: &[u8] = b"Veter Ratchet Message v1";
+
+/// Message key stored for a message that has not arrived yet
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SkippedKey {
+    header_key: [u8; 32],
+    counter: u32,
+    message_key: [u8; 32],
+}
+
+/// Double Ratchet session state
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct RatchetState {
+    dh_self: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    sending_chain: Option<[u8; 32]>,
+    receiving_chain: Option<[u8; 32]>,
+    sending_header_key: Option<[u8; 32]>,
+    receiving_header_key: Option<[u8; 32]>,
+    next_sending_header_key: [u8; 32],
+    next_receiving_header_key: [u8; 32],
+    sent: u32,
+    received: u32,
+    previous_sent: u32,
+    skipped: Vec<SkippedKey>,
+    associated_data: Vec<u8>,
+}
+
+/// Plaintext message header, encrypted with the sending header key
+#[derive(Serialize, Deserialize)]
+struct Header {
+    dh: [u8; 32],
+    previous_sent: u32,
+    counter: u32,
+}
+
+/// Ratchet message as sent over the wire
+#[derive(Serialize, Deserialize)]
+struct RatchetMessage {
+    header: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+impl RatchetState {
+    /// Initialize the session of the X3DH initiator, who can send immediately
+    pub fn init_initiator(secret: &SharedSecret, remote_ratchet_key: [u8; 32]) -> Result<Self> {
+        let (root_key, shared_header_key, shared_next_header_key) = init_keys(&secret.secret)?;
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let dh_out = dh(&dh_self, &remote_ratchet_key)?;
+        let (root_key, sending_chain, next_sending_header_key) = kdf_root(&root_key, &dh_out)?;
+
+        Ok(Self {
+            dh_self: dh_self.to_bytes(),
+            dh_remote: Some(remote_ratchet_key),
+            root_key,
+            sending_chain: Some(sending_chain),
+            receiving_chain: None,
+            sending_header_key: Some(shared_header_key),
+            receiving_header_key: None,
+            next_sending_header_key,
+            next_receiving_header_key: shared_next_header_key,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+            associated_data: secret.associated_data.clone(),
+        })
+    }
+
+    /// Initialize the session of the X3DH responder, whose first ratchet key
+    /// pair is its signed prekey. It can send once it has received a message.
+    pub fn init_responder(secret: &SharedSecret, ratchet_key: &StaticSecret) -> Result<Self> {
+        let (root_key, shared_header_key, shared_next_header_key) = init_keys(&secret.secret)?;
+
+        Ok(Self {
+            dh_self: ratchet_key.to_bytes(),
+            dh_remote: None,
+            root_key,
+            sending_chain: None,
+            receiving_chain: None,
+            sending_header_key: None,
+            receiving_header_key: None,
+            next_sending_header_key: shared_next_header_key,
+            next_receiving_header_key: shared_header_key,
+            sent: 0,
+            received: 0,
+            previous_sent: 0,
+            skipped: Vec::new(),
+            associated_data: secret.associated_data.clone(),
+        })
+    }
+
+    /// Restore a state from `Session.session_data`
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(data)?)
+    }
+
+    /// Serialize the state for `Session.session_data`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Encrypt a message bound to the given associated data, advancing the
+    /// sending chain
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        let (Some(chain_key), Some(header_key)) = (self.sending_chain, self.sending_header_key) else {
+            return Err(VeterError::Crypto("Session cannot send before receiving a message".to_string()));
+        };
+
+        let (next_chain_key, mut message_key) = kdf_chain(&chain_key)?;
+        let header = Header {
+            dh: PublicKey::from(&StaticSecret::from(self.dh_self)).to_bytes(),
+            previous_sent: self.previous_sent,
+            counter: self.sent,
+        };
+        let header = encrypt_header(&header_key, &header)?;
+        let ciphertext = encrypt_payload(&message_key, plaintext, &self.message_ad(ad, &header));
+        message_key.zeroize();
+        let ciphertext = ciphertext?;
+
+        self.sending_chain = Some(next_chain_key);
+        self.sent += 1;
+
+        Ok(bincode::serialize(&RatchetMessage { header, ciphertext })?)
+    }
+
+    /// Decrypt a message, advancing the receiving chain and performing a
+    /// DH ratchet step when the sender has one. The state is left untouched
+    /// if decryption fails.
+    pub fn decrypt(&mut self, message: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        let message: RatchetMessage = bincode::deserialize(message)?;
+
+        if let Some(plaintext) = self.try_skipped_keys(&message, ad)? {
+            return Ok(plaintext);
+        }
+
+        let mut next = self.clone();
+        let (header, ratchet_step) = next.decrypt_header(&message.header)?;
+        if ratchet_step {
+            next.skip_message_keys(header.previous_sent)?;
+            next.dh_ratchet(&header)?;
+        }
+        next.skip_message_keys(header.counter)?;
+
+        let chain_key = next.receiving_chain
+            .ok_or_else(|| VeterError::Crypto("No receiving chain".to_string()))?;
+        let (next_chain_key, mut message_key) = kdf_chain(&chain_key)?;
+        let plaintext = decrypt_payload(&message_key, &message.ciphertext, &next.message_ad(ad, &message.header));
+        message_key.zeroize();
+        let plaintext = plaintext?;
+
+        next.receiving_chain = Some(next_chain_key);
+        next.received += 1;
+        *self = next;
+
+        Ok(plaintext)
+    }
+
+    fn message_ad(&self, ad: &[u8], header: &[u8]) -> Vec<u8> {
+        let mut message_ad = self.associated_data.clone();
+        message_ad.extend_from_slice(ad);
+        message_ad.extend_from_slice(header);
+        message_ad
+    }
+
+    fn try_skipped_keys(&mut self, message: &RatchetMessage, ad: &[u8]) -> Result<Option<Vec<u8>>> {
+        for index in 0..self.skipped.len() {
+            let Ok(header) = decrypt_header(&self.skipped[index].header_key, &message.header) else {
+                continue;
+            };
+            if header.counter != self.skipped[index].counter {
+                continue;
+            }
+
+            let plaintext = decrypt_payload(&self.skipped[index].message_key, &message.ciphertext, &self.message_ad(ad, &message.header))?;
+            self.skipped.remove(index);
+            return Ok(Some(plaintext));
+        }
+
+        Ok(None)
+    }
+
+    fn decrypt_header(&self, header: &[u8]) -> Result<(Header, bool)> {
+        if let Some(header_key) = &self.receiving_header_key {
+            if let Ok(header) = decrypt_header(header_key, header) {
+                return Ok((header, false));
+            }
+        }
+        if let Ok(header) = decrypt_header(&self.next_receiving_header_key, header) {
+            return Ok((header, true));
+        }
+
+        Err(VeterError::Crypto("Failed to decrypt message header".to_string()))
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<()> {
+        if self.received.saturating_add(MAX_SKIP) < until {
+            return Err(VeterError::Crypto("Too many skipped messages".to_string()));
+        }
+
+        if let (Some(mut chain_key), Some(header_key)) = (self.receiving_chain, self.receiving_header_key) {
+            while self.received < until {
+                let (next_chain_key, message_key) = kdf_chain(&chain_key)?;
+                self.skipped.push(SkippedKey {
+                    header_key,
+                    counter: self.received,
+                    message_key,
+                });
+                chain_key = next_chain_key;
+                self.received += 1;
+            }
+            self.receiving_chain = Some(chain_key);
+        }
+
+        if self.skipped.len() > MAX_SKIPPED_KEYS {
+            let excess = self.skipped.len() - MAX_SKIPPED_KEYS;
+            self.skipped.drain(..excess);
+        }
+
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &Header) -> Result<()> {
+        self.previous_sent = self.sent;
+        self.sent = 0;
+        self.received = 0;
+        self.sending_header_key = Some(self.next_sending_header_key);
+        self.receiving_header_key = Some(self.next_receiving_header_key);
+        self.dh_remote = Some(header.dh);
+
+        let dh_out = dh(&StaticSecret::from(self.dh_self), &header.dh)?;
+        let (root_key, receiving_chain, next_receiving_header_key) = kdf_root(&self.root_key, &dh_out)?;
+        self.receiving_chain = Some(receiving_chain);
+        self.next_receiving_header_key = next_receiving_header_key;
+
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let dh_out = dh(&dh_self, &header.dh)?;
+        let (root_key, sending_chain, next_sending_header_key) = kdf_root(&root_key, &dh_out)?;
+        self.dh_self = dh_self.to_bytes();
+        self.root_key = root_key;
+        self.sending_chain = Some(sending_chain);
+        self.next_sending_header_key = next_sending_header_key;
+
+        Ok(())
+    }
+}
+
+fn dh(secret: &StaticSecret, public: &[u8; 32]) -> Result<[u8; 32]> {
+    let shared = secret.diffie_hellman(&PublicKey::from(*public));
+    if !shared.was_contributory() {
+        return Err(VeterError::Crypto("Non-contributory Diffie-Hellman output".to_string()));
+    }
+    Ok(shared.to_bytes())
+}
+
+fn hkdf_expand<const N: usize>(salt: &[u8], ikm: &[u8], info: &[u8]) -> Result<[u8; N]> {
+    let mut okm = [0u8; N];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut okm)
+        .map_err(|e| VeterError::Crypto(format!("Key derivation failed: {}", e)))?;
+    Ok(okm)
+}
+
+fn split3(mut okm: [u8; 96]) -> ([u8; 32], [u8; 32], [u8; 32]) {
+    let mut keys = ([0u8; 32], [0u8; 32], [0u8; 32]);
+    keys.0.copy_from_slice(&okm[..32]);
+    keys.1.copy_from_slice(&okm[32..64]);
+    keys.2.copy_from_slice(&okm[64..]);
+    okm.zeroize();
+    keys
+}
+
+/// Root key, shared header key and shared next header key from the X3DH secret
+fn init_keys(secret: &[u8; 32]) -> Result<([u8; 32], [u8; 32], [u8; 32])> {
+    Ok(split3(hkdf_expand::<96>(&[0u8; 32], secret, INIT_INFO)?))
+}
+
+/// KDF_RK_HE: new root key, chain key and next header key
+fn kdf_root(root_key: &[u8; 32], dh_out: &[u8; 32]) -> Result<([u8; 32], [u8; 32], [u8; 32])> {
+    Ok(split3(hkdf_expand::<96>(root_key, dh_out, ROOT_INFO)?))
+}
+
+/// KDF_CK: next chain key and message key
+pub(super) fn kdf_chain(chain_key: &[u8; 32]) -> Result<([u8; 32], [u8; 32])> {
+    let mac = |constant: u8| -> Result<[u8; 32]> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key)
+            .map_err(|e| VeterError::Crypto(format!("HMAC creation failed: {}", e)))?;
+        mac.update(&[constant]);
+        Ok(mac.finalize().into_bytes().into())
+    };
+
+    Ok((mac(0x02)?, mac(0x01)?))
+}
+
+pub(super) fn encrypt_payload(message_key: &[u8; 32], plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+    let mut okm = hkdf_expand::<44>(&[0u8; 32], message_key, MESSAGE_INFO)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&okm[..32]));
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&okm[32..]), Payload { msg: plaintext, aad: ad })
+        .map_err(|e| VeterError::Crypto(format!("Encryption failed: {}", e)));
+    okm.zeroize();
+    ciphertext
+}
+
+pub(super) fn decrypt_payload(message_key: &[u8; 32], ciphertext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+    let mut okm = hkdf_expand::<44>(&[0u8; 32], message_key, MESSAGE_INFO)?;
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&okm[..32]));
+    let plaintext = cipher.decrypt(Nonce::from_slice(&okm[32..]), Payload { msg: ciphertext, aad: ad })
+        .map_err(|e| VeterError::Crypto(format!("Decryption failed: {}", e)));
+    okm.zeroize();
+    plaintext
+}
+
+/// Header keys are reused within a chain, so every header gets a random nonce
+fn encrypt_header(header_key: &[u8; 32], header: &Header) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; 12];
+    OsRng.fill_bytes(&mut nonce);
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(header_key));
+    let ciphertext = cipher.encrypt(Nonce::from_slice(&nonce), bincode::serialize(header)?.as_slice())
+        .map_err(|e| VeterError::Crypto(format!("Header encryption failed: {}", e)))?;
+
+    let mut result = nonce.to_vec();
+    result.extend_from_slice(&ciphertext);
+    Ok(result)
+}
+
+fn decrypt_header(header_key: &[u8; 32], encrypted: &[u8]) -> Result<Header> {
+    if encrypted.len() < 12 {
+        return Err(VeterError::Crypto("Invalid message header".to_string()));
+    }
+
+    let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(header_key));
+    let header = cipher.decrypt(Nonce::from_slice(&encrypted[..12]), &encrypted[12..])
+        .map_err(|e| VeterError::Crypto(format!("Header decryption failed: {}", e)))?;
+
+    Ok(bincode::deserialize(&header)?)
+}
@@ -0,0 +1,236 @@
+//! Sender keys for group rooms
+//!
+//! Every device keeps one sender key per group room: a symmetric hash
+//! ratchet plus an Ed25519 signing key. The key is distributed to the other
+//! members' devices over the pairwise sessions, after which each message is
+//! encrypted once for the whole room and signed by the sender.
+
+use crate::{VeterError, Result, models::DeviceId};
+use super::ratchet::{decrypt_payload, encrypt_payload, kdf_chain};
+use super::x3dh::verify_signature;
+use ed25519_dalek::{Signer, SigningKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, ZeroizeOnDrop};
+
+/// Maximum number of message keys skipped within a sender chain
+const MAX_SKIP: u32 = 1000;
+
+/// Number of previous sender key generations kept for late messages
+const MAX_GENERATIONS: usize = 5;
+
+/// Sender key distribution message, sent over a pairwise session
+#[derive(Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+pub struct SenderKeyDistribution {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub chain_key: [u8; 32],
+    pub signing_key: [u8; 32],
+}
+
+/// Message encrypted with a sender key
+pub struct GroupMessage {
+    pub key_id: u32,
+    pub iteration: u32,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SkippedKey {
+    iteration: u32,
+    message_key: [u8; 32],
+}
+
+/// One generation of a sender key
+#[derive(Clone, Serialize, Deserialize, Zeroize, ZeroizeOnDrop)]
+struct SenderKeyState {
+    key_id: u32,
+    iteration: u32,
+    chain_key: [u8; 32],
+    signing_key: [u8; 32],
+    /// Private signing key, only present for this device's own sender key
+    signing_private: Option<[u8; 32]>,
+    skipped: Vec<SkippedKey>,
+    #[zeroize(skip)]
+    distributed_to: Vec<DeviceId>,
+}
+
+/// Sender key generations of one device in one room, newest first.
+/// Serializes into `SenderKey.state_data`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct SenderKeyRecord {
+    states: Vec<SenderKeyState>,
+}
+
+impl SenderKeyRecord {
+    /// Generate a fresh sender key for this device
+    pub fn generate() -> Self {
+        let signing = SigningKey::generate(&mut OsRng);
+        let mut chain_key = [0u8; 32];
+        OsRng.fill_bytes(&mut chain_key);
+
+        Self {
+            states: vec![SenderKeyState {
+                key_id: OsRng.next_u32(),
+                iteration: 0,
+                chain_key,
+                signing_key: signing.verifying_key().to_bytes(),
+                signing_private: Some(signing.to_bytes()),
+                skipped: Vec::new(),
+                distributed_to: Vec::new(),
+            }],
+        }
+    }
+
+    /// Restore a record from `SenderKey.state_data`
+    pub fn from_bytes(data: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(data)?)
+    }
+
+    /// Serialize the record for `SenderKey.state_data`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::serialize(self)?)
+    }
+
+    /// Distribution message for the current sender key
+    pub fn distribution(&self) -> SenderKeyDistribution {
+        let state = &self.states[0];
+        SenderKeyDistribution {
+            key_id: state.key_id,
+            iteration: state.iteration,
+            chain_key: state.chain_key,
+            signing_key: state.signing_key,
+        }
+    }
+
+    /// Devices that have not received the current sender key yet
+    pub fn undistributed<'a>(&'a self, devices: impl IntoIterator<Item = &'a DeviceId>) -> Vec<DeviceId> {
+        devices.into_iter()
+            .filter(|device_id| !self.states[0].distributed_to.contains(device_id))
+            .copied()
+            .collect()
+    }
+
+    /// Record that a device has received the current sender key
+    pub fn mark_distributed(&mut self, device_id: DeviceId) {
+        self.states[0].distributed_to.push(device_id);
+    }
+
+    /// Add a sender key received from another device
+    pub fn add_distribution(&mut self, distribution: &SenderKeyDistribution) {
+        // Keep a known chain, so a replayed distribution cannot rewind it
+        if self.states.iter().any(|state| state.key_id == distribution.key_id) {
+            return;
+        }
+        self.states.insert(0, SenderKeyState {
+            key_id: distribution.key_id,
+            iteration: distribution.iteration,
+            chain_key: distribution.chain_key,
+            signing_key: distribution.signing_key,
+            signing_private: None,
+            skipped: Vec::new(),
+            distributed_to: Vec::new(),
+        });
+        self.states.truncate(MAX_GENERATIONS);
+    }
+
+    /// Create a record from a distribution message
+    pub fn from_distribution(distribution: &SenderKeyDistribution) -> Self {
+        let mut record = Self { states: Vec::new() };
+        record.add_distribution(distribution);
+        record
+    }
+
+    /// Encrypt and sign a message with this device's own sender key
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<GroupMessage> {
+        let state = &mut self.states[0];
+        let signing_private = state.signing_private
+            .ok_or_else(|| VeterError::KeyManagement("Not this device's sender key".to_string()))?;
+
+        let (next_chain_key, mut message_key) = kdf_chain(&state.chain_key)?;
+        let ad = message_ad(ad, state.key_id, state.iteration);
+        let ciphertext = encrypt_payload(&message_key, plaintext, &ad);
+        message_key.zeroize();
+        let ciphertext = ciphertext?;
+
+        let signature = SigningKey::from_bytes(&signing_private)
+            .sign(&signed_data(&ad, &ciphertext))
+            .to_bytes()
+            .to_vec();
+        let message = GroupMessage {
+            key_id: state.key_id,
+            iteration: state.iteration,
+            ciphertext,
+            signature,
+        };
+        state.chain_key = next_chain_key;
+        state.iteration += 1;
+
+        Ok(message)
+    }
+
+    /// Verify and decrypt a message from the sender this record belongs to.
+    /// The record is left untouched if decryption fails.
+    pub fn decrypt(&mut self, message: &GroupMessage, ad: &[u8]) -> Result<Vec<u8>> {
+        let index = self.states.iter()
+            .position(|state| state.key_id == message.key_id)
+            .ok_or_else(|| VeterError::KeyManagement(format!("Unknown sender key {}", message.key_id)))?;
+        let mut state = self.states[index].clone();
+
+        let ad = message_ad(ad, message.key_id, message.iteration);
+        verify_signature(&state.signing_key, &signed_data(&ad, &message.ciphertext), &message.signature)?;
+
+        let mut message_key = state.message_key(message.iteration)?;
+        let plaintext = decrypt_payload(&message_key, &message.ciphertext, &ad);
+        message_key.zeroize();
+        let plaintext = plaintext?;
+
+        self.states[index] = state;
+        Ok(plaintext)
+    }
+}
+
+impl SenderKeyState {
+    /// Message key for an iteration, advancing the chain or using a skipped key
+    fn message_key(&mut self, iteration: u32) -> Result<[u8; 32]> {
+        if iteration < self.iteration {
+            let index = self.skipped.iter()
+                .position(|skipped| skipped.iteration == iteration)
+                .ok_or_else(|| VeterError::Crypto("Duplicate or expired group message".to_string()))?;
+            let message_key = self.skipped[index].message_key;
+            self.skipped.remove(index);
+            return Ok(message_key);
+        }
+        if iteration - self.iteration > MAX_SKIP {
+            return Err(VeterError::Crypto("Too many skipped messages".to_string()));
+        }
+
+        loop {
+            let (next_chain_key, message_key) = kdf_chain(&self.chain_key)?;
+            self.chain_key = next_chain_key;
+            self.iteration += 1;
+            if self.iteration - 1 == iteration {
+                break Ok(message_key);
+            }
+            self.skipped.push(SkippedKey { iteration: self.iteration - 1, message_key });
+            if self.skipped.len() > MAX_SKIP as usize {
+                self.skipped.remove(0);
+            }
+        }
+    }
+}
+
+fn message_ad(ad: &[u8], key_id: u32, iteration: u32) -> Vec<u8> {
+    let mut message_ad = ad.to_vec();
+    message_ad.extend_from_slice(&key_id.to_be_bytes());
+    message_ad.extend_from_slice(&iteration.to_be_bytes());
+    message_ad
+}
+
+fn signed_data(ad: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut data = ad.to_vec();
+    data.extend_from_slice(ciphertext);
+    data
+}
@@ -0,0 +1,309 @@
+//! X3DH (Extended Triple Diffie-Hellman) key agreement
+//!
+//! The identity key is an Ed25519 key: it signs prekeys directly and is
+//! converted to its X25519 form for the Diffie-Hellman steps, so a device
+//! only publishes a single 32-byte identity key.
+
+use crate::{VeterError, Result, models::KeyMaterial};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroize;
+
+/// Domain separation for the X3DH key derivation
+const X3DH_INFO: &[u8] = b"Veter X3DH v1";
+
+/// Long-term identity key pair of a device
+pub struct IdentityKeyPair {
+    signing_key: SigningKey,
+}
+
+impl IdentityKeyPair {
+    /// Generate a new random identity key pair
+    pub fn generate() -> Self {
+        Self {
+            signing_key: SigningKey::generate(&mut OsRng),
+        }
+    }
+
+    /// Restore an identity key pair from its 32-byte private key
+    pub fn from_private_bytes(bytes: &[u8]) -> Result<Self> {
+        let secret: [u8; 32] = bytes.try_into()
+            .map_err(|_| VeterError::KeyManagement("Identity private key must be 32 bytes".to_string()))?;
+
+        Ok(Self {
+            signing_key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    /// Private key bytes
+    pub fn private_bytes(&self) -> [u8; 32] {
+        self.signing_key.to_bytes()
+    }
+
+    /// Public identity key (Ed25519)
+    pub fn public_key(&self) -> [u8; 32] {
+        self.signing_key.verifying_key().to_bytes()
+    }
+
+    /// Sign data with the identity key
+    pub fn sign(&self, data: &[u8]) -> [u8; 64] {
+        self.signing_key.sign(data).to_bytes()
+    }
+
+    /// X25519 form of the identity key used for Diffie-Hellman
+    pub(crate) fn dh_secret(&self) -> StaticSecret {
+        StaticSecret::from(self.signing_key.to_scalar_bytes())
+    }
+}
+
+/// Convert a public Ed25519 identity key to its X25519 form
+pub(crate) fn identity_dh_public(identity_key: &[u8; 32]) -> Result<PublicKey> {
+    let verifying_key = VerifyingKey::from_bytes(identity_key)
+        .map_err(|e| VeterError::KeyManagement(format!("Invalid identity key: {}", e)))?;
+
+    Ok(PublicKey::from(verifying_key.to_montgomery().to_bytes()))
+}
+
+/// Verify an identity key signature
+pub fn verify_signature(identity_key: &[u8], data: &[u8], signature: &[u8]) -> Result<()> {
+    let identity_key: [u8; 32] = identity_key.try_into()
+        .map_err(|_| VeterError::KeyManagement("Identity key must be 32 bytes".to_string()))?;
+    let signature: [u8; 64] = signature.try_into()
+        .map_err(|_| VeterError::Crypto("Signature must be 64 bytes".to_string()))?;
+
+    VerifyingKey::from_bytes(&identity_key)
+        .map_err(|e| VeterError::KeyManagement(format!("Invalid identity key: {}", e)))?
+        .verify(data, &Signature::from_bytes(&signature))
+        .map_err(|e| VeterError::Crypto(format!("Signature verification failed: {}", e)))
+}
+
+/// Medium-term prekey signed by the identity key
+pub struct SignedPreKey {
+    secret: StaticSecret,
+    signature: [u8; 64],
+}
+
+impl SignedPreKey {
+    /// Generate a new signed prekey
+    pub fn generate(identity: &IdentityKeyPair) -> Self {
+        let secret = StaticSecret::random_from_rng(OsRng);
+        let signature = identity.sign(PublicKey::from(&secret).as_bytes());
+
+        Self { secret, signature }
+    }
+
+    /// Restore a signed prekey from its private key and signature
+    pub fn from_parts(secret: [u8; 32], signature: [u8; 64]) -> Self {
+        Self {
+            secret: StaticSecret::from(secret),
+            signature,
+        }
+    }
+
+    /// Public prekey
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// Private key bytes
+    pub fn private_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    /// Identity key signature over the public prekey
+    pub fn signature(&self) -> [u8; 64] {
+        self.signature
+    }
+
+    /// Private prekey, which also serves as the responder's first ratchet key
+    pub(crate) fn secret(&self) -> &StaticSecret {
+        &self.secret
+    }
+}
+
+/// Single-use prekey, consumed by the first session that uses it
+pub struct OneTimePreKey {
+    secret: StaticSecret,
+}
+
+impl OneTimePreKey {
+    /// Generate a new one-time prekey
+    pub fn generate() -> Self {
+        Self {
+            secret: StaticSecret::random_from_rng(OsRng),
+        }
+    }
+
+    /// Restore a one-time prekey from its private key
+    pub fn from_private_bytes(secret: [u8; 32]) -> Self {
+        Self {
+            secret: StaticSecret::from(secret),
+        }
+    }
+
+    /// Public prekey
+    pub fn public_key(&self) -> [u8; 32] {
+        PublicKey::from(&self.secret).to_bytes()
+    }
+
+    /// Private key bytes
+    pub fn private_bytes(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+}
+
+/// Public prekeys of a peer device, as handed out by the directory
+#[derive(Debug, Clone)]
+pub struct PreKeyBundle {
+    pub identity_key: [u8; 32],
+    pub signed_prekey: [u8; 32],
+    pub signed_prekey_signature: [u8; 64],
+    pub one_time_prekey: Option<[u8; 32]>,
+}
+
+impl TryFrom<&KeyMaterial> for PreKeyBundle {
+    type Error = VeterError;
+
+    /// Build a bundle from published key material, using its first one-time prekey
+    fn try_from(material: &KeyMaterial) -> Result<Self> {
+        let identity_key = material.identity_key.as_slice().try_into()
+            .map_err(|_| VeterError::KeyManagement("Identity key must be 32 bytes".to_string()))?;
+        let signed_prekey = material.signed_prekey.as_slice().try_into()
+            .map_err(|_| VeterError::KeyManagement("Signed prekey must be 32 bytes".to_string()))?;
+        let signed_prekey_signature = material.signed_prekey_signature.as_slice().try_into()
+            .map_err(|_| VeterError::KeyManagement("Signed prekey signature must be 64 bytes".to_string()))?;
+        let one_time_prekey = material.one_time_prekeys.first()
+            .map(|key| key.as_slice().try_into()
+                .map_err(|_| VeterError::KeyManagement("One-time prekey must be 32 bytes".to_string())))
+            .transpose()?;
+
+        Ok(Self {
+            identity_key,
+            signed_prekey,
+            signed_prekey_signature,
+            one_time_prekey,
+        })
+    }
+}
+
+/// Keys sent by the initiator so the responder can derive the same secret
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InitialMessage {
+    pub identity_key: [u8; 32],
+    pub ephemeral_key: [u8; 32],
+    pub signed_prekey: [u8; 32],
+    pub one_time_prekey: Option<[u8; 32]>,
+}
+
+/// Output of an X3DH agreement
+pub struct SharedSecret {
+    /// Secret key shared by both parties
+    pub secret: [u8; 32],
+    /// Encoded identity keys of initiator and responder, to be bound as AEAD associated data
+    pub associated_data: Vec<u8>,
+}
+
+impl Drop for SharedSecret {
+    fn drop(&mut self) {
+        self.secret.zeroize();
+    }
+}
+
+/// Run X3DH as the initiator against a peer's prekey bundle
+pub fn initiate(identity: &IdentityKeyPair, bundle: &PreKeyBundle) -> Result<(SharedSecret, InitialMessage)> {
+    verify_signature(&bundle.identity_key, &bundle.signed_prekey, &bundle.signed_prekey_signature)?;
+
+    let their_identity = identity_dh_public(&bundle.identity_key)?;
+    let their_signed_prekey = PublicKey::from(bundle.signed_prekey);
+    let ephemeral = StaticSecret::random_from_rng(OsRng);
+
+    let mut dh = vec![
+        identity.dh_secret().diffie_hellman(&their_signed_prekey),
+        ephemeral.diffie_hellman(&their_identity),
+        ephemeral.diffie_hellman(&their_signed_prekey),
+    ];
+    if let Some(one_time_prekey) = bundle.one_time_prekey {
+        dh.push(ephemeral.diffie_hellman(&PublicKey::from(one_time_prekey)));
+    }
+
+    let secret = derive_secret(&dh)?;
+    let message = InitialMessage {
+        identity_key: identity.public_key(),
+        ephemeral_key: PublicKey::from(&ephemeral).to_bytes(),
+        signed_prekey: bundle.signed_prekey,
+        one_time_prekey: bundle.one_time_prekey,
+    };
+
+    Ok((
+        SharedSecret {
+            secret,
+            associated_data: associated_data(&identity.public_key(), &bundle.identity_key),
+        },
+        message,
+    ))
+}
+
+/// Run X3DH as the responder for an initiator's first message
+pub fn respond(
+    identity: &IdentityKeyPair,
+    signed_prekey: &SignedPreKey,
+    one_time_prekey: Option<&OneTimePreKey>,
+    message: &InitialMessage,
+) -> Result<SharedSecret> {
+    if message.signed_prekey != signed_prekey.public_key() {
+        return Err(VeterError::KeyManagement("Unknown signed prekey".to_string()));
+    }
+
+    let their_identity = identity_dh_public(&message.identity_key)?;
+    let their_ephemeral = PublicKey::from(message.ephemeral_key);
+
+    let mut dh = vec![
+        signed_prekey.secret.diffie_hellman(&their_identity),
+        identity.dh_secret().diffie_hellman(&their_ephemeral),
+        signed_prekey.secret.diffie_hellman(&their_ephemeral),
+    ];
+    match (message.one_time_prekey, one_time_prekey) {
+        (Some(public), Some(one_time_prekey)) if public == one_time_prekey.public_key() => {
+            dh.push(one_time_prekey.secret.diffie_hellman(&their_ephemeral));
+        }
+        (None, None) => {}
+        _ => return Err(VeterError::KeyManagement("One-time prekey mismatch".to_string())),
+    }
+
+    Ok(SharedSecret {
+        secret: derive_secret(&dh)?,
+        associated_data: associated_data(&message.identity_key, &identity.public_key()),
+    })
+}
+
+/// Derive the shared secret from the concatenated DH outputs
+fn derive_secret(dh: &[x25519_dalek::SharedSecret]) -> Result<[u8; 32]> {
+    if dh.iter().any(|shared| !shared.was_contributory()) {
+        return Err(VeterError::Crypto("Non-contributory Diffie-Hellman output".to_string()));
+    }
+
+    // 32 bytes of 0xFF as in the X3DH specification for X25519
+    let mut input = vec![0xFFu8; 32];
+    for shared in dh {
+        input.extend_from_slice(shared.as_bytes());
+    }
+
+    let mut secret = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &input)
+        .expand(X3DH_INFO, &mut secret)
+        .map_err(|e| VeterError::Crypto(format!("Key derivation failed: {}", e)))?;
+    input.zeroize();
+
+    Ok(secret)
+}
+
+fn associated_data(initiator: &[u8; 32], responder: &[u8; 32]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(64);
+    data.extend_from_slice(initiator);
+    data.extend_from_slice(responder);
+    data
+}
@@ -0,0 +1,264 @@
+//! Engine owning the runtime and managers of one device
+//!
+//! [`VeterCore`] is what [`crate::init`] builds from a [`CoreConfig`]: the
+//! tokio runtime, the open database, the device's crypto state with its keys
+//! loaded from the database, the network client connected to the configured
+//! servers, and the outbox sending through the relay. Its methods block on
+//! the runtime, so they are called from outside it, e.g. from the app's
+//! worker threads; the managers are also available for anything not wrapped
+//! here.
+//!
+//! Unread messages are counted for the user owning the device, set on start
+//! once the device itself is stored, see `StorageManager::set_local_user`.
+//!
+//! Key state advanced by the crypto manager is written to the database as
+//! messages are synced. [`VeterCore::flush`] writes the rest, such as
+//! sessions started for sending, newly generated prekeys and MLS groups, and
+//! [`crate::cleanup`] flushes before shutting down.
+
+use crate::{VeterError, Result, models::*};
+use crate::crypto::CryptoManager;
+use crate::networking::{NetworkClient, Outbox, OutboxConfig, Transport, TransportConfig};
+use crate::storage::StorageManager;
+use crate::sync::SyncReport;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use zeroize::Zeroizing;
+
+/// How long shutdown waits for tasks still running on the runtime
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What [`crate::init`] needs to start the engine
+pub struct CoreConfig {
+    pub db_path: PathBuf,
+    /// Password the database key is derived from
+    pub passphrase: Zeroizing<String>,
+    pub device_id: DeviceId,
+    /// The device's identity private key, as from
+    /// `CryptoManager::generate_identity_keypair`
+    pub identity_key: Zeroizing<Vec<u8>>,
+    pub relay_endpoint: Option<String>,
+    pub directory_endpoint: Option<String>,
+    pub compliance_endpoint: Option<String>,
+    pub transport: TransportConfig,
+    pub outbox: OutboxConfig,
+}
+
+impl CoreConfig {
+    /// Configuration of a device that does not connect to any server
+    pub fn new(db_path: impl Into<PathBuf>, passphrase: &str, device_id: DeviceId, identity_key: &[u8]) -> Self {
+        Self {
+            db_path: db_path.into(),
+            passphrase: Zeroizing::new(passphrase.to_string()),
+            device_id,
+            identity_key: Zeroizing::new(identity_key.to_vec()),
+            relay_endpoint: None,
+            directory_endpoint: None,
+            compliance_endpoint: None,
+            transport: TransportConfig::default(),
+            outbox: OutboxConfig::default(),
+        }
+    }
+}
+
+/// The running engine of one device
+pub struct VeterCore {
+    device_id: DeviceId,
+    storage: Arc<StorageManager>,
+    crypto: Mutex<CryptoManager>,
+    network: NetworkClient,
+    outbox: Option<Outbox>,
+    // Dropped last, after everything that may still use it
+    runtime: Runtime,
+}
+
+impl VeterCore {
+    /// Start the runtime, open the database, load the device's keys and
+    /// connect to the configured servers
+    pub fn start(config: CoreConfig) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("veter-core")
+            .build()
+            .map_err(|e| VeterError::Internal(format!("Failed to start runtime: {}", e)))?;
+
+        let (storage, crypto, network, outbox) = runtime.block_on(async {
+            let storage = Arc::new(StorageManager::new(&config.db_path, &config.passphrase).await?);
+            if storage.get_local_user().await?.is_none() {
+                if let Some(device) = storage.get_device(&config.device_id).await? {
+                    storage.set_local_user(&device.user_id).await?;
+                }
+            }
+            let mut crypto = CryptoManager::new(config.identity_key.to_vec(), config.device_id)?;
+            load_keys(&storage, &mut crypto, config.device_id).await?;
+
+            let mut network = NetworkClient::with_transport(Transport::new(config.transport)?);
+            if let Some(endpoint) = &config.directory_endpoint {
+                network.connect_directory(endpoint).await?;
+            }
+            if let Some(endpoint) = &config.compliance_endpoint {
+                network.connect_compliance(endpoint).await?;
+            }
+            let outbox = match &config.relay_endpoint {
+                Some(endpoint) => {
+                    network.connect_relay(endpoint).await?;
+                    Some(network.start_outbox(storage.clone(), config.outbox)?)
+                }
+                None => None,
+            };
+
+            Ok::<_, VeterError>((storage, crypto, network, outbox))
+        })?;
+
+        Ok(Self {
+            device_id: config.device_id,
+            storage,
+            crypto: Mutex::new(crypto),
+            network,
+            outbox,
+            runtime,
+        })
+    }
+
+    pub fn device_id(&self) -> DeviceId {
+        self.device_id
+    }
+
+    pub fn storage(&self) -> &Arc<StorageManager> {
+        &self.storage
+    }
+
+    /// The device's crypto state, locked
+    pub fn crypto(&self) -> Result<MutexGuard<'_, CryptoManager>> {
+        self.crypto.lock().map_err(|_| VeterError::Internal("Crypto lock poisoned".to_string()))
+    }
+
+    pub fn network(&self) -> &NetworkClient {
+        &self.network
+    }
+
+    /// The outbox, if a relay is configured
+    pub fn outbox(&self) -> Option<&Outbox> {
+        self.outbox.as_ref()
+    }
+
+    /// Run a future on the engine's runtime, e.g. a call on one of the
+    /// managers
+    pub fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    /// Queue a message to be sent. Without a relay it waits in the outbox
+    /// until the engine is started with one.
+    pub fn send(&self, message: &EncryptedMessage) -> Result<()> {
+        match &self.outbox {
+            Some(outbox) => self.block_on(outbox.send(message)),
+            None => self.block_on(self.storage.queue_outgoing(message)),
+        }
+    }
+
+    /// Fetch up to `max_items` messages from the relay into the database,
+    /// see `SyncEngine::sync`. Receipts from other users mark the messages
+    /// they cover delivered in the outbox.
+    pub fn sync(&self, max_items: u32) -> Result<SyncReport> {
+        let engine = self.network.sync_engine(self.storage.clone(), self.device_id)?;
+        let mut crypto = self.crypto()?;
+        self.block_on(async {
+            let report = engine.sync(&mut crypto, max_items).await?;
+            if let Some(outbox) = &self.outbox {
+                let local_user = self.storage.get_local_user().await?;
+                for message in report.stored.iter().filter(|message| Some(message.sender_id) != local_user) {
+                    if let MessageContent::Receipt { message_id, .. } = &message.content {
+                        outbox.apply_receipt(&message.room_id, message_id).await?;
+                    }
+                }
+            }
+            Ok(report)
+        })
+    }
+
+    /// Write the key state held by the crypto manager to the database. The
+    /// rooms and devices it belongs to have to be stored.
+    pub fn flush(&self) -> Result<()> {
+        let crypto = self.crypto()?;
+        self.block_on(async {
+            for session in crypto.sessions() {
+                self.storage.store_session(session).await?;
+            }
+            for sender_key in crypto.sender_keys() {
+                self.storage.store_sender_key(sender_key).await?;
+            }
+            self.storage.store_prekey_state(&self.device_id, &crypto.prekey_state()?).await?;
+            for room_id in crypto.mls_rooms() {
+                self.storage.store_mls_group(&room_id, &crypto.mls_group_state(room_id)?).await?;
+            }
+            self.storage.store_mls_state(&self.device_id, &crypto.mls_state()?).await
+        })
+    }
+
+    /// Flush key state, let the outbox finish its batch, purge local
+    /// redactions from the search index and close the database. The keys in
+    /// memory are wiped as the engine is dropped.
+    pub fn shutdown(mut self) -> Result<()> {
+        let flushed = self.flush();
+        let outbox = self.outbox.take();
+        let purged = self.runtime.block_on(async {
+            if let Some(outbox) = outbox {
+                outbox.stop().await;
+            }
+            let purged = self.storage.purge_search_index().await;
+            self.storage.close().await;
+            purged
+        });
+
+        let Self { runtime, storage, crypto, network, .. } = self;
+        {
+            // Connections may need the runtime to close
+            let _context = runtime.enter();
+            drop((network, storage, crypto));
+        }
+        runtime.shutdown_timeout(SHUTDOWN_TIMEOUT);
+        flushed.and(purged.map(|_| ()))
+    }
+}
+
+/// Load the rooms and key state from the database into a new crypto manager
+async fn load_keys(storage: &StorageManager, crypto: &mut CryptoManager, device_id: DeviceId) -> Result<()> {
+    let mut peers = HashMap::<RoomId, Vec<Device>>::new();
+    for session in storage.get_all_sessions().await? {
+        if let Some(device) = storage.get_device(&session.device_id).await? {
+            peers.entry(session.room_id).or_default().push(device);
+        }
+        crypto.init_session(session.room_id, session.device_id, session.session_data)?;
+    }
+    for sender_key in storage.get_all_sender_keys().await? {
+        if sender_key.device_id != device_id {
+            if let Some(device) = storage.get_device(&sender_key.device_id).await? {
+                peers.entry(sender_key.room_id).or_default().push(device);
+            }
+        }
+        crypto.init_sender_key(sender_key.room_id, sender_key.device_id, sender_key.state_data)?;
+    }
+    // Drop the state of devices whose user left while the engine was not running
+    for room in storage.get_rooms().await? {
+        let devices = peers.get(&room.id).map_or(&[][..], Vec::as_slice);
+        for removed in crypto.update_room(&room, devices)? {
+            storage.delete_session(&room.id, &removed).await?;
+            storage.delete_sender_key(&room.id, &removed).await?;
+        }
+    }
+    if let Some(state_data) = storage.get_prekey_state(&device_id).await? {
+        crypto.init_prekey_state(&state_data)?;
+    }
+    if let Some(state_data) = storage.get_mls_state(&device_id).await? {
+        crypto.init_mls_state(&state_data)?;
+    }
+    for (room_id, state_data) in storage.get_mls_groups().await? {
+        crypto.init_mls_group_state(room_id, &state_data)?;
+    }
+    Ok(())
+}
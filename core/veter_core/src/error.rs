@@ -8,6 +8,9 @@ pub type Result<T> = std::result::Result<T, VeterError>;
 pub enum VeterError {
     #[error("Cryptographic error: {0}")]
     Crypto(String),
+
+    #[error("Invalid message envelope: {0}")]
+    InvalidEnvelope(String),
     
     #[error("Database error: {0}")]
     Database(String),
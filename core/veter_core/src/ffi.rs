@@ -0,0 +1,249 @@
+//! C ABI for the app
+//!
+//! The declarations are in `include/veter_core.h`. Conventions shared by all
+//! functions:
+//!
+//! - Functions return a [`VeterStatus`]. On failure, the error message can
+//!   be taken with [`veter_last_error_message`] on the same thread.
+//! - Storage, crypto and network state live behind opaque handles, created
+//!   by `*_open` / `*_new` functions and released with the matching `*_free`.
+//!   Handles may be used from any thread.
+//! - Model types travel as UTF-8 JSON in their serde representation, IDs as
+//!   UUID strings, and binary data as [`VeterBuffer`]s. Strings and buffers
+//!   returned through out parameters belong to the caller, who releases them
+//!   with [`veter_string_free`] and [`veter_buffer_free`].
+//! - Calls block until the operation completes, so the app makes them off
+//!   its UI thread. Incoming events are delivered to callbacks on a thread
+//!   of the core's runtime.
+//!
+//! # Safety
+//!
+//! Every pointer argument must be null or valid for the duration of the
+//! call: strings NUL-terminated, `(data, len)` pairs readable for `len`
+//! bytes, out parameters writable, and handles live ones from this library.
+//! Null pointers are reported as [`VeterStatus::InvalidInput`].
+
+// Safety is covered above, once for all functions
+#![allow(clippy::missing_safety_doc)]
+
+pub mod crypto;
+pub mod network;
+pub mod storage;
+
+use crate::{VeterError, Result};
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char};
+use std::panic::{AssertUnwindSafe, catch_unwind};
+use std::sync::RwLock;
+use tokio::runtime::Runtime;
+
+/// Result of an FFI call, one code per [`VeterError`] variant
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VeterStatus {
+    Ok = 0,
+    Crypto = 1,
+    InvalidEnvelope = 2,
+    Database = 3,
+    Network = 4,
+    Serialization = 5,
+    KeyManagement = 6,
+    Authentication = 7,
+    Storage = 8,
+    InvalidInput = 9,
+    Internal = 10,
+}
+
+impl From<&VeterError> for VeterStatus {
+    fn from(error: &VeterError) -> Self {
+        match error {
+            VeterError::Crypto(_) => Self::Crypto,
+            VeterError::InvalidEnvelope(_) => Self::InvalidEnvelope,
+            VeterError::Database(_) => Self::Database,
+            VeterError::Network(_) => Self::Network,
+            VeterError::Serialization(_) => Self::Serialization,
+            VeterError::KeyManagement(_) => Self::KeyManagement,
+            VeterError::Authentication(_) => Self::Authentication,
+            VeterError::Storage(_) => Self::Storage,
+            VeterError::InvalidInput(_) => Self::InvalidInput,
+            VeterError::Internal(_) => Self::Internal,
+        }
+    }
+}
+
+/// Bytes owned by whoever received them
+#[repr(C)]
+#[derive(Debug)]
+pub struct VeterBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl VeterBuffer {
+    fn from_vec(bytes: Vec<u8>) -> Self {
+        let bytes = Box::into_raw(bytes.into_boxed_slice());
+        Self {
+            data: bytes.cast(),
+            len: bytes.len(),
+        }
+    }
+}
+
+/// Runtime the blocking calls and event callbacks run on, between
+/// [`veter_init`] and [`veter_cleanup`]
+static RUNTIME: RwLock<Option<Runtime>> = RwLock::new(None);
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Start the runtime the handles run on. Has to be called before anything
+/// else; calling it again does nothing.
+#[no_mangle]
+pub extern "C" fn veter_init() -> VeterStatus {
+    call(|| {
+        let mut runtime = RUNTIME.write().map_err(|_| poisoned())?;
+        if runtime.is_none() {
+            *runtime = Some(tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .thread_name("veter-core")
+                .build()
+                .map_err(|e| VeterError::Internal(format!("Failed to start runtime: {}", e)))?);
+        }
+        Ok(())
+    })
+}
+
+/// Stop the core's runtime, ending subscriptions. Handles must not be used
+/// afterwards, except to free them.
+#[no_mangle]
+pub extern "C" fn veter_cleanup() -> VeterStatus {
+    call(|| {
+        let runtime = RUNTIME.write().map_err(|_| poisoned())?.take();
+        if let Some(runtime) = runtime {
+            runtime.shutdown_background();
+        }
+        Ok(())
+    })
+}
+
+/// Message of the last error on the calling thread, or null if there was
+/// none. Taking it clears it.
+#[no_mangle]
+pub extern "C" fn veter_last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|last| last.borrow_mut().take())
+        .map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+/// Free a string returned by the core
+#[no_mangle]
+pub unsafe extern "C" fn veter_string_free(string: *mut c_char) {
+    if !string.is_null() {
+        drop(CString::from_raw(string));
+    }
+}
+
+/// Free a buffer returned by the core
+#[no_mangle]
+pub unsafe extern "C" fn veter_buffer_free(buffer: VeterBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(std::ptr::slice_from_raw_parts_mut(buffer.data, buffer.len)));
+    }
+}
+
+/// Run the body of an FFI function, turning errors and panics into a status
+/// and the thread's last error
+fn call(body: impl FnOnce() -> Result<()>) -> VeterStatus {
+    let error = match catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => return VeterStatus::Ok,
+        Ok(Err(e)) => e,
+        Err(_) => VeterError::Internal("Panic in core".to_string()),
+    };
+
+    let status = VeterStatus::from(&error);
+    let message = CString::new(error.to_string().replace('\0', " ")).ok();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+    status
+}
+
+/// Run a future to completion on the core's runtime
+fn block_on<T>(future: impl std::future::Future<Output = Result<T>>) -> Result<T> {
+    let runtime = RUNTIME.read().map_err(|_| poisoned())?;
+    runtime.as_ref()
+        .ok_or_else(not_initialized)?
+        .block_on(future)
+}
+
+/// Handle of the core's runtime, for spawning event tasks
+fn runtime_handle() -> Result<tokio::runtime::Handle> {
+    let runtime = RUNTIME.read().map_err(|_| poisoned())?;
+    Ok(runtime.as_ref().ok_or_else(not_initialized)?.handle().clone())
+}
+
+fn not_initialized() -> VeterError {
+    VeterError::Internal("Core not initialized, call veter_init first".to_string())
+}
+
+fn poisoned() -> VeterError {
+    VeterError::Internal("Runtime lock poisoned".to_string())
+}
+
+/// Borrow the object behind a handle
+unsafe fn handle<'a, T>(handle: *const T) -> Result<&'a T> {
+    handle.as_ref().ok_or_else(|| null_argument("handle"))
+}
+
+unsafe fn string<'a>(string: *const c_char, name: &str) -> Result<&'a str> {
+    if string.is_null() {
+        return Err(null_argument(name));
+    }
+    CStr::from_ptr(string)
+        .to_str()
+        .map_err(|e| VeterError::InvalidInput(format!("{} is not UTF-8: {}", name, e)))
+}
+
+unsafe fn bytes<'a>(data: *const u8, len: usize, name: &str) -> Result<&'a [u8]> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(null_argument(name)),
+        (false, _) => Ok(std::slice::from_raw_parts(data, len)),
+    }
+}
+
+unsafe fn uuid(string: *const c_char, name: &str) -> Result<uuid::Uuid> {
+    uuid::Uuid::parse_str(self::string(string, name)?)
+        .map_err(|e| VeterError::InvalidInput(format!("Invalid {}: {}", name, e)))
+}
+
+/// An ID that may be null
+unsafe fn optional_uuid(string: *const c_char, name: &str) -> Result<Option<uuid::Uuid>> {
+    if string.is_null() {
+        Ok(None)
+    } else {
+        uuid(string, name).map(Some)
+    }
+}
+
+unsafe fn json<T: serde::de::DeserializeOwned>(string: *const c_char, name: &str) -> Result<T> {
+    serde_json::from_str(self::string(string, name)?)
+        .map_err(|e| VeterError::Serialization(format!("Invalid {}: {}", name, e)))
+}
+
+/// Write a value to an out parameter
+unsafe fn write<T>(out: *mut T, value: T) -> Result<()> {
+    if out.is_null() {
+        return Err(null_argument("out"));
+    }
+    out.write(value);
+    Ok(())
+}
+
+unsafe fn write_json(out: *mut *mut c_char, value: &impl serde::Serialize) -> Result<()> {
+    let json = CString::new(serde_json::to_string(value)?)
+        .map_err(|e| VeterError::Serialization(format!("JSON contains NUL: {}", e)))?;
+    write(out, json.into_raw())
+}
+
+fn null_argument(name: &str) -> VeterError {
+    VeterError::InvalidInput(format!("{} is null", name))
+}
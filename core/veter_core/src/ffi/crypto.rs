@@ -0,0 +1,199 @@
+//! Device keys, sessions and message encryption
+
+use crate::{VeterError, Result, models::*};
+use crate::crypto::CryptoManager;
+use super::*;
+use std::sync::{Mutex, MutexGuard};
+
+/// Opaque handle to a device's crypto state
+pub struct VeterCrypto(Mutex<CryptoManager>);
+
+impl VeterCrypto {
+    pub(crate) fn lock(&self) -> Result<MutexGuard<'_, CryptoManager>> {
+        self.0.lock().map_err(|_| VeterError::Internal("Crypto lock poisoned".to_string()))
+    }
+}
+
+/// Generate a new identity key pair
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_generate_identity_keypair(
+    out_private_key: *mut VeterBuffer,
+    out_public_key: *mut VeterBuffer,
+) -> VeterStatus {
+    call(|| {
+        if out_private_key.is_null() || out_public_key.is_null() {
+            return Err(null_argument("out"));
+        }
+        let (private_key, public_key) = CryptoManager::generate_identity_keypair()?;
+        write(out_private_key, VeterBuffer::from_vec(private_key))?;
+        write(out_public_key, VeterBuffer::from_vec(public_key))
+    })
+}
+
+/// Create the crypto state of a device from its identity private key
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_new(
+    identity_key: *const u8,
+    identity_key_len: usize,
+    device_id: *const c_char,
+    out_crypto: *mut *mut VeterCrypto,
+) -> VeterStatus {
+    call(|| {
+        let identity_key = bytes(identity_key, identity_key_len, "identity key")?.to_vec();
+        let device_id = uuid(device_id, "device ID")?;
+        let crypto = CryptoManager::new(identity_key, device_id)?;
+        write(out_crypto, Box::into_raw(Box::new(VeterCrypto(Mutex::new(crypto)))))
+    })
+}
+
+/// Free a crypto handle
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_free(crypto: *mut VeterCrypto) {
+    if !crypto.is_null() {
+        drop(Box::from_raw(crypto));
+    }
+}
+
+/// Public identity key of the device
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_identity_public_key(crypto: *const VeterCrypto, out_key: *mut VeterBuffer) -> VeterStatus {
+    call(|| {
+        let public_key = handle(crypto)?.lock()?.identity_public_key();
+        write(out_key, VeterBuffer::from_vec(public_key))
+    })
+}
+
+/// Generate a signed prekey and `one_time_prekeys` one-time prekeys,
+/// returned as `KeyMaterial` JSON for publishing
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_generate_key_material(
+    crypto: *const VeterCrypto,
+    one_time_prekeys: usize,
+    out_key_material_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let key_material = handle(crypto)?.lock()?.generate_key_material(one_time_prekeys)?;
+        write_json(out_key_material_json, &key_material)
+    })
+}
+
+/// Start a session with a device in a room from its `KeyMaterial` JSON
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_start_session(
+    crypto: *const VeterCrypto,
+    room_id: *const c_char,
+    device_id: *const c_char,
+    bundle_json: *const c_char,
+) -> VeterStatus {
+    call(|| {
+        let crypto = handle(crypto)?;
+        let room_id = uuid(room_id, "room ID")?;
+        let device_id = uuid(device_id, "device ID")?;
+        let bundle: KeyMaterial = json(bundle_json, "bundle")?;
+        crypto.lock()?.start_session(room_id, device_id, &bundle)
+    })
+}
+
+/// Tell the crypto state about a room or a change of its members, given as
+/// `Room` JSON, with the known devices of its past and present members as a
+/// JSON array of `Device`. Devices whose user has left lose their session
+/// and sender key; their IDs are returned as a JSON array so their state
+/// can be deleted from storage.
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_update_room(
+    crypto: *const VeterCrypto,
+    room_json: *const c_char,
+    devices_json: *const c_char,
+    out_removed_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let crypto = handle(crypto)?;
+        let room: Room = json(room_json, "room")?;
+        let devices: Vec<Device> = json(devices_json, "devices")?;
+        let removed = crypto.lock()?.update_room(&room, &devices)?;
+        write_json(out_removed_json, &removed)
+    })
+}
+
+/// Encrypt a message to a room
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_encrypt_message(
+    crypto: *const VeterCrypto,
+    content: *const u8,
+    content_len: usize,
+    room_id: *const c_char,
+    message_id: *const c_char,
+    out_payload: *mut VeterBuffer,
+) -> VeterStatus {
+    call(|| {
+        let crypto = handle(crypto)?;
+        let content = bytes(content, content_len, "content")?;
+        let room_id = uuid(room_id, "room ID")?;
+        let message_id = uuid(message_id, "message ID")?;
+        let payload = crypto.lock()?.encrypt_message(content, room_id, message_id)?;
+        write(out_payload, VeterBuffer::from_vec(payload))
+    })
+}
+
+/// Decrypt a message sent by a peer device
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_decrypt_message(
+    crypto: *const VeterCrypto,
+    payload: *const u8,
+    payload_len: usize,
+    room_id: *const c_char,
+    sender_device_id: *const c_char,
+    message_id: *const c_char,
+    out_content: *mut VeterBuffer,
+) -> VeterStatus {
+    call(|| {
+        let crypto = handle(crypto)?;
+        let payload = bytes(payload, payload_len, "payload")?;
+        let room_id = uuid(room_id, "room ID")?;
+        let sender_device_id = uuid(sender_device_id, "sender device ID")?;
+        let message_id = uuid(message_id, "message ID")?;
+        let content = crypto.lock()?.decrypt_message(payload, room_id, sender_device_id, message_id)?;
+        write(out_content, VeterBuffer::from_vec(content))
+    })
+}
+
+/// Encrypt file content under a fresh key, returning the key and the blob
+/// to upload
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_encrypt_file(
+    crypto: *const VeterCrypto,
+    content: *const u8,
+    content_len: usize,
+    out_key: *mut VeterBuffer,
+    out_blob: *mut VeterBuffer,
+) -> VeterStatus {
+    call(|| {
+        let crypto = handle(crypto)?;
+        let content = bytes(content, content_len, "content")?;
+        if out_key.is_null() || out_blob.is_null() {
+            return Err(null_argument("out"));
+        }
+        let (key, blob) = crypto.lock()?.encrypt_file(content)?;
+        write(out_key, VeterBuffer::from_vec(key))?;
+        write(out_blob, VeterBuffer::from_vec(blob))
+    })
+}
+
+/// Decrypt file content
+#[no_mangle]
+pub unsafe extern "C" fn veter_crypto_decrypt_file(
+    crypto: *const VeterCrypto,
+    key: *const u8,
+    key_len: usize,
+    blob: *const u8,
+    blob_len: usize,
+    out_content: *mut VeterBuffer,
+) -> VeterStatus {
+    call(|| {
+        let crypto = handle(crypto)?;
+        let key = bytes(key, key_len, "key")?;
+        let blob = bytes(blob, blob_len, "blob")?;
+        let content = crypto.lock()?.decrypt_file(key, blob)?;
+        write(out_content, VeterBuffer::from_vec(content))
+    })
+}
@@ -0,0 +1,236 @@
+//! Servers, inbound sync and pushed messages
+
+use crate::{VeterError, Result, models::*};
+use crate::networking::NetworkClient;
+use super::*;
+use super::crypto::VeterCrypto;
+use super::storage::VeterStorage;
+use std::ffi::c_void;
+use std::sync::{Mutex, MutexGuard};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+/// Opaque handle to a connection to the servers
+pub struct VeterNetwork(Mutex<NetworkClient>);
+
+impl VeterNetwork {
+    fn lock(&self) -> Result<MutexGuard<'_, NetworkClient>> {
+        self.0.lock().map_err(|_| VeterError::Internal("Network lock poisoned".to_string()))
+    }
+}
+
+/// Opaque handle to a running message subscription
+pub struct VeterSubscription(JoinHandle<()>);
+
+/// Called for each event of a subscription, with the `user_data` given when
+/// subscribing. On `Ok`, `json` is the event as JSON, otherwise it is the
+/// error message. `json` is only valid during the call.
+pub type VeterEventCallback = extern "C" fn(user_data: *mut c_void, status: VeterStatus, json: *const c_char);
+
+/// Callback and its user data, moved to the runtime thread calling it
+struct Listener {
+    callback: VeterEventCallback,
+    user_data: *mut c_void,
+}
+
+// The caller guarantees that `user_data` may be used from other threads
+unsafe impl Send for Listener {}
+
+impl Listener {
+    fn emit(&self, event: Result<String>) {
+        let (status, text) = match event {
+            Ok(json) => (VeterStatus::Ok, json),
+            Err(e) => (VeterStatus::from(&e), e.to_string()),
+        };
+        let text = CString::new(text.replace('\0', " ")).unwrap_or_default();
+        (self.callback)(self.user_data, status, text.as_ptr());
+    }
+}
+
+/// Create a network client, not yet connected to any server
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_new(out_network: *mut *mut VeterNetwork) -> VeterStatus {
+    call(|| write(out_network, Box::into_raw(Box::new(VeterNetwork(Mutex::new(NetworkClient::new()))))))
+}
+
+/// Free a network client
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_free(network: *mut VeterNetwork) {
+    if !network.is_null() {
+        drop(Box::from_raw(network));
+    }
+}
+
+/// Connect to the relay at `endpoint`
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_connect_relay(network: *const VeterNetwork, endpoint: *const c_char) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let endpoint = string(endpoint, "endpoint")?;
+        block_on(network.lock()?.connect_relay(endpoint))
+    })
+}
+
+/// Connect to the directory service at `endpoint`
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_connect_directory(network: *const VeterNetwork, endpoint: *const c_char) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let endpoint = string(endpoint, "endpoint")?;
+        block_on(network.lock()?.connect_directory(endpoint))
+    })
+}
+
+/// Register a device, given as JSON, with the directory
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_register_device(network: *const VeterNetwork, device_json: *const c_char) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let device: Device = json(device_json, "device")?;
+        block_on(network.lock()?.register_device(&device))
+    })
+}
+
+/// Devices of a user, as a JSON array of `Device`s
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_get_user_directory(
+    network: *const VeterNetwork,
+    user_id: *const c_char,
+    out_devices_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let user_id = uuid(user_id, "user ID")?;
+        let devices = block_on(network.lock()?.get_user_directory(&user_id))?;
+        write_json(out_devices_json, &devices)
+    })
+}
+
+/// Hand a JSON array of `EncryptedMessage`s to the relay, returning the IDs
+/// of the accepted ones as a JSON array
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_send_messages(
+    network: *const VeterNetwork,
+    messages_json: *const c_char,
+    out_accepted_ids_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let messages: Vec<EncryptedMessage> = json(messages_json, "messages")?;
+        let accepted = block_on(network.lock()?.send_messages(messages))?;
+        write_json(out_accepted_ids_json, &accepted)
+    })
+}
+
+/// Fetch up to `max_items` messages for a device, as a JSON array of
+/// `EncryptedMessage`s
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_receive_messages(
+    network: *const VeterNetwork,
+    device_id: *const c_char,
+    max_items: u32,
+    out_messages_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let device_id = uuid(device_id, "device ID")?;
+        let messages = block_on(network.lock()?.receive_messages(&device_id, max_items))?;
+        write_json(out_messages_json, &messages)
+    })
+}
+
+/// Acknowledge messages received by a device, given as a JSON array of IDs
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_acknowledge_messages(
+    network: *const VeterNetwork,
+    device_id: *const c_char,
+    message_ids_json: *const c_char,
+) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let device_id = uuid(device_id, "device ID")?;
+        let message_ids: Vec<MessageId> = json(message_ids_json, "message IDs")?;
+        block_on(network.lock()?.acknowledge_messages(&device_id, message_ids))
+    })
+}
+
+/// Fetch up to `max_items` messages for a device, decrypt them and commit
+/// them to storage, see `SyncEngine::sync`. Returns a `SyncReport` as JSON.
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_sync(
+    network: *const VeterNetwork,
+    storage: *const VeterStorage,
+    crypto: *const VeterCrypto,
+    device_id: *const c_char,
+    max_items: u32,
+    out_report_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let storage = handle(storage)?;
+        let crypto = handle(crypto)?;
+        let device_id = uuid(device_id, "device ID")?;
+        let engine = network.lock()?.sync_engine(storage.0.clone(), device_id)?;
+        let mut crypto = crypto.lock()?;
+        let report = block_on(engine.sync(&mut crypto, max_items))?;
+        write_json(out_report_json, &report)
+    })
+}
+
+/// Have the relay push messages for a device as they arrive. `callback` is
+/// called on a runtime thread with each message as `EncryptedMessage` JSON,
+/// or with the error when the subscription breaks, after which it resumes
+/// by itself. Messages still have to be acknowledged. The callback must not
+/// call back into the core.
+#[no_mangle]
+pub unsafe extern "C" fn veter_network_subscribe_messages(
+    network: *const VeterNetwork,
+    device_id: *const c_char,
+    credits: u32,
+    callback: Option<VeterEventCallback>,
+    user_data: *mut c_void,
+    out_subscription: *mut *mut VeterSubscription,
+) -> VeterStatus {
+    call(|| {
+        let network = handle(network)?;
+        let device_id = uuid(device_id, "device ID")?;
+        let listener = Listener {
+            callback: callback.ok_or_else(|| null_argument("callback"))?,
+            user_data,
+        };
+        if out_subscription.is_null() {
+            return Err(null_argument("out"));
+        }
+
+        let runtime = runtime_handle()?;
+        let _context = runtime.enter();
+        let mut messages = Box::pin(network.lock()?.subscribe_messages(device_id, credits)?);
+        let task = runtime.spawn(async move {
+            while let Some(message) = messages.next().await {
+                listener.emit(message.and_then(|message| Ok(serde_json::to_string(&message)?)));
+            }
+        });
+        write(out_subscription, Box::into_raw(Box::new(VeterSubscription(task))))
+    })
+}
+
+/// End a subscription and free its handle. Waits for a callback under way
+/// to return, so `user_data` can be released afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn veter_subscription_cancel(subscription: *mut VeterSubscription) -> VeterStatus {
+    call(|| {
+        if subscription.is_null() {
+            return Ok(());
+        }
+        let task = Box::from_raw(subscription).0;
+        task.abort();
+        // After `veter_cleanup` the task is gone with the runtime
+        if RUNTIME.read().map_err(|_| poisoned())?.is_none() {
+            return Ok(());
+        }
+        block_on(async {
+            let _ = task.await;
+            Ok(())
+        })
+    })
+}
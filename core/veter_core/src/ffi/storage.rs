@@ -0,0 +1,256 @@
+//! Encrypted local storage
+
+use crate::models::*;
+use crate::storage::StorageManager;
+use super::*;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Opaque handle to an open database
+pub struct VeterStorage(pub(crate) Arc<StorageManager>);
+
+/// Open or create the database at `path`. A wrong password fails with
+/// `Authentication`.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_open(
+    path: *const c_char,
+    password: *const c_char,
+    out_storage: *mut *mut VeterStorage,
+) -> VeterStatus {
+    call(|| {
+        let path = Path::new(string(path, "path")?);
+        let password = string(password, "password")?;
+        let storage = block_on(StorageManager::new(path, password))?;
+        write(out_storage, Box::into_raw(Box::new(VeterStorage(Arc::new(storage)))))
+    })
+}
+
+/// Close a database handle
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_free(storage: *mut VeterStorage) {
+    if !storage.is_null() {
+        drop(Box::from_raw(storage));
+    }
+}
+
+/// Change the database password
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_change_password(
+    storage: *const VeterStorage,
+    current_password: *const c_char,
+    new_password: *const c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let current_password = string(current_password, "current password")?;
+        let new_password = string(new_password, "new password")?;
+        block_on(storage.0.change_password(current_password, new_password))
+    })
+}
+
+/// Store a user, given as JSON
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_store_user(storage: *const VeterStorage, user_json: *const c_char) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let user: User = json(user_json, "user")?;
+        block_on(storage.0.store_user(&user))
+    })
+}
+
+/// Store a device, given as JSON
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_store_device(storage: *const VeterStorage, device_json: *const c_char) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let device: Device = json(device_json, "device")?;
+        block_on(storage.0.store_device(&device))
+    })
+}
+
+/// Store a room and its members, given as JSON
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_store_room(storage: *const VeterStorage, room_json: *const c_char) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let room: Room = json(room_json, "room")?;
+        block_on(storage.0.store_room(&room))
+    })
+}
+
+/// Store a message, given as JSON
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_store_message(storage: *const VeterStorage, message_json: *const c_char) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let message: Message = json(message_json, "message")?;
+        block_on(storage.0.store_message(&message))
+    })
+}
+
+/// Load a page of a room's messages. Takes a `MessageQuery` and returns a
+/// `MessagePage`, both as JSON.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_messages(
+    storage: *const VeterStorage,
+    room_id: *const c_char,
+    query_json: *const c_char,
+    out_page_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let room_id = uuid(room_id, "room ID")?;
+        let query: MessageQuery = json(query_json, "query")?;
+        let page = block_on(storage.0.get_messages(&room_id, &query))?;
+        write_json(out_page_json, &page)
+    })
+}
+
+/// Load a page of the replies to a message. Takes a `MessageQuery` and
+/// returns a `MessagePage`, both as JSON.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_thread(
+    storage: *const VeterStorage,
+    root_id: *const c_char,
+    query_json: *const c_char,
+    out_page_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let root_id = uuid(root_id, "root message ID")?;
+        let query: MessageQuery = json(query_json, "query")?;
+        let page = block_on(storage.0.get_thread(&root_id, &query))?;
+        write_json(out_page_json, &page)
+    })
+}
+
+/// A room's threads as seen by `user_id`, most recently active first.
+/// Returns a JSON array of `ThreadSummary`s.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_room_threads(
+    storage: *const VeterStorage,
+    user_id: *const c_char,
+    room_id: *const c_char,
+    limit: u32,
+    out_threads_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let user_id = uuid(user_id, "user ID")?;
+        let room_id = uuid(room_id, "room ID")?;
+        let threads = block_on(storage.0.get_room_threads(&user_id, &room_id, limit))?;
+        write_json(out_threads_json, &threads)
+    })
+}
+
+/// Mark a thread read up to and including one of its replies
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_mark_thread_read(
+    storage: *const VeterStorage,
+    root_id: *const c_char,
+    message_id: *const c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let root_id = uuid(root_id, "root message ID")?;
+        let message_id = uuid(message_id, "message ID")?;
+        block_on(storage.0.mark_thread_read(&root_id, &message_id))
+    })
+}
+
+/// Set the user this device belongs to, whose unread messages are counted
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_set_local_user(
+    storage: *const VeterStorage,
+    user_id: *const c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let user_id = uuid(user_id, "user ID")?;
+        block_on(storage.0.set_local_user(&user_id))
+    })
+}
+
+/// All rooms with the local user's unread counts, most recently active
+/// first. Returns a JSON array of `RoomSummary`s.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_room_summaries(
+    storage: *const VeterStorage,
+    out_rooms_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let rooms = block_on(storage.0.get_room_summaries())?;
+        write_json(out_rooms_json, &rooms)
+    })
+}
+
+/// Latest receipts of the users in a room. Returns a JSON array of
+/// `Receipt`s.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_receipts(
+    storage: *const VeterStorage,
+    room_id: *const c_char,
+    out_receipts_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let room_id = uuid(room_id, "room ID")?;
+        let receipts = block_on(storage.0.get_receipts(&room_id))?;
+        write_json(out_receipts_json, &receipts)
+    })
+}
+
+/// Reactions to messages as seen by `user_id`. Takes a JSON array of
+/// message IDs and returns a JSON object from message ID to an array of
+/// `ReactionSummary`s.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_reactions(
+    storage: *const VeterStorage,
+    user_id: *const c_char,
+    message_ids_json: *const c_char,
+    out_reactions_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let user_id = uuid(user_id, "user ID")?;
+        let message_ids: Vec<MessageId> = json(message_ids_json, "message IDs")?;
+        let reactions = block_on(storage.0.get_reactions(&user_id, &message_ids))?;
+        write_json(out_reactions_json, &reactions)
+    })
+}
+
+/// Versions of an edited message, the original first. Returns a JSON array
+/// of `MessageVersion`s.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_get_message_history(
+    storage: *const VeterStorage,
+    message_id: *const c_char,
+    out_versions_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let message_id = uuid(message_id, "message ID")?;
+        let versions = block_on(storage.0.get_message_history(&message_id))?;
+        write_json(out_versions_json, &versions)
+    })
+}
+
+/// Full-text search over messages, in one room or, if `room_id` is null, in
+/// all of them. Returns a JSON array of `SearchResult`s.
+#[no_mangle]
+pub unsafe extern "C" fn veter_storage_search_messages(
+    storage: *const VeterStorage,
+    query: *const c_char,
+    room_id: *const c_char,
+    limit: i64,
+    out_results_json: *mut *mut c_char,
+) -> VeterStatus {
+    call(|| {
+        let storage = handle(storage)?;
+        let query = string(query, "query")?;
+        let room_id = optional_uuid(room_id, "room ID")?;
+        let results = block_on(storage.0.search_messages(query, room_id.as_ref(), limit))?;
+        write_json(out_results_json, &results)
+    })
+}
@@ -3,23 +3,28 @@
 //! This crate provides the core cryptographic, storage, and networking
 //! functionality for the Veter messenger application.
 
+pub mod crdt;
 pub mod crypto;
+pub mod engine;
 pub mod storage;
 pub mod networking;
 pub mod models;
 pub mod error;
+pub mod ffi;
+pub mod sync;
 
 // Re-export commonly used types
+pub use engine::{CoreConfig, VeterCore};
 pub use error::{VeterError, Result};
 
-/// Initialize the Veter core engine
-pub fn init() -> Result<()> {
-    // TODO: Initialize crypto libraries, database connections, etc.
-    Ok(())
+/// Initialize the Veter core engine: start its runtime, open the database,
+/// load the device's keys and connect to the configured servers
+pub fn init(config: CoreConfig) -> Result<VeterCore> {
+    VeterCore::start(config)
 }
 
-/// Cleanup resources
-pub fn cleanup() -> Result<()> {
-    // TODO: Cleanup connections, save state, etc.
-    Ok(())
+/// Shut the engine down: flush key state, stop background tasks, close the
+/// database and wipe the keys in memory
+pub fn cleanup(core: VeterCore) -> Result<()> {
+    core.shutdown()
 }
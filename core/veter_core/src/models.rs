@@ -3,6 +3,7 @@
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 use chrono::{DateTime, Utc};
+use crate::crdt::RoomOp;
 
 /// Unique identifier for a device
 pub type DeviceId = Uuid;
@@ -16,6 +17,9 @@ pub type RoomId = Uuid;
 /// Unique identifier for a message
 pub type MessageId = Uuid;
 
+/// Unique identifier for a legal hold
+pub type HoldId = Uuid;
+
 /// Device information
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Device {
@@ -26,6 +30,18 @@ pub struct Device {
     pub public_key: Vec<u8>,
     pub created_at: DateTime<Utc>,
     pub last_seen: DateTime<Utc>,
+    /// Maintained by `StorageManager`, see `StorageManager::verify_device`
+    #[serde(default)]
+    pub verification: VerificationState,
+}
+
+/// Verification state of a device's identity key
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VerificationState {
+    #[default]
+    Unverified,
+    Verified,
+    Changed, // identity key changed since it was verified
 }
 
 /// Platform types
@@ -56,6 +72,9 @@ pub struct Room {
     pub name: String,
     pub description: Option<String>,
     pub room_type: RoomType,
+    /// Chosen when the room is created and never changed afterwards
+    #[serde(default)]
+    pub encryption: RoomEncryption,
     pub members: Vec<UserId>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
@@ -69,6 +88,14 @@ pub enum RoomType {
     Channel, // Broadcast channel
 }
 
+/// End-to-end encryption mode of a room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RoomEncryption {
+    #[default]
+    Signal, // Pairwise sessions, sender keys in groups
+    Mls,    // MLS (RFC 9420) group
+}
+
 /// Message content
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
@@ -91,17 +118,180 @@ pub enum MessageContent {
         mime_type: String,
         size: u64,
         url: String,
+        /// Key of the encrypted blob at `url`, see `CryptoManager::encrypt_file`
+        #[serde(default)]
+        key: Vec<u8>,
     },
     Image {
         url: String,
         width: u32,
         height: u32,
+        #[serde(default)]
+        key: Vec<u8>,
     },
     Reaction {
         emoji: String,
         target_message_id: MessageId,
     },
+    /// Withdrawal of the sender's reaction
+    RemoveReaction {
+        emoji: String,
+        target_message_id: MessageId,
+    },
     System(String), // System messages (user joined, etc.)
+    /// Change to the room's name, description or members
+    RoomEvent(RoomOp),
+    /// New content for a message, from its sender
+    Edit {
+        target_message_id: MessageId,
+        content: Box<MessageContent>,
+    },
+    /// Removal of a message's content, from its sender
+    Redact {
+        target_message_id: MessageId,
+    },
+    /// Left in place of a redacted message's content
+    Redacted,
+    /// The sender received or read the room's messages up to and including
+    /// this one. Receipts are ordered by the creation time of the stored
+    /// message, so one for a message not stored yet waits in quarantine.
+    Receipt {
+        receipt_type: ReceiptType,
+        message_id: MessageId,
+    },
+}
+
+impl MessageContent {
+    /// Whether the content can be edited or redacted, and can replace
+    /// content in an edit
+    pub fn is_editable(&self) -> bool {
+        matches!(self, MessageContent::Text(_) | MessageContent::File { .. } | MessageContent::Image { .. })
+    }
+}
+
+/// One version of an edited message's content: the original, or an edit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageVersion {
+    /// ID of the edit, or of the message for the original
+    pub id: MessageId,
+    pub sender_device_id: DeviceId,
+    pub content: MessageContent,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Position in a room's history, handed back to continue paging.
+///
+/// Opaque to callers; it encodes the timestamp and ID of the message at the
+/// edge of a page.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageCursor(pub String);
+
+/// Direction to page through a room's history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum PageDirection {
+    #[default]
+    Backward, // Towards older messages
+    Forward,  // Towards newer messages
+}
+
+/// Which messages of a room to load
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageQuery {
+    /// Continue after this cursor, or start from the newest (backward) or
+    /// oldest (forward) message
+    pub cursor: Option<MessageCursor>,
+    pub direction: PageDirection,
+    /// Only messages created at or after this time
+    pub since: Option<DateTime<Utc>>,
+    /// Only messages created before this time
+    pub until: Option<DateTime<Utc>>,
+    pub limit: u32,
+}
+
+impl MessageQuery {
+    /// The newest messages of a room
+    pub fn latest(limit: u32) -> Self {
+        Self {
+            cursor: None,
+            direction: PageDirection::Backward,
+            since: None,
+            until: None,
+            limit,
+        }
+    }
+}
+
+/// A page of messages, oldest first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// Cursor to load the messages before this page
+    pub older: Option<MessageCursor>,
+    /// Cursor to load the messages after this page
+    pub newer: Option<MessageCursor>,
+}
+
+/// How far a receipt says a user got through a room
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ReceiptType {
+    Delivered, // Received on one of the user's devices
+    Read,      // Seen by the user; implies delivered
+}
+
+/// Latest receipt of a user in a room
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub user_id: UserId,
+    pub receipt_type: ReceiptType,
+    pub message_id: MessageId,
+    pub message_created_at: DateTime<Utc>,
+}
+
+/// A room with the local user's unread counts, for the room list
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomSummary {
+    pub room: Room,
+    /// Messages from others after the read marker
+    pub unread_count: u32,
+    /// Unread messages mentioning the local user as `@username`
+    pub mention_count: u32,
+    /// Creation time of the newest message, if any
+    pub last_activity_at: Option<DateTime<Utc>>,
+    /// The local user's read marker
+    pub read_up_to: Option<MessageId>,
+}
+
+/// Reactions to a message with one emoji
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: String,
+    pub count: u32,
+    /// Whether the user is one of those who reacted
+    pub reacted: bool,
+}
+
+/// Activity in the thread of replies to a message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadSummary {
+    pub root: Message,
+    pub reply_count: u32,
+    pub last_reply_at: DateTime<Utc>,
+    /// Replies by others after the last one marked read
+    pub unread_count: u32,
+    /// Whether the user sent the root or a reply
+    pub participating: bool,
+}
+
+/// Message found by full-text search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub message: Message,
+    /// Excerpt of the indexed text around the matches
+    pub snippet: String,
+    /// Byte ranges of the matched terms within `snippet`
+    pub highlights: Vec<(usize, usize)>,
+    /// BM25 score, lower is more relevant
+    pub rank: f64,
 }
 
 /// Encrypted message payload
@@ -112,6 +302,43 @@ pub struct EncryptedMessage {
     pub sender_device_id: DeviceId,
     pub payload: Vec<u8>, // E2EE encrypted content
     pub timestamp: DateTime<Utc>,
+    /// Devices the relay queues the message for
+    #[serde(default)]
+    pub recipient_device_ids: Vec<DeviceId>,
+}
+
+/// Delivery state of a message this device sent, shown as status ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeliveryState {
+    Pending,   // In the outbox, waiting for the relay to accept it
+    Sent,      // Accepted by the relay
+    Delivered, // Received by a recipient device
+    Failed,    // Given up on after repeated failures
+}
+
+/// Message waiting in the outbox or already sent from it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingMessage {
+    pub message: EncryptedMessage,
+    pub state: DeliveryState,
+    /// Failed attempts to hand the message to the relay
+    pub attempts: u32,
+    /// When a pending message is sent next
+    pub next_attempt_at: DateTime<Utc>,
+    /// Why the last attempt failed
+    pub last_error: Option<String>,
+}
+
+/// Received message that could not be decrypted or stored yet, kept to
+/// retry, e.g. once the key it needs arrived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QuarantinedMessage {
+    pub message: EncryptedMessage,
+    /// Why the last attempt failed
+    pub error: String,
+    pub attempts: u32,
+    pub quarantined_at: DateTime<Utc>,
+    pub last_attempt_at: DateTime<Utc>,
 }
 
 /// Key material for encryption
@@ -119,15 +346,76 @@ pub struct EncryptedMessage {
 pub struct KeyMaterial {
     pub identity_key: Vec<u8>,
     pub signed_prekey: Vec<u8>,
+    pub signed_prekey_signature: Vec<u8>,
     pub one_time_prekeys: Vec<Vec<u8>>,
+    /// Serialized MLS KeyPackages, each usable for joining one MLS room
+    #[serde(default)]
+    pub mls_key_packages: Vec<Vec<u8>>,
 }
 
-/// Session state for a conversation
+/// Session state with one peer device in a conversation
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
     pub room_id: RoomId,
-    pub device_id: DeviceId,
-    pub session_data: Vec<u8>, // libsignal session state
+    pub device_id: DeviceId, // peer device
+    pub session_data: Vec<u8>, // serialized Double Ratchet state
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
+
+/// Sender key state of one device in a group room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SenderKey {
+    pub room_id: RoomId,
+    pub device_id: DeviceId, // sending device
+    pub state_data: Vec<u8>, // serialized sender key generations
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Legal hold on a user. While it is active, the user's devices escrow a
+/// copy of each message to `hold_public_key`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegalHold {
+    pub id: HoldId,
+    pub user_id: UserId,
+    pub hold_public_key: Vec<u8>, // X25519; the private key is escrowed
+    pub reason: String,
+    pub started_at: DateTime<Utc>,
+    pub stopped_at: Option<DateTime<Utc>>,
+}
+
+impl LegalHold {
+    /// Whether messages still have to be escrowed for the hold
+    pub fn is_active(&self) -> bool {
+        self.stopped_at.is_none()
+    }
+}
+
+/// Copy of a message encrypted to a legal hold key, see
+/// `CryptoManager::escrow_message`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EscrowedMessage {
+    pub hold_id: HoldId,
+    pub message_id: MessageId,
+    pub room_id: RoomId,
+    pub sender_device_id: DeviceId,
+    pub escrow_device_id: DeviceId, // held user's device that made the copy
+    pub sent_at: DateTime<Utc>,
+    pub ephemeral_key: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+    pub signature: Vec<u8>, // by the escrow device's identity key
+}
+
+/// Key state advanced by decrypting a received message, stored together
+/// with it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct KeyStateUpdate {
+    pub session: Option<Session>,
+    pub sender_key: Option<SenderKey>,
+    /// The room's MLS group, as exported by `CryptoManager::mls_group_state`
+    pub mls_group: Option<(RoomId, Vec<u8>)>,
+    /// This device's prekeys after one was consumed, as exported by
+    /// `CryptoManager::prekey_state`
+    pub prekey_state: Option<(DeviceId, Vec<u8>)>,
+}
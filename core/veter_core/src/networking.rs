@@ -1,174 +1,222 @@
 //! Networking and API client for Veter
 
-use crate::{Result, models::*};
-use std::time::Duration;
+pub mod compliance;
+pub mod directory;
+pub mod outbox;
+pub mod relay;
+pub mod transport;
+
+pub use compliance::ComplianceClient;
+pub use directory::DirectoryClient;
+pub use outbox::{Outbox, OutboxConfig};
+pub use relay::RelayClient;
+pub use transport::{Transport, TransportConfig, TransportKind};
+
+use crate::{VeterError, Result, models::*};
+use crate::storage::StorageManager;
+use crate::sync::SyncEngine;
+use std::sync::Arc;
+use tokio_stream::Stream;
 
 /// Network client for communicating with Veter servers
 pub struct NetworkClient {
+    transport: Option<Transport>,
+    relay_transport: Option<TransportKind>,
     directory_client: Option<DirectoryClient>,
     relay_client: Option<RelayClient>,
     compliance_client: Option<ComplianceClient>,
 }
 
-/// Directory service client (placeholder)
-pub struct DirectoryClient {
-    // TODO: Implement actual gRPC client
-}
-
-/// Relay service client (placeholder)
-pub struct RelayClient {
-    // TODO: Implement actual gRPC client
-}
-
-/// Compliance service client (placeholder)
-pub struct ComplianceClient {
-    // TODO: Implement actual gRPC client
+impl Default for NetworkClient {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkClient {
     /// Create a new network client
     pub fn new() -> Self {
         Self {
+            transport: None,
+            relay_transport: None,
             directory_client: None,
             relay_client: None,
             compliance_client: None,
         }
     }
 
-    /// Connect to the directory service
+    /// Create a network client that connects through `transport`
+    pub fn with_transport(transport: Transport) -> Self {
+        Self {
+            transport: Some(transport),
+            ..Self::new()
+        }
+    }
+
+    /// Connect to the directory service, over the same transports as the
+    /// relay
     pub async fn connect_directory(&mut self, endpoint: &str) -> Result<()> {
-        // TODO: Implement actual gRPC connection
-        // let channel = Channel::from_shared(endpoint.to_string())
-        //     .map_err(|e| VeterError::Network(format!("Invalid endpoint: {}", e)))?
-        //     .timeout(Duration::from_secs(30))
-        //     .connect()
-        //     .await
-        //     .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
-        
-        // self.directory_client = Some(DirectoryClient::new(channel));
+        let (channel, _) = self.transport()?.connect(endpoint).await?;
+
+        self.directory_client = Some(DirectoryClient::new(channel));
         Ok(())
     }
 
-    /// Connect to the relay service
+    /// Connect to the relay service. `https` endpoints use QUIC, or TLS if
+    /// QUIC is unavailable.
     pub async fn connect_relay(&mut self, endpoint: &str) -> Result<()> {
-        // TODO: Implement actual gRPC connection with QUIC
-        // let tls_config = ClientTlsConfig::new();
-        // let channel = Channel::from_shared(endpoint.to_string())
-        //     .map_err(|e| VeterError::Network(format!("Invalid endpoint: {}", e)))?
-        //     .tls_config(tls_config)
-        //     .map_err(|e| VeterError::Network(format!("TLS config failed: {}", e)))?
-        //     .timeout(Duration::from_secs(30))
-        //     .connect()
-        //     .await
-        //     .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
-        
-        // self.relay_client = Some(RelayClient::new(channel));
+        let (channel, kind) = self.transport()?.connect(endpoint).await?;
+
+        self.relay_client = Some(RelayClient::new(channel));
+        self.relay_transport = Some(kind);
         Ok(())
     }
 
+    /// How the relay is reached, if connected
+    pub fn relay_transport(&self) -> Option<TransportKind> {
+        self.relay_transport
+    }
+
+    /// Migrate QUIC connections after the device changed networks, e.g.
+    /// from Wi-Fi to cellular
+    pub fn network_changed(&self) -> Result<()> {
+        match &self.transport {
+            Some(transport) => transport.network_changed(),
+            None => Ok(()),
+        }
+    }
+
     /// Connect to the compliance service
     pub async fn connect_compliance(&mut self, endpoint: &str) -> Result<()> {
-        // TODO: Implement actual gRPC connection
-        // let channel = Channel::from_shared(endpoint.to_string())
-        //     .map_err(|e| VeterError::Network(format!("Invalid endpoint: {}", e)))?
-        //     .timeout(Duration::from_secs(30))
-        //     .connect()
-        //     .await
-        //     .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
-        
-        // self.compliance_client = Some(ComplianceClient::new(channel));
+        let (channel, _) = self.transport()?.connect(endpoint).await?;
+
+        self.compliance_client = Some(ComplianceClient::new(channel));
         Ok(())
     }
 
     /// Register a device with the directory service
     pub async fn register_device(&self, device: &Device) -> Result<()> {
-        // TODO: Implement actual device registration
-        // if let Some(client) = &self.directory_client {
-        //     let request = RegisterDeviceRequest {
-        //         device: Some(device.into()),
-        //     };
-        //     client.register_device(request).await?;
-        // }
-        Ok(())
+        self.directory()?.register_device(device).await
     }
 
     /// Get user directory
     pub async fn get_user_directory(&self, user_id: &UserId) -> Result<Vec<Device>> {
-        // TODO: Implement actual directory lookup
-        // if let Some(client) = &self.directory_client {
-        //     let request = GetUserDevicesRequest {
-        //         user_id: user_id.to_string(),
-        //     };
-        //     let response = client.get_user_devices(request).await?;
-        //     return Ok(response.devices.into_iter().map(|d| d.into()).collect());
-        // }
-        Ok(vec![])
+        self.directory()?.list_user_devices(user_id).await
+    }
+
+    /// Publish a device's prekeys, returning how many one-time prekeys the
+    /// directory has left for it
+    pub async fn publish_prekeys(&self, device_id: &DeviceId, key_material: &KeyMaterial) -> Result<u32> {
+        self.directory()?.publish_prekeys(device_id, key_material).await
+    }
+
+    /// Fetch a peer device's prekey bundle, consuming one of its one-time
+    /// prekeys
+    pub async fn fetch_prekey_bundle(&self, device_id: &DeviceId) -> Result<KeyMaterial> {
+        self.directory()?.fetch_prekey_bundle(device_id).await
+    }
+
+    /// Revoke one of the user's devices
+    pub async fn revoke_device(&self, device_id: &DeviceId) -> Result<()> {
+        self.directory()?.revoke_device(device_id).await
     }
 
     /// Send encrypted messages to relay
     pub async fn send_messages(&self, messages: Vec<EncryptedMessage>) -> Result<Vec<MessageId>> {
-        // TODO: Implement actual message sending
-        // if let Some(client) = &self.relay_client {
-        //     let request = EnqueueRequest {
-        //         messages: messages.into_iter().map(|m| m.into()).collect(),
-        //     };
-        //     let response = client.enqueue(request).await?;
-        //     return Ok(response.accepted_ids.into_iter().map(|id| Uuid::parse_str(&id).unwrap()).collect());
-        // }
-        Ok(vec![])
-    }
-
-    /// Receive encrypted messages from relay
+        self.relay()?.enqueue(&messages).await
+    }
+
+    /// Start sending the outbox of `storage` through the relay. Messages
+    /// sent with [`Outbox::send`] survive being offline and are retried
+    /// until the relay accepts them.
+    pub fn start_outbox(&self, storage: Arc<StorageManager>, config: OutboxConfig) -> Result<Outbox> {
+        Ok(Outbox::start(storage, self.relay()?.clone(), config))
+    }
+
+    /// Sync engine taking this device's messages from the relay into
+    /// `storage`
+    pub fn sync_engine(&self, storage: Arc<StorageManager>, device_id: DeviceId) -> Result<SyncEngine> {
+        Ok(SyncEngine::new(storage, self.relay()?.clone(), device_id))
+    }
+
+    /// Receive encrypted messages from relay. At most `max_items` may be
+    /// unacknowledged at a time; until some are acknowledged or time out, the
+    /// relay holds back the rest.
     pub async fn receive_messages(&self, device_id: &DeviceId, max_items: u32) -> Result<Vec<EncryptedMessage>> {
-        // TODO: Implement actual message receiving
-        // if let Some(client) = &self.relay_client {
-        //     let request = DequeueRequest {
-        //         device_id: device_id.to_string(),
-        //         max_items,
-        //         credits: 100, // TODO: Implement credit system
-        //     };
-        //     let response = client.dequeue(request).await?;
-        //     return Ok(response.messages.into_iter().map(|m| m.into()).collect());
-        // }
-        Ok(vec![])
-    }
-
-    /// Acknowledge received messages
-    pub async fn acknowledge_messages(&self, message_ids: Vec<MessageId>) -> Result<()> {
-        // TODO: Implement actual message acknowledgment
-        // if let Some(client) = &self.relay_client {
-        //     let request = AckRequest {
-        //         ids: message_ids.into_iter().map(|id| id.to_string()).collect(),
-        //     };
-        //     client.ack(request).await?;
-        // }
-        Ok(())
+        self.relay()?.dequeue(device_id, max_items, max_items).await
     }
 
-    /// Start legal hold
-    pub async fn start_legal_hold(&self, user_id: &UserId, hold_key: &[u8]) -> Result<()> {
-        // TODO: Implement actual legal hold
-        // if let Some(client) = &self.compliance_client {
-        //     let request = StartHoldRequest {
-        //         user_id: user_id.to_string(),
-        //         hold_key: hold_key.to_vec(),
-        //     };
-        //     client.start_hold(request).await?;
-        // }
-        Ok(())
+    /// Messages pushed by the relay as they arrive, see
+    /// [`RelayClient::subscribe_messages`]
+    pub fn subscribe_messages(&self, device_id: DeviceId, credits: u32) -> Result<impl Stream<Item = Result<EncryptedMessage>>> {
+        Ok(self.relay()?.subscribe_messages(device_id, credits))
+    }
+
+    /// Acknowledge messages received by a device
+    pub async fn acknowledge_messages(&self, device_id: &DeviceId, message_ids: Vec<MessageId>) -> Result<()> {
+        self.relay()?.ack(device_id, &message_ids).await
     }
 
-    /// Export data under legal hold
-    pub async fn export_hold_data(&self, user_id: &UserId, hold_key: &[u8]) -> Result<Vec<u8>> {
-        // TODO: Implement actual data export
-        // if let Some(client) = &self.compliance_client {
-        //     let request = ExportHoldRequest {
-        //         user_id: user_id.to_string(),
-        //         hold_key: hold_key.to_vec(),
-        //     };
-        //     let response = client.export_hold(request).await?;
-        //     return Ok(response.data);
-        // }
-        Ok(vec![])
+    /// Start a legal hold on a user, escrowing their messages to
+    /// `hold_public_key`
+    pub async fn start_legal_hold(&self, user_id: &UserId, hold_public_key: &[u8], reason: &str) -> Result<LegalHold> {
+        self.compliance()?.start_hold(user_id, hold_public_key, reason).await
     }
+
+    /// Stop a legal hold
+    pub async fn stop_legal_hold(&self, hold_id: &HoldId) -> Result<LegalHold> {
+        self.compliance()?.stop_hold(hold_id).await
+    }
+
+    /// Legal holds on a user, active and stopped. Devices poll their own
+    /// user's holds to know what to escrow and to show
+    /// [`compliance::LegalHoldEvent`]s.
+    pub async fn get_legal_holds(&self, user_id: &UserId) -> Result<Vec<LegalHold>> {
+        self.compliance()?.list_holds(user_id).await
+    }
+
+    /// Upload copies made by `CryptoManager::escrow_message`
+    pub async fn escrow_messages(&self, messages: &[EscrowedMessage]) -> Result<Vec<MessageId>> {
+        self.compliance()?.escrow(messages).await
+    }
+
+    /// Export data under legal hold as a signed archive
+    pub async fn export_hold_data(&self, hold_id: &HoldId) -> Result<compliance::HoldExport> {
+        self.compliance()?.export_hold(hold_id).await
+    }
+
+    fn transport(&mut self) -> Result<&Transport> {
+        let transport = match self.transport.take() {
+            Some(transport) => transport,
+            None => Transport::new(TransportConfig::default())?,
+        };
+        Ok(self.transport.insert(transport))
+    }
+
+    fn compliance(&self) -> Result<&ComplianceClient> {
+        self.compliance_client.as_ref()
+            .ok_or_else(|| VeterError::Network("Not connected to the compliance service".to_string()))
+    }
+
+    fn directory(&self) -> Result<&DirectoryClient> {
+        self.directory_client.as_ref()
+            .ok_or_else(|| VeterError::Network("Not connected to the directory".to_string()))
+    }
+
+    fn relay(&self) -> Result<&RelayClient> {
+        self.relay_client.as_ref()
+            .ok_or_else(|| VeterError::Network("Not connected to the relay".to_string()))
+    }
+}
+
+/// IDs travel as raw 16-byte UUIDs
+fn uuid_from_bytes(bytes: &[u8], kind: &str) -> Result<uuid::Uuid> {
+    uuid::Uuid::from_slice(bytes)
+        .map_err(|e| VeterError::InvalidInput(format!("Invalid {} ID: {}", kind, e)))
+}
+
+/// Timestamps travel as milliseconds since the Unix epoch
+fn timestamp_from_millis(millis: i64) -> Result<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::from_timestamp_millis(millis)
+        .ok_or_else(|| VeterError::InvalidInput(format!("Invalid timestamp {}", millis)))
 }
@@ -0,0 +1,315 @@
+//! Compliance service client for legal holds
+//!
+//! Legal holds keep E2EE intact for everyone who is not under hold: the
+//! service never sees plaintext. While a hold is active, the held user's
+//! devices escrow a copy of each message encrypted to the hold key (see
+//! [`crate::crypto::escrow`]), whose private key stays with the legal
+//! department. Exports are archives of those copies signed by the service,
+//! and the held user is told about every hold through
+//! [`LegalHoldEvent`]s.
+
+use crate::{VeterError, Result, models::*};
+use crate::crypto::x3dh::verify_signature;
+use super::{timestamp_from_millis, uuid_from_bytes};
+use chrono::{DateTime, Utc};
+use prost::Message as _;
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+/// Generated from `proto/compliance.proto`
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("veter.compliance.v1");
+}
+
+use proto::compliance_client::ComplianceClient as GrpcComplianceClient;
+use proto::{EscrowRequest, ExportHoldRequest, ListHoldsRequest, StartHoldRequest, StopHoldRequest};
+
+/// Compliance service client
+#[derive(Clone)]
+pub struct ComplianceClient {
+    client: GrpcComplianceClient<Channel>,
+}
+
+impl ComplianceClient {
+    /// Wrap a connected channel
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: GrpcComplianceClient::new(channel),
+        }
+    }
+
+    /// Put a user under legal hold. `hold_public_key` is the public half of
+    /// a key pair from `CryptoManager::generate_hold_keypair`.
+    pub async fn start_hold(&self, user_id: &UserId, hold_public_key: &[u8], reason: &str) -> Result<LegalHold> {
+        if hold_public_key.len() != 32 {
+            return Err(VeterError::KeyManagement("Hold public key must be 32 bytes".to_string()));
+        }
+
+        let request = StartHoldRequest {
+            user_id: user_id.as_bytes().to_vec(),
+            hold_public_key: hold_public_key.to_vec(),
+            reason: reason.to_string(),
+        };
+        let response = self.client.clone()
+            .start_hold(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Start hold failed: {}", e.message())))?;
+
+        response.into_inner()
+            .hold
+            .ok_or_else(|| VeterError::InvalidInput("Response without a hold".to_string()))
+            .and_then(LegalHold::try_from)
+    }
+
+    /// Stop a legal hold. Copies escrowed so far stay available for export.
+    pub async fn stop_hold(&self, hold_id: &HoldId) -> Result<LegalHold> {
+        let request = StopHoldRequest {
+            hold_id: hold_id.as_bytes().to_vec(),
+        };
+        let response = self.client.clone()
+            .stop_hold(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Stop hold failed: {}", e.message())))?;
+
+        response.into_inner()
+            .hold
+            .ok_or_else(|| VeterError::InvalidInput("Response without a hold".to_string()))
+            .and_then(LegalHold::try_from)
+    }
+
+    /// All holds on a user, active and stopped
+    pub async fn list_holds(&self, user_id: &UserId) -> Result<Vec<LegalHold>> {
+        let request = ListHoldsRequest {
+            user_id: user_id.as_bytes().to_vec(),
+        };
+        let response = self.client.clone()
+            .list_holds(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("List holds failed: {}", e.message())))?;
+
+        response.into_inner()
+            .holds
+            .into_iter()
+            .map(LegalHold::try_from)
+            .collect()
+    }
+
+    /// Upload escrowed copies, returning the IDs of the messages accepted
+    pub async fn escrow(&self, messages: &[EscrowedMessage]) -> Result<Vec<MessageId>> {
+        let request = EscrowRequest {
+            messages: messages.iter().map(proto::EscrowedMessage::from).collect(),
+        };
+        let response = self.client.clone()
+            .escrow(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Escrow failed: {}", e.message())))?;
+
+        response.into_inner()
+            .accepted_ids
+            .iter()
+            .map(|id| uuid_from_bytes(id, "message"))
+            .collect()
+    }
+
+    /// Export everything escrowed under a hold. Check the result with
+    /// [`HoldExport::verify`] before relying on it.
+    pub async fn export_hold(&self, hold_id: &HoldId) -> Result<HoldExport> {
+        let request = ExportHoldRequest {
+            hold_id: hold_id.as_bytes().to_vec(),
+        };
+        let response = self.client.clone()
+            .export_hold(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Export hold failed: {}", e.message())))?
+            .into_inner();
+
+        Ok(HoldExport {
+            archive: response.archive,
+            signature: response.signature,
+        })
+    }
+}
+
+/// Signed archive of the copies escrowed under a hold, as exported. Kept as
+/// the exact bytes the service signed, so it can be verified again later.
+#[derive(Debug, Clone)]
+pub struct HoldExport {
+    pub archive: Vec<u8>,
+    pub signature: Vec<u8>, // Ed25519 by the service's export key
+}
+
+/// Contents of a verified [`HoldExport`]
+#[derive(Debug, Clone)]
+pub struct HoldArchive {
+    pub hold: LegalHold,
+    pub messages: Vec<EscrowedMessage>,
+    pub exported_at: DateTime<Utc>,
+}
+
+impl HoldExport {
+    /// Check the service's signature with its public export key and decode
+    /// the archive.
+    ///
+    /// This proves the archive is complete as the service exported it. Each
+    /// copy additionally carries the signature of the device that made it,
+    /// checked by `crypto::escrow::open`.
+    pub fn verify(&self, service_key: &[u8]) -> Result<HoldArchive> {
+        verify_signature(service_key, &self.archive, &self.signature)
+            .map_err(|e| VeterError::Authentication(format!("Hold export is not authentic: {}", e)))?;
+
+        let archive = proto::HoldArchive::decode(self.archive.as_slice())
+            .map_err(|e| VeterError::Serialization(format!("Malformed hold archive: {}", e)))?;
+        let hold = archive.hold
+            .ok_or_else(|| VeterError::InvalidInput("Hold archive without a hold".to_string()))
+            .and_then(LegalHold::try_from)?;
+        let messages = archive.messages
+            .into_iter()
+            .map(EscrowedMessage::try_from)
+            .collect::<Result<Vec<_>>>()?;
+        if messages.iter().any(|message| message.hold_id != hold.id) {
+            return Err(VeterError::InvalidInput("Hold archive contains copies of another hold".to_string()));
+        }
+
+        Ok(HoldArchive {
+            hold,
+            messages,
+            exported_at: timestamp_from_millis(archive.exported_ts)?,
+        })
+    }
+}
+
+/// Start or stop of a legal hold, to show the held user
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LegalHoldEvent {
+    Started(LegalHold),
+    Stopped(LegalHold),
+}
+
+impl LegalHoldEvent {
+    /// Events between two listings of a user's holds, e.g. the holds known
+    /// before and after polling [`ComplianceClient::list_holds`]. A hold
+    /// that started and stopped in between yields both events.
+    pub fn between(previous: &[LegalHold], current: &[LegalHold]) -> Vec<Self> {
+        let mut events = Vec::new();
+        for hold in current {
+            let known = previous.iter().find(|known| known.id == hold.id);
+            if known.is_none() {
+                events.push(Self::Started(hold.clone()));
+            }
+            if !hold.is_active() && known.is_none_or(LegalHold::is_active) {
+                events.push(Self::Stopped(hold.clone()));
+            }
+        }
+        events
+    }
+
+    /// The hold the event is about
+    pub fn hold(&self) -> &LegalHold {
+        match self {
+            Self::Started(hold) | Self::Stopped(hold) => hold,
+        }
+    }
+
+    /// Text of the system message
+    pub fn content(&self) -> MessageContent {
+        match self {
+            Self::Started(hold) => MessageContent::System(format!(
+                "A legal hold was placed on your account ({}). Copies of your messages are kept for compliance review.",
+                hold.reason
+            )),
+            Self::Stopped(_) => MessageContent::System(
+                "The legal hold on your account was lifted. New messages are no longer copied for compliance review.".to_string()
+            ),
+        }
+    }
+
+    /// System message announcing the event in a room. It is only shown
+    /// locally, never sent. Its ID depends on the hold, event and room only,
+    /// so storing it again does not duplicate it.
+    pub fn message(&self, room_id: RoomId) -> Message {
+        let hold = self.hold();
+        let (kind, created_at) = match self {
+            Self::Started(hold) => ("started", hold.started_at),
+            Self::Stopped(hold) => ("stopped", hold.stopped_at.unwrap_or(hold.started_at)),
+        };
+        let mut name = kind.as_bytes().to_vec();
+        name.extend_from_slice(room_id.as_bytes());
+
+        Message {
+            id: Uuid::new_v5(&hold.id, &name),
+            room_id,
+            sender_id: hold.user_id,
+            sender_device_id: Uuid::nil(), // not from any device
+            content: self.content(),
+            created_at,
+            edited_at: None,
+            reply_to: None,
+        }
+    }
+}
+
+impl TryFrom<proto::LegalHold> for LegalHold {
+    type Error = VeterError;
+
+    fn try_from(hold: proto::LegalHold) -> Result<Self> {
+        Ok(Self {
+            id: uuid_from_bytes(&hold.id, "hold")?,
+            user_id: uuid_from_bytes(&hold.user_id, "user")?,
+            hold_public_key: hold.hold_public_key,
+            reason: hold.reason,
+            started_at: timestamp_from_millis(hold.started_ts)?,
+            stopped_at: match hold.stopped_ts {
+                0 => None,
+                stopped_ts => Some(timestamp_from_millis(stopped_ts)?),
+            },
+        })
+    }
+}
+
+impl From<&LegalHold> for proto::LegalHold {
+    fn from(hold: &LegalHold) -> Self {
+        Self {
+            id: hold.id.as_bytes().to_vec(),
+            user_id: hold.user_id.as_bytes().to_vec(),
+            hold_public_key: hold.hold_public_key.clone(),
+            reason: hold.reason.clone(),
+            started_ts: hold.started_at.timestamp_millis(),
+            stopped_ts: hold.stopped_at.map_or(0, |stopped_at| stopped_at.timestamp_millis()),
+        }
+    }
+}
+
+impl From<&EscrowedMessage> for proto::EscrowedMessage {
+    fn from(message: &EscrowedMessage) -> Self {
+        Self {
+            hold_id: message.hold_id.as_bytes().to_vec(),
+            message_id: message.message_id.as_bytes().to_vec(),
+            room_id: message.room_id.as_bytes().to_vec(),
+            sender_device_id: message.sender_device_id.as_bytes().to_vec(),
+            escrow_device_id: message.escrow_device_id.as_bytes().to_vec(),
+            sent_ts: message.sent_at.timestamp_millis(),
+            ephemeral_key: message.ephemeral_key.clone(),
+            ciphertext: message.ciphertext.clone(),
+            signature: message.signature.clone(),
+        }
+    }
+}
+
+impl TryFrom<proto::EscrowedMessage> for EscrowedMessage {
+    type Error = VeterError;
+
+    fn try_from(message: proto::EscrowedMessage) -> Result<Self> {
+        Ok(Self {
+            hold_id: uuid_from_bytes(&message.hold_id, "hold")?,
+            message_id: uuid_from_bytes(&message.message_id, "message")?,
+            room_id: uuid_from_bytes(&message.room_id, "room")?,
+            sender_device_id: uuid_from_bytes(&message.sender_device_id, "device")?,
+            escrow_device_id: uuid_from_bytes(&message.escrow_device_id, "device")?,
+            sent_at: timestamp_from_millis(message.sent_ts)?,
+            ephemeral_key: message.ephemeral_key,
+            ciphertext: message.ciphertext,
+            signature: message.signature,
+        })
+    }
+}
@@ -0,0 +1,202 @@
+//! Directory service client
+//!
+//! The directory lists the devices of each user and hands out their prekey
+//! bundles, so that other devices can start sessions with them. It only
+//! holds public keys. On the wire, IDs are raw 16-byte UUIDs and timestamps
+//! are in milliseconds since the Unix epoch.
+
+use crate::{VeterError, Result, models::*};
+use super::{timestamp_from_millis, uuid_from_bytes};
+use tonic::transport::Channel;
+
+/// Generated from `proto/directory.proto`
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("veter.directory.v1");
+}
+
+use proto::directory_client::DirectoryClient as GrpcDirectoryClient;
+use proto::{
+    FetchPrekeyBundleRequest, ListUserDevicesRequest, PublishPrekeysRequest, RegisterDeviceRequest,
+    RevokeDeviceRequest,
+};
+
+/// Directory service client
+#[derive(Clone)]
+pub struct DirectoryClient {
+    client: GrpcDirectoryClient<Channel>,
+}
+
+impl DirectoryClient {
+    /// Wrap a connected channel
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: GrpcDirectoryClient::new(channel),
+        }
+    }
+
+    /// Register a device, or update its name and platform
+    pub async fn register_device(&self, device: &Device) -> Result<()> {
+        let request = RegisterDeviceRequest {
+            device: Some(proto::Device::from(device)),
+        };
+        self.client.clone()
+            .register_device(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Register device failed: {}", e.message())))?;
+
+        Ok(())
+    }
+
+    /// Devices of a user that have not been revoked
+    pub async fn list_user_devices(&self, user_id: &UserId) -> Result<Vec<Device>> {
+        let request = ListUserDevicesRequest {
+            user_id: user_id.as_bytes().to_vec(),
+        };
+        let response = self.client.clone()
+            .list_user_devices(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("List user devices failed: {}", e.message())))?;
+
+        response.into_inner()
+            .devices
+            .into_iter()
+            .map(Device::try_from)
+            .collect()
+    }
+
+    /// Publish a device's key material, as returned by
+    /// `CryptoManager::generate_key_material`. Returns how many one-time
+    /// prekeys the directory now has for the device.
+    pub async fn publish_prekeys(&self, device_id: &DeviceId, key_material: &KeyMaterial) -> Result<u32> {
+        let request = PublishPrekeysRequest {
+            device_id: device_id.as_bytes().to_vec(),
+            key_material: Some(proto::KeyMaterial::from(key_material)),
+        };
+        let response = self.client.clone()
+            .publish_prekeys(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Publish prekeys failed: {}", e.message())))?;
+
+        Ok(response.into_inner().one_time_prekeys_remaining)
+    }
+
+    /// Fetch a device's prekey bundle for starting a session with it. The
+    /// directory hands out each one-time prekey and MLS KeyPackage once; the
+    /// bundle has none when the device ran out.
+    pub async fn fetch_prekey_bundle(&self, device_id: &DeviceId) -> Result<KeyMaterial> {
+        let request = FetchPrekeyBundleRequest {
+            device_id: device_id.as_bytes().to_vec(),
+        };
+        let response = self.client.clone()
+            .fetch_prekey_bundle(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Fetch prekey bundle failed: {}", e.message())))?;
+
+        response.into_inner()
+            .bundle
+            .map(KeyMaterial::from)
+            .ok_or_else(|| VeterError::InvalidInput("Response without a prekey bundle".to_string()))
+    }
+
+    /// Revoke a device, e.g. when it was lost
+    pub async fn revoke_device(&self, device_id: &DeviceId) -> Result<()> {
+        let request = RevokeDeviceRequest {
+            device_id: device_id.as_bytes().to_vec(),
+        };
+        self.client.clone()
+            .revoke_device(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Revoke device failed: {}", e.message())))?;
+
+        Ok(())
+    }
+}
+
+impl From<&Device> for proto::Device {
+    fn from(device: &Device) -> Self {
+        Self {
+            id: device.id.as_bytes().to_vec(),
+            user_id: device.user_id.as_bytes().to_vec(),
+            name: device.name.clone(),
+            platform: proto::Platform::from(&device.platform).into(),
+            identity_key: device.public_key.clone(),
+            created_ts: device.created_at.timestamp_millis(),
+            last_seen_ts: device.last_seen.timestamp_millis(),
+        }
+    }
+}
+
+impl TryFrom<proto::Device> for Device {
+    type Error = VeterError;
+
+    /// Verification is local state, so listed devices start out unverified
+    fn try_from(device: proto::Device) -> Result<Self> {
+        let platform = proto::Platform::try_from(device.platform)
+            .map_err(|_| VeterError::InvalidInput(format!("Unknown platform {}", device.platform)))?;
+
+        Ok(Self {
+            id: uuid_from_bytes(&device.id, "device")?,
+            user_id: uuid_from_bytes(&device.user_id, "user")?,
+            name: device.name,
+            platform: Platform::try_from(platform)?,
+            public_key: device.identity_key,
+            created_at: timestamp_from_millis(device.created_ts)?,
+            last_seen: timestamp_from_millis(device.last_seen_ts)?,
+            verification: VerificationState::default(),
+        })
+    }
+}
+
+impl From<&Platform> for proto::Platform {
+    fn from(platform: &Platform) -> Self {
+        match platform {
+            Platform::Ios => Self::Ios,
+            Platform::Android => Self::Android,
+            Platform::Macos => Self::Macos,
+            Platform::Windows => Self::Windows,
+            Platform::Linux => Self::Linux,
+            Platform::Web => Self::Web,
+        }
+    }
+}
+
+impl TryFrom<proto::Platform> for Platform {
+    type Error = VeterError;
+
+    fn try_from(platform: proto::Platform) -> Result<Self> {
+        match platform {
+            proto::Platform::Ios => Ok(Self::Ios),
+            proto::Platform::Android => Ok(Self::Android),
+            proto::Platform::Macos => Ok(Self::Macos),
+            proto::Platform::Windows => Ok(Self::Windows),
+            proto::Platform::Linux => Ok(Self::Linux),
+            proto::Platform::Web => Ok(Self::Web),
+            proto::Platform::Unspecified => Err(VeterError::InvalidInput("Device without a platform".to_string())),
+        }
+    }
+}
+
+impl From<&KeyMaterial> for proto::KeyMaterial {
+    fn from(material: &KeyMaterial) -> Self {
+        Self {
+            identity_key: material.identity_key.clone(),
+            signed_prekey: material.signed_prekey.clone(),
+            signed_prekey_signature: material.signed_prekey_signature.clone(),
+            one_time_prekeys: material.one_time_prekeys.clone(),
+            mls_key_packages: material.mls_key_packages.clone(),
+        }
+    }
+}
+
+impl From<proto::KeyMaterial> for KeyMaterial {
+    fn from(material: proto::KeyMaterial) -> Self {
+        Self {
+            identity_key: material.identity_key,
+            signed_prekey: material.signed_prekey,
+            signed_prekey_signature: material.signed_prekey_signature,
+            one_time_prekeys: material.one_time_prekeys,
+            mls_key_packages: material.mls_key_packages,
+        }
+    }
+}
@@ -0,0 +1,243 @@
+//! Outbox sender
+//!
+//! Messages are written to the outbox in the database before they are sent,
+//! so nothing the user sends while offline is lost, not even across
+//! restarts. A background task hands pending messages to the relay in
+//! batches. Messages the relay does not accept are retried with exponential
+//! backoff until they run out of attempts and are marked failed. The relay
+//! ignores message IDs it already queued, so a message sent again after a
+//! lost response is not delivered twice.
+
+use crate::{Result, models::*};
+use crate::storage::StorageManager;
+use super::RelayClient;
+use chrono::Utc;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, broadcast, watch};
+use tokio::task::JoinHandle;
+
+/// Delivery state updates kept for subscribers that fall behind
+const UPDATE_CAPACITY: usize = 256;
+
+/// Outbox retry settings
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// Wait after the first failed attempt, doubled after each further one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Attempts before a message is marked failed
+    pub max_attempts: u32,
+    /// Messages handed to the relay per request
+    pub batch_size: u32,
+    /// Longest idle wait, after which the outbox is checked regardless
+    pub poll_interval: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(5 * 60),
+            max_attempts: 12,
+            batch_size: 50,
+            poll_interval: Duration::from_secs(60),
+        }
+    }
+}
+
+impl OutboxConfig {
+    /// Wait before the next attempt after `attempts` failed ones, or `None`
+    /// once the message has run out of attempts
+    pub fn backoff(&self, attempts: u32) -> Option<Duration> {
+        if attempts >= self.max_attempts {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        Some(self.initial_backoff.saturating_mul(factor).min(self.max_backoff))
+    }
+}
+
+/// Change of a message's delivery state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeliveryUpdate {
+    pub message_id: MessageId,
+    pub state: DeliveryState,
+}
+
+/// Handle to the outbox and its sender task. The task stops when the handle
+/// is dropped, or after its current batch with [`Outbox::stop`]; pending
+/// messages stay in the database.
+pub struct Outbox {
+    storage: Arc<StorageManager>,
+    wake: Arc<Notify>,
+    updates: broadcast::Sender<DeliveryUpdate>,
+    stop: watch::Sender<bool>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Outbox {
+    /// Start sending the outbox of `storage` through `relay`, beginning with
+    /// whatever was left pending earlier
+    pub fn start(storage: Arc<StorageManager>, relay: RelayClient, config: OutboxConfig) -> Self {
+        let wake = Arc::new(Notify::new());
+        let (updates, _) = broadcast::channel(UPDATE_CAPACITY);
+        let (stop, stopped) = watch::channel(false);
+        let sender = Sender {
+            storage: storage.clone(),
+            relay,
+            config,
+            wake: wake.clone(),
+            updates: updates.clone(),
+        };
+
+        Self {
+            storage,
+            wake,
+            updates,
+            stop,
+            task: Some(tokio::spawn(sender.run(stopped))),
+        }
+    }
+
+    /// Queue a message and send it as soon as possible
+    pub async fn send(&self, message: &EncryptedMessage) -> Result<()> {
+        self.storage.queue_outgoing(message).await?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Delivery state of a message sent through the outbox
+    pub async fn delivery_state(&self, message_id: &MessageId) -> Result<Option<DeliveryState>> {
+        Ok(self.storage.get_outgoing(message_id).await?.map(|outgoing| outgoing.state))
+    }
+
+    /// Record that a recipient device received a message
+    pub async fn mark_delivered(&self, message_id: &MessageId) -> Result<()> {
+        if self.storage.mark_delivered(message_id).await? {
+            self.publish(*message_id, DeliveryState::Delivered);
+        }
+        Ok(())
+    }
+
+    /// Record the receipt of a user in a room for the messages it covers
+    pub async fn apply_receipt(&self, room_id: &RoomId, message_id: &MessageId) -> Result<()> {
+        for message_id in self.storage.mark_delivered_up_to(room_id, message_id).await? {
+            self.publish(message_id, DeliveryState::Delivered);
+        }
+        Ok(())
+    }
+
+    /// Send a failed message again, e.g. when the user taps it
+    pub async fn retry(&self, message_id: &MessageId) -> Result<()> {
+        self.storage.retry_outgoing(message_id).await?;
+        self.publish(*message_id, DeliveryState::Pending);
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Delivery state changes from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<DeliveryUpdate> {
+        self.updates.subscribe()
+    }
+
+    /// Stop the sender task once it has recorded the outcome of the batch
+    /// it is sending, if any
+    pub async fn stop(mut self) {
+        let _ = self.stop.send(true);
+        if let Some(task) = self.task.take() {
+            let _ = task.await;
+        }
+    }
+
+    fn publish(&self, message_id: MessageId, state: DeliveryState) {
+        // Nobody listening is fine
+        let _ = self.updates.send(DeliveryUpdate { message_id, state });
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        if let Some(task) = &self.task {
+            task.abort();
+        }
+    }
+}
+
+/// State of the sender task
+struct Sender {
+    storage: Arc<StorageManager>,
+    relay: RelayClient,
+    config: OutboxConfig,
+    wake: Arc<Notify>,
+    updates: broadcast::Sender<DeliveryUpdate>,
+}
+
+impl Sender {
+    async fn run(self, mut stopped: watch::Receiver<bool>) {
+        loop {
+            if *stopped.borrow() {
+                return;
+            }
+            let sent_batch = match self.send_due().await {
+                Ok(count) => count > 0,
+                Err(_) => false, // database trouble; look again after the idle wait
+            };
+            if sent_batch {
+                continue;
+            }
+
+            let idle = match self.storage.next_outgoing_attempt().await {
+                Ok(Some(next)) => (next - Utc::now())
+                    .to_std()
+                    .unwrap_or(Duration::ZERO)
+                    .min(self.config.poll_interval),
+                _ => self.config.poll_interval,
+            };
+            tokio::select! {
+                _ = tokio::time::sleep(idle) => {}
+                _ = self.wake.notified() => {}
+                _ = stopped.changed() => {}
+            }
+        }
+    }
+
+    /// Hand one batch of due messages to the relay, returning its size
+    async fn send_due(&self) -> Result<usize> {
+        let due = self.storage.get_due_outgoing(Utc::now(), self.config.batch_size).await?;
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        let messages: Vec<_> = due.iter().map(|outgoing| outgoing.message.clone()).collect();
+        let (accepted, error) = match self.relay.enqueue(&messages).await {
+            Ok(accepted) => (accepted.into_iter().collect(), "Not accepted by the relay".to_string()),
+            Err(e) => (HashSet::new(), e.to_string()),
+        };
+
+        let sent: Vec<_> = messages.iter()
+            .map(|message| message.id)
+            .filter(|id| accepted.contains(id))
+            .collect();
+        self.storage.mark_sent(&sent).await?;
+        for message_id in sent {
+            self.publish(message_id, DeliveryState::Sent);
+        }
+
+        for outgoing in due.iter().filter(|outgoing| !accepted.contains(&outgoing.message.id)) {
+            let next_attempt_at = self.config.backoff(outgoing.attempts + 1)
+                .map(|backoff| Utc::now() + backoff);
+            self.storage.record_send_failure(&outgoing.message.id, &error, next_attempt_at).await?;
+            if next_attempt_at.is_none() {
+                self.publish(outgoing.message.id, DeliveryState::Failed);
+            }
+        }
+
+        Ok(due.len())
+    }
+
+    fn publish(&self, message_id: MessageId, state: DeliveryState) {
+        let _ = self.updates.send(DeliveryUpdate { message_id, state });
+    }
+}
@@ -0,0 +1,204 @@
+//! Relay service client
+//!
+//! The relay stores encrypted messages until the recipient device fetches and
+//! acknowledges them. It only ever sees `Ciphertext`: IDs, routing
+//! information and the E2EE payload. On the wire, IDs are raw 16-byte UUIDs
+//! and `sent_ts` is in milliseconds since the Unix epoch.
+
+use crate::{VeterError, Result, models::*};
+use super::{timestamp_from_millis, uuid_from_bytes};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::Streaming;
+use tonic::transport::Channel;
+
+/// Generated from `proto/relay.proto`
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("veter.relay.v1");
+}
+
+use proto::relay_client::RelayClient as GrpcRelayClient;
+use proto::{AckRequest, Ciphertext, DequeueRequest, Delivery, EnqueueRequest, SubscribeRequest};
+
+/// Wait before the first attempt to resubscribe after a failure
+const MIN_RESUBSCRIBE_DELAY: Duration = Duration::from_millis(500);
+/// Longest wait between attempts to resubscribe
+const MAX_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(30);
+
+/// Relay service client
+#[derive(Clone)]
+pub struct RelayClient {
+    client: GrpcRelayClient<Channel>,
+}
+
+impl RelayClient {
+    /// Wrap a connected channel
+    pub fn new(channel: Channel) -> Self {
+        Self {
+            client: GrpcRelayClient::new(channel),
+        }
+    }
+
+    /// Hand messages to the relay, returning the IDs it accepted
+    pub async fn enqueue(&self, messages: &[EncryptedMessage]) -> Result<Vec<MessageId>> {
+        let request = EnqueueRequest {
+            messages: messages.iter().map(Ciphertext::from).collect(),
+        };
+        let response = self.client.clone()
+            .enqueue(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Enqueue failed: {}", e.message())))?;
+
+        response.into_inner()
+            .accepted_ids
+            .iter()
+            .map(|id| uuid_from_bytes(id, "message"))
+            .collect()
+    }
+
+    /// Fetch up to `max_items` messages queued for a device, and no more than
+    /// `credits` unacknowledged ones. They stay on the relay and are
+    /// redelivered until acknowledged.
+    pub async fn dequeue(&self, device_id: &DeviceId, max_items: u32, credits: u32) -> Result<Vec<EncryptedMessage>> {
+        let request = DequeueRequest {
+            device_id: device_id.as_bytes().to_vec(),
+            max_items,
+            credits,
+        };
+        let response = self.client.clone()
+            .dequeue(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Dequeue failed: {}", e.message())))?;
+
+        response.into_inner()
+            .messages
+            .into_iter()
+            .map(EncryptedMessage::try_from)
+            .collect()
+    }
+
+    /// Remove messages delivered to a device from its queue
+    pub async fn ack(&self, device_id: &DeviceId, message_ids: &[MessageId]) -> Result<()> {
+        let request = AckRequest {
+            ids: message_ids.iter().map(|id| id.as_bytes().to_vec()).collect(),
+            device_id: device_id.as_bytes().to_vec(),
+        };
+        self.client.clone()
+            .ack(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Ack failed: {}", e.message())))?;
+
+        Ok(())
+    }
+
+    /// Open a push subscription for a device, resuming after `cursor` if it
+    /// is not empty
+    pub async fn subscribe(&self, device_id: &DeviceId, credits: u32, cursor: &[u8]) -> Result<Streaming<Delivery>> {
+        let request = SubscribeRequest {
+            device_id: device_id.as_bytes().to_vec(),
+            credits,
+            cursor: cursor.to_vec(),
+        };
+        let response = self.client.clone()
+            .subscribe(request)
+            .await
+            .map_err(|e| VeterError::Network(format!("Subscribe failed: {}", e.message())))?;
+
+        Ok(response.into_inner())
+    }
+
+    /// Messages pushed to a device as they arrive.
+    ///
+    /// When the subscription breaks, the error is yielded and the stream
+    /// resubscribes with backoff, resuming after the last delivered message.
+    /// The relay pushes no more than `credits` unacknowledged messages, so
+    /// consumers have to acknowledge what they handled. The subscription
+    /// ends when the stream is dropped.
+    pub fn subscribe_messages(&self, device_id: DeviceId, credits: u32) -> impl Stream<Item = Result<EncryptedMessage>> {
+        let (sender, receiver) = mpsc::channel(1);
+        let client = self.clone();
+
+        tokio::spawn(async move {
+            let mut cursor = Vec::new();
+            let mut delay = MIN_RESUBSCRIBE_DELAY;
+            loop {
+                match client.subscribe(&device_id, credits, &cursor).await {
+                    Ok(mut deliveries) => loop {
+                        let delivery = tokio::select! {
+                            delivery = deliveries.message() => delivery,
+                            _ = sender.closed() => return,
+                        };
+                        let (item, broken) = match delivery {
+                            Ok(Some(delivery)) => {
+                                delay = MIN_RESUBSCRIBE_DELAY;
+                                cursor = delivery.cursor;
+                                let message = delivery.message
+                                    .ok_or_else(|| VeterError::InvalidInput("Delivery without a message".to_string()))
+                                    .and_then(EncryptedMessage::try_from);
+                                (message, false)
+                            }
+                            // The relay closed the subscription
+                            Ok(None) => break,
+                            Err(e) => (Err(VeterError::Network(format!("Subscription failed: {}", e.message()))), true),
+                        };
+
+                        if sender.send(item).await.is_err() {
+                            return;
+                        }
+                        if broken {
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        if sender.send(Err(e)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = sender.closed() => return,
+                }
+                delay = (delay * 2).min(MAX_RESUBSCRIBE_DELAY);
+            }
+        });
+
+        ReceiverStream::new(receiver)
+    }
+}
+
+impl From<&EncryptedMessage> for Ciphertext {
+    fn from(message: &EncryptedMessage) -> Self {
+        Self {
+            id: message.id.as_bytes().to_vec(),
+            sender_device_id: message.sender_device_id.as_bytes().to_vec(),
+            room_id: message.room_id.as_bytes().to_vec(),
+            payload: message.payload.clone(),
+            sent_ts: message.timestamp.timestamp_millis(),
+            recipient_device_ids: message.recipient_device_ids.iter()
+                .map(|id| id.as_bytes().to_vec())
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<Ciphertext> for EncryptedMessage {
+    type Error = VeterError;
+
+    fn try_from(ciphertext: Ciphertext) -> Result<Self> {
+        Ok(Self {
+            id: uuid_from_bytes(&ciphertext.id, "message")?,
+            room_id: uuid_from_bytes(&ciphertext.room_id, "room")?,
+            sender_device_id: uuid_from_bytes(&ciphertext.sender_device_id, "device")?,
+            payload: ciphertext.payload,
+            timestamp: timestamp_from_millis(ciphertext.sent_ts)?,
+            recipient_device_ids: ciphertext.recipient_device_ids.iter()
+                .map(|id| uuid_from_bytes(id, "device"))
+                .collect::<Result<_>>()?,
+        })
+    }
+}
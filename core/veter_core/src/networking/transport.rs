@@ -0,0 +1,419 @@
+//! Transport for the gRPC services
+//!
+//! Calls go over QUIC when possible: the HTTP/2 connection that tonic opens
+//! to a service is carried on a bidirectional QUIC stream. QUIC resumes
+//! sessions with 0-RTT and keeps the connection when the device changes
+//! networks. When the QUIC handshake does not complete in time, e.g. because a
+//! firewall drops UDP, the transport falls back to HTTP/2 over TLS on TCP.
+//!
+//! Early data can be replayed by anyone who captured it, so servers only act
+//! on a stream once the handshake has completed.
+//!
+//! `http` endpoints are plaintext HTTP/2, for local development and tests.
+
+use crate::{VeterError, Result};
+use quinn::rustls;
+use quinn::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Uri};
+use tower_service::Service;
+
+/// ALPN protocol of gRPC over QUIC streams
+pub const ALPN: &[u8] = b"veter-h2";
+
+/// Keeps idle QUIC connections open and notices dead paths
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Transport settings
+#[derive(Debug, Clone)]
+pub struct TransportConfig {
+    /// Try QUIC before HTTP/2 over TLS
+    pub quic: bool,
+    /// How long the QUIC handshake may take before falling back to TLS
+    pub quic_timeout: Duration,
+    /// Timeout of each call
+    pub request_timeout: Duration,
+    /// PEM root certificate to trust in addition to the public roots, e.g.
+    /// a corporate CA
+    pub root_certificate: Option<Vec<u8>>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            quic: true,
+            quic_timeout: Duration::from_secs(3),
+            request_timeout: Duration::from_secs(30),
+            root_certificate: None,
+        }
+    }
+}
+
+/// How a channel reaches its service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    /// QUIC; `zero_rtt` if the connection was resumed with early data
+    Quic { zero_rtt: bool },
+    /// HTTP/2 over TLS on TCP; `fallback` if QUIC was tried and failed
+    Tls { fallback: bool },
+    /// Plaintext HTTP/2, for `http` endpoints
+    Plaintext,
+}
+
+/// Opens gRPC channels. Clones share TLS sessions for 0-RTT resumption.
+#[derive(Clone)]
+pub struct Transport {
+    config: TransportConfig,
+    quic_config: Option<quinn::ClientConfig>,
+    connections: Arc<Mutex<Vec<Weak<QuicConnector>>>>,
+}
+
+impl Transport {
+    /// Create a transport
+    pub fn new(config: TransportConfig) -> Result<Self> {
+        let quic_config = if config.quic {
+            Some(quic_client_config(config.root_certificate.as_deref())?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            config,
+            quic_config,
+            connections: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Open a channel to a service endpoint such as `https://relay.example.com:443`
+    pub async fn connect(&self, endpoint: &str) -> Result<(Channel, TransportKind)> {
+        let uri: Uri = endpoint.parse()
+            .map_err(|e| VeterError::Network(format!("Invalid endpoint: {}", e)))?;
+        let host = uri.host()
+            .ok_or_else(|| VeterError::Network(format!("Endpoint {} has no host", endpoint)))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+
+        match uri.scheme_str() {
+            Some("https") => {}
+            Some("http") => {
+                let channel = self.endpoint(endpoint)?
+                    .connect()
+                    .await
+                    .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
+                return Ok((channel, TransportKind::Plaintext));
+            }
+            _ => return Err(VeterError::Network(format!("Unsupported endpoint {}", endpoint))),
+        }
+
+        let mut fallback = false;
+        if let Some(quic_config) = &self.quic_config {
+            let port = uri.port_u16().unwrap_or(443);
+            match self.connect_quic(quic_config.clone(), &host, port).await {
+                Ok(connected) => return Ok(connected),
+                // UDP may be blocked, try TCP
+                Err(_) => fallback = true,
+            }
+        }
+
+        let mut tls = ClientTlsConfig::new().domain_name(host);
+        if let Some(root_certificate) = &self.config.root_certificate {
+            tls = tls.ca_certificate(Certificate::from_pem(root_certificate));
+        }
+        let channel = self.endpoint(endpoint)?
+            .tls_config(tls)
+            .map_err(|e| VeterError::Network(format!("TLS config failed: {}", e)))?
+            .connect()
+            .await
+            .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
+
+        Ok((channel, TransportKind::Tls { fallback }))
+    }
+
+    /// Move QUIC connections to a new local socket after the device changed
+    /// networks. Connections migrate to the new path without a handshake.
+    pub fn network_changed(&self) -> Result<()> {
+        let mut connections = self.connections.lock()
+            .map_err(|_| VeterError::Internal("Transport lock poisoned".to_string()))?;
+        connections.retain(|connector| connector.strong_count() > 0);
+
+        for connector in connections.iter().filter_map(Weak::upgrade) {
+            let socket = std::net::UdpSocket::bind(unspecified_address(connector.address))
+                .map_err(|e| VeterError::Network(format!("Failed to bind socket: {}", e)))?;
+            connector.endpoint.rebind(socket)
+                .map_err(|e| VeterError::Network(format!("Failed to migrate connection: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    fn endpoint(&self, endpoint: &str) -> Result<Endpoint> {
+        Ok(Endpoint::from_shared(endpoint.to_string())
+            .map_err(|e| VeterError::Network(format!("Invalid endpoint: {}", e)))?
+            .timeout(self.config.request_timeout))
+    }
+
+    async fn connect_quic(&self, quic_config: quinn::ClientConfig, host: &str, port: u16) -> Result<(Channel, TransportKind)> {
+        let address = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| VeterError::Network(format!("Failed to resolve {}: {}", host, e)))?
+            .next()
+            .ok_or_else(|| VeterError::Network(format!("No address for {}", host)))?;
+        let mut endpoint = quinn::Endpoint::client(unspecified_address(address))
+            .map_err(|e| VeterError::Network(format!("Failed to bind socket: {}", e)))?;
+        endpoint.set_default_client_config(quic_config);
+
+        let connector = Arc::new(QuicConnector {
+            endpoint,
+            address,
+            server_name: host.to_string(),
+            connection: tokio::sync::Mutex::new(None),
+            zero_rtt: AtomicBool::new(false),
+        });
+
+        // Complete a handshake before committing to QUIC, so that blocked UDP
+        // is noticed here rather than on the first call
+        let connection = tokio::time::timeout(self.config.quic_timeout, connector.connect())
+            .await
+            .map_err(|_| VeterError::Network("QUIC handshake timed out".to_string()))??;
+        *connector.connection.lock().await = Some(connection);
+
+        // The service URI is plaintext; QUIC provides the encryption
+        let uri = format!("http://{}:{}", host, port);
+        let channel = self.endpoint(&uri)?
+            .connect_with_connector(SharedConnector(connector.clone()))
+            .await
+            .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
+
+        self.connections.lock()
+            .map_err(|_| VeterError::Internal("Transport lock poisoned".to_string()))?
+            .push(Arc::downgrade(&connector));
+        let zero_rtt = connector.zero_rtt.load(Ordering::Relaxed);
+        Ok((channel, TransportKind::Quic { zero_rtt }))
+    }
+}
+
+/// QUIC connection to one service, shared by the HTTP/2 connections of its
+/// channel
+struct QuicConnector {
+    endpoint: quinn::Endpoint,
+    address: SocketAddr,
+    server_name: String,
+    connection: tokio::sync::Mutex<Option<quinn::Connection>>,
+    zero_rtt: AtomicBool,
+}
+
+impl QuicConnector {
+    /// Connect with 0-RTT if a session can be resumed, otherwise with a full
+    /// handshake, and wait until the server has confirmed the handshake.
+    /// Streams opened on early data the server rejects would fail, so none
+    /// are opened before it has decided.
+    async fn connect(&self) -> Result<quinn::Connection> {
+        let connecting = self.endpoint.connect(self.address, &self.server_name)
+            .map_err(|e| VeterError::Network(format!("Failed to connect: {}", e)))?;
+
+        match connecting.into_0rtt() {
+            Ok((connection, accepted)) => {
+                self.zero_rtt.store(accepted.await, Ordering::Relaxed);
+                Ok(connection)
+            }
+            Err(connecting) => {
+                self.zero_rtt.store(false, Ordering::Relaxed);
+                connecting.await
+                    .map_err(|e| VeterError::Network(format!("QUIC handshake failed: {}", e)))
+            }
+        }
+    }
+
+    /// A new stream for an HTTP/2 connection, reconnecting if the QUIC
+    /// connection was lost
+    async fn open_stream(&self) -> Result<QuicStream> {
+        let mut current = self.connection.lock().await;
+        let connection = match current.as_ref() {
+            Some(connection) if connection.close_reason().is_none() => connection.clone(),
+            _ => {
+                let connection = self.connect().await?;
+                *current = Some(connection.clone());
+                connection
+            }
+        };
+        drop(current);
+
+        let (send, recv) = connection.open_bi()
+            .await
+            .map_err(|e| VeterError::Network(format!("Failed to open stream: {}", e)))?;
+        Ok(QuicStream {
+            send,
+            recv,
+            remote_address: connection.remote_address(),
+        })
+    }
+}
+
+/// Tower connector handing tonic a QUIC stream per HTTP/2 connection
+#[derive(Clone)]
+struct SharedConnector(Arc<QuicConnector>);
+
+impl Service<Uri> for SharedConnector {
+    type Response = QuicStream;
+    type Error = VeterError;
+    type Future = Pin<Box<dyn Future<Output = Result<QuicStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let connector = self.0.clone();
+        Box::pin(async move { connector.open_stream().await })
+    }
+}
+
+/// A bidirectional QUIC stream used as a byte stream
+pub struct QuicStream {
+    send: quinn::SendStream,
+    recv: quinn::RecvStream,
+    remote_address: SocketAddr,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.send), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl tonic::transport::server::Connected for QuicStream {
+    type ConnectInfo = SocketAddr;
+
+    fn connect_info(&self) -> SocketAddr {
+        self.remote_address
+    }
+}
+
+/// QUIC server settings from a PEM certificate chain and private key
+pub fn quic_server_config(certificate_chain: &[u8], private_key: &[u8]) -> Result<quinn::ServerConfig> {
+    let certificates = rustls_pemfile::certs(&mut &*certificate_chain)
+        .collect::<io::Result<Vec<CertificateDer<'static>>>>()
+        .map_err(|e| VeterError::InvalidInput(format!("Invalid certificate: {}", e)))?;
+    let key: PrivateKeyDer<'static> = rustls_pemfile::private_key(&mut &*private_key)
+        .map_err(|e| VeterError::InvalidInput(format!("Invalid private key: {}", e)))?
+        .ok_or_else(|| VeterError::InvalidInput("No private key found".to_string()))?;
+
+    let mut tls = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| VeterError::Crypto(format!("TLS config failed: {}", e)))?
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .map_err(|e| VeterError::InvalidInput(format!("Invalid certificate: {}", e)))?;
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    // QUIC requires exactly this value to accept 0-RTT
+    tls.max_early_data_size = u32::MAX;
+
+    let crypto = quinn::crypto::rustls::QuicServerConfig::try_from(tls)
+        .map_err(|e| VeterError::Crypto(format!("TLS config failed: {}", e)))?;
+    let mut config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    config.transport_config(Arc::new(transport_config()));
+    Ok(config)
+}
+
+/// Streams opened by clients of a QUIC server endpoint, to serve with
+/// `tonic::transport::Server::serve_with_incoming`
+pub fn quic_incoming(endpoint: quinn::Endpoint) -> impl Stream<Item = io::Result<QuicStream>> {
+    let (sender, receiver) = mpsc::channel(16);
+
+    tokio::spawn(async move {
+        while let Some(incoming) = endpoint.accept().await {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let Ok(connecting) = incoming.accept() else {
+                    return;
+                };
+                // Early data may be a replay, and relay calls are not
+                // idempotent: a dequeue leases messages, an ack deletes them.
+                // Streams are only served once the handshake has completed,
+                // which a replay cannot do.
+                let Ok(connection) = connecting.await else {
+                    return;
+                };
+
+                while let Ok((send, recv)) = connection.accept_bi().await {
+                    let stream = QuicStream {
+                        send,
+                        recv,
+                        remote_address: connection.remote_address(),
+                    };
+                    if sender.send(Ok(stream)).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+fn quic_client_config(root_certificate: Option<&[u8]>) -> Result<quinn::ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(root_certificate) = root_certificate {
+        for certificate in rustls_pemfile::certs(&mut &*root_certificate) {
+            let certificate = certificate
+                .map_err(|e| VeterError::InvalidInput(format!("Invalid root certificate: {}", e)))?;
+            roots.add(certificate)
+                .map_err(|e| VeterError::InvalidInput(format!("Invalid root certificate: {}", e)))?;
+        }
+    }
+
+    let mut tls = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .map_err(|e| VeterError::Crypto(format!("TLS config failed: {}", e)))?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    tls.alpn_protocols = vec![ALPN.to_vec()];
+    tls.enable_early_data = true;
+
+    let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(tls)
+        .map_err(|e| VeterError::Crypto(format!("TLS config failed: {}", e)))?;
+    let mut config = quinn::ClientConfig::new(Arc::new(crypto));
+    config.transport_config(Arc::new(transport_config()));
+    Ok(config)
+}
+
+fn transport_config() -> quinn::TransportConfig {
+    let mut config = quinn::TransportConfig::default();
+    config.keep_alive_interval(Some(KEEP_ALIVE_INTERVAL));
+    config
+}
+
+/// Local wildcard address of the same family as `remote`
+fn unspecified_address(remote: SocketAddr) -> SocketAddr {
+    match remote {
+        SocketAddr::V4(_) => (std::net::Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+    }
+}
@@ -1,160 +1,156 @@
 //! Local storage and database operations
 
+pub mod migrations;
+
 use crate::{VeterError, Result, models::*};
-use sqlx::{SqlitePool, Row};
-use std::path::Path;
+use crate::crdt::{RoomOp, RoomState};
+use sqlx::{Connection, SqlitePool, Row};
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use rand::RngCore;
+use rand::rngs::OsRng;
+use sqlx::sqlite::SqliteConnectOptions;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::sync::{RwLock, RwLockReadGuard};
+use zeroize::Zeroizing;
 use uuid::Uuid;
 
+/// Length of the Argon2id salt stored in the database header
+const SALT_LENGTH: usize = 16;
+
+/// Header of an unencrypted SQLite database file
+const PLAINTEXT_HEADER: &[u8; 16] = b"SQLite format 3\0";
+
+/// Joins a message `m` with the local user `l`, their user row `u` and their
+/// read receipt `r` in its room, for the unread conditions below
+const UNREAD_JOINS: &str = r#"
+    LEFT JOIN local_user l
+    LEFT JOIN users u ON u.id = l.user_id
+    LEFT JOIN receipts r ON r.room_id = m.room_id AND r.user_id = l.user_id AND r.receipt_type = 'Read'
+"#;
+
+/// Whether `m` is a message from someone else the local user has not read
+const UNREAD: &str = r#"
+    (m.sender_id != l.user_id
+     AND (json_type(m.content, '$.Text') IS NOT NULL
+          OR json_type(m.content, '$.File') IS NOT NULL
+          OR json_type(m.content, '$.Image') IS NOT NULL)
+     AND (r.message_id IS NULL OR (m.created_at, m.id) > (r.message_created_at, r.message_id)))
+"#;
+
+/// Whether `m` mentions the local user: `@username` standing on its own, not
+/// next to other letters, digits, `_` or `-`. The username is escaped for
+/// GLOB, and the text padded so a mention can start or end it.
+const MENTION: &str = r#"((' ' || lower(m.search_text) || ' ') GLOB
+    '*[^a-z0-9_-]@'
+    || replace(replace(replace(lower(u.username), '[', '[[]'), '*', '[*]'), '?', '[?]')
+    || '[^a-z0-9_-]*')"#;
+
+/// Columns of a room `r` and its joins, read by `room_from_row`. Members
+/// come as a JSON array in the order they joined.
+const ROOM_COLUMNS: &str = r#"
+    r.id, r.name, r.description, r.room_type, r.encryption, r.created_at, r.updated_at, s.state,
+    (SELECT json_group_array(user_id) FROM
+        (SELECT user_id FROM room_members WHERE room_id = r.id ORDER BY joined_at, rowid)) AS members
+"#;
+
+/// Joins a room `r` with its replicated state `s`
+const ROOM_JOINS: &str = "LEFT JOIN room_states s ON s.room_id = r.id";
+
 /// Database manager for local storage
 pub struct StorageManager {
+    /// Shared by every call, and held exclusively while the database is
+    /// rekeyed
+    database: RwLock<Database>,
+    db_path: PathBuf,
+}
+
+/// Connection pool and the key its connections were opened with
+struct Database {
     pool: SqlitePool,
+    key: DatabaseKey,
 }
 
 impl StorageManager {
-    /// Create a new storage manager with encrypted SQLite database
+    /// Open the SQLCipher-encrypted database, creating it if needed.
+    ///
+    /// The database key is derived from the password with Argon2id. Its salt
+    /// is kept in the first bytes of the database file, where SQLCipher
+    /// stores it unencrypted. A wrong password fails with
+    /// [`VeterError::Authentication`]. An unencrypted database from an
+    /// earlier version is encrypted in place. The schema is then migrated to
+    /// [`migrations::SCHEMA_VERSION`]; a database written by a newer build
+    /// is refused.
     pub async fn new(db_path: &Path, password: &str) -> Result<Self> {
-        // TODO: Use SQLCipher for encryption
-        // For now, use regular SQLite
-        let database_url = format!("sqlite://{}", db_path.display());
-        
-        let pool = SqlitePool::connect(&database_url)
-            .await
-            .map_err(|e| VeterError::Database(format!("Failed to connect to database: {}", e)))?;
-        
-        let manager = Self { pool };
-        manager.init_schema().await?;
-        
-        Ok(manager)
-    }
-
-    /// Initialize database schema
-    async fn init_schema(&self) -> Result<()> {
-        // Create users table
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS users (
-                id TEXT PRIMARY KEY,
-                username TEXT UNIQUE NOT NULL,
-                display_name TEXT NOT NULL,
-                avatar_url TEXT,
-                created_at TEXT NOT NULL
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create users table: {}", e)))?;
+        let salt = match read_header(db_path)? {
+            Some(header) if &header == PLAINTEXT_HEADER => {
+                let key = DatabaseKey::generate(password)?;
+                encrypt_plaintext_database(db_path, &key).await?;
+                key.salt
+            }
+            Some(header) => header,
+            None => DatabaseKey::generate(password)?.salt,
+        };
+        let key = DatabaseKey::derive(password, salt)?;
+        let pool = connect(db_path, &key).await?;
 
-        // Create devices table
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS devices (
-                id TEXT PRIMARY KEY,
-                user_id TEXT NOT NULL,
-                name TEXT NOT NULL,
-                platform TEXT NOT NULL,
-                public_key BLOB NOT NULL,
-                created_at TEXT NOT NULL,
-                last_seen TEXT NOT NULL,
-                FOREIGN KEY (user_id) REFERENCES users (id)
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create devices table: {}", e)))?;
-
-        // Create rooms table
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS rooms (
-                id TEXT PRIMARY KEY,
-                name TEXT NOT NULL,
-                description TEXT,
-                room_type TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create rooms table: {}", e)))?;
+        migrations::migrate(&pool).await?;
 
-        // Create room_members table
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS room_members (
-                room_id TEXT NOT NULL,
-                user_id TEXT NOT NULL,
-                joined_at TEXT NOT NULL,
-                PRIMARY KEY (room_id, user_id),
-                FOREIGN KEY (room_id) REFERENCES rooms (id),
-                FOREIGN KEY (user_id) REFERENCES users (id)
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create room_members table: {}", e)))?;
+        Ok(Self {
+            database: RwLock::new(Database { pool, key }),
+            db_path: db_path.to_path_buf(),
+        })
+    }
 
-        // Create messages table
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                room_id TEXT NOT NULL,
-                sender_id TEXT NOT NULL,
-                sender_device_id TEXT NOT NULL,
-                content TEXT NOT NULL,
-                created_at TEXT NOT NULL,
-                edited_at TEXT,
-                reply_to TEXT,
-                FOREIGN KEY (room_id) REFERENCES rooms (id),
-                FOREIGN KEY (sender_id) REFERENCES users (id),
-                FOREIGN KEY (sender_device_id) REFERENCES devices (id)
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create messages table: {}", e)))?;
+    /// Change the database password, re-encrypting the database under a key
+    /// derived with a fresh salt. Waits for running calls to finish; calls
+    /// made meanwhile wait for the new key.
+    pub async fn change_password(&self, current_password: &str, new_password: &str) -> Result<()> {
+        let mut database = self.database.write().await;
+        if !DatabaseKey::derive(current_password, database.key.salt)?.matches(&database.key) {
+            return Err(VeterError::Authentication("Wrong database password".to_string()));
+        }
 
-        // Create sessions table
-        sqlx::query(
-            r#"
-            CREATE TABLE IF NOT EXISTS sessions (
-                room_id TEXT PRIMARY KEY,
-                device_id TEXT NOT NULL,
-                session_data BLOB NOT NULL,
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL,
-                FOREIGN KEY (room_id) REFERENCES rooms (id),
-                FOREIGN KEY (device_id) REFERENCES devices (id)
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create sessions table: {}", e)))?;
+        let key = DatabaseKey::generate(new_password)?;
+        let mut connection = database.pool.acquire()
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to acquire connection: {}", e)))?;
+        sqlx::query(&format!("PRAGMA rekey = {}", key.pragma_value()))
+            .execute(&mut *connection)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to rekey database: {}", e)))?;
+        drop(connection);
 
-        // Create FTS5 virtual table for full-text search
-        sqlx::query(
-            r#"
-            CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
-                content,
-                content='messages',
-                content_rowid='rowid'
-            )
-            "#
-        )
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to create FTS table: {}", e)))?;
+        // Pooled connections still hold the old key
+        database.pool.close().await;
+        *database = Database {
+            pool: connect(&self.db_path, &key).await?,
+            key,
+        };
 
         Ok(())
     }
 
+    /// The connection pool, for as long as the guard is held
+    async fn pool(&self) -> RwLockReadGuard<'_, SqlitePool> {
+        RwLockReadGuard::map(self.database.read().await, |database| &database.pool)
+    }
+
+    /// Schema version of the open database
+    pub async fn schema_version(&self) -> Result<u32> {
+        migrations::schema_version(&*self.pool().await).await
+    }
+
+    /// Close the database, waiting for running queries to finish. Later
+    /// calls fail.
+    pub async fn close(&self) {
+        self.pool().await.close().await;
+    }
+
     /// Store a user
     pub async fn store_user(&self, user: &User) -> Result<()> {
         sqlx::query(
@@ -163,12 +159,12 @@ impl StorageManager {
             VALUES (?, ?, ?, ?, ?)
             "#
         )
-        .bind(&user.id.to_string())
+        .bind(user.id.to_string())
         .bind(&user.username)
         .bind(&user.display_name)
         .bind(&user.avatar_url)
-        .bind(&user.created_at.to_rfc3339())
-        .execute(&self.pool)
+        .bind(user.created_at.to_rfc3339())
+        .execute(&*self.pool().await)
         .await
         .map_err(|e| VeterError::Database(format!("Failed to store user: {}", e)))?;
 
@@ -183,8 +179,8 @@ impl StorageManager {
             FROM users WHERE id = ?
             "#
         )
-        .bind(&user_id.to_string())
-        .fetch_optional(&self.pool)
+        .bind(user_id.to_string())
+        .fetch_optional(&*self.pool().await)
         .await
         .map_err(|e| VeterError::Database(format!("Failed to get user: {}", e)))?;
 
@@ -205,200 +201,2057 @@ impl StorageManager {
         }
     }
 
-    /// Store a message
-    pub async fn store_message(&self, message: &Message) -> Result<()> {
-        let content_json = serde_json::to_string(&message.content)
-            .map_err(|e| VeterError::Serialization(format!("Failed to serialize message content: {}", e)))?;
+    /// Set the user this device belongs to, whose unread messages are
+    /// counted, and count them in every room
+    pub async fn set_local_user(&self, user_id: &UserId) -> Result<()> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to set local user: {}", e));
 
+        let pool = self.pool().await;
+        let mut tx = pool.begin().await.map_err(error)?;
+        sqlx::query("INSERT OR REPLACE INTO local_user (id, user_id) VALUES (0, ?)")
+            .bind(user_id.to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?;
+        let rooms = sqlx::query("SELECT id FROM rooms")
+            .fetch_all(&mut *tx)
+            .await
+            .map_err(error)?;
+        for row in rooms {
+            let room_id = Uuid::parse_str(&row.get::<String, _>("id"))
+                .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?;
+            recount_room(&mut tx, &room_id).await?;
+        }
+        tx.commit().await.map_err(error)
+    }
+
+    /// The user this device belongs to, if set
+    pub async fn get_local_user(&self) -> Result<Option<UserId>> {
+        let pool = self.pool().await;
+        let mut connection = pool.acquire().await
+            .map_err(|e| VeterError::Database(format!("Failed to get local user: {}", e)))?;
+        local_user(&mut connection).await
+    }
+
+    /// Store a room and its members, who have to be stored users. The
+    /// room's encryption mode is kept from when it was first stored.
+    pub async fn store_room(&self, room: &Room) -> Result<()> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to store room: {}", e));
+        let members: Vec<String> = room.members.iter().map(|id| id.to_string()).collect();
+
+        let pool = self.pool().await;
+        let mut tx = pool.begin().await.map_err(error)?;
         sqlx::query(
             r#"
-            INSERT INTO messages (id, room_id, sender_id, sender_device_id, content, created_at, edited_at, reply_to)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO rooms (id, name, description, room_type, encryption, created_at, updated_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (id) DO UPDATE SET
+                name = excluded.name,
+                description = excluded.description,
+                room_type = excluded.room_type,
+                updated_at = excluded.updated_at
             "#
         )
-        .bind(&message.id.to_string())
-        .bind(&message.room_id.to_string())
-        .bind(&message.sender_id.to_string())
-        .bind(&message.sender_device_id.to_string())
-        .bind(&content_json)
-        .bind(&message.created_at.to_rfc3339())
-        .bind(&message.edited_at.map(|t| t.to_rfc3339()))
-        .bind(&message.reply_to.map(|id| id.to_string()))
-        .execute(&self.pool)
-        .await
-        .map_err(|e| VeterError::Database(format!("Failed to store message: {}", e)))?;
-
-        // Update FTS index
+        .bind(room.id.to_string())
+        .bind(&room.name)
+        .bind(&room.description)
+        .bind(enum_to_text(&room.room_type)?)
+        .bind(enum_to_text(&room.encryption)?)
+        .bind(room.created_at.to_rfc3339())
+        .bind(room.updated_at.to_rfc3339())
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+
+        sqlx::query("DELETE FROM room_members WHERE room_id = ? AND user_id NOT IN (SELECT value FROM json_each(?))")
+            .bind(room.id.to_string())
+            .bind(serde_json::to_string(&members)?)
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?;
+        let joined_at = chrono::Utc::now().to_rfc3339();
+        for member in &members {
+            sqlx::query("INSERT OR IGNORE INTO room_members (room_id, user_id, joined_at) VALUES (?, ?, ?)")
+                .bind(room.id.to_string())
+                .bind(member)
+                .bind(&joined_at)
+                .execute(&mut *tx)
+                .await
+                .map_err(error)?;
+        }
+
+        tx.commit().await.map_err(error)
+    }
+
+    /// Get a room by ID, with its members in the order they joined
+    pub async fn get_room(&self, room_id: &RoomId) -> Result<Option<Room>> {
+        let sql = format!("SELECT {} FROM rooms r {} WHERE r.id = ?", ROOM_COLUMNS, ROOM_JOINS);
+        let row = sqlx::query(&sql)
+            .bind(room_id.to_string())
+            .fetch_optional(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get room: {}", e)))?;
+
+        row.as_ref().map(room_from_row).transpose()
+    }
+
+    /// Replicated state of a room, with the room ops stored with its
+    /// messages applied
+    pub async fn get_room_state(&self, room_id: &RoomId) -> Result<RoomState> {
+        room_state(&*self.pool().await, room_id).await
+    }
+
+    /// Get all rooms, oldest first
+    pub async fn get_rooms(&self) -> Result<Vec<Room>> {
+        let sql = format!("SELECT {} FROM rooms r {} ORDER BY r.created_at, r.rowid", ROOM_COLUMNS, ROOM_JOINS);
+        let rows = sqlx::query(&sql)
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get rooms: {}", e)))?;
+
+        rows.iter().map(room_from_row).collect()
+    }
+
+    /// Store a device, e.g. as fetched from the directory.
+    ///
+    /// The verification state is kept by the database: a verified device
+    /// whose identity key differs from the verified one becomes
+    /// [`VerificationState::Changed`], and goes back to verified if the
+    /// verified key returns.
+    pub async fn store_device(&self, device: &Device) -> Result<()> {
         sqlx::query(
             r#"
-            INSERT INTO messages_fts (rowid, content)
-            VALUES (last_insert_rowid(), ?)
+            INSERT INTO devices (id, user_id, name, platform, public_key, created_at, last_seen)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (id) DO UPDATE SET
+                name = excluded.name,
+                platform = excluded.platform,
+                public_key = excluded.public_key,
+                last_seen = excluded.last_seen,
+                verification = CASE
+                    WHEN devices.verified_key IS NULL THEN devices.verification
+                    WHEN devices.verified_key = excluded.public_key THEN 'Verified'
+                    ELSE 'Changed'
+                END
             "#
         )
-        .bind(&content_json)
-        .execute(&self.pool)
+        .bind(device.id.to_string())
+        .bind(device.user_id.to_string())
+        .bind(&device.name)
+        .bind(enum_to_text(&device.platform)?)
+        .bind(&device.public_key)
+        .bind(device.created_at.to_rfc3339())
+        .bind(device.last_seen.to_rfc3339())
+        .execute(&*self.pool().await)
         .await
-        .map_err(|e| VeterError::Database(format!("Failed to update FTS index: {}", e)))?;
+        .map_err(|e| VeterError::Database(format!("Failed to store device: {}", e)))?;
 
         Ok(())
     }
 
-    /// Get messages for a room
-    pub async fn get_messages(&self, room_id: &RoomId, limit: i64, offset: i64) -> Result<Vec<Message>> {
+    /// Get a device by ID
+    pub async fn get_device(&self, device_id: &DeviceId) -> Result<Option<Device>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id, user_id, name, platform, public_key, created_at, last_seen, verification
+            FROM devices WHERE id = ?
+            "#
+        )
+        .bind(device_id.to_string())
+        .fetch_optional(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get device: {}", e)))?;
+
+        row.map(|row| device_from_row(&row)).transpose()
+    }
+
+    /// Get all devices of a user
+    pub async fn get_user_devices(&self, user_id: &UserId) -> Result<Vec<Device>> {
         let rows = sqlx::query(
             r#"
-            SELECT id, room_id, sender_id, sender_device_id, content, created_at, edited_at, reply_to
-            FROM messages 
-            WHERE room_id = ?
-            ORDER BY created_at DESC
-            LIMIT ? OFFSET ?
+            SELECT id, user_id, name, platform, public_key, created_at, last_seen, verification
+            FROM devices WHERE user_id = ?
+            ORDER BY created_at
             "#
         )
-        .bind(&room_id.to_string())
-        .bind(limit)
-        .bind(offset)
-        .fetch_all(&self.pool)
+        .bind(user_id.to_string())
+        .fetch_all(&*self.pool().await)
         .await
-        .map_err(|e| VeterError::Database(format!("Failed to get messages: {}", e)))?;
+        .map_err(|e| VeterError::Database(format!("Failed to get devices: {}", e)))?;
 
-        let mut messages = Vec::new();
-        for row in rows {
-            let content: String = row.get("content");
-            let message_content = serde_json::from_str(&content)
-                .map_err(|e| VeterError::Serialization(format!("Failed to deserialize message content: {}", e)))?;
+        rows.iter().map(device_from_row).collect()
+    }
 
-            let message = Message {
-                id: Uuid::parse_str(&row.get::<String, _>("id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?,
-                room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
-                sender_id: Uuid::parse_str(&row.get::<String, _>("sender_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid sender ID: {}", e)))?,
-                sender_device_id: Uuid::parse_str(&row.get::<String, _>("sender_device_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
-                content: message_content,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at"))
-                    .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
-                    .with_timezone(&chrono::Utc),
-                edited_at: row.get::<Option<String>, _>("edited_at")
-                    .map(|s| chrono::DateTime::parse_from_rfc3339(&s)
-                        .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))
-                        .unwrap()
-                        .with_timezone(&chrono::Utc)),
-                reply_to: row.get::<Option<String>, _>("reply_to")
-                    .map(|s| Uuid::parse_str(&s)
-                        .map_err(|e| VeterError::Database(format!("Invalid reply ID: {}", e)))
-                        .unwrap()),
-            };
-            messages.push(message);
+    /// Get the devices whose identity key changed since it was verified
+    pub async fn get_changed_devices(&self) -> Result<Vec<Device>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, user_id, name, platform, public_key, created_at, last_seen, verification
+            FROM devices WHERE verification = 'Changed'
+            ORDER BY last_seen DESC
+            "#
+        )
+        .fetch_all(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get changed devices: {}", e)))?;
+
+        rows.iter().map(device_from_row).collect()
+    }
+
+    /// Mark a device's current identity key as verified, after the safety
+    /// numbers were compared or its QR code scanned
+    pub async fn verify_device(&self, device_id: &DeviceId) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE devices SET verification = 'Verified', verified_key = public_key WHERE id = ?"
+        )
+        .bind(device_id.to_string())
+        .execute(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to verify device: {}", e)))?;
+
+        if result.rows_affected() == 0 {
+            return Err(VeterError::InvalidInput(format!("Unknown device {}", device_id)));
         }
+        Ok(())
+    }
+
+    /// Clear a device's verification, e.g. after a changed key was acknowledged
+    pub async fn unverify_device(&self, device_id: &DeviceId) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE devices SET verification = 'Unverified', verified_key = NULL WHERE id = ?"
+        )
+        .bind(device_id.to_string())
+        .execute(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to unverify device: {}", e)))?;
+
+        if result.rows_affected() == 0 {
+            return Err(VeterError::InvalidInput(format!("Unknown device {}", device_id)));
+        }
+        Ok(())
+    }
+
+    /// Store a message. Its searchable text is indexed by a trigger in the
+    /// same statement.
+    ///
+    /// An edit or redaction is applied to its target instead of being
+    /// stored as a message of its own, see [`StorageManager::authorize_change`].
+    /// One for a message not stored yet is applied when it arrives. Reactions
+    /// are kept out of the timeline and search too, see
+    /// [`StorageManager::get_reactions`].
+    pub async fn store_message(&self, message: &Message) -> Result<()> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to store message: {}", e));
+
+        let pool = self.pool().await;
+        let mut tx = pool.begin().await.map_err(error)?;
+        insert_message(&mut tx, message).await?;
+        tx.commit().await.map_err(error)
+    }
 
-        Ok(messages)
+    /// Check that an edit or redaction may be applied to its target: the
+    /// target was sent by the same user and holds editable content, and an
+    /// edit's new content is editable. A target not stored yet is checked
+    /// when it arrives, and the change dropped if it fails. A receipt needs
+    /// its message stored in the same room. Other messages always pass.
+    pub async fn authorize_change(&self, message: &Message) -> Result<()> {
+        let pool = self.pool().await;
+        let mut connection = pool.acquire().await
+            .map_err(|e| VeterError::Database(format!("Failed to check message change: {}", e)))?;
+        check_change(&mut connection, message).await
     }
 
-    /// Search messages using full-text search
-    pub async fn search_messages(&self, query: &str, limit: i64) -> Result<Vec<Message>> {
+    /// Versions of an edited message, the original first. Empty if the
+    /// message was never edited, or was redacted.
+    pub async fn get_message_history(&self, message_id: &MessageId) -> Result<Vec<MessageVersion>> {
         let rows = sqlx::query(
             r#"
-            SELECT m.id, m.room_id, m.sender_id, m.sender_device_id, m.content, m.created_at, m.edited_at, m.reply_to
-            FROM messages m
-            JOIN messages_fts fts ON m.rowid = fts.rowid
-            WHERE messages_fts MATCH ?
-            ORDER BY fts.rank
-            LIMIT ?
+            SELECT v.id, v.sender_device_id, v.content, v.created_at
+            FROM message_versions v
+            JOIN messages m ON m.id = v.message_id AND m.sender_id = v.sender_id
+            WHERE v.message_id = ?
+            ORDER BY v.id = v.message_id DESC, v.created_at, v.id
             "#
         )
-        .bind(query)
-        .bind(limit)
-        .fetch_all(&self.pool)
+        .bind(message_id.to_string())
+        .fetch_all(&*self.pool().await)
         .await
-        .map_err(|e| VeterError::Database(format!("Failed to search messages: {}", e)))?;
+        .map_err(|e| VeterError::Database(format!("Failed to get message history: {}", e)))?;
+
+        rows.iter().map(version_from_row).collect()
+    }
+
+    /// Reactions to each of the given messages, most frequent first, with
+    /// whether `user_id` reacted. Messages without reactions are left out.
+    pub async fn get_reactions(&self, user_id: &UserId, message_ids: &[MessageId]) -> Result<HashMap<MessageId, Vec<ReactionSummary>>> {
+        let mut reactions: HashMap<MessageId, Vec<ReactionSummary>> = HashMap::new();
+        if message_ids.is_empty() {
+            return Ok(reactions);
+        }
+
+        let sql = format!(
+            r#"
+            SELECT message_id, emoji, COUNT(*) AS count, MAX(user_id = ?) AS reacted
+            FROM reactions
+            WHERE active AND message_id IN ({})
+            GROUP BY message_id, emoji
+            ORDER BY count DESC, MIN(updated_at), emoji
+            "#,
+            vec!["?"; message_ids.len()].join(", "),
+        );
+        let mut query = sqlx::query(&sql).bind(user_id.to_string());
+        for message_id in message_ids {
+            query = query.bind(message_id.to_string());
+        }
+        let rows = query.fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get reactions: {}", e)))?;
 
-        let mut messages = Vec::new();
         for row in rows {
-            let content: String = row.get("content");
-            let message_content = serde_json::from_str(&content)
-                .map_err(|e| VeterError::Serialization(format!("Failed to deserialize message content: {}", e)))?;
+            let message_id = Uuid::parse_str(&row.get::<String, _>("message_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?;
+            reactions.entry(message_id).or_default().push(ReactionSummary {
+                emoji: row.get("emoji"),
+                count: row.get::<i64, _>("count") as u32,
+                reacted: row.get("reacted"),
+            });
+        }
+        Ok(reactions)
+    }
 
-            let message = Message {
-                id: Uuid::parse_str(&row.get::<String, _>("id"))
+    /// Latest delivery and read receipts of the users in a room
+    pub async fn get_receipts(&self, room_id: &RoomId) -> Result<Vec<Receipt>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT user_id, receipt_type, message_id, message_created_at FROM receipts
+            WHERE room_id = ?
+            ORDER BY message_created_at DESC, user_id, receipt_type
+            "#
+        )
+        .bind(room_id.to_string())
+        .fetch_all(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get receipts: {}", e)))?;
+
+        rows.iter()
+            .map(|row| Ok(Receipt {
+                user_id: Uuid::parse_str(&row.get::<String, _>("user_id"))
+                    .map_err(|e| VeterError::Database(format!("Invalid user ID: {}", e)))?,
+                receipt_type: enum_from_text(&row.get::<String, _>("receipt_type"))?,
+                message_id: Uuid::parse_str(&row.get::<String, _>("message_id"))
                     .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?,
-                room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
-                sender_id: Uuid::parse_str(&row.get::<String, _>("sender_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid sender ID: {}", e)))?,
-                sender_device_id: Uuid::parse_str(&row.get::<String, _>("sender_device_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
-                content: message_content,
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at"))
-                    .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
-                    .with_timezone(&chrono::Utc),
-                edited_at: row.get::<Option<String>, _>("edited_at")
-                    .map(|s| chrono::DateTime::parse_from_rfc3339(&s)
-                        .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))
-                        .unwrap()
-                        .with_timezone(&chrono::Utc)),
-                reply_to: row.get::<Option<String>, _>("reply_to")
-                    .map(|s| Uuid::parse_str(&s)
-                        .map_err(|e| VeterError::Database(format!("Invalid reply ID: {}", e)))
-                        .unwrap()),
-            };
-            messages.push(message);
+                message_created_at: parse_timestamp(&row.get::<String, _>("message_created_at"))?,
+            }))
+            .collect()
+    }
+
+    /// All rooms with the local user's unread counts, most recently active
+    /// first. Rooms without messages are ordered by when they were last
+    /// updated, normalized to the format of message timestamps.
+    pub async fn get_room_summaries(&self) -> Result<Vec<RoomSummary>> {
+        let sql = format!(
+            r#"
+            SELECT {columns}, IFNULL(a.unread_count, 0) AS unread_count, IFNULL(a.mention_count, 0) AS mention_count,
+                   a.last_activity_at, rc.message_id AS read_up_to
+            FROM rooms r {joins}
+            LEFT JOIN room_activity a ON a.room_id = r.id
+            LEFT JOIN local_user l
+            LEFT JOIN receipts rc ON rc.room_id = r.id AND rc.user_id = l.user_id AND rc.receipt_type = 'Read'
+            ORDER BY IFNULL(a.last_activity_at, strftime('%Y-%m-%dT%H:%M:%fZ', r.updated_at)) DESC, r.created_at, r.rowid
+            "#,
+            columns = ROOM_COLUMNS,
+            joins = ROOM_JOINS,
+        );
+        let rows = sqlx::query(&sql)
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get room summaries: {}", e)))?;
+
+        rows.iter()
+            .map(|row| Ok(RoomSummary {
+                room: room_from_row(row)?,
+                unread_count: row.get::<i64, _>("unread_count") as u32,
+                mention_count: row.get::<i64, _>("mention_count") as u32,
+                last_activity_at: row.get::<Option<String>, _>("last_activity_at")
+                    .map(|time| parse_timestamp(&time))
+                    .transpose()?,
+                read_up_to: row.get::<Option<String>, _>("read_up_to")
+                    .map(|id| Uuid::parse_str(&id)
+                        .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e))))
+                    .transpose()?,
+            }))
+            .collect()
+    }
+
+    /// Delete a message with its edit history, reactions and search index
+    /// entry, and take it out of the unread counts
+    pub async fn delete_message(&self, message_id: &MessageId) -> Result<()> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to delete message: {}", e));
+
+        let pool = self.pool().await;
+        let mut tx = pool.begin().await.map_err(error)?;
+        let message = get_stored_message(&mut tx, message_id).await?;
+        for statement in [
+            "DELETE FROM messages WHERE id = ?",
+            "DELETE FROM message_versions WHERE message_id = ?",
+            "DELETE FROM reactions WHERE message_id = ?",
+        ] {
+            sqlx::query(statement)
+                .bind(message_id.to_string())
+                .execute(&mut *tx)
+                .await
+                .map_err(error)?;
+        }
+        if let Some(message) = message {
+            recount_room(&mut tx, &message.room_id).await?;
+        }
+        tx.commit().await.map_err(error)
+    }
+
+    /// Store a received message together with the key state advanced by
+    /// decrypting it, in one transaction, and take it out of quarantine.
+    /// Returns `false` without storing anything if the message was received
+    /// before.
+    pub async fn store_received_message(&self, message: &Message, keys: &KeyStateUpdate) -> Result<bool> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to store received message: {}", e));
+
+        let pool = self.pool().await;
+        let mut tx = pool.begin().await.map_err(error)?;
+        let received = sqlx::query("INSERT OR IGNORE INTO received_messages (message_id, received_at) VALUES (?, ?)")
+            .bind(message.id.to_string())
+            .bind(chrono::Utc::now().to_rfc3339())
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?;
+        if received.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        insert_message(&mut tx, message).await?;
+        if let Some(session) = &keys.session {
+            upsert_session(&mut *tx, session).await?;
+        }
+        if let Some(sender_key) = &keys.sender_key {
+            upsert_sender_key(&mut *tx, sender_key).await?;
         }
+        if let Some((room_id, state_data)) = &keys.mls_group {
+            upsert_mls_group(&mut *tx, room_id, state_data).await?;
+        }
+        if let Some((device_id, state_data)) = &keys.prekey_state {
+            upsert_prekey_state(&mut *tx, device_id, state_data).await?;
+        }
+        sqlx::query("DELETE FROM quarantine WHERE message_id = ?")
+            .bind(message.id.to_string())
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?;
 
-        Ok(messages)
+        tx.commit().await.map_err(error)?;
+        Ok(true)
     }
 
-    /// Store a session
-    pub async fn store_session(&self, session: &Session) -> Result<()> {
+    /// Whether a message was received before, stored or quarantined
+    pub async fn has_received_message(&self, message_id: &MessageId) -> Result<bool> {
+        let row = sqlx::query(
+            r#"
+            SELECT 1 FROM received_messages WHERE message_id = ?1
+            UNION ALL
+            SELECT 1 FROM quarantine WHERE message_id = ?1
+            "#
+        )
+        .bind(message_id.to_string())
+        .fetch_optional(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to look up received message: {}", e)))?;
+
+        Ok(row.is_some())
+    }
+
+    /// Keep a received message that could not be decrypted or stored, or
+    /// record another failed attempt for one already in quarantine
+    pub async fn quarantine_message(&self, message: &EncryptedMessage, error: &str) -> Result<()> {
+        let now = chrono::Utc::now().to_rfc3339();
+
         sqlx::query(
             r#"
-            INSERT OR REPLACE INTO sessions (room_id, device_id, session_data, created_at, updated_at)
-            VALUES (?, ?, ?, ?, ?)
+            INSERT INTO quarantine (message_id, room_id, sender_device_id, payload, created_at, error, quarantined_at, last_attempt_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (message_id) DO UPDATE SET
+                error = excluded.error,
+                attempts = attempts + 1,
+                last_attempt_at = excluded.last_attempt_at
             "#
         )
-        .bind(&session.room_id.to_string())
-        .bind(&session.device_id.to_string())
-        .bind(&session.session_data)
-        .bind(&session.created_at.to_rfc3339())
-        .bind(&session.updated_at.to_rfc3339())
-        .execute(&self.pool)
+        .bind(message.id.to_string())
+        .bind(message.room_id.to_string())
+        .bind(message.sender_device_id.to_string())
+        .bind(&message.payload)
+        .bind(timestamp(message.timestamp))
+        .bind(error)
+        .bind(&now)
+        .bind(&now)
+        .execute(&*self.pool().await)
         .await
-        .map_err(|e| VeterError::Database(format!("Failed to store session: {}", e)))?;
+        .map_err(|e| VeterError::Database(format!("Failed to quarantine message: {}", e)))?;
 
         Ok(())
     }
 
-    /// Get a session
-    pub async fn get_session(&self, room_id: &RoomId) -> Result<Option<Session>> {
-        let row = sqlx::query(
+    /// Get the quarantined messages, oldest first
+    pub async fn get_quarantined_messages(&self) -> Result<Vec<QuarantinedMessage>> {
+        let rows = sqlx::query(
             r#"
-            SELECT room_id, device_id, session_data, created_at, updated_at
-            FROM sessions WHERE room_id = ?
+            SELECT message_id, room_id, sender_device_id, payload, created_at, error, attempts,
+                quarantined_at, last_attempt_at
+            FROM quarantine
+            ORDER BY created_at, message_id
             "#
         )
-        .bind(&room_id.to_string())
-        .fetch_optional(&self.pool)
+        .fetch_all(&*self.pool().await)
         .await
-        .map_err(|e| VeterError::Database(format!("Failed to get session: {}", e)))?;
+        .map_err(|e| VeterError::Database(format!("Failed to get quarantined messages: {}", e)))?;
 
-        if let Some(row) = row {
-            let session = Session {
-                room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
-                device_id: Uuid::parse_str(&row.get::<String, _>("device_id"))
-                    .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
-                session_data: row.get("session_data"),
-                created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at"))
-                    .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
-                    .with_timezone(&chrono::Utc),
-                updated_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("updated_at"))
-                    .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
-                    .with_timezone(&chrono::Utc),
-            };
-            Ok(Some(session))
-        } else {
-            Ok(None)
+        rows.iter().map(quarantined_from_row).collect()
+    }
+
+    /// Give up on a quarantined message
+    pub async fn delete_quarantined_message(&self, message_id: &MessageId) -> Result<()> {
+        sqlx::query("DELETE FROM quarantine WHERE message_id = ?")
+            .bind(message_id.to_string())
+            .execute(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to delete quarantined message: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Get a page of a room's messages.
+    ///
+    /// Pages are keyed on the creation time and ID of the edge message
+    /// rather than an offset, so messages arriving while the user scrolls
+    /// are neither skipped nor repeated.
+    pub async fn get_messages(&self, room_id: &RoomId, query: &MessageQuery) -> Result<MessagePage> {
+        self.query_page(&Scope::Room(room_id), query).await
+    }
+
+    /// Get a message by ID
+    pub async fn get_message(&self, message_id: &MessageId) -> Result<Option<Message>> {
+        let pool = self.pool().await;
+        let mut connection = pool.acquire().await
+            .map_err(|e| VeterError::Database(format!("Failed to get message: {}", e)))?;
+        get_stored_message(&mut connection, message_id).await
+    }
+
+    /// Get a page of the replies to a message, paged like
+    /// [`StorageManager::get_messages`]. A thread holds the direct replies
+    /// to its root; the root itself is not part of the page.
+    pub async fn get_thread(&self, root_id: &MessageId, query: &MessageQuery) -> Result<MessagePage> {
+        self.query_page(&Scope::Thread(root_id), query).await
+    }
+
+    /// Summaries of the threads under the given messages, for those with
+    /// replies, counting unread replies for `user_id`
+    pub async fn get_thread_summaries(&self, user_id: &UserId, root_ids: &[MessageId]) -> Result<Vec<ThreadSummary>> {
+        if root_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let filter = format!("m.reply_to IN ({})", vec!["?"; root_ids.len()].join(", "));
+        let params = root_ids.iter().map(|id| id.to_string()).collect();
+        self.thread_summaries(user_id, &filter, params, false, root_ids.len() as u32).await
+    }
+
+    /// Summaries of a room's threads, most recently active first
+    pub async fn get_room_threads(&self, user_id: &UserId, room_id: &RoomId, limit: u32) -> Result<Vec<ThreadSummary>> {
+        self.thread_summaries(user_id, "m.room_id = ?", vec![room_id.to_string()], false, limit).await
+    }
+
+    /// Summaries of the threads `user_id` started or replied to, in all
+    /// rooms, most recently active first
+    pub async fn get_participating_threads(&self, user_id: &UserId, limit: u32) -> Result<Vec<ThreadSummary>> {
+        let filter = r#"
+            m.reply_to IN (
+                SELECT reply_to FROM messages WHERE sender_id = ? AND reply_to IS NOT NULL
+                UNION ALL
+                SELECT id FROM messages WHERE sender_id = ?
+            )
+        "#;
+        self.thread_summaries(user_id, filter, vec![user_id.to_string(); 2], true, limit).await
+    }
+
+    /// Mark a thread read up to and including one of its replies. Marking an
+    /// earlier reply leaves the thread as it is.
+    pub async fn mark_thread_read(&self, root_id: &MessageId, message_id: &MessageId) -> Result<()> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to mark thread read: {}", e));
+
+        let result = sqlx::query(
+            r#"
+            INSERT INTO thread_reads (root_id, message_id, created_at)
+            SELECT reply_to, id, created_at FROM messages WHERE id = ? AND reply_to = ?
+            ON CONFLICT (root_id) DO UPDATE SET message_id = excluded.message_id, created_at = excluded.created_at
+            WHERE (excluded.created_at, excluded.message_id) > (thread_reads.created_at, thread_reads.message_id)
+            "#
+        )
+        .bind(message_id.to_string())
+        .bind(root_id.to_string())
+        .execute(&*self.pool().await)
+        .await
+        .map_err(error)?;
+
+        if result.rows_affected() == 0 {
+            let exists = sqlx::query("SELECT 1 FROM messages WHERE id = ? AND reply_to = ?")
+                .bind(message_id.to_string())
+                .bind(root_id.to_string())
+                .fetch_optional(&*self.pool().await)
+                .await
+                .map_err(error)?;
+            if exists.is_none() {
+                return Err(VeterError::InvalidInput(format!("Message {} is not a reply to {}", message_id, root_id)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Summaries of the threads whose replies match `filter`, on `m`
+    async fn thread_summaries(&self, user_id: &UserId, filter: &str, params: Vec<String>, participating_only: bool, limit: u32) -> Result<Vec<ThreadSummary>> {
+        let sql = format!(
+            r#"
+            SELECT r.id, r.room_id, r.sender_id, r.sender_device_id, r.content, r.created_at, r.edited_at, r.reply_to,
+                   t.reply_count, t.last_reply_at, t.unread_count, t.replied OR r.sender_id = ? AS participating
+            FROM (
+                SELECT m.reply_to AS root_id,
+                       COUNT(*) AS reply_count,
+                       MAX(m.created_at) AS last_reply_at,
+                       SUM(m.sender_id != ? AND (tr.root_id IS NULL OR (m.created_at, m.id) > (tr.created_at, tr.message_id))) AS unread_count,
+                       MAX(m.sender_id = ?) AS replied
+                FROM messages m
+                LEFT JOIN thread_reads tr ON tr.root_id = m.reply_to
+                WHERE m.reply_to IS NOT NULL AND {}
+                GROUP BY m.reply_to
+            ) t
+            JOIN messages r ON r.id = t.root_id
+            WHERE NOT ? OR participating
+            ORDER BY t.last_reply_at DESC, r.id DESC
+            LIMIT ?
+            "#,
+            filter,
+        );
+
+        let mut query = sqlx::query(&sql).bind(user_id.to_string());
+        for param in [user_id.to_string(), user_id.to_string()].into_iter().chain(params) {
+            query = query.bind(param);
+        }
+        let rows = query.bind(participating_only)
+            .bind(i64::from(limit))
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get threads: {}", e)))?;
+
+        rows.iter()
+            .map(|row| Ok(ThreadSummary {
+                root: message_from_row(row)?,
+                reply_count: row.get::<i64, _>("reply_count") as u32,
+                last_reply_at: parse_timestamp(&row.get::<String, _>("last_reply_at"))?,
+                unread_count: row.get::<i64, _>("unread_count") as u32,
+                participating: row.get("participating"),
+            }))
+            .collect()
+    }
+
+    /// Page through the messages in `scope`
+    async fn query_page(&self, scope: &Scope<'_>, query: &MessageQuery) -> Result<MessagePage> {
+        let range = TimeRange::new(query.since, query.until);
+        let cursor = query.cursor.as_ref().map(Position::decode).transpose()?;
+
+        let mut messages = match query.direction {
+            PageDirection::Backward => {
+                let bound = cursor.as_ref().map(|cursor| ("<", cursor));
+                self.query_messages(scope, &range, bound, "DESC", query.limit).await?
+            }
+            PageDirection::Forward => {
+                let bound = cursor.as_ref().map(|cursor| (">", cursor));
+                self.query_messages(scope, &range, bound, "ASC", query.limit).await?
+            }
+        };
+        if query.direction == PageDirection::Backward {
+            messages.reverse();
+        }
+
+        self.page(scope, &range, messages, cursor).await
+    }
+
+    /// Get the messages around a message, e.g. to jump to the target of a
+    /// reply. The page holds up to `limit` messages with the target roughly
+    /// in the middle.
+    pub async fn get_messages_around(&self, room_id: &RoomId, message_id: &MessageId, limit: u32) -> Result<MessagePage> {
+        let row = sqlx::query("SELECT created_at, id FROM messages WHERE id = ? AND room_id = ?")
+            .bind(message_id.to_string())
+            .bind(room_id.to_string())
+            .fetch_optional(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get message: {}", e)))?
+            .ok_or_else(|| VeterError::InvalidInput(format!("Unknown message {} in room {}", message_id, room_id)))?;
+        let target = Position {
+            created_at: row.get("created_at"),
+            id: row.get("id"),
+        };
+
+        let scope = Scope::Room(room_id);
+        let range = TimeRange::default();
+        let older_limit = limit / 2;
+        let mut messages = self.query_messages(&scope, &range, Some(("<", &target)), "DESC", older_limit).await?;
+        messages.reverse();
+        messages.extend(self.query_messages(&scope, &range, Some((">=", &target)), "ASC", limit - older_limit).await?);
+
+        self.page(&scope, &range, messages, Some(target)).await
+    }
+
+    /// Messages in `scope` on one side of `bound`, in the given order of
+    /// creation time
+    async fn query_messages(&self, scope: &Scope<'_>, range: &TimeRange, bound: Option<(&str, &Position)>, order: &str, limit: u32) -> Result<Vec<Message>> {
+        let (conditions, params) = message_conditions(scope, range, bound);
+        let sql = format!(
+            r#"
+            SELECT id, room_id, sender_id, sender_device_id, content, created_at, edited_at, reply_to
+            FROM messages
+            WHERE {}
+            ORDER BY created_at {order}, id {order}
+            LIMIT ?
+            "#,
+            conditions,
+            order = order,
+        );
+
+        let mut query = sqlx::query(&sql);
+        for param in params {
+            query = query.bind(param);
+        }
+        let rows = query.bind(i64::from(limit))
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get messages: {}", e)))?;
+
+        rows.iter().map(message_from_row).collect()
+    }
+
+    /// Whether `scope` has messages on one side of `bound`
+    async fn has_messages(&self, scope: &Scope<'_>, range: &TimeRange, bound: (&str, &Position)) -> Result<bool> {
+        let (conditions, params) = message_conditions(scope, range, Some(bound));
+        let sql = format!("SELECT EXISTS (SELECT 1 FROM messages WHERE {}) AS found", conditions);
+
+        let mut query = sqlx::query(&sql);
+        for param in params {
+            query = query.bind(param);
+        }
+        let row = query.fetch_one(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get messages: {}", e)))?;
+
+        Ok(row.get("found"))
+    }
+
+    /// Wrap messages, oldest first, in a page with cursors for the messages
+    /// beyond either end. An empty page is bounded by the position it
+    /// started from.
+    async fn page(&self, scope: &Scope<'_>, range: &TimeRange, messages: Vec<Message>, start: Option<Position>) -> Result<MessagePage> {
+        let (first, last) = match (messages.first(), messages.last()) {
+            (Some(first), Some(last)) => (Position::of(first), Position::of(last)),
+            _ => match start {
+                Some(start) => (start.clone(), start),
+                None => return Ok(MessagePage { messages, older: None, newer: None }),
+            },
+        };
+
+        let older = self.has_messages(scope, range, ("<", &first)).await?.then(|| first.encode());
+        let newer = self.has_messages(scope, range, (">", &last)).await?.then(|| last.encode());
+
+        Ok(MessagePage { messages, older, newer })
+    }
+
+    /// Search message text and file names, best matches first, optionally
+    /// within one room.
+    ///
+    /// Every word of the query has to match; the last one also matches as a
+    /// prefix so results can update while typing. FTS query syntax in the
+    /// input is treated as plain text.
+    pub async fn search_messages(&self, query: &str, room_id: Option<&RoomId>, limit: i64) -> Result<Vec<SearchResult>> {
+        let Some(fts_query) = fts_query(query) else {
+            return Ok(Vec::new());
+        };
+
+        let rows = sqlx::query(
+            r#"
+            SELECT m.id, m.room_id, m.sender_id, m.sender_device_id, m.content, m.created_at, m.edited_at, m.reply_to,
+                   snippet(messages_fts, 0, char(2), char(3), '…', 12) AS snippet,
+                   messages_fts.rank AS rank
+            FROM messages_fts
+            JOIN messages m ON m.rowid = messages_fts.rowid
+            WHERE messages_fts MATCH ? AND (? IS NULL OR m.room_id = ?)
+            ORDER BY messages_fts.rank
+            LIMIT ?
+            "#
+        )
+        .bind(&fts_query)
+        .bind(room_id.map(|id| id.to_string()))
+        .bind(room_id.map(|id| id.to_string()))
+        .bind(limit)
+        .fetch_all(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to search messages: {}", e)))?;
+
+        rows.iter()
+            .map(|row| {
+                let (snippet, highlights) = parse_snippet(&row.get::<String, _>("snippet"));
+                Ok(SearchResult {
+                    message: message_from_row(row)?,
+                    snippet,
+                    highlights,
+                    rank: row.get("rank"),
+                })
+            })
+            .collect()
+    }
+
+    /// Drop the words of redacted messages from the search index.
+    ///
+    /// A redaction only removes a message from search results; its words
+    /// stay in the index segments until these are merged. Merging them all
+    /// rewrites the whole index, so redactions just mark it and this runs
+    /// once per batch, e.g. after a sync. Returns whether anything was
+    /// purged.
+    pub async fn purge_search_index(&self) -> Result<bool> {
+        let error = |e: sqlx::Error| VeterError::Database(format!("Failed to purge search index: {}", e));
+
+        let pool = self.pool().await;
+        let mut tx = pool.begin().await.map_err(error)?;
+        let pending = sqlx::query("DELETE FROM search_index_purge")
+            .execute(&mut *tx)
+            .await
+            .map_err(error)?
+            .rows_affected() > 0;
+        if pending {
+            sqlx::query("INSERT INTO messages_fts (messages_fts) VALUES ('optimize')")
+                .execute(&mut *tx)
+                .await
+                .map_err(error)?;
+        }
+        tx.commit().await.map_err(error)?;
+
+        Ok(pending)
+    }
+
+    /// Store a session
+    pub async fn store_session(&self, session: &Session) -> Result<()> {
+        upsert_session(&*self.pool().await, session).await
+    }
+
+    /// Get the session with a peer device in a room
+    pub async fn get_session(&self, room_id: &RoomId, device_id: &DeviceId) -> Result<Option<Session>> {
+        let row = sqlx::query(
+            r#"
+            SELECT room_id, device_id, session_data, created_at, updated_at
+            FROM sessions WHERE room_id = ? AND device_id = ?
+            "#
+        )
+        .bind(room_id.to_string())
+        .bind(device_id.to_string())
+        .fetch_optional(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get session: {}", e)))?;
+
+        row.as_ref().map(session_from_row).transpose()
+    }
+
+    /// Get all sessions, e.g. to load them into a `CryptoManager`
+    pub async fn get_all_sessions(&self) -> Result<Vec<Session>> {
+        sqlx::query("SELECT room_id, device_id, session_data, created_at, updated_at FROM sessions")
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get sessions: {}", e)))?
+            .iter()
+            .map(session_from_row)
+            .collect()
+    }
+
+    /// Delete the session with a peer device in a room
+    pub async fn delete_session(&self, room_id: &RoomId, device_id: &DeviceId) -> Result<()> {
+        sqlx::query("DELETE FROM sessions WHERE room_id = ? AND device_id = ?")
+            .bind(room_id.to_string())
+            .bind(device_id.to_string())
+            .execute(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to delete session: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Store the sender key of a device in a room
+    pub async fn store_sender_key(&self, sender_key: &SenderKey) -> Result<()> {
+        upsert_sender_key(&*self.pool().await, sender_key).await
+    }
+
+    /// Get the sender keys of all devices in a room
+    pub async fn get_sender_keys(&self, room_id: &RoomId) -> Result<Vec<SenderKey>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT room_id, device_id, state_data, created_at, updated_at
+            FROM sender_keys WHERE room_id = ?
+            "#
+        )
+        .bind(room_id.to_string())
+        .fetch_all(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get sender keys: {}", e)))?;
+
+        rows.iter().map(sender_key_from_row).collect()
+    }
+
+    /// Get the sender keys of all devices in all rooms
+    pub async fn get_all_sender_keys(&self) -> Result<Vec<SenderKey>> {
+        sqlx::query("SELECT room_id, device_id, state_data, created_at, updated_at FROM sender_keys")
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get sender keys: {}", e)))?
+            .iter()
+            .map(sender_key_from_row)
+            .collect()
+    }
+
+    /// Delete the sender key of a device in a room
+    pub async fn delete_sender_key(&self, room_id: &RoomId, device_id: &DeviceId) -> Result<()> {
+        sqlx::query("DELETE FROM sender_keys WHERE room_id = ? AND device_id = ?")
+            .bind(room_id.to_string())
+            .bind(device_id.to_string())
+            .execute(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to delete sender key: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Store the MLS state of this device, as exported by `CryptoManager::mls_state`
+    pub async fn store_mls_state(&self, device_id: &DeviceId, state_data: &[u8]) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT OR REPLACE INTO mls_state (device_id, state_data, updated_at)
+            VALUES (?, ?, ?)
+            "#
+        )
+        .bind(device_id.to_string())
+        .bind(state_data)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to store MLS state: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Get the MLS state of this device
+    pub async fn get_mls_state(&self, device_id: &DeviceId) -> Result<Option<Vec<u8>>> {
+        let row = sqlx::query("SELECT state_data FROM mls_state WHERE device_id = ?")
+            .bind(device_id.to_string())
+            .fetch_optional(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get MLS state: {}", e)))?;
+
+        Ok(row.map(|row| row.get("state_data")))
+    }
+
+    /// Store the MLS group of a room, as exported by `CryptoManager::mls_group_state`
+    pub async fn store_mls_group(&self, room_id: &RoomId, state_data: &[u8]) -> Result<()> {
+        upsert_mls_group(&*self.pool().await, room_id, state_data).await
+    }
+
+    /// Get the MLS groups of this device with their room ids
+    pub async fn get_mls_groups(&self) -> Result<Vec<(RoomId, Vec<u8>)>> {
+        let rows = sqlx::query("SELECT room_id, state_data FROM mls_groups")
+            .fetch_all(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get MLS groups: {}", e)))?;
+
+        rows.iter()
+            .map(|row| {
+                let room_id = Uuid::parse_str(&row.get::<String, _>("room_id"))
+                    .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?;
+                Ok((room_id, row.get("state_data")))
+            })
+            .collect()
+    }
+
+    /// Store the prekey private keys of this device, as exported by
+    /// `CryptoManager::prekey_state`
+    pub async fn store_prekey_state(&self, device_id: &DeviceId, state_data: &[u8]) -> Result<()> {
+        upsert_prekey_state(&*self.pool().await, device_id, state_data).await
+    }
+
+    /// Get the prekey private keys of this device
+    pub async fn get_prekey_state(&self, device_id: &DeviceId) -> Result<Option<Zeroizing<Vec<u8>>>> {
+        let row = sqlx::query("SELECT state_data FROM prekeys WHERE device_id = ?")
+            .bind(device_id.to_string())
+            .fetch_optional(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get prekeys: {}", e)))?;
+
+        Ok(row.map(|row| Zeroizing::new(row.get("state_data"))))
+    }
+
+    /// Put an encrypted message in the outbox, to be sent as soon as
+    /// possible. Queueing a message that is already in the outbox does
+    /// nothing.
+    pub async fn queue_outgoing(&self, message: &EncryptedMessage) -> Result<()> {
+        let recipients = serde_json::to_string(&message.recipient_device_ids)?;
+
+        sqlx::query(
+            r#"
+            INSERT OR IGNORE INTO outbox (message_id, room_id, sender_device_id, payload, recipient_device_ids, created_at, next_attempt_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            "#
+        )
+        .bind(message.id.to_string())
+        .bind(message.room_id.to_string())
+        .bind(message.sender_device_id.to_string())
+        .bind(&message.payload)
+        .bind(&recipients)
+        .bind(timestamp(message.timestamp))
+        .bind(timestamp(chrono::Utc::now()))
+        .execute(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to queue message: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Get a message from the outbox
+    pub async fn get_outgoing(&self, message_id: &MessageId) -> Result<Option<OutgoingMessage>> {
+        let row = sqlx::query(
+            r#"
+            SELECT message_id, room_id, sender_device_id, payload, recipient_device_ids, created_at,
+                state, attempts, next_attempt_at, last_error
+            FROM outbox WHERE message_id = ?
+            "#
+        )
+        .bind(message_id.to_string())
+        .fetch_optional(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get outgoing message: {}", e)))?;
+
+        row.map(|row| outgoing_from_row(&row)).transpose()
+    }
+
+    /// Pending messages whose next attempt is due at `now`, oldest first
+    pub async fn get_due_outgoing(&self, now: chrono::DateTime<chrono::Utc>, limit: u32) -> Result<Vec<OutgoingMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT message_id, room_id, sender_device_id, payload, recipient_device_ids, created_at,
+                state, attempts, next_attempt_at, last_error
+            FROM outbox WHERE state = 'Pending' AND next_attempt_at <= ?
+            ORDER BY created_at, message_id
+            LIMIT ?
+            "#
+        )
+        .bind(timestamp(now))
+        .bind(limit as i64)
+        .fetch_all(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get due messages: {}", e)))?;
+
+        rows.iter().map(outgoing_from_row).collect()
+    }
+
+    /// When the next pending message is due, if there is one
+    pub async fn next_outgoing_attempt(&self) -> Result<Option<chrono::DateTime<chrono::Utc>>> {
+        let row = sqlx::query("SELECT MIN(next_attempt_at) AS next_attempt_at FROM outbox WHERE state = 'Pending'")
+            .fetch_one(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to get next attempt: {}", e)))?;
+
+        row.get::<Option<String>, _>("next_attempt_at")
+            .map(|s| parse_timestamp(&s))
+            .transpose()
+    }
+
+    /// Record that the relay accepted pending messages
+    pub async fn mark_sent(&self, message_ids: &[MessageId]) -> Result<()> {
+        for message_id in message_ids {
+            sqlx::query("UPDATE outbox SET state = 'Sent', last_error = NULL WHERE message_id = ? AND state = 'Pending'")
+                .bind(message_id.to_string())
+                .execute(&*self.pool().await)
+                .await
+                .map_err(|e| VeterError::Database(format!("Failed to mark message sent: {}", e)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Record that a recipient device received a message. Returns whether
+    /// its state changed.
+    pub async fn mark_delivered(&self, message_id: &MessageId) -> Result<bool> {
+        let result = sqlx::query("UPDATE outbox SET state = 'Delivered', last_error = NULL WHERE message_id = ? AND state != 'Delivered'")
+            .bind(message_id.to_string())
+            .execute(&*self.pool().await)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to mark message delivered: {}", e)))?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Record that a user received the messages this device sent to a room
+    /// up to and including the given one, as a receipt says. Returns the
+    /// messages whose state changed, none if the given one is not stored in
+    /// the room.
+    pub async fn mark_delivered_up_to(&self, room_id: &RoomId, message_id: &MessageId) -> Result<Vec<MessageId>> {
+        let rows = sqlx::query(
+            r#"
+            UPDATE outbox SET state = 'Delivered', last_error = NULL
+            WHERE room_id = ?1 AND state = 'Sent'
+              AND (created_at, message_id) <= (SELECT created_at, id FROM messages WHERE id = ?2 AND room_id = ?1)
+            RETURNING message_id
+            "#
+        )
+        .bind(room_id.to_string())
+        .bind(message_id.to_string())
+        .fetch_all(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to mark messages delivered: {}", e)))?;
+
+        rows.iter()
+            .map(|row| Uuid::parse_str(&row.get::<String, _>("message_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e))))
+            .collect()
+    }
+
+    /// Record a failed attempt to send a pending message. It is tried again
+    /// at `next_attempt_at`, or marked failed if that is `None`.
+    pub async fn record_send_failure(&self, message_id: &MessageId, error: &str, next_attempt_at: Option<chrono::DateTime<chrono::Utc>>) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE outbox SET
+                attempts = attempts + 1,
+                last_error = ?,
+                state = CASE WHEN ? IS NULL THEN 'Failed' ELSE state END,
+                next_attempt_at = COALESCE(?, next_attempt_at)
+            WHERE message_id = ? AND state = 'Pending'
+            "#
+        )
+        .bind(error)
+        .bind(next_attempt_at.map(timestamp))
+        .bind(next_attempt_at.map(timestamp))
+        .bind(message_id.to_string())
+        .execute(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to record send failure: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Make a failed message pending again, to be sent right away with a
+    /// fresh count of attempts
+    pub async fn retry_outgoing(&self, message_id: &MessageId) -> Result<()> {
+        let result = sqlx::query(
+            "UPDATE outbox SET state = 'Pending', attempts = 0, next_attempt_at = ? WHERE message_id = ? AND state = 'Failed'"
+        )
+        .bind(timestamp(chrono::Utc::now()))
+        .bind(message_id.to_string())
+        .execute(&*self.pool().await)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to retry message: {}", e)))?;
+
+        if result.rows_affected() == 0 {
+            return Err(VeterError::InvalidInput(format!("No failed message {}", message_id)));
+        }
+        Ok(())
+    }
+}
+
+/// Insert a message and, for a room event, its op, counting it as unread,
+/// or record the edit, redaction, reaction or receipt it carries. Edits and
+/// redactions are then applied to their target if it is stored.
+async fn insert_message(connection: &mut sqlx::SqliteConnection, message: &Message) -> Result<()> {
+    check_change(&mut *connection, message).await?;
+    let error = |e: sqlx::Error| VeterError::Database(format!("Failed to store message: {}", e));
+
+    let target_id = match &message.content {
+        MessageContent::Reaction { emoji, target_message_id } => {
+            return upsert_reaction(connection, message, target_message_id, emoji, true).await;
+        }
+        MessageContent::RemoveReaction { emoji, target_message_id } => {
+            return upsert_reaction(connection, message, target_message_id, emoji, false).await;
+        }
+        MessageContent::Receipt { receipt_type, message_id } => {
+            return upsert_receipt(connection, message, *receipt_type, message_id).await;
+        }
+        MessageContent::Edit { target_message_id, content } => {
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO message_versions (id, message_id, sender_id, sender_device_id, content, created_at)
+                VALUES (?, ?, ?, ?, ?, ?)
+                "#
+            )
+            .bind(message.id.to_string())
+            .bind(target_message_id.to_string())
+            .bind(message.sender_id.to_string())
+            .bind(message.sender_device_id.to_string())
+            .bind(serde_json::to_string(content)?)
+            .bind(timestamp(message.created_at))
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+            target_message_id
+        }
+        MessageContent::Redact { target_message_id } => {
+            sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO redactions (message_id, id, sender_id, sender_device_id, redacted_at)
+                VALUES (?, ?, ?, ?, ?)
+                "#
+            )
+            .bind(target_message_id.to_string())
+            .bind(message.id.to_string())
+            .bind(message.sender_id.to_string())
+            .bind(message.sender_device_id.to_string())
+            .bind(timestamp(message.created_at))
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+            target_message_id
+        }
+        _ => {
+            sqlx::query(
+                r#"
+                INSERT INTO messages (id, room_id, sender_id, sender_device_id, content, search_text, created_at, edited_at, reply_to)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+                "#
+            )
+            .bind(message.id.to_string())
+            .bind(message.room_id.to_string())
+            .bind(message.sender_id.to_string())
+            .bind(message.sender_device_id.to_string())
+            .bind(serde_json::to_string(&message.content)?)
+            .bind(search_text(&message.content))
+            .bind(timestamp(message.created_at))
+            .bind(message.edited_at.map(timestamp))
+            .bind(message.reply_to.map(|id| id.to_string()))
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+
+            if let MessageContent::RoomEvent(op) = &message.content {
+                insert_room_op(&mut *connection, op).await?;
+            }
+            count_message(&mut *connection, &message.id).await?;
+            &message.id
+        }
+    };
+
+    apply_changes(&mut *connection, target_id).await?;
+    if target_id != &message.id {
+        // An edit may add or drop a mention, a redaction an unread message
+        if let Some(target) = get_stored_message(&mut *connection, target_id).await? {
+            recount_room(connection, &target.room_id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Record that the sender of `event` reacted to a message with an emoji, or
+/// withdrew the reaction, unless a later event of theirs did already
+async fn upsert_reaction(connection: &mut sqlx::SqliteConnection, event: &Message, message_id: &MessageId, emoji: &str, active: bool) -> Result<()> {
+    if emoji.is_empty() {
+        return Err(VeterError::InvalidInput(format!("Reaction {} has no emoji", event.id)));
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO reactions (message_id, user_id, emoji, active, event_id, updated_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        ON CONFLICT (message_id, user_id, emoji) DO UPDATE
+        SET active = excluded.active, event_id = excluded.event_id, updated_at = excluded.updated_at
+        WHERE (excluded.updated_at, excluded.event_id) > (reactions.updated_at, reactions.event_id)
+        "#
+    )
+    .bind(message_id.to_string())
+    .bind(event.sender_id.to_string())
+    .bind(emoji)
+    .bind(active)
+    .bind(event.id.to_string())
+    .bind(timestamp(event.created_at))
+    .execute(connection)
+    .await
+    .map_err(|e| VeterError::Database(format!("Failed to store reaction: {}", e)))?;
+
+    Ok(())
+}
+
+/// Move the sender's receipt in the room forward to a stored message of the
+/// room, ordered by its creation time here rather than one the sender could
+/// claim. A read receipt of the local user, e.g. from another of their
+/// devices, is their read marker and recounts the room's unread messages.
+async fn upsert_receipt(connection: &mut sqlx::SqliteConnection, event: &Message, receipt_type: ReceiptType, message_id: &MessageId) -> Result<()> {
+    let receipt_types = match receipt_type {
+        ReceiptType::Delivered => vec![ReceiptType::Delivered],
+        ReceiptType::Read => vec![ReceiptType::Delivered, ReceiptType::Read],
+    };
+    for receipt_type in receipt_types {
+        sqlx::query(
+            r#"
+            INSERT INTO receipts (room_id, user_id, receipt_type, message_id, message_created_at)
+            SELECT room_id, ?, ?, id, created_at FROM messages WHERE id = ? AND room_id = ?
+            ON CONFLICT (room_id, user_id, receipt_type) DO UPDATE
+            SET message_id = excluded.message_id, message_created_at = excluded.message_created_at
+            WHERE (excluded.message_created_at, excluded.message_id) > (receipts.message_created_at, receipts.message_id)
+            "#
+        )
+        .bind(event.sender_id.to_string())
+        .bind(enum_to_text(&receipt_type)?)
+        .bind(message_id.to_string())
+        .bind(event.room_id.to_string())
+        .execute(&mut *connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to store receipt: {}", e)))?;
+    }
+
+    if receipt_type == ReceiptType::Read && local_user(&mut *connection).await? == Some(event.sender_id) {
+        recount_room(connection, &event.room_id).await?;
+    }
+    Ok(())
+}
+
+async fn local_user(connection: &mut sqlx::SqliteConnection) -> Result<Option<UserId>> {
+    let row = sqlx::query("SELECT user_id FROM local_user")
+        .fetch_optional(connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get local user: {}", e)))?;
+
+    row.map(|row| Uuid::parse_str(&row.get::<String, _>("user_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid user ID: {}", e))))
+        .transpose()
+}
+
+/// Add a new message to its room's activity and unread counts
+async fn count_message(connection: &mut sqlx::SqliteConnection, message_id: &MessageId) -> Result<()> {
+    let sql = format!(
+        r#"
+        INSERT INTO room_activity (room_id, unread_count, mention_count, last_activity_at)
+        SELECT m.room_id, IFNULL({unread}, 0), IFNULL({unread} AND {mention}, 0), m.created_at
+        FROM messages m {joins}
+        WHERE m.id = ?
+        ON CONFLICT (room_id) DO UPDATE SET
+            unread_count = unread_count + excluded.unread_count,
+            mention_count = mention_count + excluded.mention_count,
+            last_activity_at = MAX(IFNULL(last_activity_at, ''), excluded.last_activity_at)
+        "#,
+        unread = UNREAD,
+        mention = MENTION,
+        joins = UNREAD_JOINS,
+    );
+
+    sqlx::query(&sql)
+        .bind(message_id.to_string())
+        .execute(connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to count message: {}", e)))?;
+
+    Ok(())
+}
+
+/// Count a room's unread messages again, after the read marker moved. Only
+/// the messages after the marker are read, through `messages_room_created`.
+async fn recount_room(connection: &mut sqlx::SqliteConnection, room_id: &RoomId) -> Result<()> {
+    let sql = format!(
+        r#"
+        INSERT INTO room_activity (room_id, unread_count, mention_count, last_activity_at)
+        SELECT ?, IFNULL(SUM({unread}), 0), IFNULL(SUM({unread} AND {mention}), 0),
+               (SELECT MAX(created_at) FROM messages WHERE room_id = ?)
+        FROM messages m {joins}
+        WHERE m.room_id = ? AND (r.message_id IS NULL OR (m.created_at, m.id) > (r.message_created_at, r.message_id))
+        ON CONFLICT (room_id) DO UPDATE SET
+            unread_count = excluded.unread_count,
+            mention_count = excluded.mention_count,
+            last_activity_at = excluded.last_activity_at
+        "#,
+        unread = UNREAD,
+        mention = MENTION,
+        joins = UNREAD_JOINS,
+    );
+
+    sqlx::query(&sql)
+        .bind(room_id.to_string())
+        .bind(room_id.to_string())
+        .bind(room_id.to_string())
+        .execute(connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to count unread messages: {}", e)))?;
+
+    Ok(())
+}
+
+/// Refuse an edit or redaction its stored target does not allow, or a
+/// receipt for a message not stored in its room, see
+/// [`StorageManager::authorize_change`]
+async fn check_change(connection: &mut sqlx::SqliteConnection, message: &Message) -> Result<()> {
+    let target_id = match &message.content {
+        MessageContent::Edit { target_message_id, content } => {
+            if !content.is_editable() {
+                return Err(VeterError::InvalidInput(format!("Edit {} has content that cannot be edited", message.id)));
+            }
+            target_message_id
+        }
+        MessageContent::Redact { target_message_id } => target_message_id,
+        MessageContent::Receipt { message_id, .. } => {
+            let stored = get_stored_message(connection, message_id).await?;
+            if stored.is_none_or(|target| target.room_id != message.room_id) {
+                return Err(VeterError::InvalidInput(format!("Receipt {} is for message {} not stored in its room", message.id, message_id)));
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    };
+
+    let Some(target) = get_stored_message(connection, target_id).await? else {
+        return Ok(());
+    };
+    if target.sender_id != message.sender_id {
+        return Err(VeterError::Authentication(format!("Message {} was not sent by the sender of {}", target.id, message.id)));
+    }
+    if target.room_id != message.room_id || !(target.content.is_editable() || matches!(target.content, MessageContent::Redacted)) {
+        return Err(VeterError::InvalidInput(format!("Message {} cannot be changed by {}", target.id, message.id)));
+    }
+    Ok(())
+}
+
+/// Bring a stored message up to date with its recorded redaction or latest
+/// edit. Changes by anyone but its sender are dropped.
+async fn apply_changes(connection: &mut sqlx::SqliteConnection, message_id: &MessageId) -> Result<()> {
+    let error = |e: sqlx::Error| VeterError::Database(format!("Failed to apply message changes: {}", e));
+    let Some(message) = get_stored_message(&mut *connection, message_id).await? else {
+        return Ok(());
+    };
+    let changeable = message.content.is_editable() || matches!(message.content, MessageContent::Redacted);
+
+    for statement in [
+        "DELETE FROM redactions WHERE message_id = ? AND (sender_id != ? OR ?)",
+        "DELETE FROM message_versions WHERE message_id = ? AND (sender_id != ? OR ?)",
+    ] {
+        sqlx::query(statement)
+            .bind(message_id.to_string())
+            .bind(message.sender_id.to_string())
+            .bind(!changeable)
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+    }
+
+    let redacted = sqlx::query("SELECT 1 FROM redactions WHERE message_id = ?")
+        .bind(message_id.to_string())
+        .fetch_optional(&mut *connection)
+        .await
+        .map_err(error)?
+        .is_some();
+    if redacted {
+        // With secure_delete on, the old content is overwritten on disk.
+        // Its words stay in the index segments until they are merged, see
+        // `StorageManager::purge_search_index`.
+        sqlx::query("UPDATE messages SET content = ?, search_text = '' WHERE id = ?")
+            .bind(serde_json::to_string(&MessageContent::Redacted)?)
+            .bind(message_id.to_string())
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+        sqlx::query("DELETE FROM message_versions WHERE message_id = ?")
+            .bind(message_id.to_string())
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+        sqlx::query("INSERT OR IGNORE INTO search_index_purge (id) VALUES (0)")
+            .execute(&mut *connection)
+            .await
+            .map_err(error)?;
+        return Ok(());
+    }
+
+    let latest = sqlx::query(
+        r#"
+        SELECT id, sender_device_id, content, created_at FROM message_versions
+        WHERE message_id = ? AND id != message_id
+        ORDER BY created_at DESC, id DESC
+        LIMIT 1
+        "#
+    )
+    .bind(message_id.to_string())
+    .fetch_optional(&mut *connection)
+    .await
+    .map_err(error)?;
+    let Some(latest) = latest.as_ref().map(version_from_row).transpose()? else {
+        return Ok(());
+    };
+
+    // The original is kept as the first version when the message is first
+    // edited, before its content is replaced
+    sqlx::query(
+        r#"
+        INSERT OR IGNORE INTO message_versions (id, message_id, sender_id, sender_device_id, content, created_at)
+        VALUES (?, ?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(message.id.to_string())
+    .bind(message.id.to_string())
+    .bind(message.sender_id.to_string())
+    .bind(message.sender_device_id.to_string())
+    .bind(serde_json::to_string(&message.content)?)
+    .bind(timestamp(message.created_at))
+    .execute(&mut *connection)
+    .await
+    .map_err(error)?;
+
+    sqlx::query("UPDATE messages SET content = ?, search_text = ?, edited_at = ? WHERE id = ?")
+        .bind(serde_json::to_string(&latest.content)?)
+        .bind(search_text(&latest.content))
+        .bind(timestamp(latest.created_at))
+        .bind(message_id.to_string())
+        .execute(&mut *connection)
+        .await
+        .map_err(error)?;
+    Ok(())
+}
+
+async fn get_stored_message(connection: &mut sqlx::SqliteConnection, message_id: &MessageId) -> Result<Option<Message>> {
+    let row = sqlx::query(
+        r#"
+        SELECT id, room_id, sender_id, sender_device_id, content, created_at, edited_at, reply_to
+        FROM messages WHERE id = ?
+        "#
+    )
+    .bind(message_id.to_string())
+    .fetch_optional(connection)
+    .await
+    .map_err(|e| VeterError::Database(format!("Failed to get message: {}", e)))?;
+
+    row.as_ref().map(message_from_row).transpose()
+}
+
+/// Store a room op and apply it to the stored state of its room
+async fn insert_room_op(connection: &mut sqlx::SqliteConnection, op: &RoomOp) -> Result<()> {
+    let error = |e: sqlx::Error| VeterError::Database(format!("Failed to store room op: {}", e));
+    let result = sqlx::query("INSERT OR IGNORE INTO room_ops (id, room_id, device_id, op, stored_at) VALUES (?, ?, ?, ?, ?)")
+        .bind(op.id.to_string())
+        .bind(op.room_id.to_string())
+        .bind(op.device_id.to_string())
+        .bind(serde_json::to_string(op)?)
+        .bind(chrono::Utc::now().to_rfc3339())
+        .execute(&mut *connection)
+        .await
+        .map_err(error)?;
+    if result.rows_affected() == 0 {
+        return Ok(());
+    }
+
+    let mut state = room_state(&mut *connection, &op.room_id).await?;
+    state.apply(op.clone())?;
+    sqlx::query("INSERT INTO room_states (room_id, state) VALUES (?, ?) ON CONFLICT (room_id) DO UPDATE SET state = excluded.state")
+        .bind(op.room_id.to_string())
+        .bind(serde_json::to_string(&state)?)
+        .execute(connection)
+        .await
+        .map_err(error)?;
+
+    Ok(())
+}
+
+async fn room_state<'e>(executor: impl sqlx::SqliteExecutor<'e>, room_id: &RoomId) -> Result<RoomState> {
+    let row = sqlx::query("SELECT state FROM room_states WHERE room_id = ?")
+        .bind(room_id.to_string())
+        .fetch_optional(executor)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to get room state: {}", e)))?;
+
+    match row {
+        Some(row) => Ok(serde_json::from_str(&row.get::<String, _>("state"))?),
+        None => Ok(RoomState::new(*room_id)),
+    }
+}
+
+async fn upsert_session<'e>(executor: impl sqlx::SqliteExecutor<'e>, session: &Session) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO sessions (room_id, device_id, session_data, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(session.room_id.to_string())
+    .bind(session.device_id.to_string())
+    .bind(&session.session_data)
+    .bind(session.created_at.to_rfc3339())
+    .bind(session.updated_at.to_rfc3339())
+    .execute(executor)
+    .await
+    .map_err(|e| VeterError::Database(format!("Failed to store session: {}", e)))?;
+
+    Ok(())
+}
+
+async fn upsert_sender_key<'e>(executor: impl sqlx::SqliteExecutor<'e>, sender_key: &SenderKey) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO sender_keys (room_id, device_id, state_data, created_at, updated_at)
+        VALUES (?, ?, ?, ?, ?)
+        "#
+    )
+    .bind(sender_key.room_id.to_string())
+    .bind(sender_key.device_id.to_string())
+    .bind(&sender_key.state_data)
+    .bind(sender_key.created_at.to_rfc3339())
+    .bind(sender_key.updated_at.to_rfc3339())
+    .execute(executor)
+    .await
+    .map_err(|e| VeterError::Database(format!("Failed to store sender key: {}", e)))?;
+
+    Ok(())
+}
+
+async fn upsert_mls_group<'e>(executor: impl sqlx::SqliteExecutor<'e>, room_id: &RoomId, state_data: &[u8]) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO mls_groups (room_id, state_data, updated_at)
+        VALUES (?, ?, ?)
+        "#
+    )
+    .bind(room_id.to_string())
+    .bind(state_data)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .execute(executor)
+    .await
+    .map_err(|e| VeterError::Database(format!("Failed to store MLS group: {}", e)))?;
+
+    Ok(())
+}
+
+async fn upsert_prekey_state<'e>(executor: impl sqlx::SqliteExecutor<'e>, device_id: &DeviceId, state_data: &[u8]) -> Result<()> {
+    sqlx::query(
+        r#"
+        INSERT OR REPLACE INTO prekeys (device_id, state_data, updated_at)
+        VALUES (?, ?, ?)
+        "#
+    )
+    .bind(device_id.to_string())
+    .bind(state_data)
+    .bind(chrono::Utc::now().to_rfc3339())
+    .execute(executor)
+    .await
+    .map_err(|e| VeterError::Database(format!("Failed to store prekeys: {}", e)))?;
+
+    Ok(())
+}
+
+/// Room from a row of `ROOM_COLUMNS`, brought up to its replicated state
+fn room_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Room> {
+    let mut room = Room {
+        id: Uuid::parse_str(&row.get::<String, _>("id"))
+            .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
+        name: row.get("name"),
+        description: row.get("description"),
+        room_type: enum_from_text(&row.get::<String, _>("room_type"))?,
+        encryption: enum_from_text(&row.get::<String, _>("encryption"))?,
+        members: serde_json::from_str(&row.get::<String, _>("members"))
+            .map_err(|e| VeterError::Database(format!("Invalid room members: {}", e)))?,
+        created_at: parse_timestamp(&row.get::<String, _>("created_at"))?,
+        updated_at: parse_timestamp(&row.get::<String, _>("updated_at"))?,
+    };
+    if let Some(state) = row.get::<Option<String>, _>("state") {
+        serde_json::from_str::<RoomState>(&state)?.apply_to(&mut room);
+    }
+    Ok(room)
+}
+
+fn device_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Device> {
+    Ok(Device {
+        id: Uuid::parse_str(&row.get::<String, _>("id"))
+            .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+        user_id: Uuid::parse_str(&row.get::<String, _>("user_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid user ID: {}", e)))?,
+        name: row.get("name"),
+        platform: enum_from_text(&row.get::<String, _>("platform"))?,
+        public_key: row.get("public_key"),
+        created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at"))
+            .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
+            .with_timezone(&chrono::Utc),
+        last_seen: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("last_seen"))
+            .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
+            .with_timezone(&chrono::Utc),
+        verification: enum_from_text(&row.get::<String, _>("verification"))?,
+    })
+}
+
+/// Variant name of a unit enum, as stored in TEXT columns
+fn enum_to_text<T: serde::Serialize>(value: &T) -> Result<String> {
+    match serde_json::to_value(value)? {
+        serde_json::Value::String(text) => Ok(text),
+        other => Err(VeterError::Serialization(format!("Expected a unit variant, got {}", other))),
+    }
+}
+
+fn enum_from_text<T: serde::de::DeserializeOwned>(text: &str) -> Result<T> {
+    serde_json::from_value(serde_json::Value::String(text.to_string()))
+        .map_err(|e| VeterError::Database(format!("Invalid value '{}': {}", text, e)))
+}
+
+/// SQLCipher key derived from the database password
+struct DatabaseKey {
+    key: Zeroizing<[u8; 32]>,
+    salt: [u8; SALT_LENGTH],
+}
+
+impl DatabaseKey {
+    /// Derive a key with a fresh random salt
+    fn generate(password: &str) -> Result<Self> {
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(password, salt)
+    }
+
+    /// Derive the key for a salt with Argon2id
+    fn derive(password: &str, salt: [u8; SALT_LENGTH]) -> Result<Self> {
+        let mut key = Zeroizing::new([0u8; 32]);
+        Argon2::default()
+            .hash_password_into(password.as_bytes(), &salt, key.as_mut())
+            .map_err(|e| VeterError::Crypto(format!("Key derivation failed: {}", e)))?;
+
+        Ok(Self { key, salt })
+    }
+
+    fn matches(&self, other: &DatabaseKey) -> bool {
+        self.key.iter().zip(other.key.iter()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    /// Raw key and salt in the form SQLCipher takes for `PRAGMA key`, so it
+    /// skips its own key derivation and writes our salt into the header
+    fn pragma_value(&self) -> String {
+        format!("\"x'{}{}'\"", hex::encode(self.key.as_ref()), hex::encode(self.salt))
+    }
+}
+
+/// First bytes of an existing database file: the salt, or the plaintext header
+fn read_header(db_path: &Path) -> Result<Option<[u8; SALT_LENGTH]>> {
+    let mut file = match std::fs::File::open(db_path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(VeterError::Storage(format!("Failed to open database file: {}", e))),
+    };
+
+    let mut header = [0u8; SALT_LENGTH];
+    match file.read_exact(&mut header) {
+        Ok(()) => Ok(Some(header)),
+        // An empty file has no database yet
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(VeterError::Storage(format!("Failed to read database file: {}", e))),
+    }
+}
+
+async fn connect(db_path: &Path, key: &DatabaseKey) -> Result<SqlitePool> {
+    let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path.display()))
+        .map_err(|e| VeterError::Database(format!("Invalid database path: {}", e)))?
+        .create_if_missing(true)
+        .pragma("key", key.pragma_value())
+        // Overwrite deleted content, e.g. of redacted messages
+        .pragma("secure_delete", "ON");
+
+    let pool = SqlitePool::connect_with(options)
+        .await
+        .map_err(|e| key_error(e, "Failed to connect to database"))?;
+
+    // The key is only checked once a page is read
+    sqlx::query("SELECT count(*) FROM sqlite_master")
+        .execute(&pool)
+        .await
+        .map_err(|e| key_error(e, "Failed to read database"))?;
+
+    Ok(pool)
+}
+
+fn key_error(e: sqlx::Error, context: &str) -> VeterError {
+    match e.as_database_error().and_then(|e| e.code()) {
+        // SQLITE_NOTADB: the key does not decrypt the database
+        Some(code) if code == "26" => VeterError::Authentication("Wrong database password".to_string()),
+        _ => VeterError::Database(format!("{}: {}", context, e)),
+    }
+}
+
+/// Encrypt an unencrypted database with `sqlcipher_export`, replacing the file
+async fn encrypt_plaintext_database(db_path: &Path, key: &DatabaseKey) -> Result<()> {
+    let encrypted_path = db_path.with_extension("encrypting");
+    let _ = std::fs::remove_file(&encrypted_path);
+
+    // Attached databases are opened with the main database's flags, so
+    // creation has to be allowed for the encrypted copy
+    let options = SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path.display()))
+        .map_err(|e| VeterError::Database(format!("Invalid database path: {}", e)))?
+        .create_if_missing(true);
+    let mut connection = sqlx::SqliteConnection::connect_with(&options)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to open unencrypted database: {}", e)))?;
+
+    sqlx::query(&format!("ATTACH DATABASE ? AS encrypted KEY {}", key.pragma_value()))
+        .bind(encrypted_path.display().to_string())
+        .execute(&mut connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to create encrypted database: {}", e)))?;
+    sqlx::query("SELECT sqlcipher_export('encrypted')")
+        .execute(&mut connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to encrypt database: {}", e)))?;
+    sqlx::query("DETACH DATABASE encrypted")
+        .execute(&mut connection)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to encrypt database: {}", e)))?;
+    connection.close()
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to close database: {}", e)))?;
+
+    std::fs::rename(&encrypted_path, db_path)
+        .map_err(|e| VeterError::Storage(format!("Failed to replace unencrypted database: {}", e)))
+}
+
+fn message_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Message> {
+    let content: String = row.get("content");
+
+    Ok(Message {
+        id: Uuid::parse_str(&row.get::<String, _>("id"))
+            .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?,
+        room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
+        sender_id: Uuid::parse_str(&row.get::<String, _>("sender_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid sender ID: {}", e)))?,
+        sender_device_id: Uuid::parse_str(&row.get::<String, _>("sender_device_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+        content: serde_json::from_str(&content)
+            .map_err(|e| VeterError::Serialization(format!("Failed to deserialize message content: {}", e)))?,
+        created_at: chrono::DateTime::parse_from_rfc3339(&row.get::<String, _>("created_at"))
+            .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))?
+            .with_timezone(&chrono::Utc),
+        edited_at: row.get::<Option<String>, _>("edited_at")
+            .map(|s| chrono::DateTime::parse_from_rfc3339(&s)
+                .map(|t| t.with_timezone(&chrono::Utc))
+                .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e))))
+            .transpose()?,
+        reply_to: row.get::<Option<String>, _>("reply_to")
+            .map(|s| Uuid::parse_str(&s)
+                .map_err(|e| VeterError::Database(format!("Invalid reply ID: {}", e))))
+            .transpose()?,
+    })
+}
+
+fn version_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<MessageVersion> {
+    Ok(MessageVersion {
+        id: Uuid::parse_str(&row.get::<String, _>("id"))
+            .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?,
+        sender_device_id: Uuid::parse_str(&row.get::<String, _>("sender_device_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+        content: serde_json::from_str(&row.get::<String, _>("content"))
+            .map_err(|e| VeterError::Serialization(format!("Failed to deserialize message content: {}", e)))?,
+        created_at: parse_timestamp(&row.get::<String, _>("created_at"))?,
+    })
+}
+
+fn session_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<Session> {
+    Ok(Session {
+        room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
+        device_id: Uuid::parse_str(&row.get::<String, _>("device_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+        session_data: row.get("session_data"),
+        created_at: parse_timestamp(&row.get::<String, _>("created_at"))?,
+        updated_at: parse_timestamp(&row.get::<String, _>("updated_at"))?,
+    })
+}
+
+fn sender_key_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<SenderKey> {
+    Ok(SenderKey {
+        room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
+        device_id: Uuid::parse_str(&row.get::<String, _>("device_id"))
+            .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+        state_data: row.get("state_data"),
+        created_at: parse_timestamp(&row.get::<String, _>("created_at"))?,
+        updated_at: parse_timestamp(&row.get::<String, _>("updated_at"))?,
+    })
+}
+
+fn outgoing_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<OutgoingMessage> {
+    let recipients: String = row.get("recipient_device_ids");
+
+    Ok(OutgoingMessage {
+        message: EncryptedMessage {
+            id: Uuid::parse_str(&row.get::<String, _>("message_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?,
+            room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
+            sender_device_id: Uuid::parse_str(&row.get::<String, _>("sender_device_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+            payload: row.get("payload"),
+            timestamp: parse_timestamp(&row.get::<String, _>("created_at"))?,
+            recipient_device_ids: serde_json::from_str(&recipients)
+                .map_err(|e| VeterError::Database(format!("Invalid recipients: {}", e)))?,
+        },
+        state: enum_from_text(&row.get::<String, _>("state"))?,
+        attempts: row.get::<i64, _>("attempts") as u32,
+        next_attempt_at: parse_timestamp(&row.get::<String, _>("next_attempt_at"))?,
+        last_error: row.get("last_error"),
+    })
+}
+
+fn quarantined_from_row(row: &sqlx::sqlite::SqliteRow) -> Result<QuarantinedMessage> {
+    Ok(QuarantinedMessage {
+        message: EncryptedMessage {
+            id: Uuid::parse_str(&row.get::<String, _>("message_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid message ID: {}", e)))?,
+            room_id: Uuid::parse_str(&row.get::<String, _>("room_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid room ID: {}", e)))?,
+            sender_device_id: Uuid::parse_str(&row.get::<String, _>("sender_device_id"))
+                .map_err(|e| VeterError::Database(format!("Invalid device ID: {}", e)))?,
+            payload: row.get("payload"),
+            timestamp: parse_timestamp(&row.get::<String, _>("created_at"))?,
+            recipient_device_ids: Vec::new(),
+        },
+        error: row.get("error"),
+        attempts: row.get::<i64, _>("attempts") as u32,
+        quarantined_at: parse_timestamp(&row.get::<String, _>("quarantined_at"))?,
+        last_attempt_at: parse_timestamp(&row.get::<String, _>("last_attempt_at"))?,
+    })
+}
+
+fn parse_timestamp(text: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    chrono::DateTime::parse_from_rfc3339(text)
+        .map(|time| time.with_timezone(&chrono::Utc))
+        .map_err(|e| VeterError::Database(format!("Invalid timestamp: {}", e)))
+}
+
+/// Message timestamp with a fixed number of fractional digits, so that
+/// timestamps order correctly as text
+fn timestamp(time: chrono::DateTime<chrono::Utc>) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+}
+
+/// Position of a message in its room's history
+#[derive(Clone)]
+struct Position {
+    created_at: String,
+    id: String,
+}
+
+impl Position {
+    fn of(message: &Message) -> Self {
+        Self {
+            created_at: timestamp(message.created_at),
+            id: message.id.to_string(),
+        }
+    }
+
+    fn encode(&self) -> MessageCursor {
+        MessageCursor(URL_SAFE_NO_PAD.encode(format!("{}|{}", self.created_at, self.id)))
+    }
+
+    fn decode(cursor: &MessageCursor) -> Result<Self> {
+        let invalid = || VeterError::InvalidInput("Invalid message cursor".to_string());
+
+        let decoded = URL_SAFE_NO_PAD.decode(&cursor.0).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (created_at, id) = decoded.split_once('|').ok_or_else(invalid)?;
+        Uuid::parse_str(id).map_err(|_| invalid())?;
+        chrono::DateTime::parse_from_rfc3339(created_at).map_err(|_| invalid())?;
+
+        Ok(Self {
+            created_at: created_at.to_string(),
+            id: id.to_string(),
+        })
+    }
+}
+
+/// Creation time filter of a message query
+#[derive(Default)]
+struct TimeRange {
+    since: Option<String>,
+    until: Option<String>,
+}
+
+impl TimeRange {
+    fn new(since: Option<chrono::DateTime<chrono::Utc>>, until: Option<chrono::DateTime<chrono::Utc>>) -> Self {
+        Self {
+            since: since.map(timestamp),
+            until: until.map(timestamp),
+        }
+    }
+}
+
+/// Messages a query pages through
+enum Scope<'a> {
+    Room(&'a RoomId),
+    /// Replies to a message
+    Thread(&'a MessageId),
+}
+
+/// WHERE clause selecting the messages in a scope within a time range and
+/// on one side of a position, with its parameters. Written as a row value
+/// comparison so SQLite can seek on `messages_room_created` or
+/// `messages_thread`.
+fn message_conditions(scope: &Scope, range: &TimeRange, bound: Option<(&str, &Position)>) -> (String, Vec<String>) {
+    let (mut conditions, mut params) = match scope {
+        Scope::Room(room_id) => (vec!["room_id = ?".to_string()], vec![room_id.to_string()]),
+        Scope::Thread(root_id) => (vec!["reply_to = ?".to_string()], vec![root_id.to_string()]),
+    };
+
+    if let Some(since) = &range.since {
+        conditions.push("created_at >= ?".to_string());
+        params.push(since.clone());
+    }
+    if let Some(until) = &range.until {
+        conditions.push("created_at < ?".to_string());
+        params.push(until.clone());
+    }
+    if let Some((operator, position)) = bound {
+        conditions.push(format!("(created_at, id) {} (?, ?)", operator));
+        params.push(position.created_at.clone());
+        params.push(position.id.clone());
+    }
+
+    (conditions.join(" AND "), params)
+}
+
+/// Text of a message that users can see and search for
+fn search_text(content: &MessageContent) -> &str {
+    match content {
+        MessageContent::Text(text) => text,
+        MessageContent::File { name, .. } => name,
+        _ => "",
+    }
+}
+
+/// Turn user input into an FTS5 query: every word quoted as a literal, the
+/// last one as a prefix
+fn fts_query(query: &str) -> Option<String> {
+    let terms: Vec<String> = query.split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect();
+    if terms.is_empty() {
+        return None;
+    }
+
+    Some(terms.join(" ") + "*")
+}
+
+/// Strip the highlight markers from a snippet, returning the match ranges
+fn parse_snippet(marked: &str) -> (String, Vec<(usize, usize)>) {
+    let mut snippet = String::with_capacity(marked.len());
+    let mut highlights = Vec::new();
+    let mut start = None;
+
+    for c in marked.chars() {
+        match c {
+            '\u{2}' => start = Some(snippet.len()),
+            '\u{3}' => {
+                if let Some(start) = start.take() {
+                    highlights.push((start, snippet.len()));
+                }
+            }
+            c => snippet.push(c),
         }
     }
+    (snippet, highlights)
 }
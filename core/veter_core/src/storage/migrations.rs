@@ -0,0 +1,493 @@
+//! Versioned schema migrations
+//!
+//! The schema version is kept in the `schema_version` table. Migrations are
+//! applied in order, each one in its own transaction together with the
+//! version bump, so an interrupted upgrade resumes where it stopped.
+//! Migrations are never edited once released; schema changes are made by
+//! appending a new one.
+
+use crate::{VeterError, Result};
+use sqlx::{Row, SqlitePool};
+
+/// A schema migration
+pub struct Migration {
+    /// Schema version after this migration
+    pub version: u32,
+    pub description: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+/// All migrations, in order
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Initial schema",
+        statements: &[
+            r#"
+            CREATE TABLE users (
+                id TEXT PRIMARY KEY,
+                username TEXT UNIQUE NOT NULL,
+                display_name TEXT NOT NULL,
+                avatar_url TEXT,
+                created_at TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE devices (
+                id TEXT PRIMARY KEY,
+                user_id TEXT NOT NULL,
+                name TEXT NOT NULL,
+                platform TEXT NOT NULL,
+                public_key BLOB NOT NULL,
+                created_at TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                FOREIGN KEY (user_id) REFERENCES users (id)
+            )
+            "#,
+            r#"
+            CREATE TABLE rooms (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                description TEXT,
+                room_type TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE room_members (
+                room_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                joined_at TEXT NOT NULL,
+                PRIMARY KEY (room_id, user_id),
+                FOREIGN KEY (room_id) REFERENCES rooms (id),
+                FOREIGN KEY (user_id) REFERENCES users (id)
+            )
+            "#,
+            r#"
+            CREATE TABLE messages (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                sender_device_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                edited_at TEXT,
+                reply_to TEXT,
+                FOREIGN KEY (room_id) REFERENCES rooms (id),
+                FOREIGN KEY (sender_id) REFERENCES users (id),
+                FOREIGN KEY (sender_device_id) REFERENCES devices (id)
+            )
+            "#,
+            r#"
+            CREATE TABLE sessions (
+                room_id TEXT PRIMARY KEY,
+                device_id TEXT NOT NULL,
+                session_data BLOB NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                FOREIGN KEY (room_id) REFERENCES rooms (id),
+                FOREIGN KEY (device_id) REFERENCES devices (id)
+            )
+            "#,
+            r#"
+            CREATE VIRTUAL TABLE messages_fts USING fts5(
+                content,
+                content='messages',
+                content_rowid='rowid'
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 2,
+        description: "Persist prekey private keys",
+        statements: &[
+            r#"
+            CREATE TABLE prekeys (
+                device_id TEXT PRIMARY KEY,
+                state_data BLOB NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 3,
+        description: "Key sessions by room and peer device",
+        statements: &[
+            // Version 1 sessions held no ratchet state, so there is nothing to keep
+            "DROP TABLE sessions",
+            r#"
+            CREATE TABLE sessions (
+                room_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                session_data BLOB NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (room_id, device_id),
+                FOREIGN KEY (room_id) REFERENCES rooms (id),
+                FOREIGN KEY (device_id) REFERENCES devices (id)
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 4,
+        description: "Add sender keys",
+        statements: &[
+            r#"
+            CREATE TABLE sender_keys (
+                room_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                state_data BLOB NOT NULL,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (room_id, device_id),
+                FOREIGN KEY (room_id) REFERENCES rooms (id)
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 5,
+        description: "Add MLS rooms",
+        statements: &[
+            "ALTER TABLE rooms ADD COLUMN encryption TEXT NOT NULL DEFAULT 'Signal'",
+            r#"
+            CREATE TABLE mls_state (
+                device_id TEXT PRIMARY KEY,
+                state_data BLOB NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+            // No foreign key: a group joined from a welcome may arrive
+            // before its room
+            r#"
+            CREATE TABLE mls_groups (
+                room_id TEXT PRIMARY KEY,
+                state_data BLOB NOT NULL,
+                updated_at TEXT NOT NULL
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 6,
+        description: "Add device verification",
+        statements: &[
+            "ALTER TABLE devices ADD COLUMN verification TEXT NOT NULL DEFAULT 'Unverified'",
+            "ALTER TABLE devices ADD COLUMN verified_key BLOB",
+        ],
+    },
+    Migration {
+        version: 7,
+        description: "Index only visible message text, kept in sync by triggers",
+        statements: &[
+            "ALTER TABLE messages ADD COLUMN search_text TEXT NOT NULL DEFAULT ''",
+            r#"
+            UPDATE messages SET search_text = COALESCE(
+                json_extract(content, '$.Text'),
+                json_extract(content, '$.File.name'),
+                ''
+            )
+            "#,
+            "DROP TABLE messages_fts",
+            r#"
+            CREATE VIRTUAL TABLE messages_fts USING fts5(
+                search_text,
+                content='messages',
+                content_rowid='rowid',
+                tokenize='unicode61 remove_diacritics 2'
+            )
+            "#,
+            r#"
+            CREATE TRIGGER messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, search_text) VALUES (new.rowid, new.search_text);
+            END
+            "#,
+            r#"
+            CREATE TRIGGER messages_fts_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, search_text) VALUES ('delete', old.rowid, old.search_text);
+            END
+            "#,
+            r#"
+            CREATE TRIGGER messages_fts_update AFTER UPDATE OF search_text ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, search_text) VALUES ('delete', old.rowid, old.search_text);
+                INSERT INTO messages_fts (rowid, search_text) VALUES (new.rowid, new.search_text);
+            END
+            "#,
+            "INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')",
+        ],
+    },
+    Migration {
+        version: 8,
+        description: "Index messages for keyset pagination",
+        statements: &[
+            // Timestamps had a varying number of fractional digits, which
+            // breaks ordering them as text
+            "UPDATE messages SET created_at = strftime('%Y-%m-%dT%H:%M:%fZ', created_at)",
+            "CREATE INDEX messages_room_created ON messages (room_id, created_at, id)",
+        ],
+    },
+    Migration {
+        version: 9,
+        description: "Add outbox for messages waiting to be sent",
+        statements: &[
+            r#"
+            CREATE TABLE outbox (
+                message_id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                sender_device_id TEXT NOT NULL,
+                payload BLOB NOT NULL,
+                recipient_device_ids TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                state TEXT NOT NULL DEFAULT 'Pending',
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at TEXT NOT NULL,
+                last_error TEXT
+            )
+            "#,
+            "CREATE INDEX outbox_due ON outbox (state, next_attempt_at)",
+        ],
+    },
+    Migration {
+        version: 10,
+        description: "Track received messages and quarantine undecryptable ones",
+        statements: &[
+            r#"
+            CREATE TABLE received_messages (
+                message_id TEXT PRIMARY KEY,
+                received_at TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE quarantine (
+                message_id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                sender_device_id TEXT NOT NULL,
+                payload BLOB NOT NULL,
+                created_at TEXT NOT NULL,
+                error TEXT NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 1,
+                quarantined_at TEXT NOT NULL,
+                last_attempt_at TEXT NOT NULL
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 11,
+        description: "Persist replicated room state ops",
+        statements: &[
+            r#"
+            CREATE TABLE room_ops (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                device_id TEXT NOT NULL,
+                op TEXT NOT NULL,
+                stored_at TEXT NOT NULL
+            )
+            "#,
+            "CREATE INDEX room_ops_room ON room_ops (room_id)",
+            // State of each room with its stored ops applied
+            r#"
+            CREATE TABLE room_states (
+                room_id TEXT PRIMARY KEY,
+                state TEXT NOT NULL
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 12,
+        description: "Keep edit history and redactions",
+        statements: &[
+            // Edits, and the original content once a message is edited;
+            // edits of messages not received yet wait here too
+            r#"
+            CREATE TABLE message_versions (
+                id TEXT PRIMARY KEY,
+                message_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                sender_device_id TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )
+            "#,
+            "CREATE INDEX message_versions_message ON message_versions (message_id, created_at)",
+            r#"
+            CREATE TABLE redactions (
+                message_id TEXT PRIMARY KEY,
+                id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                sender_device_id TEXT NOT NULL,
+                redacted_at TEXT NOT NULL
+            )
+            "#,
+            // A row while the index still holds words of redacted messages
+            r#"
+            CREATE TABLE search_index_purge (
+                id INTEGER PRIMARY KEY CHECK (id = 0)
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 13,
+        description: "Index threads and track thread reads",
+        statements: &[
+            // Partial, as most messages are not replies
+            "CREATE INDEX messages_thread ON messages (reply_to, created_at, id) WHERE reply_to IS NOT NULL",
+            "CREATE INDEX messages_room_thread ON messages (room_id, reply_to) WHERE reply_to IS NOT NULL",
+            // Full, to find the threads a user started too
+            "CREATE INDEX messages_sender_thread ON messages (sender_id, reply_to)",
+            r#"
+            CREATE TABLE thread_reads (
+                root_id TEXT PRIMARY KEY,
+                message_id TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )
+            "#,
+        ],
+    },
+    Migration {
+        version: 14,
+        description: "Aggregate reactions instead of storing them as messages",
+        statements: &[
+            // Removed reactions are kept inactive, so that an older addition
+            // arriving late does not bring them back
+            r#"
+            CREATE TABLE reactions (
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                active INTEGER NOT NULL,
+                event_id TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (message_id, user_id, emoji)
+            )
+            "#,
+            r#"
+            INSERT OR IGNORE INTO reactions (message_id, user_id, emoji, active, event_id, updated_at)
+            SELECT json_extract(content, '$.Reaction.target_message_id'), sender_id,
+                   json_extract(content, '$.Reaction.emoji'), 1, id, created_at
+            FROM messages
+            WHERE json_type(content, '$.Reaction') IS NOT NULL
+            ORDER BY created_at
+            "#,
+            "DELETE FROM messages WHERE json_type(content, '$.Reaction') IS NOT NULL",
+        ],
+    },
+    Migration {
+        version: 15,
+        description: "Track receipts and unread counts",
+        statements: &[
+            r#"
+            CREATE TABLE receipts (
+                room_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                receipt_type TEXT NOT NULL,
+                message_id TEXT NOT NULL,
+                message_created_at TEXT NOT NULL,
+                PRIMARY KEY (room_id, user_id, receipt_type)
+            )
+            "#,
+            // The user this device belongs to, whose unread messages are counted
+            r#"
+            CREATE TABLE local_user (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                user_id TEXT NOT NULL
+            )
+            "#,
+            r#"
+            CREATE TABLE room_activity (
+                room_id TEXT PRIMARY KEY,
+                unread_count INTEGER NOT NULL,
+                mention_count INTEGER NOT NULL,
+                last_activity_at TEXT
+            )
+            "#,
+            r#"
+            INSERT INTO room_activity (room_id, unread_count, mention_count, last_activity_at)
+            SELECT room_id, 0, 0, MAX(created_at) FROM messages GROUP BY room_id
+            "#,
+        ],
+    },
+];
+
+/// Schema version this build creates and understands
+pub const SCHEMA_VERSION: u32 = MIGRATIONS[MIGRATIONS.len() - 1].version;
+
+/// Current schema version of a database
+pub async fn schema_version(pool: &SqlitePool) -> Result<u32> {
+    let has_version_table = table_exists(pool, "schema_version").await?;
+    if has_version_table {
+        let version: i64 = sqlx::query("SELECT version FROM schema_version")
+            .fetch_one(pool)
+            .await
+            .map_err(|e| VeterError::Database(format!("Failed to read schema version: {}", e)))?
+            .get("version");
+        return Ok(version as u32);
+    }
+
+    // Databases from before versioning have the initial schema
+    if table_exists(pool, "users").await? {
+        Ok(1)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Bring a database up to [`SCHEMA_VERSION`]
+pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+    let current = schema_version(pool).await?;
+    if current > SCHEMA_VERSION {
+        return Err(VeterError::Storage(format!(
+            "Database schema version {} is newer than the supported version {}",
+            current, SCHEMA_VERSION
+        )));
+    }
+
+    sqlx::query("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")
+        .execute(pool)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to create schema_version table: {}", e)))?;
+    sqlx::query("INSERT INTO schema_version (version) SELECT ? WHERE NOT EXISTS (SELECT 1 FROM schema_version)")
+        .bind(current as i64)
+        .execute(pool)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to record schema version: {}", e)))?;
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current) {
+        apply(pool, migration).await?;
+    }
+    Ok(())
+}
+
+async fn apply(pool: &SqlitePool, migration: &Migration) -> Result<()> {
+    let error = |e: sqlx::Error| VeterError::Database(format!(
+        "Migration to version {} ({}) failed: {}", migration.version, migration.description, e
+    ));
+
+    let mut tx = pool.begin().await.map_err(error)?;
+    for statement in migration.statements {
+        sqlx::query(statement).execute(&mut *tx).await.map_err(error)?;
+    }
+    sqlx::query("UPDATE schema_version SET version = ?")
+        .bind(migration.version as i64)
+        .execute(&mut *tx)
+        .await
+        .map_err(error)?;
+    tx.commit().await.map_err(error)
+}
+
+async fn table_exists(pool: &SqlitePool, name: &str) -> Result<bool> {
+    let row = sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?")
+        .bind(name)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| VeterError::Database(format!("Failed to inspect schema: {}", e)))?;
+
+    Ok(row.is_some())
+}
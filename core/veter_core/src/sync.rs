@@ -0,0 +1,185 @@
+//! Inbound sync engine
+//!
+//! Takes messages from the relay through decryption into storage. A message
+//! is only acknowledged to the relay once it is safe on this device: either
+//! its plaintext and the key state advanced by decrypting it were committed
+//! in one transaction, or its ciphertext was put in quarantine because it
+//! could not be decrypted yet, e.g. as it arrived before the sender key it
+//! needs. Quarantined messages are retried with
+//! [`SyncEngine::retry_quarantined`]. Messages the relay delivers again are
+//! recognised by ID and only acknowledged.
+//!
+//! Plaintexts are JSON-serialized [`Message`]s. Room ops they carry are
+//! stored with them, see [`crate::crdt`]. Edits and redactions are applied
+//! to the message they target; ones not from its sender are quarantined.
+//! Words of messages redacted in a batch are purged from the search index
+//! once the batch is done.
+
+use crate::{VeterError, Result, models::*};
+use crate::crypto::{CryptoManager, StagedKeys};
+use crate::networking::RelayClient;
+use crate::storage::StorageManager;
+use serde::Serialize;
+use std::sync::Arc;
+
+/// What a sync did with the messages it handled
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SyncReport {
+    /// Messages decrypted and stored, in the order received
+    pub stored: Vec<Message>,
+    /// Messages put in or kept in quarantine
+    pub quarantined: Vec<MessageId>,
+    /// Messages received before and skipped
+    pub redelivered: Vec<MessageId>,
+}
+
+/// Outcome for one received message
+enum Received {
+    Stored(Box<Message>),
+    Quarantined,
+    Redelivered,
+}
+
+/// Inbound sync engine of one device
+pub struct SyncEngine {
+    storage: Arc<StorageManager>,
+    relay: RelayClient,
+    device_id: DeviceId,
+}
+
+impl SyncEngine {
+    /// Create a sync engine for `device_id`, storing into `storage`
+    pub fn new(storage: Arc<StorageManager>, relay: RelayClient, device_id: DeviceId) -> Self {
+        Self {
+            storage,
+            relay,
+            device_id,
+        }
+    }
+
+    /// Fetch up to `max_items` messages from the relay and process them
+    pub async fn sync(&self, crypto: &mut CryptoManager, max_items: u32) -> Result<SyncReport> {
+        let messages = self.relay.dequeue(&self.device_id, max_items, max_items).await?;
+        self.process(crypto, messages).await
+    }
+
+    /// Decrypt and store messages in order, e.g. as pushed by
+    /// `RelayClient::subscribe_messages`, then acknowledge the ones that are
+    /// safe.
+    ///
+    /// Processing stops at the first storage error. Messages handled before
+    /// it are still acknowledged; the rest stay on the relay to be
+    /// redelivered.
+    pub async fn process(&self, crypto: &mut CryptoManager, messages: Vec<EncryptedMessage>) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        let mut handled = Vec::new();
+        let mut result = Ok(());
+
+        for encrypted in messages {
+            match self.receive(crypto, &encrypted).await {
+                Ok(received) => {
+                    report.add(encrypted.id, received);
+                    handled.push(encrypted.id);
+                }
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        if !handled.is_empty() {
+            self.relay.ack(&self.device_id, &handled).await?;
+        }
+        self.storage.purge_search_index().await?;
+        result.map(|_| report)
+    }
+
+    /// Try the quarantined messages again, oldest first. Messages that
+    /// still fail stay in quarantine with the new error.
+    pub async fn retry_quarantined(&self, crypto: &mut CryptoManager) -> Result<SyncReport> {
+        let mut report = SyncReport::default();
+        for quarantined in self.storage.get_quarantined_messages().await? {
+            let received = self.open_and_store(crypto, &quarantined.message).await?;
+            report.add(quarantined.message.id, received);
+        }
+        self.storage.purge_search_index().await?;
+        Ok(report)
+    }
+
+    async fn receive(&self, crypto: &mut CryptoManager, encrypted: &EncryptedMessage) -> Result<Received> {
+        if self.storage.has_received_message(&encrypted.id).await? {
+            return Ok(Received::Redelivered);
+        }
+        self.open_and_store(crypto, encrypted).await
+    }
+
+    /// Decrypt a message and commit it with the advanced key state, or
+    /// quarantine it if it cannot be decrypted. The key state in memory is
+    /// only advanced once the message is stored, so a message that is not
+    /// stored can be decrypted again.
+    async fn open_and_store(&self, crypto: &mut CryptoManager, encrypted: &EncryptedMessage) -> Result<Received> {
+        // Storing the message needs its sender device and room, so it waits
+        // for them in quarantine
+        let Some(sender) = self.storage.get_device(&encrypted.sender_device_id).await? else {
+            let error = VeterError::KeyManagement(format!("Unknown device {}", encrypted.sender_device_id));
+            return self.quarantine(encrypted, error).await;
+        };
+        if self.storage.get_room(&encrypted.room_id).await?.is_none() {
+            let error = VeterError::InvalidInput(format!("Unknown room {}", encrypted.room_id));
+            return self.quarantine(encrypted, error).await;
+        }
+
+        let (message, keys) = match open(crypto, encrypted, &sender) {
+            Ok(opened) => opened,
+            Err(e) => return self.quarantine(encrypted, e).await,
+        };
+        match self.storage.authorize_change(&message).await {
+            Ok(()) => {}
+            Err(e @ (VeterError::Authentication(_) | VeterError::InvalidInput(_))) => {
+                return self.quarantine(encrypted, e).await;
+            }
+            Err(e) => return Err(e),
+        }
+
+        let key_state = crypto.staged_key_state(&keys)?;
+        if !self.storage.store_received_message(&message, &key_state).await? {
+            return Ok(Received::Redelivered);
+        }
+        crypto.commit_keys(keys)?;
+        Ok(Received::Stored(Box::new(message)))
+    }
+
+    async fn quarantine(&self, encrypted: &EncryptedMessage, error: VeterError) -> Result<Received> {
+        self.storage.quarantine_message(encrypted, &error.to_string()).await?;
+        Ok(Received::Quarantined)
+    }
+}
+
+impl SyncReport {
+    fn add(&mut self, message_id: MessageId, received: Received) {
+        match received {
+            Received::Stored(message) => self.stored.push(*message),
+            Received::Quarantined => self.quarantined.push(message_id),
+            Received::Redelivered => self.redelivered.push(message_id),
+        }
+    }
+}
+
+/// Decrypt a message and check that it is what its envelope says, from the
+/// user owning the sending device. The key state it advances is staged.
+fn open(crypto: &CryptoManager, encrypted: &EncryptedMessage, sender: &Device) -> Result<(Message, StagedKeys)> {
+    let (plaintext, keys) = crypto.open_message(&encrypted.payload, encrypted.room_id, encrypted.sender_device_id, encrypted.id)?;
+    let message: Message = serde_json::from_slice(&plaintext)?;
+
+    if message.id != encrypted.id || message.room_id != encrypted.room_id
+        || message.sender_device_id != encrypted.sender_device_id || message.sender_id != sender.user_id {
+        return Err(VeterError::InvalidEnvelope(format!("Message {} does not match its envelope", encrypted.id)));
+    }
+    if let MessageContent::RoomEvent(op) = &message.content {
+        if !op.matches(&message) {
+            return Err(VeterError::InvalidEnvelope(format!("Room op in message {} does not match it", encrypted.id)));
+        }
+    }
+    Ok((message, keys))
+}
@@ -0,0 +1,90 @@
+//! Fixtures shared by the integration tests
+
+// Every test file is its own crate and uses only some of these
+#![allow(dead_code)]
+
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+
+pub const PASSWORD: &str = "correct horse battery staple";
+
+/// Temporary database file, removed on drop with its WAL and shared memory
+/// files
+pub struct TempDb(pub PathBuf);
+
+impl TempDb {
+    /// A new database path, `name` telling the test files apart
+    pub fn new(name: &str) -> Self {
+        Self(std::env::temp_dir().join(format!("veter-{}-{}.db", name, Uuid::new_v4())))
+    }
+
+    /// Open storage on the database with [`PASSWORD`]
+    pub async fn open(&self) -> StorageManager {
+        StorageManager::new(&self.0, PASSWORD).await.unwrap()
+    }
+}
+
+impl Drop for TempDb {
+    fn drop(&mut self) {
+        remove_database(&self.0);
+    }
+}
+
+/// Remove a database file and the `-wal` and `-shm` files SQLite keeps
+/// next to it
+pub fn remove_database(path: &Path) {
+    for suffix in ["", "-wal", "-shm"] {
+        let mut file = path.as_os_str().to_owned();
+        file.push(suffix);
+        let _ = std::fs::remove_file(file);
+    }
+}
+
+/// A user with a unique username starting with `name`, and their phone
+/// with `public_key` as its identity key
+pub fn user(name: &str, public_key: Vec<u8>) -> (User, Device) {
+    let now = chrono::Utc::now();
+    let user = User {
+        id: Uuid::new_v4(),
+        username: format!("{}{}", name, Uuid::new_v4().simple()),
+        display_name: name.to_string(),
+        avatar_url: None,
+        created_at: now,
+    };
+    let device = Device {
+        id: Uuid::new_v4(),
+        user_id: user.id,
+        name: format!("{}'s phone", name),
+        platform: Platform::Android,
+        public_key,
+        created_at: now,
+        last_seen: now,
+        verification: VerificationState::Unverified,
+    };
+    (user, device)
+}
+
+/// Store a user named `name` with their phone, returning the phone
+pub async fn store_user(storage: &StorageManager, name: &str) -> Device {
+    let (user, device) = user(name, vec![0; 32]);
+    storage.store_user(&user).await.unwrap();
+    storage.store_device(&device).await.unwrap();
+    device
+}
+
+/// A group room in Signal mode
+pub fn room(name: &str, members: Vec<UserId>) -> Room {
+    let now = chrono::Utc::now();
+    Room {
+        id: Uuid::new_v4(),
+        name: name.to_string(),
+        description: None,
+        room_type: RoomType::Group,
+        encryption: RoomEncryption::Signal,
+        members,
+        created_at: now,
+        updated_at: now,
+    }
+}
@@ -0,0 +1,258 @@
+//! Legal hold tests against an in-process compliance service
+
+use ed25519_dalek::{Signer, SigningKey};
+use prost::Message as _;
+use std::sync::Mutex;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::crypto::{CryptoManager, escrow};
+use veter_core::models::*;
+use veter_core::networking::NetworkClient;
+use veter_core::networking::compliance::LegalHoldEvent;
+use veter_core::networking::compliance::proto::compliance_server::{Compliance, ComplianceServer};
+use veter_core::networking::compliance::proto::{
+    self, EscrowRequest, EscrowResponse, ExportHoldRequest, ExportHoldResponse, HoldArchive, ListHoldsRequest,
+    ListHoldsResponse, StartHoldRequest, StartHoldResponse, StopHoldRequest, StopHoldResponse,
+};
+use veter_core::VeterError;
+
+/// Compliance service keeping holds and escrowed copies in memory and
+/// signing exports with its own key
+struct TestCompliance {
+    export_key: SigningKey,
+    holds: Mutex<Vec<proto::LegalHold>>,
+    escrowed: Mutex<Vec<proto::EscrowedMessage>>,
+}
+
+impl TestCompliance {
+    fn new() -> Self {
+        Self {
+            export_key: SigningKey::from_bytes(&[7; 32]),
+            holds: Mutex::new(Vec::new()),
+            escrowed: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn hold(&self, hold_id: &[u8]) -> Option<proto::LegalHold> {
+        self.holds.lock().unwrap()
+            .iter()
+            .find(|hold| hold.id == hold_id)
+            .cloned()
+    }
+}
+
+#[tonic::async_trait]
+impl Compliance for TestCompliance {
+    async fn start_hold(&self, request: Request<StartHoldRequest>) -> Result<Response<StartHoldResponse>, Status> {
+        let request = request.into_inner();
+        let hold = proto::LegalHold {
+            id: Uuid::new_v4().as_bytes().to_vec(),
+            user_id: request.user_id,
+            hold_public_key: request.hold_public_key,
+            reason: request.reason,
+            started_ts: chrono::Utc::now().timestamp_millis(),
+            stopped_ts: 0,
+        };
+        self.holds.lock().unwrap().push(hold.clone());
+
+        Ok(Response::new(StartHoldResponse { hold: Some(hold) }))
+    }
+
+    async fn stop_hold(&self, request: Request<StopHoldRequest>) -> Result<Response<StopHoldResponse>, Status> {
+        let hold_id = request.into_inner().hold_id;
+        let mut holds = self.holds.lock().unwrap();
+        let hold = holds.iter_mut()
+            .find(|hold| hold.id == hold_id)
+            .ok_or_else(|| Status::not_found("Unknown hold"))?;
+        hold.stopped_ts = chrono::Utc::now().timestamp_millis();
+
+        Ok(Response::new(StopHoldResponse { hold: Some(hold.clone()) }))
+    }
+
+    async fn list_holds(&self, request: Request<ListHoldsRequest>) -> Result<Response<ListHoldsResponse>, Status> {
+        let user_id = request.into_inner().user_id;
+        let holds = self.holds.lock().unwrap()
+            .iter()
+            .filter(|hold| hold.user_id == user_id)
+            .cloned()
+            .collect();
+
+        Ok(Response::new(ListHoldsResponse { holds }))
+    }
+
+    async fn escrow(&self, request: Request<EscrowRequest>) -> Result<Response<EscrowResponse>, Status> {
+        let mut accepted_ids = Vec::new();
+        for message in request.into_inner().messages {
+            if self.hold(&message.hold_id).is_some_and(|hold| hold.stopped_ts == 0) {
+                accepted_ids.push(message.message_id.clone());
+                self.escrowed.lock().unwrap().push(message);
+            }
+        }
+
+        Ok(Response::new(EscrowResponse { accepted_ids }))
+    }
+
+    async fn export_hold(&self, request: Request<ExportHoldRequest>) -> Result<Response<ExportHoldResponse>, Status> {
+        let hold = self.hold(&request.into_inner().hold_id)
+            .ok_or_else(|| Status::not_found("Unknown hold"))?;
+        let messages = self.escrowed.lock().unwrap()
+            .iter()
+            .filter(|message| message.hold_id == hold.id)
+            .cloned()
+            .collect();
+        let archive = HoldArchive {
+            hold: Some(hold),
+            messages,
+            exported_ts: chrono::Utc::now().timestamp_millis(),
+        }
+        .encode_to_vec();
+        let signature = self.export_key.sign(&archive).to_bytes().to_vec();
+
+        Ok(Response::new(ExportHoldResponse { archive, signature }))
+    }
+}
+
+/// Serve a [`TestCompliance`] on a local port, returning a connected client
+/// and the service's export key
+async fn connected_client() -> (NetworkClient, Vec<u8>) {
+    let service = TestCompliance::new();
+    let service_key = service.export_key.verifying_key().to_bytes().to_vec();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(ComplianceServer::new(service))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = NetworkClient::new();
+    client.connect_compliance(&format!("http://{}", address)).await.unwrap();
+    (client, service_key)
+}
+
+/// A device of the held user, returning its crypto manager and identity key
+fn held_device() -> (CryptoManager, Vec<u8>) {
+    let (private_key, public_key) = CryptoManager::generate_identity_keypair().unwrap();
+    (CryptoManager::new(private_key, Uuid::new_v4()).unwrap(), public_key)
+}
+
+fn text_message(text: &str) -> Message {
+    Message {
+        id: Uuid::new_v4(),
+        room_id: Uuid::new_v4(),
+        sender_id: Uuid::new_v4(),
+        sender_device_id: Uuid::new_v4(),
+        content: MessageContent::Text(text.to_string()),
+        created_at: chrono::Utc::now(),
+        edited_at: None,
+        reply_to: None,
+    }
+}
+
+#[tokio::test]
+async fn exports_verifiable_archives_of_escrowed_messages() {
+    let (client, service_key) = connected_client().await;
+    let (hold_private_key, hold_public_key) = CryptoManager::generate_hold_keypair();
+    let (device, identity_key) = held_device();
+    let user_id = Uuid::new_v4();
+
+    let hold = client.start_legal_hold(&user_id, &hold_public_key, "Case 42").await.unwrap();
+    assert!(hold.is_active());
+    assert_eq!(hold.user_id, user_id);
+
+    let message = text_message("quarterly numbers");
+    let escrowed = device.escrow_message(&hold, &message).unwrap();
+    assert_eq!(client.escrow_messages(std::slice::from_ref(&escrowed)).await.unwrap(), vec![message.id]);
+
+    let export = client.export_hold_data(&hold.id).await.unwrap();
+    let archive = export.verify(&service_key).unwrap();
+    assert_eq!(archive.hold, hold);
+    assert_eq!(archive.messages.len(), 1);
+
+    let opened = escrow::open(&hold_private_key, &archive.messages[0], &identity_key).unwrap();
+    assert_eq!(opened.id, message.id);
+    assert!(matches!(opened.content, MessageContent::Text(text) if text == "quarterly numbers"));
+
+    // Only the escrowed hold key opens copies, and only genuine ones
+    let (other_private_key, _) = CryptoManager::generate_hold_keypair();
+    assert!(matches!(escrow::open(&other_private_key, &archive.messages[0], &identity_key), Err(VeterError::InvalidEnvelope(_))));
+    let (_, other_identity_key) = held_device();
+    assert!(matches!(escrow::open(&hold_private_key, &archive.messages[0], &other_identity_key), Err(VeterError::InvalidEnvelope(_))));
+    let mut forged = archive.messages[0].clone();
+    forged.room_id = Uuid::new_v4();
+    assert!(matches!(escrow::open(&hold_private_key, &forged, &identity_key), Err(VeterError::InvalidEnvelope(_))));
+
+    // Archives cannot be altered after export
+    let mut tampered = export.clone();
+    let last = tampered.archive.len() - 1;
+    tampered.archive[last] ^= 1;
+    assert!(matches!(tampered.verify(&service_key), Err(VeterError::Authentication(_))));
+    let (_, other_service_key) = held_device();
+    assert!(matches!(export.verify(&other_service_key), Err(VeterError::Authentication(_))));
+}
+
+#[tokio::test]
+async fn stopped_holds_take_no_copies() {
+    let (client, _) = connected_client().await;
+    let (_, hold_public_key) = CryptoManager::generate_hold_keypair();
+    let (device, _) = held_device();
+    let user_id = Uuid::new_v4();
+
+    let hold = client.start_legal_hold(&user_id, &hold_public_key, "Case 7").await.unwrap();
+    let late_copy = device.escrow_message(&hold, &text_message("made before the hold stopped")).unwrap();
+    let stopped = client.stop_legal_hold(&hold.id).await.unwrap();
+    assert!(!stopped.is_active());
+
+    let result = device.escrow_message(&stopped, &text_message("after the hold"));
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    assert!(client.escrow_messages(&[late_copy]).await.unwrap().is_empty());
+
+    let result = client.start_legal_hold(&user_id, &[1, 2, 3], "Case 8").await;
+    assert!(matches!(result, Err(VeterError::KeyManagement(_))));
+}
+
+#[tokio::test]
+async fn tells_the_held_user_about_holds() {
+    let (client, _) = connected_client().await;
+    let (_, hold_public_key) = CryptoManager::generate_hold_keypair();
+    let user_id = Uuid::new_v4();
+    let room_id = Uuid::new_v4();
+
+    let before = client.get_legal_holds(&user_id).await.unwrap();
+    assert!(before.is_empty());
+    let hold = client.start_legal_hold(&user_id, &hold_public_key, "Case 42").await.unwrap();
+    let during = client.get_legal_holds(&user_id).await.unwrap();
+    client.stop_legal_hold(&hold.id).await.unwrap();
+    let after = client.get_legal_holds(&user_id).await.unwrap();
+
+    let started = LegalHoldEvent::between(&before, &during);
+    assert!(matches!(started.as_slice(), [LegalHoldEvent::Started(started)] if started.id == hold.id));
+    let stopped = LegalHoldEvent::between(&during, &after);
+    assert!(matches!(stopped.as_slice(), [LegalHoldEvent::Stopped(stopped)] if stopped.id == hold.id));
+    assert!(LegalHoldEvent::between(&after, &after).is_empty());
+    // A device that missed the whole hold still hears about it
+    assert_eq!(LegalHoldEvent::between(&before, &after).len(), 2);
+
+    let message = started[0].message(room_id);
+    assert_eq!(message.room_id, room_id);
+    assert_eq!(message.sender_id, user_id);
+    assert_eq!(message.created_at, hold.started_at);
+    assert!(matches!(&message.content, MessageContent::System(text) if text.contains("Case 42")));
+    // Announcing the same event again yields the same message
+    assert_eq!(started[0].message(room_id).id, message.id);
+    assert_ne!(stopped[0].message(room_id).id, message.id);
+    assert!(matches!(stopped[0].content(), MessageContent::System(_)));
+}
+
+#[tokio::test]
+async fn requires_connection() {
+    let client = NetworkClient::new();
+
+    let result = client.get_legal_holds(&Uuid::new_v4()).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+    let result = client.export_hold_data(&Uuid::new_v4()).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+}
@@ -0,0 +1,161 @@
+//! Replicated room state tests: devices converge whatever the op order
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::crdt::{RoomOp, RoomOpKind, RoomState};
+use veter_core::models::*;
+use common::{TempDb, room, store_user};
+
+/// Apply ops in order to a fresh state
+fn replay(room_id: RoomId, ops: &[RoomOp]) -> RoomState {
+    let mut state = RoomState::new(room_id);
+    for op in ops {
+        state.apply(op.clone()).unwrap();
+    }
+    state
+}
+
+/// Name, description and members, to compare states
+fn summary(state: &RoomState) -> (Option<String>, Option<String>, Vec<UserId>) {
+    (state.name().map(str::to_string), state.description().map(str::to_string), state.members())
+}
+
+/// Every order of `items`
+fn permutations<T: Clone>(items: &[T]) -> Vec<Vec<T>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut result = Vec::new();
+    for i in 0..items.len() {
+        let mut rest = items.to_vec();
+        let first = rest.remove(i);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, first.clone());
+            result.push(permutation);
+        }
+    }
+    result
+}
+
+#[test]
+fn concurrent_edits_converge_in_any_order() {
+    let (alice, bob, carol) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let (phone, laptop) = (Uuid::new_v4(), Uuid::new_v4());
+    let room = room("Lunch", vec![alice, bob, carol]);
+    let (created, genesis) = RoomState::create(&room, phone);
+
+    // Both devices go offline with the same state and edit concurrently
+    let mut on_phone = created.clone();
+    let mut on_laptop = created;
+    let mut edits = vec![
+        on_phone.set_name(phone, "Lunch on Friday"),
+        on_phone.remove_member(phone, carol).unwrap(),
+        on_laptop.add_member(laptop, carol),
+        on_laptop.set_description(laptop, Some("Bring snacks".to_string())),
+    ];
+    edits.push(on_laptop.set_name(laptop, "Team lunch"));
+
+    let expected = summary(&replay(room.id, &[genesis.clone(), edits.clone()].concat()));
+    // The laptop named the room last
+    assert_eq!(expected.0.as_deref(), Some("Team lunch"));
+    assert_eq!(expected.1.as_deref(), Some("Bring snacks"));
+    // The laptop's addition was not seen by the phone's removal, so it wins
+    assert_eq!(expected.2, vec![alice, bob, carol]);
+
+    for order in permutations(&edits) {
+        let state = replay(room.id, &[genesis.clone(), order].concat());
+        assert_eq!(summary(&state), expected);
+        assert!(state.pending().is_empty());
+    }
+}
+
+#[test]
+fn removal_after_seeing_the_addition_removes() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let device = Uuid::new_v4();
+    let room = room("Lunch", vec![alice]);
+    let (mut state, mut ops) = RoomState::create(&room, device);
+
+    ops.push(state.add_member(device, bob));
+    assert!(state.is_member(&bob));
+    ops.push(state.remove_member(device, bob).unwrap());
+    assert!(!state.is_member(&bob));
+    assert!(state.remove_member(device, bob).is_none());
+
+    let mut room = room.clone();
+    replay(room.id, &ops).apply_to(&mut room);
+    assert_eq!(room.members, vec![alice]);
+}
+
+#[test]
+fn holds_back_ops_until_their_dependencies_arrive() {
+    let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+    let device = Uuid::new_v4();
+    let room = room("Lunch", vec![alice]);
+    let (mut sender, genesis) = RoomState::create(&room, device);
+    let added = sender.add_member(device, bob);
+    let removed = sender.remove_member(device, bob).unwrap();
+
+    let mut receiver = replay(room.id, &genesis);
+    // The removal overtook the addition it cancels
+    receiver.apply(removed.clone()).unwrap();
+    assert_eq!(receiver.pending(), std::slice::from_ref(&removed));
+    assert!(!receiver.is_member(&bob));
+
+    receiver.apply(added).unwrap();
+    assert!(receiver.pending().is_empty());
+    assert!(!receiver.is_member(&bob));
+    assert_eq!(summary(&receiver), summary(&sender));
+
+    // Ops are applied once
+    receiver.apply(removed).unwrap();
+    assert_eq!(receiver.members(), vec![alice]);
+}
+
+#[test]
+fn rejects_ops_for_other_rooms() {
+    let device = Uuid::new_v4();
+    let (_, ops) = RoomState::create(&room("Lunch", vec![Uuid::new_v4()]), device);
+    let mut other = RoomState::new(Uuid::new_v4());
+    assert!(other.apply(ops[0].clone()).is_err());
+}
+
+#[tokio::test]
+async fn stored_ops_shape_the_room() {
+    let db = TempDb::new("crdt");
+    let storage = db.open().await;
+    let device = store_user(&storage, "alice").await;
+    let room = room("Lunch", vec![device.user_id]);
+    storage.store_room(&room).await.unwrap();
+
+    let (mut state, mut ops) = RoomState::create(&room, device.id);
+    let newcomer = Uuid::new_v4();
+    ops.push(state.add_member(device.id, newcomer));
+    ops.push(state.set_name(device.id, "Dinner"));
+    // Stored out of order, as they may arrive
+    ops.reverse();
+    for op in &ops {
+        storage.store_message(&op.to_message(device.user_id)).await.unwrap();
+    }
+
+    let stored = storage.get_room(&room.id).await.unwrap().unwrap();
+    assert_eq!(stored.name, "Dinner");
+    assert_eq!(stored.members, vec![device.user_id, newcomer]);
+    assert_eq!(summary(&storage.get_room_state(&room.id).await.unwrap()), summary(&state));
+
+    // Events are part of the room's history too
+    let page = storage.get_messages(&room.id, &MessageQuery::latest(10)).await.unwrap();
+    assert_eq!(page.messages.len(), ops.len());
+    assert!(page.messages.iter().all(|message| matches!(
+        &message.content,
+        MessageContent::RoomEvent(op) if matches!(op.kind, RoomOpKind::SetName(_) | RoomOpKind::SetDescription(_) | RoomOpKind::AddMember(_))
+    )));
+
+    // Changes continue from the stored state
+    let mut stored_state = storage.get_room_state(&room.id).await.unwrap();
+    let op = stored_state.remove_member(device.id, newcomer).unwrap();
+    storage.store_message(&op.to_message(device.user_id)).await.unwrap();
+    assert_eq!(storage.get_room(&room.id).await.unwrap().unwrap().members, vec![device.user_id]);
+    assert_eq!(summary(&storage.get_room_state(&room.id).await.unwrap()), summary(&stored_state));
+}
@@ -0,0 +1,230 @@
+//! Directory client tests against an in-process directory
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::crypto::CryptoManager;
+use veter_core::models::*;
+use veter_core::networking::NetworkClient;
+use veter_core::networking::directory::proto::directory_server::{Directory, DirectoryServer};
+use veter_core::networking::directory::proto::{
+    self, FetchPrekeyBundleRequest, FetchPrekeyBundleResponse, ListUserDevicesRequest, ListUserDevicesResponse,
+    PublishPrekeysRequest, PublishPrekeysResponse, RegisterDeviceRequest, RegisterDeviceResponse,
+    RevokeDeviceRequest, RevokeDeviceResponse,
+};
+use veter_core::VeterError;
+
+/// Directory keeping registered devices and their key material in memory
+#[derive(Default)]
+struct TestDirectory {
+    devices: Mutex<HashMap<Vec<u8>, Entry>>,
+}
+
+struct Entry {
+    device: proto::Device,
+    revoked: bool,
+    key_material: Option<proto::KeyMaterial>,
+}
+
+#[tonic::async_trait]
+impl Directory for TestDirectory {
+    async fn register_device(&self, request: Request<RegisterDeviceRequest>) -> Result<Response<RegisterDeviceResponse>, Status> {
+        let device = request.into_inner().device.ok_or_else(|| Status::invalid_argument("No device"))?;
+        self.devices.lock().unwrap().insert(device.id.clone(), Entry {
+            device,
+            revoked: false,
+            key_material: None,
+        });
+
+        Ok(Response::new(RegisterDeviceResponse {}))
+    }
+
+    async fn list_user_devices(&self, request: Request<ListUserDevicesRequest>) -> Result<Response<ListUserDevicesResponse>, Status> {
+        let user_id = request.into_inner().user_id;
+        let mut devices: Vec<_> = self.devices.lock().unwrap()
+            .values()
+            .filter(|entry| entry.device.user_id == user_id && !entry.revoked)
+            .map(|entry| entry.device.clone())
+            .collect();
+        devices.sort_by_key(|device| device.created_ts);
+
+        Ok(Response::new(ListUserDevicesResponse { devices }))
+    }
+
+    async fn publish_prekeys(&self, request: Request<PublishPrekeysRequest>) -> Result<Response<PublishPrekeysResponse>, Status> {
+        let request = request.into_inner();
+        let published = request.key_material.ok_or_else(|| Status::invalid_argument("No key material"))?;
+        let mut devices = self.devices.lock().unwrap();
+        let entry = devices.get_mut(&request.device_id).ok_or_else(|| Status::not_found("Unknown device"))?;
+        if published.identity_key != entry.device.identity_key {
+            return Err(Status::permission_denied("Identity key mismatch"));
+        }
+
+        let material = entry.key_material.get_or_insert_with(Default::default);
+        material.identity_key = published.identity_key;
+        material.signed_prekey = published.signed_prekey;
+        material.signed_prekey_signature = published.signed_prekey_signature;
+        material.one_time_prekeys.extend(published.one_time_prekeys);
+        material.mls_key_packages.extend(published.mls_key_packages);
+
+        Ok(Response::new(PublishPrekeysResponse {
+            one_time_prekeys_remaining: material.one_time_prekeys.len() as u32,
+        }))
+    }
+
+    async fn fetch_prekey_bundle(&self, request: Request<FetchPrekeyBundleRequest>) -> Result<Response<FetchPrekeyBundleResponse>, Status> {
+        let device_id = request.into_inner().device_id;
+        let mut devices = self.devices.lock().unwrap();
+        let material = devices.get_mut(&device_id)
+            .filter(|entry| !entry.revoked)
+            .and_then(|entry| entry.key_material.as_mut())
+            .ok_or_else(|| Status::not_found("No prekeys for device"))?;
+
+        let take_first = |keys: &mut Vec<Vec<u8>>| if keys.is_empty() { Vec::new() } else { vec![keys.remove(0)] };
+        let bundle = proto::KeyMaterial {
+            identity_key: material.identity_key.clone(),
+            signed_prekey: material.signed_prekey.clone(),
+            signed_prekey_signature: material.signed_prekey_signature.clone(),
+            one_time_prekeys: take_first(&mut material.one_time_prekeys),
+            mls_key_packages: take_first(&mut material.mls_key_packages),
+        };
+
+        Ok(Response::new(FetchPrekeyBundleResponse { bundle: Some(bundle) }))
+    }
+
+    async fn revoke_device(&self, request: Request<RevokeDeviceRequest>) -> Result<Response<RevokeDeviceResponse>, Status> {
+        let device_id = request.into_inner().device_id;
+        let mut devices = self.devices.lock().unwrap();
+        let entry = devices.get_mut(&device_id).ok_or_else(|| Status::not_found("Unknown device"))?;
+        entry.revoked = true;
+        entry.key_material = None;
+
+        Ok(Response::new(RevokeDeviceResponse {}))
+    }
+}
+
+/// Serve a [`TestDirectory`] on a local port and connect a client to it
+async fn connected_client() -> NetworkClient {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(DirectoryServer::new(TestDirectory::default()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = NetworkClient::new();
+    client.connect_directory(&format!("http://{}", address)).await.unwrap();
+    client
+}
+
+fn device(user_id: UserId, identity_key: Vec<u8>, platform: Platform, created_at_millis: i64) -> Device {
+    let created_at = chrono::DateTime::from_timestamp_millis(created_at_millis).unwrap();
+    Device {
+        id: Uuid::new_v4(),
+        user_id,
+        name: format!("{:?} device", platform),
+        platform,
+        public_key: identity_key,
+        created_at,
+        last_seen: created_at,
+        verification: VerificationState::Verified,
+    }
+}
+
+#[tokio::test]
+async fn lists_registered_devices_until_revoked() {
+    let client = connected_client().await;
+    let alice = Uuid::new_v4();
+    let phone = device(alice, vec![1; 32], Platform::Ios, 1_700_000_000_123);
+    let laptop = device(alice, vec![2; 32], Platform::Linux, 1_700_000_000_456);
+    client.register_device(&phone).await.unwrap();
+    client.register_device(&laptop).await.unwrap();
+    client.register_device(&device(Uuid::new_v4(), vec![3; 32], Platform::Web, 0)).await.unwrap();
+
+    let listed = client.get_user_directory(&alice).await.unwrap();
+    assert_eq!(listed.len(), 2);
+    for (listed, registered) in listed.iter().zip([&phone, &laptop]) {
+        assert_eq!(listed.id, registered.id);
+        assert_eq!(listed.user_id, alice);
+        assert_eq!(listed.name, registered.name);
+        assert_eq!(format!("{:?}", listed.platform), format!("{:?}", registered.platform));
+        assert_eq!(listed.public_key, registered.public_key);
+        assert_eq!(listed.created_at, registered.created_at);
+        assert_eq!(listed.last_seen, registered.last_seen);
+        // Verification is not the directory's to vouch for
+        assert_eq!(listed.verification, VerificationState::Unverified);
+    }
+
+    client.revoke_device(&phone.id).await.unwrap();
+    let listed = client.get_user_directory(&alice).await.unwrap();
+    assert_eq!(listed.iter().map(|device| device.id).collect::<Vec<_>>(), vec![laptop.id]);
+    assert!(client.fetch_prekey_bundle(&phone.id).await.is_err());
+}
+
+#[tokio::test]
+async fn fetching_a_bundle_consumes_a_one_time_prekey() {
+    let client = connected_client().await;
+    let (bob_private, bob_public) = CryptoManager::generate_identity_keypair().unwrap();
+    let bob_device = device(Uuid::new_v4(), bob_public, Platform::Android, 0);
+    let mut bob = CryptoManager::new(bob_private, bob_device.id).unwrap();
+    client.register_device(&bob_device).await.unwrap();
+
+    let published = bob.generate_key_material(2).unwrap();
+    assert_eq!(client.publish_prekeys(&bob_device.id, &published).await.unwrap(), 2);
+
+    let first = client.fetch_prekey_bundle(&bob_device.id).await.unwrap();
+    let second = client.fetch_prekey_bundle(&bob_device.id).await.unwrap();
+    let exhausted = client.fetch_prekey_bundle(&bob_device.id).await.unwrap();
+    assert_eq!(first.identity_key, published.identity_key);
+    assert_eq!(first.signed_prekey, published.signed_prekey);
+    assert_eq!(first.signed_prekey_signature, published.signed_prekey_signature);
+    assert_eq!(first.one_time_prekeys, vec![published.one_time_prekeys[0].clone()]);
+    assert_eq!(first.mls_key_packages, vec![published.mls_key_packages[0].clone()]);
+    assert_eq!(second.one_time_prekeys, vec![published.one_time_prekeys[1].clone()]);
+    assert!(exhausted.one_time_prekeys.is_empty());
+    assert!(exhausted.mls_key_packages.is_empty());
+
+    // Bundles are usable for starting sessions, with or without a one-time prekey
+    let (alice_private, _) = CryptoManager::generate_identity_keypair().unwrap();
+    let mut alice = CryptoManager::new(alice_private, Uuid::new_v4()).unwrap();
+    alice.start_session(Uuid::new_v4(), bob_device.id, &first).unwrap();
+    alice.start_session(Uuid::new_v4(), bob_device.id, &exhausted).unwrap();
+
+    // Publishing more refills the supply
+    let refill = bob.generate_key_material(1).unwrap();
+    assert_eq!(client.publish_prekeys(&bob_device.id, &refill).await.unwrap(), 1);
+    let refilled = client.fetch_prekey_bundle(&bob_device.id).await.unwrap();
+    assert_eq!(refilled.signed_prekey, refill.signed_prekey);
+    assert_eq!(refilled.one_time_prekeys, refill.one_time_prekeys);
+}
+
+#[tokio::test]
+async fn requires_connection() {
+    let client = NetworkClient::new();
+
+    let result = client.get_user_directory(&Uuid::new_v4()).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+    let result = client.fetch_prekey_bundle(&Uuid::new_v4()).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+}
+
+#[test]
+fn rejects_malformed_devices() {
+    let registered = device(Uuid::new_v4(), vec![1; 32], Platform::Macos, 0);
+    let wire = proto::Device::from(&registered);
+    assert_eq!(Device::try_from(wire.clone()).unwrap().id, registered.id);
+
+    let result = Device::try_from(proto::Device { id: vec![1, 2, 3], ..wire.clone() });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    let result = Device::try_from(proto::Device { platform: proto::Platform::Unspecified.into(), ..wire.clone() });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    let result = Device::try_from(proto::Device { platform: 42, ..wire.clone() });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    let result = Device::try_from(proto::Device { created_ts: i64::MAX, ..wire });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+}
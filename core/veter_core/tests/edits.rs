@@ -0,0 +1,227 @@
+//! Edit and redaction tests: history, search and out-of-order arrival
+
+mod common;
+
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::{ConnectOptions, Row};
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use veter_core::VeterError;
+use common::{PASSWORD, TempDb, room, store_user};
+
+/// Storage knowing Alice, Bob and a room of theirs
+struct Setup {
+    db: TempDb,
+    storage: StorageManager,
+    room_id: RoomId,
+    alice: Device,
+    bob: Device,
+}
+
+impl Setup {
+    async fn new() -> Self {
+        let db = TempDb::new("edits");
+        let storage = db.open().await;
+        let alice = store_user(&storage, "alice").await;
+        let bob = store_user(&storage, "bob").await;
+        let room = room("Lunch", vec![alice.user_id, bob.user_id]);
+        storage.store_room(&room).await.unwrap();
+
+        Self { db, storage, room_id: room.id, alice, bob }
+    }
+
+    /// A message from `sender`, `offset_ms` after a fixed start time
+    fn message(&self, sender: &Device, content: MessageContent, offset_ms: i64) -> Message {
+        Message {
+            id: Uuid::new_v4(),
+            room_id: self.room_id,
+            sender_id: sender.user_id,
+            sender_device_id: sender.id,
+            content,
+            created_at: chrono::DateTime::from_timestamp_millis(1_700_000_000_000 + offset_ms).unwrap(),
+            edited_at: None,
+            reply_to: None,
+        }
+    }
+
+    fn edit(&self, sender: &Device, target: &Message, text: &str, offset_ms: i64) -> Message {
+        let content = MessageContent::Edit {
+            target_message_id: target.id,
+            content: Box::new(text_content(text)),
+        };
+        self.message(sender, content, offset_ms)
+    }
+
+    fn redact(&self, sender: &Device, target: &Message, offset_ms: i64) -> Message {
+        self.message(sender, MessageContent::Redact { target_message_id: target.id }, offset_ms)
+    }
+
+    async fn stored(&self) -> Vec<Message> {
+        self.storage.get_messages(&self.room_id, &MessageQuery::latest(10)).await.unwrap().messages
+    }
+
+    async fn search(&self, query: &str) -> usize {
+        self.storage.search_messages(query, None, 10).await.unwrap().len()
+    }
+}
+
+/// Whether a word is still written anywhere in the raw search index,
+/// read through a connection of our own
+async fn index_contains(db_path: &Path, word: &str) -> bool {
+    let salt = &std::fs::read(db_path).unwrap()[..16];
+    let mut key = [0u8; 32];
+    argon2::Argon2::default().hash_password_into(PASSWORD.as_bytes(), salt, &mut key).unwrap();
+    let mut connection = SqliteConnectOptions::from_str(&format!("sqlite://{}", db_path.display()))
+        .unwrap()
+        .pragma("key", format!("\"x'{}{}'\"", hex::encode(key), hex::encode(salt)))
+        .connect()
+        .await
+        .unwrap();
+
+    let mut blobs: Vec<Vec<u8>> = Vec::new();
+    for query in ["SELECT block FROM messages_fts_data", "SELECT term FROM messages_fts_idx"] {
+        for row in sqlx::query(query).fetch_all(&mut connection).await.unwrap() {
+            blobs.push(row.get(0));
+        }
+    }
+    blobs.iter().any(|blob| blob.windows(word.len()).any(|window| window == word.as_bytes()))
+}
+
+fn text_content(text: &str) -> MessageContent {
+    MessageContent::Text(text.to_string())
+}
+
+fn text(content: &MessageContent) -> &str {
+    match content {
+        MessageContent::Text(text) => text,
+        other => panic!("Not a text message: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn edits_replace_content_and_keep_history() {
+    let setup = Setup::new().await;
+    let original = setup.message(&setup.alice, text_content("lunch at noon"), 0);
+    setup.storage.store_message(&original).await.unwrap();
+    assert!(setup.storage.get_message_history(&original.id).await.unwrap().is_empty());
+
+    let first = setup.edit(&setup.alice, &original, "lunch at one", 1_000);
+    let second = setup.edit(&setup.alice, &original, "dinner at seven", 2_000);
+    // The later edit wins whatever order they arrive in
+    setup.storage.store_message(&second).await.unwrap();
+    setup.storage.store_message(&first).await.unwrap();
+
+    let stored = setup.stored().await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(text(&stored[0].content), "dinner at seven");
+    assert_eq!(stored[0].edited_at, Some(second.created_at));
+    assert_eq!(setup.search("dinner").await, 1);
+    assert_eq!(setup.search("noon").await, 0);
+
+    let history = setup.storage.get_message_history(&original.id).await.unwrap();
+    let ids: Vec<_> = history.iter().map(|version| version.id).collect();
+    assert_eq!(ids, vec![original.id, first.id, second.id]);
+    let texts: Vec<_> = history.iter().map(|version| text(&version.content)).collect();
+    assert_eq!(texts, vec!["lunch at noon", "lunch at one", "dinner at seven"]);
+}
+
+#[tokio::test]
+async fn redaction_leaves_a_tombstone_without_history() {
+    let setup = Setup::new().await;
+    let original = setup.message(&setup.alice, text_content("my password is hunter2"), 0);
+    setup.storage.store_message(&original).await.unwrap();
+    setup.storage.store_message(&setup.edit(&setup.alice, &original, "my password is swordfish", 1_000)).await.unwrap();
+
+    setup.storage.store_message(&setup.redact(&setup.alice, &original, 2_000)).await.unwrap();
+    let stored = setup.stored().await;
+    assert_eq!(stored.len(), 1);
+    assert_eq!(stored[0].id, original.id);
+    assert!(matches!(stored[0].content, MessageContent::Redacted));
+    assert!(setup.storage.get_message_history(&original.id).await.unwrap().is_empty());
+    assert_eq!(setup.search("password").await, 0);
+
+    // Later edits do not bring content back
+    setup.storage.store_message(&setup.edit(&setup.alice, &original, "my password is back", 3_000)).await.unwrap();
+    assert!(matches!(setup.stored().await[0].content, MessageContent::Redacted));
+    assert!(setup.storage.get_message_history(&original.id).await.unwrap().is_empty());
+    assert_eq!(setup.search("password").await, 0);
+}
+
+#[tokio::test]
+async fn purging_drops_redacted_words_from_the_index() {
+    let setup = Setup::new().await;
+    // Words without a shared prefix are written out whole in the index
+    let secret = setup.message(&setup.alice, text_content("the xylophone code"), 0);
+    let kept = setup.message(&setup.alice, text_content("lunch today"), 1_000);
+    setup.storage.store_message(&secret).await.unwrap();
+    setup.storage.store_message(&kept).await.unwrap();
+
+    setup.storage.store_message(&setup.redact(&setup.alice, &secret, 2_000)).await.unwrap();
+    assert_eq!(setup.search("xylophone").await, 0);
+    // Until the purge, the redacted words only lose their matches
+    assert!(index_contains(&setup.db.0, "xylophone").await);
+
+    assert!(setup.storage.purge_search_index().await.unwrap());
+    assert!(!index_contains(&setup.db.0, "xylophone").await);
+    assert!(index_contains(&setup.db.0, "lunch").await);
+    assert_eq!(setup.search("lunch").await, 1);
+    // Nothing left to purge
+    assert!(!setup.storage.purge_search_index().await.unwrap());
+}
+
+#[tokio::test]
+async fn changes_arriving_before_their_message_apply_when_it_arrives() {
+    let setup = Setup::new().await;
+    let edited = setup.message(&setup.alice, text_content("see you soon"), 0);
+    let redacted = setup.message(&setup.alice, text_content("oops, wrong room"), 0);
+    setup.storage.store_message(&setup.edit(&setup.alice, &edited, "see you at 6", 1_000)).await.unwrap();
+    setup.storage.store_message(&setup.redact(&setup.alice, &redacted, 1_000)).await.unwrap();
+    assert!(setup.stored().await.is_empty());
+
+    setup.storage.store_message(&edited).await.unwrap();
+    setup.storage.store_message(&redacted).await.unwrap();
+    let stored = setup.stored().await;
+    let edited = stored.iter().find(|message| message.id == edited.id).unwrap();
+    assert_eq!(text(&edited.content), "see you at 6");
+    let redacted = stored.iter().find(|message| message.id == redacted.id).unwrap();
+    assert!(matches!(redacted.content, MessageContent::Redacted));
+    assert_eq!(setup.search("wrong").await, 0);
+}
+
+#[tokio::test]
+async fn only_the_sender_can_change_a_message() {
+    let setup = Setup::new().await;
+    let original = setup.message(&setup.alice, text_content("I'll bring dessert"), 0);
+    setup.storage.store_message(&original).await.unwrap();
+
+    let forged = setup.edit(&setup.bob, &original, "I'll bring nothing", 1_000);
+    assert!(matches!(setup.storage.authorize_change(&forged).await, Err(VeterError::Authentication(_))));
+    assert!(matches!(setup.storage.store_message(&forged).await, Err(VeterError::Authentication(_))));
+    let forged = setup.redact(&setup.bob, &original, 1_000);
+    assert!(matches!(setup.storage.store_message(&forged).await, Err(VeterError::Authentication(_))));
+    assert_eq!(text(&setup.stored().await[0].content), "I'll bring dessert");
+
+    // A forged change waiting for its message is dropped when it arrives
+    let later = setup.message(&setup.alice, text_content("running late"), 2_000);
+    setup.storage.store_message(&setup.redact(&setup.bob, &later, 3_000)).await.unwrap();
+    setup.storage.store_message(&setup.edit(&setup.bob, &later, "not coming", 3_000)).await.unwrap();
+    setup.storage.store_message(&later).await.unwrap();
+    let stored = setup.stored().await;
+    let later = stored.iter().find(|message| message.id == later.id).unwrap();
+    assert_eq!(text(&later.content), "running late");
+    assert!(setup.storage.get_message_history(&later.id).await.unwrap().is_empty());
+
+    // Only message content can be edited, into message content
+    let event = setup.message(&setup.alice, MessageContent::System("Alice joined".to_string()), 4_000);
+    setup.storage.store_message(&event).await.unwrap();
+    let edit = setup.edit(&setup.alice, &event, "Alice left", 5_000);
+    assert!(matches!(setup.storage.store_message(&edit).await, Err(VeterError::InvalidInput(_))));
+    let nested = setup.message(&setup.alice, MessageContent::Edit {
+        target_message_id: original.id,
+        content: Box::new(MessageContent::Redacted),
+    }, 5_000);
+    assert!(matches!(setup.storage.store_message(&nested).await, Err(VeterError::InvalidInput(_))));
+}
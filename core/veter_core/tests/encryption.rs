@@ -0,0 +1,129 @@
+//! Database encryption tests: the file is unreadable without the password,
+//! and changing the password re-keys it
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::VeterError;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use common::{PASSWORD, TempDb};
+
+const NEW_PASSWORD: &str = "tr0ub4dor&3";
+const USERNAME: &str = "unmistakable-username";
+
+impl TempDb {
+    fn header(&self) -> Vec<u8> {
+        std::fs::read(&self.0).unwrap()[..16].to_vec()
+    }
+}
+
+fn user() -> User {
+    User {
+        id: Uuid::new_v4(),
+        username: USERNAME.to_string(),
+        display_name: "Alice".to_string(),
+        avatar_url: None,
+        created_at: chrono::Utc::now(),
+    }
+}
+
+async fn open(db: &TempDb, password: &str) -> veter_core::Result<StorageManager> {
+    StorageManager::new(&db.0, password).await
+}
+
+#[tokio::test]
+async fn database_file_is_encrypted() {
+    let db = TempDb::new("encryption");
+    let storage = open(&db, PASSWORD).await.unwrap();
+    storage.store_user(&user()).await.unwrap();
+    storage.close().await;
+
+    let contents = std::fs::read(&db.0).unwrap();
+    assert!(!contents.starts_with(b"SQLite format 3\0"));
+    assert!(!contents.windows(USERNAME.len()).any(|window| window == USERNAME.as_bytes()));
+}
+
+#[tokio::test]
+async fn refuses_a_wrong_password() {
+    let db = TempDb::new("encryption");
+    open(&db, PASSWORD).await.unwrap().close().await;
+
+    assert!(matches!(open(&db, "wrong password").await, Err(VeterError::Authentication(_))));
+    assert!(open(&db, PASSWORD).await.is_ok());
+}
+
+#[tokio::test]
+async fn changing_the_password_rekeys_the_database() {
+    let db = TempDb::new("encryption");
+    let user = user();
+    let storage = open(&db, PASSWORD).await.unwrap();
+    storage.store_user(&user).await.unwrap();
+    let salt = db.header();
+
+    storage.change_password(PASSWORD, NEW_PASSWORD).await.unwrap();
+    // The open manager keeps working under the new key
+    assert_eq!(storage.get_user(&user.id).await.unwrap().unwrap().username, USERNAME);
+    let later = User { id: Uuid::new_v4(), username: "later".to_string(), ..user.clone() };
+    storage.store_user(&later).await.unwrap();
+    storage.close().await;
+
+    // A fresh salt, so the new key is not derived from the old one
+    assert_ne!(db.header(), salt);
+    assert!(matches!(open(&db, PASSWORD).await, Err(VeterError::Authentication(_))));
+    let storage = open(&db, NEW_PASSWORD).await.unwrap();
+    assert_eq!(storage.get_user(&user.id).await.unwrap().unwrap().username, USERNAME);
+    assert!(storage.get_user(&later.id).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn changing_the_password_needs_the_current_one() {
+    let db = TempDb::new("encryption");
+    let user = user();
+    let storage = open(&db, PASSWORD).await.unwrap();
+    storage.store_user(&user).await.unwrap();
+
+    let result = storage.change_password("wrong password", NEW_PASSWORD).await;
+    assert!(matches!(result, Err(VeterError::Authentication(_))));
+    assert!(storage.get_user(&user.id).await.unwrap().is_some());
+    storage.close().await;
+
+    assert!(matches!(open(&db, NEW_PASSWORD).await, Err(VeterError::Authentication(_))));
+    assert!(open(&db, PASSWORD).await.is_ok());
+}
+
+#[tokio::test]
+async fn password_can_change_twice() {
+    let db = TempDb::new("encryption");
+    let storage = open(&db, PASSWORD).await.unwrap();
+    storage.change_password(PASSWORD, NEW_PASSWORD).await.unwrap();
+    storage.change_password(NEW_PASSWORD, PASSWORD).await.unwrap();
+    storage.close().await;
+
+    assert!(matches!(open(&db, NEW_PASSWORD).await, Err(VeterError::Authentication(_))));
+    let storage = open(&db, PASSWORD).await.unwrap();
+    assert_eq!(storage.schema_version().await.unwrap(), veter_core::storage::migrations::SCHEMA_VERSION);
+}
+
+#[tokio::test]
+async fn calls_during_a_password_change_use_the_new_key() {
+    let db = TempDb::new("encryption");
+    let storage = std::sync::Arc::new(open(&db, PASSWORD).await.unwrap());
+
+    let users: Vec<User> = (0..8).map(|i| User { username: format!("user-{}", i), ..user() }).collect();
+    let mut tasks = Vec::new();
+    for user in users.clone() {
+        let storage = storage.clone();
+        tasks.push(tokio::spawn(async move { storage.store_user(&user).await }));
+    }
+    storage.change_password(PASSWORD, NEW_PASSWORD).await.unwrap();
+    for task in tasks {
+        task.await.unwrap().unwrap();
+    }
+    storage.close().await;
+
+    let storage = open(&db, NEW_PASSWORD).await.unwrap();
+    for user in &users {
+        assert!(storage.get_user(&user.id).await.unwrap().is_some());
+    }
+}
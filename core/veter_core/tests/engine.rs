@@ -0,0 +1,274 @@
+//! Engine lifecycle tests: keys and queued messages surviving a restart
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::crypto::CryptoManager;
+use veter_core::models::*;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+use veter_core::{CoreConfig, VeterError};
+use common::{PASSWORD, TempDb, user};
+
+/// Relay that accepts every message
+#[derive(Default)]
+struct TestRelay {
+    queued: Mutex<Vec<Vec<u8>>>,
+}
+
+#[tonic::async_trait]
+impl Relay for TestRelay {
+    async fn enqueue(&self, request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        let accepted_ids: Vec<_> = request.into_inner().messages.into_iter().map(|message| message.id).collect();
+        self.queued.lock().unwrap().extend(accepted_ids.iter().cloned());
+
+        Ok(Response::new(EnqueueResponse { accepted_ids }))
+    }
+
+    async fn dequeue(&self, _request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        Ok(Response::new(DequeueResponse { messages: Vec::new() }))
+    }
+
+    async fn ack(&self, _request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Delivery, Status>>;
+
+    async fn subscribe(&self, _request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+}
+
+/// A user's device with its identity private key
+struct Peer {
+    user: User,
+    device: Device,
+    identity_key: Vec<u8>,
+}
+
+impl Peer {
+    fn new(name: &str) -> Self {
+        let (identity_key, public_key) = CryptoManager::generate_identity_keypair().unwrap();
+        let (user, device) = user(name, public_key);
+
+        Self { user, device, identity_key }
+    }
+
+    fn crypto(&self) -> CryptoManager {
+        CryptoManager::new(self.identity_key.clone(), self.device.id).unwrap()
+    }
+
+    fn config(&self, db: &TempDb) -> CoreConfig {
+        CoreConfig::new(&db.0, PASSWORD, self.device.id, &self.identity_key)
+    }
+}
+
+fn message(room_id: RoomId, sender_device_id: DeviceId) -> EncryptedMessage {
+    EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id,
+        sender_device_id,
+        payload: vec![1, 2, 3],
+        timestamp: chrono::Utc::now(),
+        recipient_device_ids: vec![Uuid::new_v4()],
+    }
+}
+
+#[test]
+fn restores_rooms_and_sessions_after_a_restart() {
+    let db = TempDb::new("engine");
+    let alice = Peer::new("alice");
+    let bob = Peer::new("bob");
+    let mut bob_crypto = bob.crypto();
+    let now = chrono::Utc::now();
+    let room = Room {
+        id: Uuid::new_v4(),
+        name: "Lunch".to_string(),
+        description: None,
+        room_type: RoomType::Direct,
+        encryption: RoomEncryption::Signal,
+        members: vec![alice.user.id, bob.user.id],
+        created_at: now,
+        updated_at: now,
+    };
+
+    let core = veter_core::init(alice.config(&db)).unwrap();
+    core.block_on(async {
+        for peer in [&alice, &bob] {
+            core.storage().store_user(&peer.user).await.unwrap();
+            core.storage().store_device(&peer.device).await.unwrap();
+        }
+        core.storage().store_room(&room).await.unwrap();
+    });
+    let bundle = bob_crypto.generate_key_material(5).unwrap();
+    let first_id = Uuid::new_v4();
+    let (first, session) = {
+        let mut crypto = core.crypto().unwrap();
+        crypto.update_room(&room, &[]).unwrap();
+        crypto.start_session(room.id, bob.device.id, &bundle).unwrap();
+        let first = crypto.encrypt_message(b"first", room.id, first_id).unwrap();
+        (first, crypto.get_session(room.id, bob.device.id).unwrap().session_data.clone())
+    };
+    veter_core::cleanup(core).unwrap();
+
+    let core = veter_core::init(alice.config(&db)).unwrap();
+    let second_id = Uuid::new_v4();
+    let second = {
+        let mut crypto = core.crypto().unwrap();
+        assert_eq!(crypto.get_session(room.id, bob.device.id).unwrap().session_data, session);
+        // The room is known without telling the crypto manager again
+        crypto.encrypt_message(b"second", room.id, second_id).unwrap()
+    };
+    veter_core::cleanup(core).unwrap();
+
+    bob_crypto.update_room(&room, &[]).unwrap();
+    assert_eq!(bob_crypto.decrypt_message(&first, room.id, alice.device.id, first_id).unwrap(), b"first");
+    assert_eq!(bob_crypto.decrypt_message(&second, room.id, alice.device.id, second_id).unwrap(), b"second");
+}
+
+#[test]
+fn accepts_sessions_to_prekeys_published_before_a_restart() {
+    let db = TempDb::new("engine");
+    let alice = Peer::new("alice");
+    let bob = Peer::new("bob");
+    let mut alice_crypto = alice.crypto();
+    let now = chrono::Utc::now();
+    let room = Room {
+        id: Uuid::new_v4(),
+        name: "Lunch".to_string(),
+        description: None,
+        room_type: RoomType::Direct,
+        encryption: RoomEncryption::Signal,
+        members: vec![alice.user.id, bob.user.id],
+        created_at: now,
+        updated_at: now,
+    };
+
+    let core = veter_core::init(bob.config(&db)).unwrap();
+    core.block_on(async {
+        for peer in [&alice, &bob] {
+            core.storage().store_user(&peer.user).await.unwrap();
+            core.storage().store_device(&peer.device).await.unwrap();
+        }
+        core.storage().store_room(&room).await.unwrap();
+    });
+    let bundle = core.crypto().unwrap().generate_key_material(5).unwrap();
+    veter_core::cleanup(core).unwrap();
+
+    alice_crypto.update_room(&room, &[]).unwrap();
+    alice_crypto.start_session(room.id, bob.device.id, &bundle).unwrap();
+    let message_id = Uuid::new_v4();
+    let encrypted = alice_crypto.encrypt_message(b"hi bob", room.id, message_id).unwrap();
+
+    let core = veter_core::init(bob.config(&db)).unwrap();
+    {
+        let mut crypto = core.crypto().unwrap();
+        assert_eq!(crypto.one_time_prekey_count(), 5);
+        assert_eq!(crypto.decrypt_message(&encrypted, room.id, alice.device.id, message_id).unwrap(), b"hi bob");
+        assert_eq!(crypto.one_time_prekey_count(), 4);
+    }
+    veter_core::cleanup(core).unwrap();
+}
+
+#[test]
+fn drops_sessions_of_members_who_left_while_stopped() {
+    let db = TempDb::new("engine");
+    let alice = Peer::new("alice");
+    let bob = Peer::new("bob");
+    let carol = Peer::new("carol");
+    let now = chrono::Utc::now();
+    let room = Room {
+        id: Uuid::new_v4(),
+        name: "Lunch".to_string(),
+        description: None,
+        room_type: RoomType::Group,
+        encryption: RoomEncryption::Signal,
+        members: vec![alice.user.id, bob.user.id],
+        created_at: now,
+        updated_at: now,
+    };
+    let session = |device_id| Session {
+        room_id: room.id,
+        device_id,
+        session_data: vec![1, 2, 3],
+        created_at: now,
+        updated_at: now,
+    };
+
+    let core = veter_core::init(alice.config(&db)).unwrap();
+    core.block_on(async {
+        for peer in [&alice, &bob, &carol] {
+            core.storage().store_user(&peer.user).await.unwrap();
+            core.storage().store_device(&peer.device).await.unwrap();
+        }
+        // Carol was removed from the room after her session was stored
+        core.storage().store_room(&room).await.unwrap();
+        core.storage().store_session(&session(bob.device.id)).await.unwrap();
+        core.storage().store_session(&session(carol.device.id)).await.unwrap();
+    });
+    veter_core::cleanup(core).unwrap();
+
+    let core = veter_core::init(alice.config(&db)).unwrap();
+    {
+        let crypto = core.crypto().unwrap();
+        assert!(crypto.get_session(room.id, bob.device.id).is_some());
+        assert!(crypto.get_session(room.id, carol.device.id).is_none());
+    }
+    let stored: Vec<_> = core.block_on(core.storage().get_all_sessions()).unwrap()
+        .into_iter()
+        .map(|session| session.device_id)
+        .collect();
+    assert_eq!(stored, vec![bob.device.id]);
+    veter_core::cleanup(core).unwrap();
+}
+
+#[test]
+fn sends_messages_queued_without_a_relay_once_connected() {
+    let db = TempDb::new("engine");
+    let alice = Peer::new("alice");
+    let message = message(Uuid::new_v4(), alice.device.id);
+
+    let core = veter_core::init(alice.config(&db)).unwrap();
+    assert!(core.outbox().is_none());
+    core.send(&message).unwrap();
+    veter_core::cleanup(core).unwrap();
+
+    // The relay runs on its own runtime, as it would in another process
+    let relay = Arc::new(TestRelay::default());
+    let server = tokio::runtime::Runtime::new().unwrap();
+    let listener = server.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+    let mut config = alice.config(&db);
+    config.relay_endpoint = Some(format!("http://{}", listener.local_addr().unwrap()));
+    server.spawn(
+        Server::builder()
+            .add_service(RelayServer::from_arc(relay.clone()))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let core = veter_core::init(config).unwrap();
+    let outbox = core.outbox().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while core.block_on(outbox.delivery_state(&message.id)).unwrap() != Some(DeliveryState::Sent) {
+        assert!(Instant::now() < deadline, "Message was never sent");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(*relay.queued.lock().unwrap(), vec![message.id.as_bytes().to_vec()]);
+    veter_core::cleanup(core).unwrap();
+}
+
+#[test]
+fn refuses_a_wrong_passphrase() {
+    let db = TempDb::new("engine");
+    let alice = Peer::new("alice");
+    veter_core::cleanup(veter_core::init(alice.config(&db)).unwrap()).unwrap();
+
+    let mut config = alice.config(&db);
+    config.passphrase = "wrong passphrase".to_string().into();
+    assert!(matches!(veter_core::init(config), Err(VeterError::Authentication(_))));
+}
@@ -0,0 +1,117 @@
+//! Envelope tests: round trips, and rejection of legacy and unknown layouts
+
+use uuid::Uuid;
+use veter_core::VeterError;
+use veter_core::crypto::envelope::{self, Envelope, GroupEnvelope, MlsEnvelope, RecipientKey, ENVELOPE_VERSION};
+
+fn envelope() -> Envelope {
+    Envelope {
+        recipients: vec![RecipientKey {
+            device_id: Uuid::new_v4(),
+            initial_message: None,
+            wrapped_key: vec![7; 48],
+        }],
+        nonce: [1; 12],
+        ciphertext: vec![2; 40],
+    }
+}
+
+fn assert_invalid<T: std::fmt::Debug>(result: veter_core::Result<T>) {
+    match result {
+        Err(VeterError::InvalidEnvelope(_)) => {}
+        other => panic!("expected InvalidEnvelope, got {:?}", other),
+    }
+}
+
+#[test]
+fn message_envelopes_round_trip() {
+    let sent = envelope();
+    let received = Envelope::from_bytes(&sent.to_bytes().unwrap()).unwrap();
+
+    assert_eq!(received.nonce, sent.nonce);
+    assert_eq!(received.ciphertext, sent.ciphertext);
+    let device_id = sent.recipients[0].device_id;
+    assert_eq!(received.recipient(device_id).unwrap().wrapped_key, vec![7; 48]);
+    assert_invalid(received.recipient(Uuid::new_v4()));
+}
+
+#[test]
+fn rejects_legacy_layouts() {
+    // Before envelopes, a message was the bare ratchet ciphertext
+    assert_invalid(Envelope::from_bytes(&bincode::serialize(&envelope()).unwrap()));
+    assert_invalid(Envelope::from_bytes(&[0u8; 64]));
+    assert_invalid(Envelope::from_bytes(b"VTE"));
+    assert_invalid(Envelope::from_bytes(&[]));
+
+    // Before envelopes, a file blob was key ‖ nonce ‖ ciphertext
+    let mut legacy_file = vec![3u8; 32];
+    legacy_file.extend_from_slice(&[4u8; 12]);
+    legacy_file.extend_from_slice(&[5u8; 32]);
+    assert_invalid(envelope::open_file(&[3u8; 32], &legacy_file));
+}
+
+#[test]
+fn rejects_unknown_versions() {
+    let mut data = envelope().to_bytes().unwrap();
+    assert_eq!(data[3], ENVELOPE_VERSION);
+    data[3] = ENVELOPE_VERSION + 1;
+    assert_invalid(Envelope::from_bytes(&data));
+
+    let group = GroupEnvelope {
+        distributions: Vec::new(),
+        key_id: 1,
+        iteration: 0,
+        ciphertext: vec![1; 16],
+        signature: vec![2; 64],
+    };
+    let mut data = group.to_bytes().unwrap();
+    assert!(GroupEnvelope::matches(&data));
+    data[3] = 0;
+    assert_invalid(GroupEnvelope::from_bytes(&data));
+
+    let mut data = MlsEnvelope { message: vec![1; 16] }.to_bytes().unwrap();
+    assert!(MlsEnvelope::matches(&data));
+    data[3] = ENVELOPE_VERSION + 1;
+    assert_invalid(MlsEnvelope::from_bytes(&data));
+
+    let (key, mut blob) = envelope::seal_file(b"file").unwrap();
+    blob[3] = ENVELOPE_VERSION + 1;
+    assert_invalid(envelope::open_file(&key, &blob));
+}
+
+#[test]
+fn envelope_kinds_are_not_interchangeable() {
+    let data = envelope().to_bytes().unwrap();
+    assert!(!GroupEnvelope::matches(&data));
+    assert!(!MlsEnvelope::matches(&data));
+    assert_invalid(GroupEnvelope::from_bytes(&data));
+    assert_invalid(MlsEnvelope::from_bytes(&data));
+}
+
+#[test]
+fn rejects_truncated_envelopes() {
+    let data = envelope().to_bytes().unwrap();
+    assert_invalid(Envelope::from_bytes(&data[..data.len() - 10]));
+}
+
+#[test]
+fn content_is_bound_to_its_associated_data() {
+    let (room_id, device_id, message_id) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+    let ad = envelope::associated_data(room_id, device_id, message_id);
+    let (key, nonce, ciphertext) = envelope::seal(b"hello", &ad).unwrap();
+
+    assert_eq!(envelope::open(key.as_ref(), &nonce, &ciphertext, &ad).unwrap(), b"hello");
+    let other = envelope::associated_data(room_id, device_id, Uuid::new_v4());
+    assert_invalid(envelope::open(key.as_ref(), &nonce, &ciphertext, &other));
+    assert_invalid(envelope::open(&key[..16], &nonce, &ciphertext, &ad));
+}
+
+#[test]
+fn files_round_trip_and_reject_tampering() {
+    let (key, mut blob) = envelope::seal_file(b"file content").unwrap();
+    assert_eq!(envelope::open_file(&key, &blob).unwrap(), b"file content");
+
+    let last = blob.len() - 1;
+    blob[last] ^= 1;
+    assert_invalid(envelope::open_file(&key, &blob));
+}
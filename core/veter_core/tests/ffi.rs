@@ -0,0 +1,343 @@
+//! C interface tests, calling the exported functions the way the app does
+
+mod common;
+
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio_stream::Stream;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::ffi::*;
+use veter_core::ffi::crypto::*;
+use veter_core::ffi::network::*;
+use veter_core::ffi::storage::*;
+use veter_core::models::*;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+use common::{PASSWORD, TempDb, user};
+
+impl TempDb {
+    fn path(&self) -> CString {
+        CString::new(self.0.to_str().unwrap()).unwrap()
+    }
+}
+
+/// Relay pushing the given messages to every subscriber, then staying open
+struct PushRelay {
+    messages: Vec<Ciphertext>,
+}
+
+#[tonic::async_trait]
+impl Relay for PushRelay {
+    async fn enqueue(&self, _request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+
+    async fn dequeue(&self, _request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+
+    async fn ack(&self, _request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<Delivery, Status>> + Send>>;
+
+    async fn subscribe(&self, _request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let deliveries: Vec<_> = self.messages.iter()
+            .map(|message| Delivery { message: Some(message.clone()), cursor: message.id.clone() })
+            .map(Ok)
+            .collect();
+        let stream = tokio_stream::StreamExt::chain(tokio_stream::iter(deliveries), tokio_stream::pending());
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+fn init() {
+    assert_eq!(veter_init(), VeterStatus::Ok);
+}
+
+fn c(text: &str) -> CString {
+    CString::new(text).unwrap()
+}
+
+fn json(value: &impl serde::Serialize) -> CString {
+    c(&serde_json::to_string(value).unwrap())
+}
+
+/// Copy and free a string returned by the core
+unsafe fn take_string(string: *mut c_char) -> String {
+    assert!(!string.is_null());
+    let copy = CStr::from_ptr(string).to_str().unwrap().to_string();
+    veter_string_free(string);
+    copy
+}
+
+/// Copy and free a buffer returned by the core
+unsafe fn take_buffer(buffer: VeterBuffer) -> Vec<u8> {
+    let copy = std::slice::from_raw_parts(buffer.data, buffer.len).to_vec();
+    veter_buffer_free(buffer);
+    copy
+}
+
+fn last_error() -> Option<String> {
+    let message = veter_last_error_message();
+    (!message.is_null()).then(|| unsafe { take_string(message) })
+}
+
+/// A device's crypto handle with its identity
+struct Peer {
+    user: User,
+    device: Device,
+    crypto: *mut VeterCrypto,
+}
+
+impl Peer {
+    fn new(name: &str) -> Self {
+        let mut private_key = VeterBuffer { data: ptr::null_mut(), len: 0 };
+        let mut public_key = VeterBuffer { data: ptr::null_mut(), len: 0 };
+        unsafe {
+            assert_eq!(veter_crypto_generate_identity_keypair(&mut private_key, &mut public_key), VeterStatus::Ok);
+        }
+        let (private_key, public_key) = unsafe { (take_buffer(private_key), take_buffer(public_key)) };
+
+        let (user, device) = user(name, public_key);
+
+        let mut crypto = ptr::null_mut();
+        let device_id = c(&device.id.to_string());
+        unsafe {
+            assert_eq!(veter_crypto_new(private_key.as_ptr(), private_key.len(), device_id.as_ptr(), &mut crypto), VeterStatus::Ok);
+        }
+        Self { user, device, crypto }
+    }
+}
+
+impl Drop for Peer {
+    fn drop(&mut self) {
+        unsafe { veter_crypto_free(self.crypto) };
+    }
+}
+
+fn room(members: &[&Peer]) -> Room {
+    let now = chrono::Utc::now();
+    Room {
+        id: Uuid::new_v4(),
+        name: "Lunch".to_string(),
+        description: None,
+        room_type: RoomType::Direct,
+        encryption: RoomEncryption::Signal,
+        members: members.iter().map(|peer| peer.user.id).collect(),
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+#[test]
+fn stores_and_loads_models_as_json() {
+    init();
+    let db = TempDb::new("ffi");
+    let alice = Peer::new("alice");
+    let room = room(&[&alice]);
+    let message = Message {
+        id: Uuid::new_v4(),
+        room_id: room.id,
+        sender_id: alice.user.id,
+        sender_device_id: alice.device.id,
+        content: MessageContent::Text("meet at noon".to_string()),
+        created_at: chrono::Utc::now(),
+        edited_at: None,
+        reply_to: None,
+    };
+
+    unsafe {
+        let mut storage = ptr::null_mut();
+        assert_eq!(veter_storage_open(db.path().as_ptr(), c(PASSWORD).as_ptr(), &mut storage), VeterStatus::Ok);
+        assert_eq!(veter_storage_store_user(storage, json(&alice.user).as_ptr()), VeterStatus::Ok);
+        assert_eq!(veter_storage_store_device(storage, json(&alice.device).as_ptr()), VeterStatus::Ok);
+        assert_eq!(veter_storage_store_room(storage, json(&room).as_ptr()), VeterStatus::Ok);
+        assert_eq!(veter_storage_store_message(storage, json(&message).as_ptr()), VeterStatus::Ok);
+
+        let mut page_json = ptr::null_mut();
+        let room_id = c(&room.id.to_string());
+        let status = veter_storage_get_messages(storage, room_id.as_ptr(), json(&MessageQuery::latest(10)).as_ptr(), &mut page_json);
+        assert_eq!(status, VeterStatus::Ok);
+        let page: MessagePage = serde_json::from_str(&take_string(page_json)).unwrap();
+        assert_eq!(page.messages.len(), 1);
+        assert_eq!(page.messages[0].id, message.id);
+
+        let mut results_json = ptr::null_mut();
+        let status = veter_storage_search_messages(storage, c("noon").as_ptr(), ptr::null(), 10, &mut results_json);
+        assert_eq!(status, VeterStatus::Ok);
+        let results: Vec<SearchResult> = serde_json::from_str(&take_string(results_json)).unwrap();
+        assert_eq!(results.len(), 1);
+
+        // Malformed JSON is reported, not a crash
+        assert_eq!(veter_storage_store_message(storage, c("{").as_ptr()), VeterStatus::Serialization);
+        assert!(last_error().unwrap().contains("Invalid message"));
+
+        veter_storage_free(storage);
+    }
+}
+
+#[test]
+fn maps_errors_to_status_codes() {
+    init();
+    let db = TempDb::new("ffi");
+
+    unsafe {
+        let mut storage = ptr::null_mut();
+        assert_eq!(veter_storage_open(db.path().as_ptr(), c(PASSWORD).as_ptr(), &mut storage), VeterStatus::Ok);
+        veter_storage_free(storage);
+
+        let mut storage = ptr::null_mut();
+        let status = veter_storage_open(db.path().as_ptr(), c("wrong password").as_ptr(), &mut storage);
+        assert_eq!(status, VeterStatus::Authentication);
+        assert!(storage.is_null());
+        assert!(last_error().unwrap().contains("Wrong database password"));
+        // Taking the message clears it
+        assert_eq!(last_error(), None);
+
+        assert_eq!(veter_storage_open(ptr::null(), c(PASSWORD).as_ptr(), &mut storage), VeterStatus::InvalidInput);
+        assert_eq!(veter_storage_store_user(ptr::null(), c("{}").as_ptr()), VeterStatus::InvalidInput);
+        assert!(last_error().unwrap().contains("handle is null"));
+        assert_eq!(veter_crypto_update_room(ptr::null(), c("{}").as_ptr(), c("[]").as_ptr(), &mut ptr::null_mut()), VeterStatus::InvalidInput);
+        assert_eq!(veter_crypto_new(ptr::null(), 0, c("not a uuid").as_ptr(), &mut ptr::null_mut()), VeterStatus::InvalidInput);
+        assert_eq!(veter_crypto_new(ptr::null(), 32, c(&Uuid::new_v4().to_string()).as_ptr(), &mut ptr::null_mut()), VeterStatus::InvalidInput);
+
+        // Freeing null is a no-op
+        veter_storage_free(ptr::null_mut());
+        veter_string_free(ptr::null_mut());
+        veter_buffer_free(VeterBuffer { data: ptr::null_mut(), len: 0 });
+    }
+}
+
+#[test]
+fn encrypts_between_crypto_handles() {
+    init();
+    let alice = Peer::new("alice");
+    let bob = Peer::new("bob");
+    let room = room(&[&alice, &bob]);
+    let (room_id, message_id) = (c(&room.id.to_string()), c(&Uuid::new_v4().to_string()));
+    let plaintext = b"hi bob";
+
+    unsafe {
+        let mut bundle_json = ptr::null_mut();
+        assert_eq!(veter_crypto_generate_key_material(bob.crypto, 5, &mut bundle_json), VeterStatus::Ok);
+        let bundle = c(&take_string(bundle_json));
+        for peer in [&alice, &bob] {
+            let mut removed_json = ptr::null_mut();
+            assert_eq!(veter_crypto_update_room(peer.crypto, json(&room).as_ptr(), c("[]").as_ptr(), &mut removed_json), VeterStatus::Ok);
+            assert_eq!(take_string(removed_json), "[]");
+        }
+        let bob_device_id = c(&bob.device.id.to_string());
+        assert_eq!(veter_crypto_start_session(alice.crypto, room_id.as_ptr(), bob_device_id.as_ptr(), bundle.as_ptr()), VeterStatus::Ok);
+
+        let mut payload = VeterBuffer { data: ptr::null_mut(), len: 0 };
+        let status = veter_crypto_encrypt_message(alice.crypto, plaintext.as_ptr(), plaintext.len(), room_id.as_ptr(), message_id.as_ptr(), &mut payload);
+        assert_eq!(status, VeterStatus::Ok);
+        let payload = take_buffer(payload);
+
+        let alice_device_id = c(&alice.device.id.to_string());
+        let mut content = VeterBuffer { data: ptr::null_mut(), len: 0 };
+        let status = veter_crypto_decrypt_message(bob.crypto, payload.as_ptr(), payload.len(), room_id.as_ptr(), alice_device_id.as_ptr(), message_id.as_ptr(), &mut content);
+        assert_eq!(status, VeterStatus::Ok);
+        assert_eq!(take_buffer(content), plaintext);
+
+        // The same message under another ID fails authentication
+        let other_id = c(&Uuid::new_v4().to_string());
+        let mut content = VeterBuffer { data: ptr::null_mut(), len: 0 };
+        let status = veter_crypto_decrypt_message(bob.crypto, payload.as_ptr(), payload.len(), room_id.as_ptr(), alice_device_id.as_ptr(), other_id.as_ptr(), &mut content);
+        assert_ne!(status, VeterStatus::Ok);
+        assert!(last_error().is_some());
+
+        let (mut key, mut blob) = (VeterBuffer { data: ptr::null_mut(), len: 0 }, VeterBuffer { data: ptr::null_mut(), len: 0 });
+        assert_eq!(veter_crypto_encrypt_file(alice.crypto, plaintext.as_ptr(), plaintext.len(), &mut key, &mut blob), VeterStatus::Ok);
+        let (key, blob) = (take_buffer(key), take_buffer(blob));
+        let mut content = VeterBuffer { data: ptr::null_mut(), len: 0 };
+        assert_eq!(veter_crypto_decrypt_file(bob.crypto, key.as_ptr(), key.len(), blob.as_ptr(), blob.len(), &mut content), VeterStatus::Ok);
+        assert_eq!(take_buffer(content), plaintext);
+    }
+}
+
+#[test]
+fn returns_the_devices_of_members_who_left() {
+    init();
+    let alice = Peer::new("alice");
+    let bob = Peer::new("bob");
+    let mut room = room(&[&alice, &bob]);
+    let room_id = c(&room.id.to_string());
+
+    unsafe {
+        let mut bundle_json = ptr::null_mut();
+        assert_eq!(veter_crypto_generate_key_material(bob.crypto, 1, &mut bundle_json), VeterStatus::Ok);
+        let bundle = c(&take_string(bundle_json));
+        let mut removed_json = ptr::null_mut();
+        assert_eq!(veter_crypto_update_room(alice.crypto, json(&room).as_ptr(), c("[]").as_ptr(), &mut removed_json), VeterStatus::Ok);
+        take_string(removed_json);
+        let bob_device_id = c(&bob.device.id.to_string());
+        assert_eq!(veter_crypto_start_session(alice.crypto, room_id.as_ptr(), bob_device_id.as_ptr(), bundle.as_ptr()), VeterStatus::Ok);
+
+        room.members.retain(|member| *member != bob.user.id);
+        let devices = json(&[&bob.device]);
+        let mut removed_json = ptr::null_mut();
+        assert_eq!(veter_crypto_update_room(alice.crypto, json(&room).as_ptr(), devices.as_ptr(), &mut removed_json), VeterStatus::Ok);
+        let removed: Vec<Uuid> = serde_json::from_str(&take_string(removed_json)).unwrap();
+        assert_eq!(removed, vec![bob.device.id]);
+    }
+}
+
+extern "C" fn forward_event(user_data: *mut c_void, status: VeterStatus, json: *const c_char) {
+    let sender = unsafe { &*(user_data as *const mpsc::Sender<(VeterStatus, String)>) };
+    let json = unsafe { CStr::from_ptr(json) }.to_str().unwrap().to_string();
+    let _ = sender.send((status, json));
+}
+
+#[test]
+fn calls_back_with_pushed_messages() {
+    init();
+    let message = EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id: Uuid::new_v4(),
+        sender_device_id: Uuid::new_v4(),
+        payload: vec![1, 2, 3],
+        timestamp: chrono::DateTime::from_timestamp_millis(chrono::Utc::now().timestamp_millis()).unwrap(),
+        recipient_device_ids: Vec::new(),
+    };
+
+    // The relay runs on its own runtime, as it would in another process
+    let server = tokio::runtime::Runtime::new().unwrap();
+    let listener = server.block_on(tokio::net::TcpListener::bind("127.0.0.1:0")).unwrap();
+    let endpoint = c(&format!("http://{}", listener.local_addr().unwrap()));
+    server.spawn(
+        Server::builder()
+            .add_service(RelayServer::new(PushRelay { messages: vec![Ciphertext::from(&message)] }))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let (sender, events) = mpsc::channel::<(VeterStatus, String)>();
+    unsafe {
+        let mut network = ptr::null_mut();
+        assert_eq!(veter_network_new(&mut network), VeterStatus::Ok);
+        assert_eq!(veter_network_connect_relay(network, endpoint.as_ptr()), VeterStatus::Ok);
+
+        let mut subscription = ptr::null_mut();
+        let device_id = c(&Uuid::new_v4().to_string());
+        let user_data = &sender as *const _ as *mut c_void;
+        let status = veter_network_subscribe_messages(network, device_id.as_ptr(), 10, Some(forward_event), user_data, &mut subscription);
+        assert_eq!(status, VeterStatus::Ok);
+
+        let (status, json) = events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(status, VeterStatus::Ok);
+        let received: EncryptedMessage = serde_json::from_str(&json).unwrap();
+        assert_eq!(received.id, message.id);
+        assert_eq!(received.payload, message.payload);
+
+        assert_eq!(veter_subscription_cancel(subscription), VeterStatus::Ok);
+        veter_network_free(network);
+    }
+}
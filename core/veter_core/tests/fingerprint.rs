@@ -0,0 +1,134 @@
+//! Safety number tests: both sides agree, QR codes round trip, and devices
+//! whose verified key changes are flagged
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::VeterError;
+use veter_core::crypto::CryptoManager;
+use veter_core::crypto::fingerprint::{SafetyNumber, FINGERPRINT_VERSION};
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use common::{TempDb, user};
+
+/// A user's device with its keys
+struct Peer {
+    user_id: UserId,
+    public_key: Vec<u8>,
+    crypto: CryptoManager,
+}
+
+impl Peer {
+    fn new() -> Self {
+        let (private_key, public_key) = CryptoManager::generate_identity_keypair().unwrap();
+        let crypto = CryptoManager::new(private_key, Uuid::new_v4()).unwrap();
+
+        Self { user_id: Uuid::new_v4(), public_key, crypto }
+    }
+
+    fn safety_number(&self, peer: &Peer) -> SafetyNumber {
+        self.crypto.safety_number(self.user_id, peer.user_id, &peer.public_key).unwrap()
+    }
+}
+
+#[test]
+fn both_sides_see_the_same_safety_number() {
+    let (alice, bob) = (Peer::new(), Peer::new());
+    let seen_by_alice = alice.safety_number(&bob).displayable();
+    let seen_by_bob = bob.safety_number(&alice).displayable();
+
+    assert_eq!(seen_by_alice, seen_by_bob);
+    assert_eq!(seen_by_alice.len(), 60);
+    assert!(seen_by_alice.chars().all(|c| c.is_ascii_digit()));
+    // Deterministic for the same keys
+    assert_eq!(alice.safety_number(&bob).displayable(), seen_by_alice);
+}
+
+#[test]
+fn another_key_gives_another_safety_number() {
+    let (alice, bob, mallory) = (Peer::new(), Peer::new(), Peer::new());
+    let genuine = alice.safety_number(&bob).displayable();
+
+    // Mallory's key presented under Bob's user id
+    let substituted = alice.crypto.safety_number(alice.user_id, bob.user_id, &mallory.public_key).unwrap();
+    assert_ne!(substituted.displayable(), genuine);
+    // Bob's key under another user id
+    let renamed = alice.crypto.safety_number(alice.user_id, Uuid::new_v4(), &bob.public_key).unwrap();
+    assert_ne!(renamed.displayable(), genuine);
+}
+
+#[test]
+fn qr_codes_round_trip_between_both_sides() {
+    let (alice, bob) = (Peer::new(), Peer::new());
+    let alice_number = alice.safety_number(&bob);
+    let bob_number = bob.safety_number(&alice);
+
+    let payload = alice_number.qr_payload();
+    assert_eq!(payload[0], FINGERPRINT_VERSION);
+    assert!(bob_number.matches_qr(&payload).unwrap());
+    assert!(alice_number.matches_qr(&bob_number.qr_payload()).unwrap());
+    // Scanning one's own code is not a match
+    assert!(!alice_number.matches_qr(&payload).unwrap());
+}
+
+#[test]
+fn qr_codes_do_not_match_a_substituted_key() {
+    let (alice, bob, mallory) = (Peer::new(), Peer::new(), Peer::new());
+    // Alice was given Mallory's key for Bob
+    let alice_number = alice.crypto.safety_number(alice.user_id, bob.user_id, &mallory.public_key).unwrap();
+    let bob_number = bob.safety_number(&alice);
+
+    assert!(!bob_number.matches_qr(&alice_number.qr_payload()).unwrap());
+    assert!(!alice_number.matches_qr(&bob_number.qr_payload()).unwrap());
+}
+
+#[test]
+fn rejects_malformed_qr_codes_and_keys() {
+    let (alice, bob) = (Peer::new(), Peer::new());
+    let number = alice.safety_number(&bob);
+    let mut payload = bob.safety_number(&alice).qr_payload();
+
+    assert!(matches!(number.matches_qr(&payload[..payload.len() - 1]), Err(VeterError::InvalidInput(_))));
+    assert!(matches!(number.matches_qr(&[]), Err(VeterError::InvalidInput(_))));
+    payload[0] = FINGERPRINT_VERSION + 1;
+    assert!(matches!(number.matches_qr(&payload), Err(VeterError::InvalidInput(_))));
+
+    let short_key = &bob.public_key[..31];
+    assert!(matches!(alice.crypto.safety_number(alice.user_id, bob.user_id, short_key), Err(VeterError::KeyManagement(_))));
+}
+
+async fn verification(storage: &StorageManager, device_id: DeviceId) -> VerificationState {
+    storage.get_device(&device_id).await.unwrap().unwrap().verification
+}
+
+#[tokio::test]
+async fn verified_devices_are_flagged_when_their_key_changes() {
+    let db = TempDb::new("fingerprint");
+    let storage = db.open().await;
+    let (user, mut device) = user("bob", Peer::new().public_key);
+    storage.store_user(&user).await.unwrap();
+    storage.store_device(&device).await.unwrap();
+    assert_eq!(verification(&storage, device.id).await, VerificationState::Unverified);
+
+    storage.verify_device(&device.id).await.unwrap();
+    assert_eq!(verification(&storage, device.id).await, VerificationState::Verified);
+
+    let verified_key = device.public_key.clone();
+    device.public_key = Peer::new().public_key;
+    storage.store_device(&device).await.unwrap();
+    assert_eq!(verification(&storage, device.id).await, VerificationState::Changed);
+    let changed: Vec<_> = storage.get_changed_devices().await.unwrap().into_iter().map(|device| device.id).collect();
+    assert_eq!(changed, vec![device.id]);
+
+    // The verified key coming back restores the verification
+    device.public_key = verified_key;
+    storage.store_device(&device).await.unwrap();
+    assert_eq!(verification(&storage, device.id).await, VerificationState::Verified);
+
+    storage.unverify_device(&device.id).await.unwrap();
+    device.public_key = Peer::new().public_key;
+    storage.store_device(&device).await.unwrap();
+    assert_eq!(verification(&storage, device.id).await, VerificationState::Unverified);
+
+    assert!(matches!(storage.verify_device(&Uuid::new_v4()).await, Err(VeterError::InvalidInput(_))));
+}
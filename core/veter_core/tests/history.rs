@@ -0,0 +1,214 @@
+//! Message history tests: keyset paging in both directions, jumping to a
+//! message, and time ranges
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use veter_core::VeterError;
+use common::{TempDb, room, store_user};
+
+/// Storage knowing Alice and two rooms of hers
+struct Setup {
+    _db: TempDb,
+    storage: StorageManager,
+    room_id: RoomId,
+    other_room_id: RoomId,
+    alice: Device,
+}
+
+impl Setup {
+    async fn new() -> Self {
+        let db = TempDb::new("history");
+        let storage = db.open().await;
+        let alice = store_user(&storage, "alice").await;
+
+        let mut rooms = Vec::new();
+        for name in ["Lunch", "Work"] {
+            let room = room(name, vec![alice.user_id]);
+            storage.store_room(&room).await.unwrap();
+            rooms.push(room.id);
+        }
+
+        Self { _db: db, storage, room_id: rooms[0], other_room_id: rooms[1], alice }
+    }
+
+    /// Store a message in `room_id`, `second` seconds after a fixed start
+    async fn send_to(&self, room_id: RoomId, second: i64) -> Message {
+        let message = Message {
+            id: Uuid::new_v4(),
+            room_id,
+            sender_id: self.alice.user_id,
+            sender_device_id: self.alice.id,
+            content: MessageContent::Text(format!("at {}", second)),
+            created_at: at(second),
+            edited_at: None,
+            reply_to: None,
+        };
+        self.storage.store_message(&message).await.unwrap();
+        message
+    }
+
+    /// Store messages at the given seconds, returning their IDs in history
+    /// order: by creation time, then ID
+    async fn send_all(&self, seconds: &[i64]) -> Vec<MessageId> {
+        let mut messages = Vec::new();
+        for &second in seconds {
+            messages.push(self.send_to(self.room_id, second).await);
+        }
+        messages.sort_by_key(|message| (message.created_at, message.id));
+        messages.into_iter().map(|message| message.id).collect()
+    }
+
+    async fn page(&self, query: &MessageQuery) -> MessagePage {
+        self.storage.get_messages(&self.room_id, query).await.unwrap()
+    }
+}
+
+fn at(second: i64) -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::from_timestamp(1_700_000_000 + second, 0).unwrap()
+}
+
+fn ids(page: &MessagePage) -> Vec<MessageId> {
+    page.messages.iter().map(|message| message.id).collect()
+}
+
+fn query(direction: PageDirection, cursor: Option<MessageCursor>, limit: u32) -> MessageQuery {
+    MessageQuery { cursor, direction, ..MessageQuery::latest(limit) }
+}
+
+/// Several messages share each timestamp, so pages have to break ties by ID
+const SECONDS: [i64; 10] = [1, 1, 1, 2, 3, 3, 3, 3, 4, 5];
+
+#[tokio::test]
+async fn pages_backward_across_equal_timestamps() {
+    let setup = Setup::new().await;
+    let expected = setup.send_all(&SECONDS).await;
+
+    let mut seen = Vec::new();
+    let mut page = setup.page(&MessageQuery::latest(3)).await;
+    assert!(page.newer.is_none());
+    loop {
+        // Each page is oldest first and goes before the ones already seen
+        seen.splice(0..0, ids(&page));
+        match page.older.clone() {
+            Some(cursor) => page = setup.page(&query(PageDirection::Backward, Some(cursor), 3)).await,
+            None => break,
+        }
+        assert!(page.newer.is_some());
+    }
+    assert_eq!(seen, expected);
+}
+
+#[tokio::test]
+async fn pages_forward_across_equal_timestamps() {
+    let setup = Setup::new().await;
+    let expected = setup.send_all(&SECONDS).await;
+
+    let mut seen = Vec::new();
+    let mut page = setup.page(&query(PageDirection::Forward, None, 3)).await;
+    assert!(page.older.is_none());
+    loop {
+        seen.extend(ids(&page));
+        match page.newer.clone() {
+            Some(cursor) => page = setup.page(&query(PageDirection::Forward, Some(cursor), 3)).await,
+            None => break,
+        }
+        assert!(page.older.is_some());
+    }
+    assert_eq!(seen, expected);
+}
+
+#[tokio::test]
+async fn cursors_keep_their_place_when_messages_arrive() {
+    let setup = Setup::new().await;
+    let expected = setup.send_all(&SECONDS).await;
+
+    let first = setup.page(&MessageQuery::latest(4)).await;
+    assert_eq!(ids(&first), expected[6..]);
+    // New messages, including one at the timestamp of the page's edge
+    setup.send_to(setup.room_id, 6).await;
+    setup.send_to(setup.room_id, 3).await;
+
+    let second = setup.page(&query(PageDirection::Backward, first.older.clone(), 4)).await;
+    let second_ids = ids(&second);
+    assert!(second_ids.iter().all(|id| !ids(&first).contains(id)));
+    // Paging back towards the newer messages returns to the first page
+    let back = setup.page(&query(PageDirection::Forward, second.newer.clone(), 4)).await;
+    assert_eq!(back.messages[0].id, first.messages[0].id);
+}
+
+#[tokio::test]
+async fn pages_around_a_message() {
+    let setup = Setup::new().await;
+    let expected = setup.send_all(&SECONDS).await;
+
+    let page = setup.storage.get_messages_around(&setup.room_id, &expected[5], 4).await.unwrap();
+    assert_eq!(ids(&page), expected[3..7]);
+    // Paging on from either edge continues without gaps
+    let older = setup.page(&query(PageDirection::Backward, page.older.clone(), 10)).await;
+    assert_eq!(ids(&older), expected[..3]);
+    assert!(older.older.is_none());
+    let newer = setup.page(&query(PageDirection::Forward, page.newer.clone(), 10)).await;
+    assert_eq!(ids(&newer), expected[7..]);
+    assert!(newer.newer.is_none());
+
+    // At the start of the history the page only holds newer messages
+    let page = setup.storage.get_messages_around(&setup.room_id, &expected[0], 4).await.unwrap();
+    assert_eq!(ids(&page), expected[..2]);
+    assert!(page.older.is_none());
+    assert!(page.newer.is_some());
+}
+
+#[tokio::test]
+async fn refuses_to_page_around_unknown_messages() {
+    let setup = Setup::new().await;
+    setup.send_all(&SECONDS).await;
+    let elsewhere = setup.send_to(setup.other_room_id, 1).await;
+
+    let result = setup.storage.get_messages_around(&setup.room_id, &Uuid::new_v4(), 4).await;
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    // A message of another room is not in this room's history
+    let result = setup.storage.get_messages_around(&setup.room_id, &elsewhere.id, 4).await;
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+}
+
+#[tokio::test]
+async fn limits_pages_to_a_time_range() {
+    let setup = Setup::new().await;
+    let expected = setup.send_all(&SECONDS).await;
+    setup.send_to(setup.other_room_id, 3).await;
+
+    // `since` is inclusive and `until` exclusive
+    let range = MessageQuery { since: Some(at(2)), until: Some(at(4)), ..MessageQuery::latest(10) };
+    let page = setup.page(&range).await;
+    assert_eq!(ids(&page), expected[3..8]);
+    // Cursors stop at the edges of the range
+    assert!(page.older.is_none());
+    assert!(page.newer.is_none());
+
+    let page = setup.page(&MessageQuery { limit: 2, ..range.clone() }).await;
+    assert_eq!(ids(&page), expected[6..8]);
+    let rest = setup.page(&MessageQuery { cursor: page.older.clone(), ..range.clone() }).await;
+    assert_eq!(ids(&rest), expected[3..6]);
+    assert!(rest.older.is_none());
+
+    let since = setup.page(&MessageQuery { since: Some(at(4)), ..MessageQuery::latest(10) }).await;
+    assert_eq!(ids(&since), expected[8..]);
+    let until = setup.page(&MessageQuery { until: Some(at(2)), ..MessageQuery::latest(10) }).await;
+    assert_eq!(ids(&until), expected[..3]);
+    let empty = setup.page(&MessageQuery { since: Some(at(6)), ..MessageQuery::latest(10) }).await;
+    assert!(empty.messages.is_empty());
+}
+
+#[tokio::test]
+async fn rejects_malformed_cursors() {
+    let setup = Setup::new().await;
+    setup.send_all(&SECONDS).await;
+
+    for cursor in ["", "not base64!", "bm8gc2VwYXJhdG9y"] {
+        let result = setup.storage.get_messages(&setup.room_id, &query(PageDirection::Backward, Some(MessageCursor(cursor.to_string())), 3)).await;
+        assert!(matches!(result, Err(VeterError::InvalidInput(_))), "{:?} accepted", cursor);
+    }
+}
@@ -0,0 +1,409 @@
+//! Schema migration tests: every historical schema version must upgrade to
+//! the current one without losing data
+
+mod common;
+
+use sqlx::{Connection, SqliteConnection};
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use veter_core::storage::migrations::{MIGRATIONS, SCHEMA_VERSION};
+use veter_core::VeterError;
+use common::{PASSWORD, TempDb};
+
+const USER_ID: &str = "0b9c1f5e-7d1a-4f3e-9a55-3f8c2b1d0e01";
+const ROOM_ID: &str = "0b9c1f5e-7d1a-4f3e-9a55-3f8c2b1d0e02";
+const DEVICE_ID: &str = "0b9c1f5e-7d1a-4f3e-9a55-3f8c2b1d0e03";
+const MESSAGE_ID: &str = "0b9c1f5e-7d1a-4f3e-9a55-3f8c2b1d0e04";
+
+/// Create an unencrypted database at a historical schema version, as an
+/// install of that version left it, with one message in it
+async fn create_historical(db: &TempDb, version: u32, with_version_table: bool) {
+    let mut connection = SqliteConnection::connect(&format!("sqlite://{}?mode=rwc", db.0.display()))
+        .await
+        .unwrap();
+
+    for migration in MIGRATIONS.iter().take_while(|migration| migration.version <= version) {
+        for statement in migration.statements {
+            sqlx::query(statement).execute(&mut connection).await.unwrap();
+        }
+    }
+    if with_version_table {
+        sqlx::query("CREATE TABLE schema_version (version INTEGER NOT NULL)")
+            .execute(&mut connection)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO schema_version (version) VALUES (?)")
+            .bind(version as i64)
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    }
+
+    let now = "2024-01-01T00:00:00+00:00";
+    sqlx::query("INSERT INTO users (id, username, display_name, avatar_url, created_at) VALUES (?, 'alice', 'Alice', NULL, ?)")
+        .bind(USER_ID)
+        .bind(now)
+        .execute(&mut connection)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO devices (id, user_id, name, platform, public_key, created_at, last_seen) VALUES (?, ?, 'Laptop', 'Linux', ?, ?, ?)")
+        .bind(DEVICE_ID)
+        .bind(USER_ID)
+        .bind(vec![7u8; 32])
+        .bind(now)
+        .bind(now)
+        .execute(&mut connection)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO rooms (id, name, description, room_type, created_at, updated_at) VALUES (?, 'General', NULL, 'Group', ?, ?)")
+        .bind(ROOM_ID)
+        .bind(now)
+        .bind(now)
+        .execute(&mut connection)
+        .await
+        .unwrap();
+    sqlx::query("INSERT INTO messages (id, room_id, sender_id, sender_device_id, content, created_at, edited_at, reply_to) VALUES (?, ?, ?, ?, ?, ?, NULL, NULL)")
+        .bind(MESSAGE_ID)
+        .bind(ROOM_ID)
+        .bind(USER_ID)
+        .bind(DEVICE_ID)
+        .bind(r#"{"Text":"hello"}"#)
+        .bind(now)
+        .execute(&mut connection)
+        .await
+        .unwrap();
+
+    if version >= 7 {
+        // Since version 7 the searchable text is written with the message
+        sqlx::query("UPDATE messages SET search_text = 'hello'")
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    }
+    if version >= 8 {
+        // Since version 8 message timestamps have a fixed format
+        sqlx::query("UPDATE messages SET created_at = '2024-01-01T00:00:00.000Z'")
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    }
+    // A reaction to the message, stored as a message before version 14
+    if version >= 14 {
+        sqlx::query("INSERT INTO reactions (message_id, user_id, emoji, active, event_id, updated_at) VALUES (?, ?, '👍', 1, ?, ?)")
+            .bind(MESSAGE_ID)
+            .bind(USER_ID)
+            .bind(Uuid::new_v4().to_string())
+            .bind(now)
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    } else {
+        sqlx::query("INSERT INTO messages (id, room_id, sender_id, sender_device_id, content, created_at, edited_at, reply_to) VALUES (?, ?, ?, ?, ?, ?, NULL, NULL)")
+            .bind(Uuid::new_v4().to_string())
+            .bind(ROOM_ID)
+            .bind(USER_ID)
+            .bind(DEVICE_ID)
+            .bind(format!(r#"{{"Reaction":{{"emoji":"👍","target_message_id":"{}"}}}}"#, MESSAGE_ID))
+            .bind(now)
+            .execute(&mut connection)
+            .await
+            .unwrap();
+    }
+
+    connection.close().await.unwrap();
+}
+
+fn id(id: &str) -> Uuid {
+    Uuid::parse_str(id).unwrap()
+}
+
+/// Open a database upgraded from a historical schema version
+async fn upgrade(db: &TempDb, version: u32) -> StorageManager {
+    create_historical(db, version, true).await;
+    let storage = db.open().await;
+    assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+    storage
+}
+
+/// Open a database upgraded from the version before a migration
+async fn upgrade_across(db: &TempDb, migration: u32) -> StorageManager {
+    upgrade(db, migration - 1).await
+}
+
+/// Check that an upgraded database kept its data
+async fn assert_kept_data(storage: &StorageManager) {
+    let user = storage.get_user(&id(USER_ID)).await.unwrap().unwrap();
+    assert_eq!(user.username, "alice");
+    let device = storage.get_device(&id(DEVICE_ID)).await.unwrap().unwrap();
+    assert_eq!(device.public_key, vec![7u8; 32]);
+    let room = storage.get_room(&id(ROOM_ID)).await.unwrap().unwrap();
+    assert_eq!(room.name, "General");
+
+    let messages = storage.get_messages(&id(ROOM_ID), &MessageQuery::latest(10)).await.unwrap().messages;
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].id, id(MESSAGE_ID));
+    assert!(matches!(&messages[0].content, MessageContent::Text(text) if text == "hello"));
+}
+
+fn message(content: MessageContent) -> Message {
+    Message {
+        id: Uuid::new_v4(),
+        room_id: id(ROOM_ID),
+        sender_id: id(USER_ID),
+        sender_device_id: id(DEVICE_ID),
+        content,
+        created_at: chrono::Utc::now(),
+        edited_at: None,
+        reply_to: None,
+    }
+}
+
+fn message_with_text(text: &str) -> Message {
+    message(MessageContent::Text(text.to_string()))
+}
+
+#[tokio::test]
+async fn new_database_has_current_schema() {
+    let db = TempDb::new("migrations");
+    let storage = db.open().await;
+
+    assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+}
+
+#[tokio::test]
+async fn upgrades_unversioned_database() {
+    // Installs from before schema versioning have the initial schema and no
+    // schema_version table
+    let db = TempDb::new("migrations");
+    create_historical(&db, 1, false).await;
+    let storage = db.open().await;
+
+    assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+    assert_kept_data(&storage).await;
+}
+
+#[tokio::test]
+async fn upgrades_from_every_version() {
+    for migration in MIGRATIONS {
+        let db = TempDb::new("migrations");
+        let storage = upgrade(&db, migration.version).await;
+
+        assert_kept_data(&storage).await;
+    }
+}
+
+#[tokio::test]
+async fn prekeys_are_stored_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 2).await;
+    let device_id = id(DEVICE_ID);
+
+    assert!(storage.get_prekey_state(&device_id).await.unwrap().is_none());
+    storage.store_prekey_state(&device_id, &[11, 12]).await.unwrap();
+    assert_eq!(storage.get_prekey_state(&device_id).await.unwrap().unwrap().as_slice(), &[11, 12]);
+}
+
+#[tokio::test]
+async fn sessions_are_keyed_by_room_and_device_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 3).await;
+    let (room_id, device_id) = (id(ROOM_ID), id(DEVICE_ID));
+
+    storage.store_session(&Session {
+        room_id,
+        device_id,
+        session_data: vec![1, 2, 3],
+        created_at: chrono::Utc::now(),
+        updated_at: chrono::Utc::now(),
+    }).await.unwrap();
+    assert_eq!(storage.get_session(&room_id, &device_id).await.unwrap().unwrap().session_data, vec![1, 2, 3]);
+    assert!(storage.get_session(&Uuid::new_v4(), &device_id).await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn sender_keys_are_stored_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 4).await;
+    let (room_id, device_id) = (id(ROOM_ID), id(DEVICE_ID));
+
+    storage.store_sender_key(&SenderKey {
+        room_id,
+        device_id,
+        state_data: vec![4, 5, 6],
+        created_at: chrono::Utc::now(),
+        updated_at: chrono::Utc::now(),
+    }).await.unwrap();
+    assert_eq!(storage.get_sender_keys(&room_id).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn existing_rooms_stay_on_signal_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 5).await;
+
+    let room = storage.get_room(&id(ROOM_ID)).await.unwrap().unwrap();
+    assert_eq!(room.encryption, RoomEncryption::Signal);
+    storage.store_mls_state(&id(DEVICE_ID), &[8, 9]).await.unwrap();
+    assert_eq!(storage.get_mls_state(&id(DEVICE_ID)).await.unwrap().unwrap(), vec![8, 9]);
+    assert!(storage.get_mls_groups().await.unwrap().is_empty());
+    storage.store_mls_group(&room.id, &[13, 14]).await.unwrap();
+    assert_eq!(storage.get_mls_groups().await.unwrap(), vec![(room.id, vec![13, 14])]);
+}
+
+#[tokio::test]
+async fn existing_devices_are_unverified_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 6).await;
+    let device_id = id(DEVICE_ID);
+
+    assert_eq!(storage.get_device(&device_id).await.unwrap().unwrap().verification, VerificationState::Unverified);
+    storage.verify_device(&device_id).await.unwrap();
+    assert_eq!(storage.get_device(&device_id).await.unwrap().unwrap().verification, VerificationState::Verified);
+}
+
+#[tokio::test]
+async fn existing_messages_are_searchable_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 7).await;
+
+    let results = storage.search_messages("hello", None, 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].message.id, id(MESSAGE_ID));
+    // Only the visible text is indexed, not the JSON around it
+    assert!(storage.search_messages("Text", None, 10).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn existing_timestamps_are_normalized_on_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 8).await;
+
+    let message = storage.get_message(&id(MESSAGE_ID)).await.unwrap().unwrap();
+    assert_eq!(message.created_at.to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    // Newer messages sort after it under keyset paging
+    let newer = message_with_text("newer");
+    storage.store_message(&newer).await.unwrap();
+    let page = storage.get_messages(&id(ROOM_ID), &MessageQuery::latest(1)).await.unwrap();
+    assert_eq!(page.messages[0].id, newer.id);
+}
+
+#[tokio::test]
+async fn outbox_and_quarantine_work_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 9).await;
+    let device_id = id(DEVICE_ID);
+    let outgoing = EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id: id(ROOM_ID),
+        sender_device_id: device_id,
+        payload: vec![10],
+        timestamp: chrono::Utc::now(),
+        recipient_device_ids: vec![device_id],
+    };
+
+    storage.queue_outgoing(&outgoing).await.unwrap();
+    assert_eq!(storage.get_due_outgoing(chrono::Utc::now(), 10).await.unwrap().len(), 1);
+    storage.quarantine_message(&outgoing, "No session").await.unwrap();
+    assert!(storage.has_received_message(&outgoing.id).await.unwrap());
+    assert_eq!(storage.get_quarantined_messages().await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn room_state_ops_apply_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 11).await;
+    let room_id = id(ROOM_ID);
+
+    let op = storage.get_room_state(&room_id).await.unwrap().set_name(id(DEVICE_ID), "Renamed");
+    storage.store_message(&op.to_message(id(USER_ID))).await.unwrap();
+    assert_eq!(storage.get_room(&room_id).await.unwrap().unwrap().name, "Renamed");
+}
+
+#[tokio::test]
+async fn existing_messages_keep_edit_history_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 12).await;
+    let message_id = id(MESSAGE_ID);
+
+    storage.store_message(&message(MessageContent::Edit {
+        target_message_id: message_id,
+        content: Box::new(MessageContent::Text("hello again".to_string())),
+    })).await.unwrap();
+    assert_eq!(storage.get_message_history(&message_id).await.unwrap().len(), 2);
+    let edited = storage.get_message(&message_id).await.unwrap().unwrap();
+    assert!(matches!(&edited.content, MessageContent::Text(text) if text == "hello again"));
+
+    // Redacted words are purged from the search index
+    assert!(!storage.purge_search_index().await.unwrap());
+    storage.store_message(&message(MessageContent::Redact { target_message_id: message_id })).await.unwrap();
+    assert!(storage.purge_search_index().await.unwrap());
+}
+
+#[tokio::test]
+async fn existing_messages_get_threads_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 13).await;
+    let root_id = id(MESSAGE_ID);
+
+    let reply = Message { reply_to: Some(root_id), ..message_with_text("reply") };
+    storage.store_message(&reply).await.unwrap();
+    let summaries = storage.get_thread_summaries(&Uuid::new_v4(), &[root_id]).await.unwrap();
+    assert_eq!(summaries.len(), 1);
+    assert_eq!(summaries[0].reply_count, 1);
+    assert_eq!(summaries[0].unread_count, 1);
+    storage.mark_thread_read(&root_id, &reply.id).await.unwrap();
+    assert_eq!(storage.get_thread_summaries(&Uuid::new_v4(), &[root_id]).await.unwrap()[0].unread_count, 0);
+}
+
+#[tokio::test]
+async fn reaction_messages_become_reactions_on_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 14).await;
+    let (user_id, message_id) = (id(USER_ID), id(MESSAGE_ID));
+
+    let reactions = storage.get_reactions(&user_id, &[message_id]).await.unwrap();
+    let expected = ReactionSummary { emoji: "👍".to_string(), count: 1, reacted: true };
+    assert_eq!(reactions[&message_id], vec![expected]);
+    // The reaction is no longer a message of its own
+    let messages = storage.get_messages(&id(ROOM_ID), &MessageQuery::latest(10)).await.unwrap().messages;
+    assert_eq!(messages.len(), 1);
+}
+
+#[tokio::test]
+async fn rooms_get_activity_and_unread_counts_after_upgrade() {
+    let db = TempDb::new("migrations");
+    let storage = upgrade_across(&db, 15).await;
+
+    let summaries = storage.get_room_summaries().await.unwrap();
+    assert_eq!(summaries[0].last_activity_at.unwrap().to_rfc3339(), "2024-01-01T00:00:00+00:00");
+    // Counts start at zero, until the local user is known
+    assert_eq!(summaries[0].unread_count, 0);
+    storage.set_local_user(&Uuid::new_v4()).await.unwrap();
+    assert_eq!(storage.get_room_summaries().await.unwrap()[0].unread_count, 1);
+}
+
+#[tokio::test]
+async fn reopening_keeps_schema_version() {
+    let db = TempDb::new("migrations");
+    drop(db.open().await);
+
+    let storage = db.open().await;
+    assert_eq!(storage.schema_version().await.unwrap(), SCHEMA_VERSION);
+}
+
+#[tokio::test]
+async fn refuses_newer_database() {
+    let db = TempDb::new("migrations");
+    create_historical(&db, SCHEMA_VERSION, true).await;
+    let mut connection = SqliteConnection::connect(&format!("sqlite://{}", db.0.display())).await.unwrap();
+    sqlx::query("UPDATE schema_version SET version = ?")
+        .bind(SCHEMA_VERSION as i64 + 1)
+        .execute(&mut connection)
+        .await
+        .unwrap();
+    connection.close().await.unwrap();
+
+    let result = StorageManager::new(&db.0, PASSWORD).await;
+    assert!(matches!(result, Err(VeterError::Storage(_))));
+}
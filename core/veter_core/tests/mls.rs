@@ -0,0 +1,185 @@
+//! MLS room tests: adding and removing devices, application messages, and
+//! state persisted per group
+
+use uuid::Uuid;
+use veter_core::VeterError;
+use veter_core::crypto::CryptoManager;
+use veter_core::crypto::mls::MlsHandshake;
+use veter_core::models::*;
+
+/// A device with its keys
+struct Peer {
+    device_id: DeviceId,
+    identity_key: Vec<u8>,
+    crypto: CryptoManager,
+}
+
+impl Peer {
+    fn new() -> Self {
+        let (identity_key, _) = CryptoManager::generate_identity_keypair().unwrap();
+        let device_id = Uuid::new_v4();
+        let crypto = CryptoManager::new(identity_key.clone(), device_id).unwrap();
+
+        Self { device_id, identity_key, crypto }
+    }
+
+    fn send(&mut self, room_id: RoomId, text: &str) -> (MessageId, Vec<u8>) {
+        let message_id = Uuid::new_v4();
+        (message_id, self.crypto.encrypt_message(text.as_bytes(), room_id, message_id).unwrap())
+    }
+
+    fn receive(&mut self, room_id: RoomId, sender: &Peer, (message_id, payload): &(MessageId, Vec<u8>)) -> veter_core::Result<Vec<u8>> {
+        self.crypto.decrypt_message(payload, room_id, sender.device_id, *message_id)
+    }
+
+    /// The same device after a restart, with its key state restored
+    fn restart(&self) -> Self {
+        let mut crypto = CryptoManager::new(self.identity_key.clone(), self.device_id).unwrap();
+        crypto.init_mls_state(&self.crypto.mls_state().unwrap()).unwrap();
+        for room_id in self.crypto.mls_rooms() {
+            crypto.init_mls_group_state(room_id, &self.crypto.mls_group_state(room_id).unwrap()).unwrap();
+        }
+
+        Self { device_id: self.device_id, identity_key: self.identity_key.clone(), crypto }
+    }
+}
+
+fn mls_room() -> Room {
+    let now = chrono::Utc::now();
+    Room {
+        id: Uuid::new_v4(),
+        name: "Lunch".to_string(),
+        description: None,
+        room_type: RoomType::Group,
+        encryption: RoomEncryption::Mls,
+        members: Vec::new(),
+        created_at: now,
+        updated_at: now,
+    }
+}
+
+/// Alice creates a room and adds Bob
+fn room_with(alice: &mut Peer, bob: &mut Peer) -> RoomId {
+    let room = mls_room();
+    alice.crypto.create_mls_room(&room).unwrap();
+    let material = bob.crypto.generate_key_material(1).unwrap();
+    let commit = alice.crypto.add_mls_members(room.id, &[material]).unwrap();
+    assert_eq!(bob.crypto.join_mls_room(&commit.welcome.unwrap()).unwrap(), room.id);
+    room.id
+}
+
+#[test]
+fn members_exchange_messages_after_joining() {
+    let (mut alice, mut bob) = (Peer::new(), Peer::new());
+    let room_id = room_with(&mut alice, &mut bob);
+    assert_eq!(alice.crypto.mls_epoch(room_id).unwrap(), 1);
+    assert_eq!(bob.crypto.mls_epoch(room_id).unwrap(), 1);
+
+    let hello = alice.send(room_id, "hello bob");
+    assert_eq!(bob.receive(room_id, &alice, &hello).unwrap(), b"hello bob");
+    let reply = bob.send(room_id, "hi alice");
+    assert_eq!(alice.receive(room_id, &bob, &reply).unwrap(), b"hi alice");
+}
+
+#[test]
+fn removed_devices_cannot_read_later_messages() {
+    let (mut alice, mut bob, mut carol) = (Peer::new(), Peer::new(), Peer::new());
+    let room_id = room_with(&mut alice, &mut bob);
+
+    let material = carol.crypto.generate_key_material(1).unwrap();
+    let added = alice.crypto.add_mls_members(room_id, &[material]).unwrap();
+    carol.crypto.join_mls_room(&added.welcome.unwrap()).unwrap();
+    assert_eq!(bob.crypto.process_mls_handshake(room_id, &added.commit).unwrap(), MlsHandshake::CommitMerged { epoch: 2 });
+
+    let before = alice.send(room_id, "before");
+    assert_eq!(carol.receive(room_id, &alice, &before).unwrap(), b"before");
+
+    let removed = alice.crypto.remove_mls_members(room_id, &[carol.device_id]).unwrap();
+    assert!(removed.welcome.is_none());
+    assert_eq!(bob.crypto.process_mls_handshake(room_id, &removed.commit).unwrap(), MlsHandshake::CommitMerged { epoch: 3 });
+    assert_eq!(carol.crypto.process_mls_handshake(room_id, &removed.commit).unwrap(), MlsHandshake::Removed);
+
+    let after = alice.send(room_id, "after");
+    assert_eq!(bob.receive(room_id, &alice, &after).unwrap(), b"after");
+    assert!(carol.receive(room_id, &alice, &after).is_err());
+}
+
+#[test]
+fn rejected_messages_do_not_consume_secrets() {
+    let (mut alice, mut bob) = (Peer::new(), Peer::new());
+    let room_id = room_with(&mut alice, &mut bob);
+    let (message_id, payload) = alice.send(room_id, "hello");
+
+    // Bound to another message id, so the associated data does not match
+    let replayed = (Uuid::new_v4(), payload.clone());
+    assert!(matches!(bob.receive(room_id, &alice, &replayed), Err(VeterError::InvalidEnvelope(_))));
+    // Claimed to come from another device than the one that signed it
+    assert!(bob.receive(room_id, &Peer::new(), &(message_id, payload.clone())).is_err());
+
+    assert_eq!(bob.receive(room_id, &alice, &(message_id, payload.clone())).unwrap(), b"hello");
+    // Once accepted, the message key is gone
+    assert!(bob.receive(room_id, &alice, &(message_id, payload)).is_err());
+}
+
+#[test]
+fn key_packages_are_not_used_twice() {
+    let (mut alice, mut bob) = (Peer::new(), Peer::new());
+    let material = bob.crypto.generate_key_material(2).unwrap();
+
+    let mut joined = Vec::new();
+    for _ in 0..2 {
+        let room = mls_room();
+        alice.crypto.create_mls_room(&room).unwrap();
+        // The same, stale copy of Bob's key material every time
+        let commit = alice.crypto.add_mls_members(room.id, std::slice::from_ref(&material)).unwrap();
+        joined.push(bob.crypto.join_mls_room(&commit.welcome.unwrap()).unwrap());
+    }
+    assert_eq!(joined.len(), 2);
+    assert_ne!(joined[0], joined[1]);
+
+    let room = mls_room();
+    alice.crypto.create_mls_room(&room).unwrap();
+    assert!(matches!(alice.crypto.add_mls_members(room.id, std::slice::from_ref(&material)), Err(VeterError::KeyManagement(_))));
+
+    // Which KeyPackages were used survives a restart
+    let mut alice = alice.restart();
+    assert!(matches!(alice.crypto.add_mls_members(room.id, &[material]), Err(VeterError::KeyManagement(_))));
+}
+
+#[test]
+fn key_packages_must_be_signed_by_the_published_identity_key() {
+    let (mut alice, mut bob, mut mallory) = (Peer::new(), Peer::new(), Peer::new());
+    let room = mls_room();
+    alice.crypto.create_mls_room(&room).unwrap();
+
+    let mut material = bob.crypto.generate_key_material(1).unwrap();
+    material.mls_key_packages = mallory.crypto.generate_key_material(1).unwrap().mls_key_packages;
+    assert!(matches!(alice.crypto.add_mls_members(room.id, &[material]), Err(VeterError::KeyManagement(_))));
+    assert_eq!(alice.crypto.mls_epoch(room.id).unwrap(), 0);
+}
+
+#[test]
+fn group_state_survives_a_restart() {
+    let (mut alice, mut bob) = (Peer::new(), Peer::new());
+    let room_id = room_with(&mut alice, &mut bob);
+    let first = alice.send(room_id, "first");
+    assert_eq!(bob.receive(room_id, &alice, &first).unwrap(), b"first");
+
+    let (mut alice, mut bob) = (alice.restart(), bob.restart());
+    assert_eq!(bob.crypto.mls_rooms().collect::<Vec<_>>(), vec![room_id]);
+    let second = alice.send(room_id, "second");
+    assert_eq!(bob.receive(room_id, &alice, &second).unwrap(), b"second");
+    assert!(bob.receive(room_id, &alice, &first).is_err());
+}
+
+#[test]
+fn unused_key_packages_survive_a_restart() {
+    let (mut alice, mut bob) = (Peer::new(), Peer::new());
+    let material = bob.crypto.generate_key_material(1).unwrap();
+    let mut bob = bob.restart();
+
+    let room = mls_room();
+    alice.crypto.create_mls_room(&room).unwrap();
+    let commit = alice.crypto.add_mls_members(room.id, &[material]).unwrap();
+    assert_eq!(bob.crypto.join_mls_room(&commit.welcome.unwrap()).unwrap(), room.id);
+}
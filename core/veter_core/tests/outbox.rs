@@ -0,0 +1,231 @@
+//! Outbox tests against an in-process relay that can be made to fail
+
+mod common;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::networking::outbox::DeliveryUpdate;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+use veter_core::networking::{NetworkClient, OutboxConfig};
+use veter_core::VeterError;
+use common::TempDb;
+
+/// Relay that fails the first `unavailable` requests, never accepts the
+/// messages in `rejected`, and queues each accepted message once
+#[derive(Default)]
+struct FlakyRelay {
+    unavailable: Mutex<u32>,
+    rejected: Mutex<HashSet<Vec<u8>>>,
+    queued: Mutex<Vec<Vec<u8>>>,
+    requests: Mutex<u32>,
+}
+
+#[tonic::async_trait]
+impl Relay for FlakyRelay {
+    async fn enqueue(&self, request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        *self.requests.lock().unwrap() += 1;
+        {
+            let mut unavailable = self.unavailable.lock().unwrap();
+            if *unavailable > 0 {
+                *unavailable -= 1;
+                return Err(Status::unavailable("Relay is down"));
+            }
+        }
+
+        let rejected = self.rejected.lock().unwrap();
+        let mut queued = self.queued.lock().unwrap();
+        let mut accepted_ids = Vec::new();
+        for message in request.into_inner().messages {
+            if rejected.contains(&message.id) {
+                continue;
+            }
+            if !queued.contains(&message.id) {
+                queued.push(message.id.clone());
+            }
+            accepted_ids.push(message.id);
+        }
+
+        Ok(Response::new(EnqueueResponse { accepted_ids }))
+    }
+
+    async fn dequeue(&self, _request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        Ok(Response::new(DequeueResponse { messages: Vec::new() }))
+    }
+
+    async fn ack(&self, _request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Delivery, Status>>;
+
+    async fn subscribe(&self, _request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+}
+
+/// Serve a relay on a local port and connect a client to it
+async fn connected_client(relay: Arc<FlakyRelay>) -> NetworkClient {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(RelayServer::from_arc(relay))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = NetworkClient::new();
+    client.connect_relay(&format!("http://{}", address)).await.unwrap();
+    client
+}
+
+fn config(max_attempts: u32) -> OutboxConfig {
+    OutboxConfig {
+        initial_backoff: Duration::from_millis(20),
+        max_backoff: Duration::from_millis(100),
+        max_attempts,
+        ..OutboxConfig::default()
+    }
+}
+
+fn message() -> EncryptedMessage {
+    EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id: Uuid::new_v4(),
+        sender_device_id: Uuid::new_v4(),
+        payload: vec![1, 2, 3],
+        timestamp: chrono::Utc::now(),
+        recipient_device_ids: vec![Uuid::new_v4(), Uuid::new_v4()],
+    }
+}
+
+/// Wait until a message reaches `state`
+async fn wait_for(updates: &mut broadcast::Receiver<DeliveryUpdate>, message_id: MessageId, state: DeliveryState) {
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            let update = updates.recv().await.unwrap();
+            if update.message_id == message_id && update.state == state {
+                return;
+            }
+        }
+    })
+    .await
+    .unwrap_or_else(|_| panic!("Message never became {:?}", state));
+}
+
+#[tokio::test]
+async fn retries_until_the_relay_accepts() {
+    let db = TempDb::new("outbox");
+    let storage = Arc::new(db.open().await);
+    let relay = Arc::new(FlakyRelay { unavailable: Mutex::new(2), ..FlakyRelay::default() });
+    let client = connected_client(relay.clone()).await;
+    let outbox = client.start_outbox(storage.clone(), config(5)).unwrap();
+    let mut updates = outbox.subscribe();
+
+    let message = message();
+    outbox.send(&message).await.unwrap();
+    // Sending it again while it is queued does not queue a second copy
+    outbox.send(&message).await.unwrap();
+    wait_for(&mut updates, message.id, DeliveryState::Sent).await;
+
+    let outgoing = storage.get_outgoing(&message.id).await.unwrap().unwrap();
+    assert_eq!(outgoing.state, DeliveryState::Sent);
+    assert_eq!(outgoing.attempts, 2);
+    assert_eq!(outgoing.last_error, None);
+    assert_eq!(outgoing.message.recipient_device_ids, message.recipient_device_ids);
+    assert_eq!(outgoing.message.payload, message.payload);
+    assert_eq!(*relay.queued.lock().unwrap(), vec![message.id.as_bytes().to_vec()]);
+    assert_eq!(*relay.requests.lock().unwrap(), 3);
+}
+
+#[tokio::test]
+async fn fails_after_the_last_attempt_until_retried() {
+    let db = TempDb::new("outbox");
+    let storage = Arc::new(db.open().await);
+    let relay = Arc::new(FlakyRelay::default());
+    let client = connected_client(relay.clone()).await;
+    let outbox = client.start_outbox(storage.clone(), config(3)).unwrap();
+    let mut updates = outbox.subscribe();
+
+    let message = message();
+    relay.rejected.lock().unwrap().insert(message.id.as_bytes().to_vec());
+    outbox.send(&message).await.unwrap();
+    wait_for(&mut updates, message.id, DeliveryState::Failed).await;
+
+    let outgoing = storage.get_outgoing(&message.id).await.unwrap().unwrap();
+    assert_eq!(outgoing.attempts, 3);
+    assert!(outgoing.last_error.is_some());
+    assert!(relay.queued.lock().unwrap().is_empty());
+
+    // Only failed messages can be retried
+    let result = outbox.retry(&Uuid::new_v4()).await;
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+
+    relay.rejected.lock().unwrap().clear();
+    outbox.retry(&message.id).await.unwrap();
+    wait_for(&mut updates, message.id, DeliveryState::Sent).await;
+    assert_eq!(outbox.delivery_state(&message.id).await.unwrap(), Some(DeliveryState::Sent));
+}
+
+#[tokio::test]
+async fn delivery_is_final() {
+    let db = TempDb::new("outbox");
+    let storage = Arc::new(db.open().await);
+    let client = connected_client(Arc::new(FlakyRelay::default())).await;
+    let outbox = client.start_outbox(storage, config(5)).unwrap();
+    let mut updates = outbox.subscribe();
+
+    let message = message();
+    outbox.send(&message).await.unwrap();
+    wait_for(&mut updates, message.id, DeliveryState::Sent).await;
+    outbox.mark_delivered(&message.id).await.unwrap();
+    wait_for(&mut updates, message.id, DeliveryState::Delivered).await;
+
+    // A second receipt changes nothing
+    outbox.mark_delivered(&message.id).await.unwrap();
+    assert!(matches!(updates.try_recv(), Err(broadcast::error::TryRecvError::Empty)));
+    assert_eq!(outbox.delivery_state(&message.id).await.unwrap(), Some(DeliveryState::Delivered));
+    assert_eq!(outbox.delivery_state(&Uuid::new_v4()).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn sends_what_was_queued_before_a_restart() {
+    let db = TempDb::new("outbox");
+    let message = message();
+    {
+        // Queued while offline, then the app quit
+        let storage = Arc::new(db.open().await);
+        storage.queue_outgoing(&message).await.unwrap();
+    }
+
+    let storage = Arc::new(db.open().await);
+    let outgoing = storage.get_outgoing(&message.id).await.unwrap().unwrap();
+    assert_eq!(outgoing.state, DeliveryState::Pending);
+
+    let relay = Arc::new(FlakyRelay::default());
+    let client = connected_client(relay.clone()).await;
+    let outbox = client.start_outbox(storage, config(5)).unwrap();
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while outbox.delivery_state(&message.id).await.unwrap() != Some(DeliveryState::Sent) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(relay.queued.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn requires_connection() {
+    let db = TempDb::new("outbox");
+    let result = NetworkClient::new().start_outbox(Arc::new(db.open().await), OutboxConfig::default());
+    assert!(matches!(result, Err(VeterError::Network(_))));
+}
@@ -0,0 +1,103 @@
+//! Double Ratchet tests: both directions, out-of-order delivery, skip
+//! limits and header protection
+
+use veter_core::crypto::ratchet::RatchetState;
+use veter_core::crypto::x3dh::{self, IdentityKeyPair, PreKeyBundle, SignedPreKey};
+
+const AD: &[u8] = b"room and message";
+
+/// Sessions of Alice, who started, and Bob, who answers
+fn sessions() -> (RatchetState, RatchetState) {
+    let alice = IdentityKeyPair::generate();
+    let bob = IdentityKeyPair::generate();
+    let signed_prekey = SignedPreKey::generate(&bob);
+    let bundle = PreKeyBundle {
+        identity_key: bob.public_key(),
+        signed_prekey: signed_prekey.public_key(),
+        signed_prekey_signature: signed_prekey.signature(),
+        one_time_prekey: None,
+    };
+    let (sent, message) = x3dh::initiate(&alice, &bundle).unwrap();
+    let received = x3dh::respond(&bob, &signed_prekey, None, &message).unwrap();
+
+    (
+        RatchetState::init_initiator(&sent, message.signed_prekey).unwrap(),
+        // The responder's first ratchet key is its signed prekey
+        RatchetState::init_responder(&received, &x25519_secret(&signed_prekey)).unwrap(),
+    )
+}
+
+fn x25519_secret(prekey: &SignedPreKey) -> x25519_dalek::StaticSecret {
+    x25519_dalek::StaticSecret::from(prekey.private_bytes())
+}
+
+#[test]
+fn messages_flow_both_ways_across_ratchet_steps() {
+    let (mut alice, mut bob) = sessions();
+    assert!(bob.encrypt(b"too early", AD).is_err());
+
+    for round in 0..3 {
+        let to_bob = alice.encrypt(format!("ping {}", round).as_bytes(), AD).unwrap();
+        assert_eq!(bob.decrypt(&to_bob, AD).unwrap(), format!("ping {}", round).as_bytes());
+        let to_alice = bob.encrypt(format!("pong {}", round).as_bytes(), AD).unwrap();
+        assert_eq!(alice.decrypt(&to_alice, AD).unwrap(), format!("pong {}", round).as_bytes());
+    }
+
+    // The state survives being stored between messages
+    let mut bob = RatchetState::from_bytes(&bob.to_bytes().unwrap()).unwrap();
+    let message = alice.encrypt(b"after a restart", AD).unwrap();
+    assert_eq!(bob.decrypt(&message, AD).unwrap(), b"after a restart");
+}
+
+#[test]
+fn decrypts_messages_delivered_out_of_order_once() {
+    let (mut alice, mut bob) = sessions();
+    let first: Vec<_> = (0..3).map(|i| alice.encrypt(&[i], AD).unwrap()).collect();
+    assert_eq!(bob.decrypt(&first[0], AD).unwrap(), [0]);
+    let reply = bob.encrypt(b"ok", AD).unwrap();
+    alice.decrypt(&reply, AD).unwrap();
+    // A new chain starts while messages of the previous one are missing
+    let second: Vec<_> = (3..6).map(|i| alice.encrypt(&[i], AD).unwrap()).collect();
+
+    for (message, expected) in [(&second[2], 5), (&first[2], 2), (&second[0], 3), (&first[1], 1), (&second[1], 4)] {
+        assert_eq!(bob.decrypt(message, AD).unwrap(), [expected]);
+    }
+    // Message keys are deleted once used
+    assert!(bob.decrypt(&first[1], AD).is_err());
+    assert!(bob.decrypt(&second[2], AD).is_err());
+}
+
+#[test]
+fn refuses_to_skip_more_than_the_limit() {
+    let (mut alice, mut bob) = sessions();
+    let messages: Vec<_> = (0..1002).map(|_| alice.encrypt(b"spam", AD).unwrap()).collect();
+
+    // 1000 skipped keys are kept, 1001 are not
+    assert!(bob.decrypt(&messages[1001], AD).is_err());
+    assert_eq!(bob.decrypt(&messages[1000], AD).unwrap(), b"spam");
+    assert_eq!(bob.decrypt(&messages[0], AD).unwrap(), b"spam");
+    assert_eq!(bob.decrypt(&messages[1001], AD).unwrap(), b"spam");
+}
+
+#[test]
+fn headers_are_encrypted_and_authenticated() {
+    let (mut alice, mut bob) = sessions();
+    let (mut eve, _) = sessions();
+    let first = alice.encrypt(b"same", AD).unwrap();
+    let second = alice.encrypt(b"same", AD).unwrap();
+    // Headers in one chain share a key but not a nonce, so they do not link
+    assert_ne!(first[8..20], second[8..20]);
+
+    // Someone else's session cannot read the header
+    let intruder = eve.encrypt(b"hello", AD).unwrap();
+    assert!(bob.decrypt(&intruder, AD).is_err());
+
+    // Tampered headers and wrong associated data are rejected without
+    // touching the state
+    let mut tampered = first.clone();
+    tampered[30] ^= 1;
+    assert!(bob.decrypt(&tampered, AD).is_err());
+    assert!(bob.decrypt(&first, b"another message").is_err());
+    assert_eq!(bob.decrypt(&first, AD).unwrap(), b"same");
+    assert_eq!(bob.decrypt(&second, AD).unwrap(), b"same");
+}
@@ -0,0 +1,152 @@
+//! Reaction tests: aggregation, withdrawal and keeping reactions out of the
+//! timeline
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use common::{TempDb, room, store_user};
+
+/// Storage knowing three users with a device each and a room of theirs
+struct Setup {
+    _db: TempDb,
+    storage: StorageManager,
+    room_id: RoomId,
+    devices: Vec<Device>,
+}
+
+impl Setup {
+    async fn new() -> Self {
+        let db = TempDb::new("reactions");
+        let storage = db.open().await;
+        let mut devices = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            devices.push(store_user(&storage, name).await);
+        }
+        let room = room("Lunch", devices.iter().map(|device| device.user_id).collect());
+        storage.store_room(&room).await.unwrap();
+
+        Self { _db: db, storage, room_id: room.id, devices }
+    }
+
+    fn user(&self, index: usize) -> UserId {
+        self.devices[index].user_id
+    }
+
+    /// A message from user `index`, `offset_ms` after a fixed start time
+    fn message(&self, index: usize, content: MessageContent, offset_ms: i64) -> Message {
+        let sender = &self.devices[index];
+        Message {
+            id: Uuid::new_v4(),
+            room_id: self.room_id,
+            sender_id: sender.user_id,
+            sender_device_id: sender.id,
+            content,
+            created_at: chrono::DateTime::from_timestamp_millis(1_700_000_000_000 + offset_ms).unwrap(),
+            edited_at: None,
+            reply_to: None,
+        }
+    }
+
+    async fn post(&self, index: usize, content: MessageContent, offset_ms: i64) -> Message {
+        let message = self.message(index, content, offset_ms);
+        self.storage.store_message(&message).await.unwrap();
+        message
+    }
+
+    async fn react(&self, index: usize, target: &Message, emoji: &str, offset_ms: i64) {
+        let content = MessageContent::Reaction { emoji: emoji.to_string(), target_message_id: target.id };
+        self.post(index, content, offset_ms).await;
+    }
+
+    async fn unreact(&self, index: usize, target: &Message, emoji: &str, offset_ms: i64) {
+        let content = MessageContent::RemoveReaction { emoji: emoji.to_string(), target_message_id: target.id };
+        self.post(index, content, offset_ms).await;
+    }
+
+    /// (emoji, count, reacted) for one message, as seen by user `index`
+    async fn reactions(&self, index: usize, target: &Message) -> Vec<(String, u32, bool)> {
+        let mut reactions = self.storage.get_reactions(&self.user(index), &[target.id]).await.unwrap();
+        reactions.remove(&target.id)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|summary| (summary.emoji, summary.count, summary.reacted))
+            .collect()
+    }
+}
+
+fn text(text: &str) -> MessageContent {
+    MessageContent::Text(text.to_string())
+}
+
+fn summary(emoji: &str, count: u32, reacted: bool) -> (String, u32, bool) {
+    (emoji.to_string(), count, reacted)
+}
+
+#[tokio::test]
+async fn aggregates_reactions_per_emoji() {
+    let setup = Setup::new().await;
+    let message = setup.post(0, text("pizza for lunch?"), 0).await;
+    let other = setup.post(1, text("or sushi?"), 1_000).await;
+    let quiet = setup.post(2, text("either works"), 2_000).await;
+
+    setup.react(1, &message, "🍕", 3_000).await;
+    setup.react(2, &message, "🍕", 4_000).await;
+    setup.react(2, &message, "👍", 5_000).await;
+    setup.react(0, &other, "🍣", 6_000).await;
+    // Reacting twice with the same emoji counts once
+    setup.react(1, &message, "🍕", 7_000).await;
+
+    assert_eq!(setup.reactions(0, &message).await, vec![summary("🍕", 2, false), summary("👍", 1, false)]);
+    assert_eq!(setup.reactions(2, &message).await, vec![summary("🍕", 2, true), summary("👍", 1, true)]);
+
+    let all = setup.storage.get_reactions(&setup.user(0), &[message.id, other.id, quiet.id]).await.unwrap();
+    assert_eq!(all.len(), 2);
+    assert_eq!(all[&other.id], vec![ReactionSummary { emoji: "🍣".to_string(), count: 1, reacted: true }]);
+    assert!(!all.contains_key(&quiet.id));
+}
+
+#[tokio::test]
+async fn later_events_win_whatever_order_they_arrive_in() {
+    let setup = Setup::new().await;
+    let message = setup.post(0, text("pizza for lunch?"), 0).await;
+
+    setup.react(1, &message, "🍕", 1_000).await;
+    setup.unreact(1, &message, "🍕", 2_000).await;
+    assert!(setup.reactions(0, &message).await.is_empty());
+
+    // An addition made before the removal arrives late
+    setup.react(1, &message, "🍕", 1_500).await;
+    assert!(setup.reactions(0, &message).await.is_empty());
+
+    setup.unreact(2, &message, "👍", 4_000).await;
+    setup.react(2, &message, "👍", 3_000).await;
+    assert!(setup.reactions(0, &message).await.is_empty());
+
+    setup.react(1, &message, "🍕", 5_000).await;
+    assert_eq!(setup.reactions(1, &message).await, vec![summary("🍕", 1, true)]);
+}
+
+#[tokio::test]
+async fn keeps_reactions_out_of_the_timeline_and_search() {
+    let setup = Setup::new().await;
+    let message = setup.post(0, text("pizza for lunch?"), 0).await;
+    setup.react(1, &message, "pizza", 1_000).await;
+    // Reactions to messages not received yet are kept for them
+    let later = setup.message(0, text("look at this"), 2_000);
+    setup.react(2, &later, "👀", 3_000).await;
+
+    let page = setup.storage.get_messages(&setup.room_id, &MessageQuery::latest(10)).await.unwrap();
+    assert_eq!(page.messages.len(), 1);
+    assert!(page.messages.iter().all(|message| matches!(message.content, MessageContent::Text(_))));
+    let results = setup.storage.search_messages("pizza", None, 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].message.id, message.id);
+
+    setup.storage.store_message(&later).await.unwrap();
+    assert_eq!(setup.reactions(0, &later).await, vec![summary("👀", 1, false)]);
+
+    setup.storage.delete_message(&message.id).await.unwrap();
+    assert!(setup.reactions(0, &message).await.is_empty());
+}
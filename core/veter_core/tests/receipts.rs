@@ -0,0 +1,258 @@
+//! Receipt tests: read markers, unread and mention counts, and delivery
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use veter_core::VeterError;
+use common::{TempDb, room, user};
+
+/// Storage of Alice's device, knowing Bob, Carol and two rooms of theirs
+struct Setup {
+    _db: TempDb,
+    storage: StorageManager,
+    rooms: Vec<RoomId>,
+    users: Vec<User>,
+    devices: Vec<Device>,
+}
+
+impl Setup {
+    async fn new() -> Self {
+        let db = TempDb::new("receipts");
+        let storage = db.open().await;
+        let mut users = Vec::new();
+        let mut devices = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            let (user, device) = user(name, vec![0; 32]);
+            storage.store_user(&user).await.unwrap();
+            storage.store_device(&device).await.unwrap();
+            users.push(user);
+            devices.push(device);
+        }
+        let mut rooms = Vec::new();
+        for name in ["Lunch", "Dinner"] {
+            let room = room(name, users.iter().map(|user| user.id).collect());
+            storage.store_room(&room).await.unwrap();
+            rooms.push(room.id);
+        }
+        storage.set_local_user(&users[0].id).await.unwrap();
+
+        Self { _db: db, storage, rooms, users, devices }
+    }
+
+    /// A message from user `index` to room `room`, `offset_ms` after a
+    /// fixed start time
+    fn message(&self, room: usize, index: usize, content: MessageContent, offset_ms: i64) -> Message {
+        let sender = &self.devices[index];
+        Message {
+            id: Uuid::new_v4(),
+            room_id: self.rooms[room],
+            sender_id: sender.user_id,
+            sender_device_id: sender.id,
+            content,
+            created_at: chrono::DateTime::from_timestamp_millis(1_700_000_000_000 + offset_ms).unwrap(),
+            edited_at: None,
+            reply_to: None,
+        }
+    }
+
+    async fn post(&self, room: usize, index: usize, text: &str, offset_ms: i64) -> Message {
+        let message = self.message(room, index, MessageContent::Text(text.to_string()), offset_ms);
+        self.storage.store_message(&message).await.unwrap();
+        message
+    }
+
+    async fn receipt(&self, index: usize, receipt_type: ReceiptType, target: &Message, offset_ms: i64) {
+        let room = self.rooms.iter().position(|id| *id == target.room_id).unwrap();
+        self.storage.store_message(&self.receipt_message(room, index, receipt_type, target.id, offset_ms)).await.unwrap();
+    }
+
+    fn receipt_message(&self, room: usize, index: usize, receipt_type: ReceiptType, message_id: MessageId, offset_ms: i64) -> Message {
+        self.message(room, index, MessageContent::Receipt { receipt_type, message_id }, offset_ms)
+    }
+
+    /// (unread, mentions) in room `room`
+    async fn counts(&self, room: usize) -> (u32, u32) {
+        let summaries = self.storage.get_room_summaries().await.unwrap();
+        let summary = summaries.iter().find(|summary| summary.room.id == self.rooms[room]).unwrap();
+        (summary.unread_count, summary.mention_count)
+    }
+}
+
+#[tokio::test]
+async fn counts_unread_messages_and_mentions_up_to_the_read_marker() {
+    let setup = Setup::new().await;
+    let alice = &setup.users[0].username;
+    let first = setup.post(0, 1, "lunch?", 0).await;
+    let mention = setup.post(0, 2, &format!("@{} are you coming?", alice.to_uppercase()), 1_000).await;
+    // Alice's own messages and receipts are not unread
+    let own = setup.post(0, 0, "yes", 2_000).await;
+    setup.receipt(1, ReceiptType::Read, &own, 3_000).await;
+    assert_eq!(setup.counts(0).await, (2, 1));
+    assert_eq!(setup.counts(1).await, (0, 0));
+
+    // Read on another of Alice's devices
+    setup.receipt(0, ReceiptType::Read, &mention, 4_000).await;
+    assert_eq!(setup.counts(0).await, (0, 0));
+    let later = setup.post(0, 1, &format!("see you @{}", alice), 5_000).await;
+    assert_eq!(setup.counts(0).await, (1, 1));
+
+    // A receipt for an earlier message does not move the marker back
+    setup.receipt(0, ReceiptType::Read, &first, 6_000).await;
+    assert_eq!(setup.counts(0).await, (1, 1));
+    let summaries = setup.storage.get_room_summaries().await.unwrap();
+    let summary = summaries.iter().find(|summary| summary.room.id == setup.rooms[0]).unwrap();
+    assert_eq!(summary.read_up_to, Some(mention.id));
+
+    // Redacting the unread message leaves nothing to read
+    setup.storage.store_message(&setup.message(0, 1, MessageContent::Redact { target_message_id: later.id }, 7_000)).await.unwrap();
+    assert_eq!(setup.counts(0).await, (0, 0));
+}
+
+#[tokio::test]
+async fn mentions_need_the_whole_username() {
+    let setup = Setup::new().await;
+    let alice = setup.users[0].username.clone();
+    // Longer usernames and addresses only contain Alice's
+    let texts = [format!("@{}by are you coming?", alice), format!("@{}_2 too?", alice), format!("mail me@{}.example", alice)];
+    for (offset, text) in texts.iter().enumerate() {
+        setup.post(0, 1, text, offset as i64 * 1_000).await;
+    }
+    assert_eq!(setup.counts(0).await, (3, 0));
+
+    // Punctuation and the start and end of the text separate words
+    let texts = [format!("@{}, are you coming?", alice), format!("hi (@{})", alice), format!("see you @{}", alice)];
+    for (offset, text) in texts.iter().enumerate() {
+        setup.post(0, 1, text, 10_000 + offset as i64 * 1_000).await;
+    }
+    assert_eq!(setup.counts(0).await, (6, 3));
+}
+
+#[tokio::test]
+async fn keeps_the_latest_receipt_of_each_user() {
+    let setup = Setup::new().await;
+    let first = setup.post(0, 0, "lunch?", 0).await;
+    let second = setup.post(0, 0, "anyone?", 1_000).await;
+    setup.receipt(1, ReceiptType::Delivered, &second, 2_000).await;
+    setup.receipt(1, ReceiptType::Read, &first, 3_000).await;
+    // Reading implies receiving
+    setup.receipt(2, ReceiptType::Read, &second, 4_000).await;
+    setup.receipt(2, ReceiptType::Delivered, &first, 5_000).await;
+
+    let receipts = setup.storage.get_receipts(&setup.rooms[0]).await.unwrap();
+    let mut receipts: Vec<_> = receipts.iter()
+        .map(|receipt| (receipt.user_id, receipt.receipt_type, receipt.message_id))
+        .collect();
+    receipts.sort_by_key(|receipt| (receipt.0 != setup.users[1].id, receipt.1 == ReceiptType::Read));
+    assert_eq!(receipts, vec![
+        (setup.users[1].id, ReceiptType::Delivered, second.id),
+        (setup.users[1].id, ReceiptType::Read, first.id),
+        (setup.users[2].id, ReceiptType::Delivered, second.id),
+        (setup.users[2].id, ReceiptType::Read, second.id),
+    ]);
+    assert!(setup.storage.get_receipts(&setup.rooms[1]).await.unwrap().is_empty());
+
+    // Receipts are not part of the timeline
+    let page = setup.storage.get_messages(&setup.rooms[0], &MessageQuery::latest(10)).await.unwrap();
+    assert_eq!(page.messages.len(), 2);
+}
+
+#[tokio::test]
+async fn rejects_receipts_for_messages_not_stored_in_their_room() {
+    let setup = Setup::new().await;
+    let lunch = setup.post(0, 1, "lunch?", 0).await;
+    let dinner = setup.post(1, 1, "dinner?", 1_000).await;
+    setup.receipt(1, ReceiptType::Read, &lunch, 2_000).await;
+
+    // Neither an unknown message nor one of another room can move a marker
+    for receipt in [
+        setup.receipt_message(0, 0, ReceiptType::Read, Uuid::new_v4(), 3_000),
+        setup.receipt_message(0, 0, ReceiptType::Read, dinner.id, 4_000),
+        setup.receipt_message(0, 1, ReceiptType::Read, dinner.id, 5_000),
+    ] {
+        assert!(matches!(setup.storage.authorize_change(&receipt).await, Err(VeterError::InvalidInput(_))));
+        assert!(matches!(setup.storage.store_message(&receipt).await, Err(VeterError::InvalidInput(_))));
+    }
+    assert_eq!(setup.counts(0).await, (1, 0));
+    let receipts = setup.storage.get_receipts(&setup.rooms[0]).await.unwrap();
+    assert!(receipts.iter().all(|receipt| receipt.message_id == lunch.id));
+    // The receipt is ordered by the stored message, not the receipt
+    assert!(receipts.iter().all(|receipt| receipt.message_created_at == lunch.created_at));
+}
+
+#[tokio::test]
+async fn lists_rooms_by_last_activity() {
+    let setup = Setup::new().await;
+    setup.post(1, 1, "dinner?", 0).await;
+    setup.post(0, 1, "lunch?", 1_000).await;
+    let rooms: Vec<_> = setup.storage.get_room_summaries().await.unwrap().iter().map(|summary| summary.room.id).collect();
+    assert_eq!(rooms, vec![setup.rooms[0], setup.rooms[1]]);
+
+    // Late messages do not count as activity before newer ones
+    setup.post(1, 2, "8pm", 500).await;
+    let summaries = setup.storage.get_room_summaries().await.unwrap();
+    assert_eq!(summaries[0].room.id, setup.rooms[0]);
+    assert_eq!(summaries[1].unread_count, 2);
+    setup.post(1, 2, "or 9pm", 2_000).await;
+    let summaries = setup.storage.get_room_summaries().await.unwrap();
+    assert_eq!(summaries[0].room.id, setup.rooms[1]);
+    assert_eq!(summaries[0].last_activity_at, Some(chrono::DateTime::from_timestamp_millis(1_700_000_002_000).unwrap()));
+
+    // A room without messages counts as active when it was last updated
+    let now = chrono::Utc::now();
+    let empty = Room {
+        id: Uuid::new_v4(),
+        name: "Breakfast".to_string(),
+        description: None,
+        room_type: RoomType::Group,
+        encryption: RoomEncryption::Signal,
+        members: setup.users.iter().map(|user| user.id).collect(),
+        created_at: now,
+        updated_at: now,
+    };
+    setup.storage.store_room(&empty).await.unwrap();
+    let summaries = setup.storage.get_room_summaries().await.unwrap();
+    let rooms: Vec<_> = summaries.iter().map(|summary| summary.room.id).collect();
+    assert_eq!(rooms, vec![empty.id, setup.rooms[1], setup.rooms[0]]);
+    assert_eq!((summaries[0].unread_count, summaries[0].last_activity_at), (0, None));
+    assert_eq!(summaries[0].room.members.len(), 3);
+}
+
+#[tokio::test]
+async fn receipts_mark_sent_messages_delivered() {
+    let setup = Setup::new().await;
+    let mut sent = Vec::new();
+    for offset_ms in [0, 1_000, 2_000] {
+        let stored = setup.post(0, 0, "lunch?", offset_ms).await;
+        let message = EncryptedMessage {
+            id: stored.id,
+            room_id: setup.rooms[0],
+            sender_device_id: setup.devices[0].id,
+            payload: vec![1, 2, 3],
+            timestamp: stored.created_at,
+            recipient_device_ids: vec![setup.devices[1].id],
+        };
+        setup.storage.queue_outgoing(&message).await.unwrap();
+        sent.push(message);
+    }
+    setup.storage.mark_sent(&[sent[0].id, sent[1].id]).await.unwrap();
+
+    // Receipts for messages not stored in the room cover nothing
+    let dinner = setup.post(1, 1, "dinner?", 5_000).await;
+    assert!(setup.storage.mark_delivered_up_to(&setup.rooms[0], &dinner.id).await.unwrap().is_empty());
+    assert!(setup.storage.mark_delivered_up_to(&setup.rooms[0], &Uuid::new_v4()).await.unwrap().is_empty());
+
+    // Messages not accepted by the relay yet stay pending
+    let delivered = setup.storage.mark_delivered_up_to(&setup.rooms[0], &sent[2].id).await.unwrap();
+    assert_eq!(delivered.len(), 2);
+    assert!(delivered.contains(&sent[0].id) && delivered.contains(&sent[1].id));
+    let state = |message: &EncryptedMessage| {
+        let id = message.id;
+        let storage = &setup.storage;
+        async move { storage.get_outgoing(&id).await.unwrap().unwrap().state }
+    };
+    assert_eq!(state(&sent[1]).await, DeliveryState::Delivered);
+    assert_eq!(state(&sent[2]).await, DeliveryState::Pending);
+    assert!(setup.storage.mark_delivered_up_to(&setup.rooms[0], &sent[2].id).await.unwrap().is_empty());
+}
@@ -0,0 +1,236 @@
+//! Relay client tests against an in-process relay
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::networking::NetworkClient;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+use veter_core::VeterError;
+
+/// Relay that keeps one queue of (recipient, message) pairs and hands a
+/// device its messages until they are acknowledged.
+///
+/// Subscriptions use the queue index as cursor. The first subscription
+/// breaks after one delivery.
+#[derive(Default)]
+struct TestRelay {
+    queue: Mutex<Vec<(Vec<u8>, Ciphertext)>>,
+    subscription_cursors: Mutex<Vec<Vec<u8>>>,
+    subscriptions: Mutex<Vec<mpsc::Sender<Result<Delivery, Status>>>>,
+}
+
+#[tonic::async_trait]
+impl Relay for TestRelay {
+    async fn enqueue(&self, request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        let messages = request.into_inner().messages;
+        let accepted_ids = messages.iter().map(|message| message.id.clone()).collect();
+        let mut queue = self.queue.lock().unwrap();
+        for message in messages {
+            for recipient in &message.recipient_device_ids {
+                queue.push((recipient.clone(), message.clone()));
+            }
+        }
+
+        Ok(Response::new(EnqueueResponse { accepted_ids }))
+    }
+
+    async fn dequeue(&self, request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        let request = request.into_inner();
+        let messages = self.queue.lock().unwrap()
+            .iter()
+            .filter(|(recipient, _)| *recipient == request.device_id)
+            .take(request.max_items as usize)
+            .map(|(_, message)| message.clone())
+            .collect();
+
+        Ok(Response::new(DequeueResponse { messages }))
+    }
+
+    async fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        let request = request.into_inner();
+        let ids: HashSet<Vec<u8>> = request.ids.into_iter().collect();
+        self.queue.lock().unwrap()
+            .retain(|(recipient, message)| *recipient != request.device_id || !ids.contains(&message.id));
+
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Delivery, Status>>;
+
+    async fn subscribe(&self, request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let start = match <[u8; 8]>::try_from(request.cursor.as_slice()) {
+            Ok(cursor) => u64::from_be_bytes(cursor) as usize + 1,
+            Err(_) => 0,
+        };
+        let mut cursors = self.subscription_cursors.lock().unwrap();
+        cursors.push(request.cursor.clone());
+
+        let (sender, receiver) = mpsc::channel(16);
+        let queue = self.queue.lock().unwrap();
+        let pending = queue.iter()
+            .enumerate()
+            .skip(start)
+            .filter(|(_, (recipient, _))| *recipient == request.device_id);
+        for (sent, (index, (_, message))) in pending.enumerate() {
+            if cursors.len() == 1 && sent == 1 {
+                // Fail once the first delivery has been flushed to the client
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    let _ = sender.send(Err(Status::unavailable("connection lost"))).await;
+                });
+                break;
+            }
+            sender.try_send(Ok(Delivery {
+                message: Some(message.clone()),
+                cursor: (index as u64).to_be_bytes().to_vec(),
+            })).unwrap();
+        }
+        // Keep the stream open
+        self.subscriptions.lock().unwrap().push(sender);
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+}
+
+/// Serve a [`TestRelay`] on a local port, returning its endpoint
+async fn start_relay() -> String {
+    start_relay_with(std::sync::Arc::new(TestRelay::default())).await
+}
+
+async fn start_relay_with(relay: std::sync::Arc<TestRelay>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(RelayServer::from_arc(relay))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    format!("http://{}", address)
+}
+
+async fn connected_client(endpoint: &str) -> NetworkClient {
+    let mut client = NetworkClient::new();
+    client.connect_relay(endpoint).await.unwrap();
+    client
+}
+
+fn encrypted_message(sender_device_id: DeviceId, recipient_device_ids: Vec<DeviceId>) -> EncryptedMessage {
+    EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id: Uuid::new_v4(),
+        sender_device_id,
+        payload: vec![1, 2, 3],
+        timestamp: chrono::DateTime::from_timestamp_millis(1_700_000_000_123).unwrap(),
+        recipient_device_ids,
+    }
+}
+
+async fn next_item<S: tokio_stream::Stream + Unpin>(stream: &mut S) -> S::Item {
+    tokio::time::timeout(Duration::from_secs(5), stream.next()).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn delivers_until_acknowledged() {
+    let endpoint = start_relay().await;
+    let alice = connected_client(&endpoint).await;
+    let bob = connected_client(&endpoint).await;
+    let alice_device = Uuid::new_v4();
+    let bob_device = Uuid::new_v4();
+
+    let sent = vec![
+        encrypted_message(alice_device, vec![bob_device]),
+        encrypted_message(alice_device, vec![bob_device]),
+    ];
+    let accepted = alice.send_messages(sent.clone()).await.unwrap();
+    assert_eq!(accepted, sent.iter().map(|message| message.id).collect::<Vec<_>>());
+
+    // Unacknowledged messages are delivered again
+    let received = bob.receive_messages(&bob_device, 10).await.unwrap();
+    assert_eq!(received.len(), 2);
+    assert_eq!(bob.receive_messages(&bob_device, 10).await.unwrap().len(), 2);
+    assert_eq!(bob.receive_messages(&bob_device, 1).await.unwrap().len(), 1);
+
+    for (received, sent) in received.iter().zip(&sent) {
+        assert_eq!(received.id, sent.id);
+        assert_eq!(received.room_id, sent.room_id);
+        assert_eq!(received.sender_device_id, sent.sender_device_id);
+        assert_eq!(received.payload, sent.payload);
+        assert_eq!(received.timestamp, sent.timestamp);
+        assert_eq!(received.recipient_device_ids, vec![bob_device]);
+    }
+
+    bob.acknowledge_messages(&bob_device, vec![sent[0].id]).await.unwrap();
+    let remaining = bob.receive_messages(&bob_device, 10).await.unwrap();
+    assert_eq!(remaining.len(), 1);
+    assert_eq!(remaining[0].id, sent[1].id);
+}
+
+#[tokio::test]
+async fn subscription_resumes_after_failure() {
+    let relay = std::sync::Arc::new(TestRelay::default());
+    let endpoint = start_relay_with(relay.clone()).await;
+    let client = connected_client(&endpoint).await;
+    let alice_device = Uuid::new_v4();
+    let bob_device = Uuid::new_v4();
+
+    let sent: Vec<_> = (0..3).map(|_| encrypted_message(alice_device, vec![bob_device])).collect();
+    client.send_messages(sent.clone()).await.unwrap();
+
+    let mut stream = client.subscribe_messages(bob_device, 10).unwrap();
+    assert_eq!(next_item(&mut stream).await.unwrap().id, sent[0].id);
+    assert!(matches!(next_item(&mut stream).await, Err(VeterError::Network(_))));
+
+    // Resubscribed after the first delivery, so nothing is repeated
+    assert_eq!(next_item(&mut stream).await.unwrap().id, sent[1].id);
+    assert_eq!(next_item(&mut stream).await.unwrap().id, sent[2].id);
+    assert_eq!(*relay.subscription_cursors.lock().unwrap(), vec![Vec::new(), 0u64.to_be_bytes().to_vec()]);
+}
+
+#[tokio::test]
+async fn requires_connection() {
+    let client = NetworkClient::new();
+
+    let result = client.send_messages(vec![encrypted_message(Uuid::new_v4(), vec![Uuid::new_v4()])]).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+    let result = client.receive_messages(&Uuid::new_v4(), 10).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+    assert!(matches!(client.subscribe_messages(Uuid::new_v4(), 10), Err(VeterError::Network(_))));
+}
+
+#[tokio::test]
+async fn connect_fails_without_relay() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    drop(listener);
+
+    let mut client = NetworkClient::new();
+    let result = client.connect_relay(&format!("http://{}", address)).await;
+    assert!(matches!(result, Err(VeterError::Network(_))));
+}
+
+#[test]
+fn rejects_malformed_ciphertext() {
+    let message = encrypted_message(Uuid::new_v4(), vec![Uuid::new_v4()]);
+    let ciphertext = Ciphertext::from(&message);
+    let converted = EncryptedMessage::try_from(ciphertext.clone()).unwrap();
+    assert_eq!(converted.id, message.id);
+
+    let result = EncryptedMessage::try_from(Ciphertext { id: vec![1, 2, 3], ..ciphertext.clone() });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    let result = EncryptedMessage::try_from(Ciphertext { sent_ts: i64::MAX, ..ciphertext.clone() });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+    let result = EncryptedMessage::try_from(Ciphertext { recipient_device_ids: vec![vec![0; 4]], ..ciphertext });
+    assert!(matches!(result, Err(VeterError::InvalidInput(_))));
+}
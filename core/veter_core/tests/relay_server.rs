@@ -0,0 +1,228 @@
+//! End-to-end tests of the `veter-relay` binary
+
+mod common;
+
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::time::Duration;
+use tokio_stream::StreamExt;
+use tonic::transport::Endpoint;
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::networking::RelayClient;
+use common::remove_database;
+
+/// Lease used by the test relays, short enough to wait for redelivery
+const LEASE: Duration = Duration::from_millis(500);
+
+/// Wait for pushes that are expected to arrive
+const PUSH_TIMEOUT: Duration = Duration::from_secs(5);
+/// Wait for pushes that are expected not to arrive, well within the lease
+const NO_PUSH_TIMEOUT: Duration = Duration::from_millis(150);
+
+/// Running relay process with its own database, stopped on drop
+struct TestRelay {
+    process: Child,
+    database: PathBuf,
+    endpoint: String,
+}
+
+impl TestRelay {
+    async fn start() -> Self {
+        let database = std::env::temp_dir().join(format!("veter-relay-{}.db", Uuid::new_v4()));
+        Self::start_with(database).await
+    }
+
+    async fn start_with(database: PathBuf) -> Self {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let process = Command::new(env!("CARGO_BIN_EXE_veter-relay"))
+            .args(["--listen", &format!("127.0.0.1:{}", port)])
+            .arg("--database").arg(&database)
+            .args(["--lease", &LEASE.as_secs_f64().to_string()])
+            .spawn()
+            .unwrap();
+
+        Self {
+            process,
+            database,
+            endpoint: format!("http://127.0.0.1:{}", port),
+        }
+    }
+
+    /// Connect once the relay accepts connections
+    async fn client(&self) -> RelayClient {
+        let endpoint = Endpoint::from_shared(self.endpoint.clone()).unwrap();
+        for _ in 0..100 {
+            if let Ok(channel) = endpoint.connect().await {
+                return RelayClient::new(channel);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("relay did not start");
+    }
+
+    /// Stop the relay, keeping its database
+    fn stop(mut self) -> PathBuf {
+        self.process.kill().unwrap();
+        self.process.wait().unwrap();
+        std::mem::take(&mut self.database)
+    }
+}
+
+impl Drop for TestRelay {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        if !self.database.as_os_str().is_empty() {
+            remove_database(&self.database);
+        }
+    }
+}
+
+fn encrypted_message(recipient_device_ids: Vec<DeviceId>) -> EncryptedMessage {
+    EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id: Uuid::new_v4(),
+        sender_device_id: Uuid::new_v4(),
+        payload: b"ciphertext".to_vec(),
+        timestamp: chrono::Utc::now(),
+        recipient_device_ids,
+    }
+}
+
+async fn next_message<S: tokio_stream::Stream<Item = veter_core::Result<EncryptedMessage>> + Unpin>(stream: &mut S) -> EncryptedMessage {
+    tokio::time::timeout(PUSH_TIMEOUT, stream.next()).await.unwrap().unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn queues_per_device_and_redelivers_until_acknowledged() {
+    let relay = TestRelay::start().await;
+    let client = relay.client().await;
+    let bob = Uuid::new_v4();
+    let carol = Uuid::new_v4();
+
+    let message = encrypted_message(vec![bob, carol]);
+    assert_eq!(client.enqueue(std::slice::from_ref(&message)).await.unwrap(), vec![message.id]);
+
+    let received = client.dequeue(&bob, 10, 10).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].id, message.id);
+    assert_eq!(received[0].payload, message.payload);
+
+    // Leased to Bob, but still queued for Carol
+    assert!(client.dequeue(&bob, 10, 10).await.unwrap().is_empty());
+    assert_eq!(client.dequeue(&carol, 10, 10).await.unwrap().len(), 1);
+
+    // Not acknowledged in time, so delivered again
+    tokio::time::sleep(LEASE * 2).await;
+    assert_eq!(client.dequeue(&bob, 10, 10).await.unwrap().len(), 1);
+
+    client.ack(&bob, &[message.id]).await.unwrap();
+    tokio::time::sleep(LEASE * 2).await;
+    assert!(client.dequeue(&bob, 10, 10).await.unwrap().is_empty());
+    assert_eq!(client.dequeue(&carol, 10, 10).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn enforces_credits() {
+    let relay = TestRelay::start().await;
+    let client = relay.client().await;
+    let bob = Uuid::new_v4();
+
+    let messages: Vec<_> = (0..5).map(|_| encrypted_message(vec![bob])).collect();
+    client.enqueue(&messages).await.unwrap();
+
+    let first = client.dequeue(&bob, 10, 2).await.unwrap();
+    assert_eq!(first.iter().map(|m| m.id).collect::<Vec<_>>(), vec![messages[0].id, messages[1].id]);
+    assert!(client.dequeue(&bob, 10, 2).await.unwrap().is_empty());
+
+    // Acknowledging frees credit; max_items still limits the batch
+    client.ack(&bob, &[first[0].id]).await.unwrap();
+    let next = client.dequeue(&bob, 10, 2).await.unwrap();
+    assert_eq!(next.iter().map(|m| m.id).collect::<Vec<_>>(), vec![messages[2].id]);
+    client.ack(&bob, &[first[1].id, next[0].id]).await.unwrap();
+    assert_eq!(client.dequeue(&bob, 1, 10).await.unwrap().len(), 1);
+    assert!(client.dequeue(&bob, 10, 0).await.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn keeps_queues_across_restarts() {
+    let relay = TestRelay::start().await;
+    let bob = Uuid::new_v4();
+    let message = encrypted_message(vec![bob]);
+    relay.client().await.enqueue(std::slice::from_ref(&message)).await.unwrap();
+
+    let relay = TestRelay::start_with(relay.stop()).await;
+    let received = relay.client().await.dequeue(&bob, 10, 10).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].id, message.id);
+}
+
+#[tokio::test]
+async fn accepts_only_routable_messages() {
+    let relay = TestRelay::start().await;
+    let client = relay.client().await;
+    let bob = Uuid::new_v4();
+
+    let routable = encrypted_message(vec![bob]);
+    let unaddressed = encrypted_message(Vec::new());
+    let accepted = client.enqueue(&[routable.clone(), unaddressed]).await.unwrap();
+    assert_eq!(accepted, vec![routable.id]);
+
+    // Enqueuing again does not duplicate the message
+    client.enqueue(&[routable]).await.unwrap();
+    assert_eq!(client.dequeue(&bob, 10, 10).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn pushes_within_credits() {
+    let relay = TestRelay::start().await;
+    let client = relay.client().await;
+    let bob = Uuid::new_v4();
+
+    let queued: Vec<_> = (0..3).map(|_| encrypted_message(vec![bob])).collect();
+    client.enqueue(&queued).await.unwrap();
+
+    let mut stream = Box::pin(client.subscribe_messages(bob, 2));
+    let first = next_message(&mut stream).await;
+    let second = next_message(&mut stream).await;
+    assert_eq!((first.id, second.id), (queued[0].id, queued[1].id));
+
+    // Out of credit until a message is acknowledged
+    assert!(tokio::time::timeout(NO_PUSH_TIMEOUT, stream.next()).await.is_err());
+    client.ack(&bob, &[first.id]).await.unwrap();
+    let third = next_message(&mut stream).await;
+    assert_eq!(third.id, queued[2].id);
+
+    // New messages are pushed as they arrive
+    client.ack(&bob, &[second.id, third.id]).await.unwrap();
+    let live = encrypted_message(vec![bob]);
+    client.enqueue(std::slice::from_ref(&live)).await.unwrap();
+    let pushed = next_message(&mut stream).await;
+    assert_eq!(pushed.id, live.id);
+}
+
+#[tokio::test]
+async fn resumes_subscription_from_cursor() {
+    let relay = TestRelay::start().await;
+    let client = relay.client().await;
+    let bob = Uuid::new_v4();
+
+    let queued: Vec<_> = (0..3).map(|_| encrypted_message(vec![bob])).collect();
+    client.enqueue(&queued).await.unwrap();
+
+    // All three are pushed, but the connection drops after the first one
+    // reached the client
+    let mut deliveries = client.subscribe(&bob, 10, &[]).await.unwrap();
+    let first = deliveries.message().await.unwrap().unwrap();
+    assert_eq!(first.message.unwrap().id, queued[0].id.as_bytes().to_vec());
+    drop(deliveries);
+
+    // Resuming sends the rest right away; the first stays leased
+    let mut deliveries = client.subscribe(&bob, 10, &first.cursor).await.unwrap();
+    for expected in &queued[1..] {
+        let delivery = tokio::time::timeout(PUSH_TIMEOUT, deliveries.message()).await.unwrap().unwrap().unwrap();
+        assert_eq!(delivery.message.unwrap().id, expected.id.as_bytes().to_vec());
+    }
+    assert!(tokio::time::timeout(NO_PUSH_TIMEOUT, deliveries.message()).await.is_err());
+}
@@ -0,0 +1,179 @@
+//! Full-text search tests: what is indexed, snippets, and keeping the index
+//! in sync with edits and deletes
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use common::{TempDb, room, store_user};
+
+/// Storage knowing Alice and two rooms of hers
+struct Setup {
+    _db: TempDb,
+    storage: StorageManager,
+    rooms: Vec<RoomId>,
+    alice: Device,
+    clock: i64,
+}
+
+impl Setup {
+    async fn new() -> Self {
+        let db = TempDb::new("search");
+        let storage = db.open().await;
+        let alice = store_user(&storage, "alice").await;
+
+        let mut rooms = Vec::new();
+        for name in ["Lunch", "Work"] {
+            let room = room(name, vec![alice.user_id]);
+            storage.store_room(&room).await.unwrap();
+            rooms.push(room.id);
+        }
+
+        Self { _db: db, storage, rooms, alice, clock: 0 }
+    }
+
+    /// Store a message from Alice in the first room, a second after the last
+    async fn send(&mut self, content: MessageContent) -> Message {
+        let room_id = self.rooms[0];
+        self.send_to(room_id, content).await
+    }
+
+    async fn send_to(&mut self, room_id: RoomId, content: MessageContent) -> Message {
+        self.clock += 1_000;
+        let message = Message {
+            id: Uuid::new_v4(),
+            room_id,
+            sender_id: self.alice.user_id,
+            sender_device_id: self.alice.id,
+            content,
+            created_at: chrono::DateTime::from_timestamp_millis(1_700_000_000_000 + self.clock).unwrap(),
+            edited_at: None,
+            reply_to: None,
+        };
+        self.storage.store_message(&message).await.unwrap();
+        message
+    }
+
+    async fn search(&self, query: &str) -> Vec<MessageId> {
+        self.storage.search_messages(query, None, 10).await.unwrap()
+            .into_iter()
+            .map(|result| result.message.id)
+            .collect()
+    }
+}
+
+fn text(text: &str) -> MessageContent {
+    MessageContent::Text(text.to_string())
+}
+
+fn file(name: &str) -> MessageContent {
+    MessageContent::File {
+        name: name.to_string(),
+        mime_type: "application/pdf".to_string(),
+        size: 1024,
+        url: "https://files.example.com/blob".to_string(),
+        key: vec![1; 32],
+    }
+}
+
+#[tokio::test]
+async fn finds_words_prefixes_and_files() {
+    let mut setup = Setup::new().await;
+    let lunch = setup.send(text("Lunch at the café at noon")).await;
+    let report = setup.send(file("quarterly-report.pdf")).await;
+
+    assert_eq!(setup.search("noon").await, vec![lunch.id]);
+    assert_eq!(setup.search("NOON").await, vec![lunch.id]);
+    // The last word matches as a prefix, the others in full
+    assert_eq!(setup.search("lun").await, vec![lunch.id]);
+    assert_eq!(setup.search("lunch no").await, vec![lunch.id]);
+    assert!(setup.search("lun noon").await.is_empty());
+    // Diacritics are ignored
+    assert_eq!(setup.search("cafe").await, vec![lunch.id]);
+    assert_eq!(setup.search("quarterly report").await, vec![report.id]);
+}
+
+#[tokio::test]
+async fn does_not_match_serialized_content() {
+    let mut setup = Setup::new().await;
+    setup.send(text("hello")).await;
+    setup.send(file("report.pdf")).await;
+    setup.send(MessageContent::System("Alice joined".to_string())).await;
+
+    // Neither the JSON keys nor fields other than the visible text
+    for query in ["text", "file", "name", "mime", "application", "files.example", "https", "system", "joined"] {
+        assert!(setup.search(query).await.is_empty(), "{} matched", query);
+    }
+}
+
+#[tokio::test]
+async fn snippets_mark_the_matched_words() {
+    let mut setup = Setup::new().await;
+    setup.send(text("Shall we meet for lunch at noon, or is Noon too early?")).await;
+
+    let results = setup.storage.search_messages("noon", None, 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    let result = &results[0];
+    assert!(!result.snippet.contains(['\u{2}', '\u{3}']));
+    let highlighted: Vec<_> = result.highlights.iter().map(|&(start, end)| &result.snippet[start..end]).collect();
+    assert_eq!(highlighted, vec!["noon", "Noon"]);
+
+    // Long messages are cut around the match
+    let long = format!("{} needle {}", "hay ".repeat(50), "hay ".repeat(50));
+    setup.send(text(&long)).await;
+    let results = setup.storage.search_messages("needle", None, 10).await.unwrap();
+    let result = &results[0];
+    assert!(result.snippet.len() < long.len());
+    assert!(result.snippet.contains('…'));
+    let (start, end) = result.highlights[0];
+    assert_eq!(&result.snippet[start..end], "needle");
+}
+
+#[tokio::test]
+async fn edits_and_deletes_keep_the_index_in_sync() {
+    let mut setup = Setup::new().await;
+    let original = setup.send(text("lunch at noon")).await;
+
+    setup.send(MessageContent::Edit {
+        target_message_id: original.id,
+        content: Box::new(text("dinner at seven")),
+    }).await;
+    assert_eq!(setup.search("dinner").await, vec![original.id]);
+    // Earlier versions stay in the history, not in the index
+    assert!(setup.search("noon").await.is_empty());
+
+    setup.storage.delete_message(&original.id).await.unwrap();
+    assert!(setup.search("dinner").await.is_empty());
+    assert!(setup.search("at").await.is_empty());
+}
+
+#[tokio::test]
+async fn filters_by_room_and_limits_results() {
+    let mut setup = Setup::new().await;
+    let (lunch, work) = (setup.rooms[0], setup.rooms[1]);
+    for _ in 0..3 {
+        setup.send_to(lunch, text("pizza today")).await;
+    }
+    let at_work = setup.send_to(work, text("pizza at work")).await;
+
+    assert_eq!(setup.search("pizza").await.len(), 4);
+    let results = setup.storage.search_messages("pizza", Some(&work), 10).await.unwrap();
+    assert_eq!(results.len(), 1);
+    assert_eq!(results[0].message.id, at_work.id);
+    assert_eq!(setup.storage.search_messages("pizza", Some(&lunch), 2).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn query_syntax_in_input_is_taken_literally() {
+    let mut setup = Setup::new().await;
+    let quoted = setup.send(text(r#"she said "maybe" OR not"#)).await;
+
+    for query in [r#"""#, "*", "AND", "NOT", "OR", "(", "-", "noon:", "^"] {
+        assert!(setup.storage.search_messages(query, None, 10).await.is_ok(), "{} failed", query);
+    }
+    assert_eq!(setup.search(r#""maybe""#).await, vec![quoted.id]);
+    assert_eq!(setup.search("or not").await, vec![quoted.id]);
+    assert!(setup.search("").await.is_empty());
+    assert!(setup.search("   ").await.is_empty());
+}
@@ -0,0 +1,226 @@
+//! Sender key tests: distribution over pairwise sessions, rotation when a
+//! member leaves, and state that only advances for verified messages
+
+use uuid::Uuid;
+use veter_core::VeterError;
+use veter_core::crypto::CryptoManager;
+use veter_core::crypto::envelope::GroupEnvelope;
+use veter_core::models::*;
+
+/// A user's device with its keys
+struct Peer {
+    user: User,
+    device: Device,
+    crypto: CryptoManager,
+}
+
+impl Peer {
+    fn new(name: &str) -> Self {
+        let (private_key, public_key) = CryptoManager::generate_identity_keypair().unwrap();
+        let now = chrono::Utc::now();
+        let user = User {
+            id: Uuid::new_v4(),
+            username: name.to_string(),
+            display_name: name.to_string(),
+            avatar_url: None,
+            created_at: now,
+        };
+        let device = Device {
+            id: Uuid::new_v4(),
+            user_id: user.id,
+            name: format!("{}'s phone", name),
+            platform: Platform::Android,
+            public_key,
+            created_at: now,
+            last_seen: now,
+            verification: VerificationState::Unverified,
+        };
+        let crypto = CryptoManager::new(private_key, device.id).unwrap();
+
+        Self { user, device, crypto }
+    }
+
+    fn decrypt(&mut self, room: &Room, sender: &Peer, (message_id, payload): &(MessageId, Vec<u8>)) -> veter_core::Result<Vec<u8>> {
+        self.crypto.decrypt_message(payload, room.id, sender.device.id, *message_id)
+    }
+}
+
+/// Alice, Bob and Carol in a group room, Alice with sessions to the others
+struct Setup {
+    room: Room,
+    alice: Peer,
+    bob: Peer,
+    carol: Peer,
+}
+
+impl Setup {
+    fn new() -> Self {
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+        let mut carol = Peer::new("carol");
+        let now = chrono::Utc::now();
+        let room = Room {
+            id: Uuid::new_v4(),
+            name: "Lunch".to_string(),
+            description: None,
+            room_type: RoomType::Group,
+            encryption: RoomEncryption::Signal,
+            members: vec![alice.user.id, bob.user.id, carol.user.id],
+            created_at: now,
+            updated_at: now,
+        };
+
+        for peer in [&mut alice, &mut bob, &mut carol] {
+            peer.crypto.update_room(&room, &[]).unwrap();
+        }
+        for peer in [&mut bob, &mut carol] {
+            let bundle = peer.crypto.generate_key_material(5).unwrap();
+            alice.crypto.start_session(room.id, peer.device.id, &bundle).unwrap();
+        }
+
+        Self { room, alice, bob, carol }
+    }
+
+    fn send(&mut self, text: &str) -> (MessageId, Vec<u8>) {
+        let message_id = Uuid::new_v4();
+        (message_id, self.alice.crypto.encrypt_message(text.as_bytes(), self.room.id, message_id).unwrap())
+    }
+}
+
+fn group_envelope((_, payload): &(MessageId, Vec<u8>)) -> GroupEnvelope {
+    GroupEnvelope::from_bytes(payload).unwrap()
+}
+
+fn recipients(envelope: &GroupEnvelope) -> Vec<DeviceId> {
+    let mut recipients: Vec<_> = envelope.distributions.iter().map(|recipient| recipient.device_id).collect();
+    recipients.sort();
+    recipients
+}
+
+fn sorted(mut device_ids: Vec<DeviceId>) -> Vec<DeviceId> {
+    device_ids.sort();
+    device_ids
+}
+
+#[test]
+fn distributes_the_sender_key_once_per_device() {
+    let mut setup = Setup::new();
+    let first = setup.send("first");
+    let second = setup.send("second");
+
+    assert_eq!(recipients(&group_envelope(&first)), sorted(vec![setup.bob.device.id, setup.carol.device.id]));
+    assert!(group_envelope(&second).distributions.is_empty());
+    assert_eq!(group_envelope(&first).key_id, group_envelope(&second).key_id);
+
+    let Setup { room, alice, bob, carol } = &mut setup;
+    for peer in [bob, carol] {
+        assert_eq!(peer.decrypt(room, alice, &first).unwrap(), b"first");
+        assert_eq!(peer.decrypt(room, alice, &second).unwrap(), b"second");
+        assert!(peer.crypto.get_sender_key(room.id, alice.device.id).is_some());
+    }
+}
+
+#[test]
+fn messages_without_a_distribution_need_the_sender_key() {
+    let mut setup = Setup::new();
+    let first = setup.send("first");
+    let second = setup.send("second");
+
+    let Setup { room, alice, bob, .. } = &mut setup;
+    assert!(matches!(bob.decrypt(room, alice, &second), Err(VeterError::KeyManagement(_))));
+    assert_eq!(bob.decrypt(room, alice, &first).unwrap(), b"first");
+    assert_eq!(bob.decrypt(room, alice, &second).unwrap(), b"second");
+}
+
+#[test]
+fn rotates_the_sender_key_when_a_member_leaves() {
+    let mut setup = Setup::new();
+    let before = setup.send("before");
+    let late = setup.send("late");
+
+    let mut room = setup.room.clone();
+    room.members.retain(|member| *member != setup.carol.user.id);
+    let devices = [setup.bob.device.clone(), setup.carol.device.clone()];
+    let removed = setup.alice.crypto.update_room(&room, &devices).unwrap();
+    assert_eq!(removed, vec![setup.carol.device.id]);
+    assert!(setup.alice.crypto.get_session(room.id, setup.carol.device.id).is_none());
+    assert!(setup.alice.crypto.get_session(room.id, setup.bob.device.id).is_some());
+    setup.room = room;
+
+    let after = setup.send("after");
+    let rotated = group_envelope(&after);
+    assert_ne!(rotated.key_id, group_envelope(&before).key_id);
+    // The new key only goes to the remaining members
+    assert_eq!(recipients(&rotated), vec![setup.bob.device.id]);
+
+    let Setup { room, alice, bob, carol } = &mut setup;
+    assert_eq!(bob.decrypt(room, alice, &before).unwrap(), b"before");
+    assert_eq!(bob.decrypt(room, alice, &after).unwrap(), b"after");
+    // Messages sent under the previous key still decrypt
+    assert_eq!(bob.decrypt(room, alice, &late).unwrap(), b"late");
+
+    assert_eq!(carol.decrypt(room, alice, &before).unwrap(), b"before");
+    assert!(carol.decrypt(room, alice, &after).is_err());
+}
+
+#[test]
+fn updates_without_a_leaver_keep_the_sender_key() {
+    let mut setup = Setup::new();
+    let first = setup.send("first");
+
+    let room = setup.room.clone();
+    let devices = [setup.bob.device.clone(), setup.carol.device.clone()];
+    assert!(setup.alice.crypto.update_room(&room, &devices).unwrap().is_empty());
+
+    let second = setup.send("second");
+    assert_eq!(group_envelope(&first).key_id, group_envelope(&second).key_id);
+    assert!(group_envelope(&second).distributions.is_empty());
+}
+
+#[test]
+fn rejected_group_messages_leave_sessions_and_sender_keys_untouched() {
+    let mut setup = Setup::new();
+    let (message_id, payload) = setup.send("first");
+
+    let mut forged = GroupEnvelope::from_bytes(&payload).unwrap();
+    forged.signature[0] ^= 1;
+    let forged = (message_id, forged.to_bytes().unwrap());
+
+    let Setup { room, alice, bob, .. } = &mut setup;
+    assert!(matches!(bob.decrypt(room, alice, &forged), Err(VeterError::InvalidEnvelope(_))));
+    // Neither the session accepted from the distribution nor the sender key
+    // was stored
+    assert!(bob.crypto.get_session(room.id, alice.device.id).is_none());
+    assert!(bob.crypto.get_sender_key(room.id, alice.device.id).is_none());
+
+    assert_eq!(bob.decrypt(room, alice, &(message_id, payload)).unwrap(), b"first");
+    assert!(bob.crypto.get_session(room.id, alice.device.id).is_some());
+}
+
+#[test]
+fn rejected_group_messages_do_not_advance_an_existing_session() {
+    let mut setup = Setup::new();
+    let first = setup.send("first");
+    let Setup { room, alice, bob, .. } = &mut setup;
+    assert_eq!(bob.decrypt(room, alice, &first).unwrap(), b"first");
+
+    // A leaver triggers a new distribution over the established session
+    let mut room = room.clone();
+    room.members.retain(|member| *member != setup.carol.user.id);
+    setup.alice.crypto.update_room(&room, &[setup.carol.device.clone()]).unwrap();
+    setup.room = room;
+    let (message_id, payload) = setup.send("second");
+
+    let mut forged = GroupEnvelope::from_bytes(&payload).unwrap();
+    forged.ciphertext[0] ^= 1;
+    let forged = (message_id, forged.to_bytes().unwrap());
+
+    let Setup { room, alice, bob, .. } = &mut setup;
+    let session = bob.crypto.get_session(room.id, alice.device.id).unwrap().session_data.clone();
+    let sender_key = bob.crypto.get_sender_key(room.id, alice.device.id).unwrap().state_data.clone();
+    assert!(bob.decrypt(room, alice, &forged).is_err());
+    assert_eq!(bob.crypto.get_session(room.id, alice.device.id).unwrap().session_data, session);
+    assert_eq!(bob.crypto.get_sender_key(room.id, alice.device.id).unwrap().state_data, sender_key);
+
+    assert_eq!(bob.decrypt(room, alice, &(message_id, payload)).unwrap(), b"second");
+}
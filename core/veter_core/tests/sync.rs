@@ -0,0 +1,385 @@
+//! Sync engine tests: relay to decryption to storage
+
+mod common;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tonic::transport::Server;
+use uuid::Uuid;
+use veter_core::crypto::CryptoManager;
+use veter_core::models::*;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+use veter_core::networking::NetworkClient;
+use veter_core::storage::StorageManager;
+use veter_core::sync::SyncEngine;
+use common::{TempDb, room, user};
+
+/// Relay that hands out queued messages until they are acknowledged
+#[derive(Default)]
+struct TestRelay {
+    queue: Mutex<Vec<Ciphertext>>,
+}
+
+impl TestRelay {
+    fn push(&self, message: &EncryptedMessage) {
+        self.queue.lock().unwrap().push(Ciphertext::from(message));
+    }
+
+    fn len(&self) -> usize {
+        self.queue.lock().unwrap().len()
+    }
+}
+
+#[tonic::async_trait]
+impl Relay for TestRelay {
+    async fn enqueue(&self, _request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+
+    async fn dequeue(&self, request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        let max_items = request.into_inner().max_items as usize;
+        let messages = self.queue.lock().unwrap().iter().take(max_items).cloned().collect();
+
+        Ok(Response::new(DequeueResponse { messages }))
+    }
+
+    async fn ack(&self, request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        let ids = request.into_inner().ids;
+        self.queue.lock().unwrap().retain(|message| !ids.contains(&message.id));
+
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Delivery, Status>>;
+
+    async fn subscribe(&self, _request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+}
+
+/// A user's device with its keys
+struct Peer {
+    user: User,
+    device: Device,
+    crypto: CryptoManager,
+}
+
+impl Peer {
+    fn new(name: &str) -> Self {
+        let (private_key, public_key) = CryptoManager::generate_identity_keypair().unwrap();
+        let (user, device) = user(name, public_key);
+        let crypto = CryptoManager::new(private_key, device.id).unwrap();
+
+        Self { user, device, crypto }
+    }
+
+    /// Encrypt a text message to the room, claiming to be sent by `sender_id`
+    fn message_as(&mut self, room_id: RoomId, sender_id: UserId, text: &str) -> (Message, EncryptedMessage) {
+        self.encrypt(room_id, sender_id, MessageContent::Text(text.to_string()))
+    }
+
+    fn encrypt(&mut self, room_id: RoomId, sender_id: UserId, content: MessageContent) -> (Message, EncryptedMessage) {
+        let message = Message {
+            id: Uuid::new_v4(),
+            room_id,
+            sender_id,
+            sender_device_id: self.device.id,
+            content,
+            created_at: chrono::Utc::now(),
+            edited_at: None,
+            reply_to: None,
+        };
+        let plaintext = serde_json::to_vec(&message).unwrap();
+        let encrypted = EncryptedMessage {
+            id: message.id,
+            room_id,
+            sender_device_id: self.device.id,
+            payload: self.crypto.encrypt_message(&plaintext, room_id, message.id).unwrap(),
+            timestamp: message.created_at,
+            recipient_device_ids: Vec::new(),
+        };
+        (message, encrypted)
+    }
+
+    fn message(&mut self, room_id: RoomId, text: &str) -> (Message, EncryptedMessage) {
+        self.message_as(room_id, self.user.id, text)
+    }
+}
+
+/// Bob's device receiving from Alice in a room, with Bob's storage knowing
+/// both of them and the room
+struct Setup {
+    _db: TempDb,
+    storage: Arc<StorageManager>,
+    relay: Arc<TestRelay>,
+    engine: SyncEngine,
+    room: Room,
+    alice: Peer,
+    bob: Peer,
+}
+
+impl Setup {
+    async fn new(room_type: RoomType) -> Self {
+        let db = TempDb::new("sync");
+        let storage = Arc::new(db.open().await);
+        let mut alice = Peer::new("alice");
+        let mut bob = Peer::new("bob");
+
+        let room = Room { room_type, ..room("Lunch", vec![alice.user.id, bob.user.id]) };
+        for peer in [&alice, &bob] {
+            storage.store_user(&peer.user).await.unwrap();
+            storage.store_device(&peer.device).await.unwrap();
+        }
+        storage.store_room(&room).await.unwrap();
+
+        let bundle = bob.crypto.generate_key_material(5).unwrap();
+        alice.crypto.update_room(&room, &[]).unwrap();
+        alice.crypto.start_session(room.id, bob.device.id, &bundle).unwrap();
+        bob.crypto.update_room(&room, &[]).unwrap();
+
+        let relay = Arc::new(TestRelay::default());
+        let client = connected_client(relay.clone()).await;
+        let engine = client.sync_engine(storage.clone(), bob.device.id).unwrap();
+
+        Self { _db: db, storage, relay, engine, room, alice, bob }
+    }
+}
+
+/// Serve a relay on a local port and connect a client to it
+async fn connected_client(relay: Arc<TestRelay>) -> NetworkClient {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(RelayServer::from_arc(relay))
+            .serve_with_incoming(TcpListenerStream::new(listener)),
+    );
+
+    let mut client = NetworkClient::new();
+    client.connect_relay(&format!("http://{}", address)).await.unwrap();
+    client
+}
+
+fn text(message: &Message) -> &str {
+    match &message.content {
+        MessageContent::Text(text) => text,
+        other => panic!("Not a text message: {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn stores_decrypted_messages_with_their_session_before_acking() {
+    let mut setup = Setup::new(RoomType::Direct).await;
+    let (_, encrypted) = setup.alice.message(setup.room.id, "hi bob");
+    setup.relay.push(&encrypted);
+
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.stored.len(), 1);
+    assert_eq!(text(&report.stored[0]), "hi bob");
+    assert_eq!(setup.relay.len(), 0);
+
+    let page = setup.storage.get_messages(&setup.room.id, &MessageQuery::latest(10)).await.unwrap();
+    assert_eq!(page.messages.len(), 1);
+    assert_eq!(page.messages[0].sender_id, setup.alice.user.id);
+    let session = setup.storage.get_session(&setup.room.id, &setup.alice.device.id).await.unwrap().unwrap();
+    let in_memory = setup.bob.crypto.get_session(setup.room.id, setup.alice.device.id).unwrap();
+    assert_eq!(session.session_data, in_memory.session_data);
+
+    // The relay lost the ack and delivers the message again
+    setup.relay.push(&encrypted);
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert!(report.stored.is_empty());
+    assert_eq!(report.redelivered, vec![encrypted.id]);
+    assert_eq!(setup.relay.len(), 0);
+    let page = setup.storage.get_messages(&setup.room.id, &MessageQuery::latest(10)).await.unwrap();
+    assert_eq!(page.messages.len(), 1);
+
+    // The session keeps working afterwards
+    let (_, encrypted) = setup.alice.message(setup.room.id, "still there?");
+    setup.relay.push(&encrypted);
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.stored.len(), 1);
+}
+
+#[tokio::test]
+async fn quarantines_messages_until_their_key_arrives() {
+    let mut setup = Setup::new(RoomType::Group).await;
+    // The first message carries Alice's sender key, the second only uses it
+    let (_, first) = setup.alice.message(setup.room.id, "first");
+    let (_, second) = setup.alice.message(setup.room.id, "second");
+    setup.relay.push(&second);
+    setup.relay.push(&first);
+
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.quarantined, vec![second.id]);
+    assert_eq!(report.stored.iter().map(text).collect::<Vec<_>>(), vec!["first"]);
+    // Quarantined messages are safe locally, so they are acknowledged too
+    assert_eq!(setup.relay.len(), 0);
+    assert_eq!(setup.storage.get_sender_keys(&setup.room.id).await.unwrap().len(), 1);
+    let quarantined = setup.storage.get_quarantined_messages().await.unwrap();
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].message.payload, second.payload);
+    assert_eq!(quarantined[0].attempts, 1);
+
+    let report = setup.engine.retry_quarantined(&mut setup.bob.crypto).await.unwrap();
+    assert_eq!(report.stored.iter().map(text).collect::<Vec<_>>(), vec!["second"]);
+    assert!(setup.storage.get_quarantined_messages().await.unwrap().is_empty());
+
+    setup.relay.push(&second);
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.redelivered, vec![second.id]);
+}
+
+#[tokio::test]
+async fn quarantines_messages_from_unknown_devices_without_decrypting() {
+    let mut setup = Setup::new(RoomType::Direct).await;
+    let mut carol = Peer::new("carol");
+    let bundle = setup.bob.crypto.generate_key_material(5).unwrap();
+    carol.crypto.start_session(setup.room.id, setup.bob.device.id, &bundle).unwrap();
+    let (_, encrypted) = carol.message(setup.room.id, "hello from carol");
+    setup.relay.push(&encrypted);
+
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.quarantined, vec![encrypted.id]);
+    assert!(setup.bob.crypto.get_session(setup.room.id, carol.device.id).is_none());
+    let quarantined = setup.storage.get_quarantined_messages().await.unwrap();
+    assert!(quarantined[0].error.contains("Unknown device"));
+
+    // Still unknown: another attempt is recorded
+    setup.engine.retry_quarantined(&mut setup.bob.crypto).await.unwrap();
+    assert_eq!(setup.storage.get_quarantined_messages().await.unwrap()[0].attempts, 2);
+
+    setup.storage.store_user(&carol.user).await.unwrap();
+    setup.storage.store_device(&carol.device).await.unwrap();
+    let report = setup.engine.retry_quarantined(&mut setup.bob.crypto).await.unwrap();
+    assert_eq!(report.stored.iter().map(text).collect::<Vec<_>>(), vec!["hello from carol"]);
+}
+
+#[tokio::test]
+async fn rejects_messages_claiming_another_sender() {
+    let mut setup = Setup::new(RoomType::Direct).await;
+    let bob_id = setup.bob.user.id;
+    let (_, forged) = setup.alice.message_as(setup.room.id, bob_id, "it was me, bob");
+    setup.relay.push(&forged);
+
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.quarantined, vec![forged.id]);
+    let page = setup.storage.get_messages(&setup.room.id, &MessageQuery::latest(10)).await.unwrap();
+    assert!(page.messages.is_empty());
+}
+
+#[tokio::test]
+async fn applies_edits_only_from_the_original_sender() {
+    let mut setup = Setup::new(RoomType::Direct).await;
+    let (room_id, alice_id) = (setup.room.id, setup.alice.user.id);
+    let (original, encrypted) = setup.alice.message(room_id, "lunch at 12?");
+    setup.relay.push(&encrypted);
+    let (_, edit) = setup.alice.encrypt(room_id, alice_id, MessageContent::Edit {
+        target_message_id: original.id,
+        content: Box::new(MessageContent::Text("lunch at 1?".to_string())),
+    });
+    setup.relay.push(&edit);
+
+    // Bob's own message, which Alice must not be able to change
+    let bob_message = Message {
+        id: Uuid::new_v4(),
+        room_id,
+        sender_id: setup.bob.user.id,
+        sender_device_id: setup.bob.device.id,
+        content: MessageContent::Text("sure".to_string()),
+        created_at: chrono::Utc::now(),
+        edited_at: None,
+        reply_to: None,
+    };
+    setup.storage.store_message(&bob_message).await.unwrap();
+    let (_, forged) = setup.alice.encrypt(room_id, alice_id, MessageContent::Redact { target_message_id: bob_message.id });
+    setup.relay.push(&forged);
+
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.stored.len(), 2);
+    assert_eq!(report.quarantined, vec![forged.id]);
+    assert!(setup.storage.get_quarantined_messages().await.unwrap()[0].error.contains("not sent by"));
+
+    let page = setup.storage.get_messages(&room_id, &MessageQuery::latest(10)).await.unwrap();
+    let texts: Vec<_> = page.messages.iter().map(text).collect();
+    assert_eq!(texts.len(), 2);
+    assert!(texts.contains(&"lunch at 1?") && texts.contains(&"sure"));
+}
+
+#[tokio::test]
+async fn rejected_first_messages_leave_no_session_behind() {
+    let mut setup = Setup::new(RoomType::Direct).await;
+    let (room_id, bob_id) = (setup.room.id, setup.bob.user.id);
+    // Decrypts over a new session from Alice's initial message, then fails
+    // the envelope check
+    let (_, forged) = setup.alice.message_as(room_id, bob_id, "it was me, bob");
+    setup.relay.push(&forged);
+
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.quarantined, vec![forged.id]);
+    assert!(setup.bob.crypto.get_session(room_id, setup.alice.device.id).is_none());
+    // The one-time prekey it used is still there for the genuine message
+    assert_eq!(setup.bob.crypto.one_time_prekey_count(), 5);
+    assert!(setup.storage.get_prekey_state(&setup.bob.device.id).await.unwrap().is_none());
+
+    let (_, genuine) = setup.alice.message(room_id, "hi bob");
+    setup.relay.push(&genuine);
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.stored.iter().map(text).collect::<Vec<_>>(), vec!["hi bob"]);
+    assert_eq!(setup.bob.crypto.one_time_prekey_count(), 4);
+    let stored = setup.storage.get_prekey_state(&setup.bob.device.id).await.unwrap().unwrap();
+    assert_eq!(stored.as_slice(), setup.bob.crypto.prekey_state().unwrap().as_slice());
+}
+
+/// Storage entries of an MLS group, as exported by `mls_group_state`
+fn mls_entries(crypto: &CryptoManager, room_id: RoomId) -> HashMap<Vec<u8>, Vec<u8>> {
+    bincode::deserialize(&crypto.mls_group_state(room_id).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn rejected_mls_messages_leave_the_group_untouched() {
+    let mut setup = Setup::new(RoomType::Group).await;
+    let room = Room { id: Uuid::new_v4(), encryption: RoomEncryption::Mls, ..setup.room.clone() };
+    setup.storage.store_room(&room).await.unwrap();
+    setup.alice.crypto.create_mls_room(&room).unwrap();
+    let material = setup.bob.crypto.generate_key_material(1).unwrap();
+    let commit = setup.alice.crypto.add_mls_members(room.id, &[material]).unwrap();
+    setup.bob.crypto.join_mls_room(&commit.welcome.unwrap()).unwrap();
+
+    let (room_id, alice_id) = (room.id, setup.alice.user.id);
+    let (_, first) = setup.alice.message(room_id, "first");
+    // Alice cannot redact what she did not send
+    let bob_message = Message {
+        id: Uuid::new_v4(),
+        room_id,
+        sender_id: setup.bob.user.id,
+        sender_device_id: setup.bob.device.id,
+        content: MessageContent::Text("mine".to_string()),
+        created_at: chrono::Utc::now(),
+        edited_at: None,
+        reply_to: None,
+    };
+    setup.storage.store_message(&bob_message).await.unwrap();
+    let (_, forged) = setup.alice.encrypt(room_id, alice_id, MessageContent::Redact { target_message_id: bob_message.id });
+
+    let before = mls_entries(&setup.bob.crypto, room_id);
+    setup.relay.push(&forged);
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.quarantined, vec![forged.id]);
+    assert_eq!(mls_entries(&setup.bob.crypto, room_id), before);
+    assert!(setup.storage.get_mls_groups().await.unwrap().is_empty());
+
+    setup.relay.push(&first);
+    let report = setup.engine.sync(&mut setup.bob.crypto, 10).await.unwrap();
+    assert_eq!(report.stored.iter().map(text).collect::<Vec<_>>(), vec!["first"]);
+    assert_ne!(mls_entries(&setup.bob.crypto, room_id), before);
+    // What was stored is what is in memory
+    let groups = setup.storage.get_mls_groups().await.unwrap();
+    assert_eq!(groups.len(), 1);
+    let stored: HashMap<Vec<u8>, Vec<u8>> = bincode::deserialize(&groups[0].1).unwrap();
+    assert_eq!(stored, mls_entries(&setup.bob.crypto, room_id));
+}
@@ -0,0 +1,150 @@
+//! Thread tests: paging replies, summaries, participation and read state
+
+mod common;
+
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::storage::StorageManager;
+use veter_core::VeterError;
+use common::{TempDb, room, store_user};
+
+/// Storage knowing three users with a device each and a room of theirs
+struct Setup {
+    _db: TempDb,
+    storage: StorageManager,
+    room_id: RoomId,
+    devices: Vec<Device>,
+    clock: i64,
+}
+
+impl Setup {
+    async fn new() -> Self {
+        let db = TempDb::new("threads");
+        let storage = db.open().await;
+        let mut devices = Vec::new();
+        for name in ["alice", "bob", "carol"] {
+            devices.push(store_user(&storage, name).await);
+        }
+        let room = room("Lunch", devices.iter().map(|device| device.user_id).collect());
+        storage.store_room(&room).await.unwrap();
+
+        Self { _db: db, storage, room_id: room.id, devices, clock: 1_700_000_000_000 }
+    }
+
+    fn user(&self, index: usize) -> UserId {
+        self.devices[index].user_id
+    }
+
+    /// Store a message from user `index`, a second after the previous one
+    async fn post(&mut self, index: usize, text: &str, reply_to: Option<MessageId>) -> Message {
+        self.clock += 1_000;
+        let sender = &self.devices[index];
+        let message = Message {
+            id: Uuid::new_v4(),
+            room_id: self.room_id,
+            sender_id: sender.user_id,
+            sender_device_id: sender.id,
+            content: MessageContent::Text(text.to_string()),
+            created_at: chrono::DateTime::from_timestamp_millis(self.clock).unwrap(),
+            edited_at: None,
+            reply_to,
+        };
+        self.storage.store_message(&message).await.unwrap();
+        message
+    }
+}
+
+fn texts(messages: &[Message]) -> Vec<&str> {
+    messages.iter()
+        .map(|message| match &message.content {
+            MessageContent::Text(text) => text.as_str(),
+            other => panic!("Not a text message: {:?}", other),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn pages_through_the_replies_to_a_message() {
+    let mut setup = Setup::new().await;
+    let root = setup.post(0, "where for lunch?", None).await;
+    let mut replies = Vec::new();
+    for (index, text) in ["pizza", "sushi", "tacos", "salad", "curry"].into_iter().enumerate() {
+        replies.push(setup.post(index % 3, text, Some(root.id)).await);
+        setup.post(1, "unrelated", None).await;
+    }
+    // A reply to a reply starts a thread of its own
+    setup.post(2, "which pizza place?", Some(replies[0].id)).await;
+
+    let page = setup.storage.get_thread(&root.id, &MessageQuery::latest(3)).await.unwrap();
+    assert_eq!(texts(&page.messages), vec!["tacos", "salad", "curry"]);
+    assert!(page.newer.is_none());
+    let query = MessageQuery { cursor: page.older, ..MessageQuery::latest(3) };
+    let page = setup.storage.get_thread(&root.id, &query).await.unwrap();
+    assert_eq!(texts(&page.messages), vec!["pizza", "sushi"]);
+    assert!(page.older.is_none());
+
+    assert_eq!(setup.storage.get_message(&root.id).await.unwrap().unwrap().id, root.id);
+    assert!(setup.storage.get_thread(&Uuid::new_v4(), &MessageQuery::latest(3)).await.unwrap().messages.is_empty());
+}
+
+#[tokio::test]
+async fn summarizes_threads_with_unread_replies() {
+    let mut setup = Setup::new().await;
+    let (alice, bob) = (setup.user(0), setup.user(1));
+    let root = setup.post(0, "lunch at noon?", None).await;
+    let quiet = setup.post(0, "anyone?", None).await;
+    setup.post(1, "sure", Some(root.id)).await;
+    let second = setup.post(1, "where?", Some(root.id)).await;
+    let last = setup.post(0, "the usual", Some(root.id)).await;
+
+    let summaries = setup.storage.get_thread_summaries(&alice, &[root.id, quiet.id]).await.unwrap();
+    assert_eq!(summaries.len(), 1);
+    let summary = &summaries[0];
+    assert_eq!(summary.root.id, root.id);
+    assert_eq!(summary.reply_count, 3);
+    assert_eq!(summary.last_reply_at, last.created_at);
+    // Alice's own reply is not unread for her
+    assert_eq!(summary.unread_count, 2);
+    assert!(summary.participating);
+    assert_eq!(setup.storage.get_thread_summaries(&bob, &[root.id]).await.unwrap()[0].unread_count, 1);
+
+    setup.storage.mark_thread_read(&root.id, &second.id).await.unwrap();
+    assert_eq!(setup.storage.get_thread_summaries(&alice, &[root.id]).await.unwrap()[0].unread_count, 0);
+    // Marking an earlier reply does not make later ones unread again
+    let first = setup.storage.get_thread(&root.id, &MessageQuery::latest(1)).await.unwrap();
+    setup.storage.mark_thread_read(&root.id, &first.messages[0].id).await.unwrap();
+    setup.storage.mark_thread_read(&root.id, &root.id).await.unwrap_err();
+    assert!(matches!(
+        setup.storage.mark_thread_read(&quiet.id, &second.id).await,
+        Err(VeterError::InvalidInput(_))
+    ));
+    assert_eq!(setup.storage.get_thread_summaries(&alice, &[root.id]).await.unwrap()[0].unread_count, 0);
+
+    setup.post(1, "on my way", Some(root.id)).await;
+    assert_eq!(setup.storage.get_thread_summaries(&alice, &[root.id]).await.unwrap()[0].unread_count, 1);
+}
+
+#[tokio::test]
+async fn lists_threads_by_activity_and_participation() {
+    let mut setup = Setup::new().await;
+    let carol = setup.user(2);
+    let started = setup.post(2, "movie tonight?", None).await;
+    let joined = setup.post(0, "lunch?", None).await;
+    let other = setup.post(0, "coffee?", None).await;
+    setup.post(1, "yes", Some(started.id)).await;
+    setup.post(2, "me too", Some(joined.id)).await;
+    setup.post(1, "always", Some(other.id)).await;
+    // Carol's own message without replies is no thread
+    setup.post(2, "hello?", None).await;
+
+    let room_threads = setup.storage.get_room_threads(&carol, &setup.room_id, 10).await.unwrap();
+    let roots: Vec<_> = room_threads.iter().map(|summary| summary.root.id).collect();
+    assert_eq!(roots, vec![other.id, joined.id, started.id]);
+    let participating: Vec<_> = room_threads.iter().map(|summary| summary.participating).collect();
+    assert_eq!(participating, vec![false, true, true]);
+    assert_eq!(setup.storage.get_room_threads(&carol, &setup.room_id, 1).await.unwrap().len(), 1);
+
+    let threads = setup.storage.get_participating_threads(&carol, 10).await.unwrap();
+    let roots: Vec<_> = threads.iter().map(|summary| summary.root.id).collect();
+    assert_eq!(roots, vec![joined.id, started.id]);
+}
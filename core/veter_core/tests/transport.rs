@@ -0,0 +1,217 @@
+//! Transport tests against in-process relays over QUIC and TLS
+
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use uuid::Uuid;
+use veter_core::models::*;
+use veter_core::networking::relay::proto::relay_server::{Relay, RelayServer};
+use veter_core::networking::relay::proto::*;
+use veter_core::networking::transport::{quic_incoming, quic_server_config};
+use veter_core::networking::{NetworkClient, Transport, TransportConfig, TransportKind};
+
+/// Relay that accepts every message and delivers none
+struct AcceptingRelay;
+
+#[tonic::async_trait]
+impl Relay for AcceptingRelay {
+    async fn enqueue(&self, request: Request<EnqueueRequest>) -> Result<Response<EnqueueResponse>, Status> {
+        let accepted_ids = request.into_inner().messages.into_iter().map(|message| message.id).collect();
+        Ok(Response::new(EnqueueResponse { accepted_ids }))
+    }
+
+    async fn dequeue(&self, _request: Request<DequeueRequest>) -> Result<Response<DequeueResponse>, Status> {
+        Ok(Response::new(DequeueResponse { messages: Vec::new() }))
+    }
+
+    async fn ack(&self, _request: Request<AckRequest>) -> Result<Response<AckResponse>, Status> {
+        Ok(Response::new(AckResponse {}))
+    }
+
+    type SubscribeStream = ReceiverStream<Result<Delivery, Status>>;
+
+    async fn subscribe(&self, _request: Request<SubscribeRequest>) -> Result<Response<Self::SubscribeStream>, Status> {
+        Err(Status::unimplemented("not needed"))
+    }
+}
+
+/// Relay serving TLS on a TCP port and, unless `udp` says otherwise, QUIC on
+/// the same UDP port
+struct TestServer {
+    endpoint: String,
+    address: std::net::SocketAddr,
+    certificate: Vec<u8>,
+    key: Vec<u8>,
+    quic: Option<quinn::Endpoint>,
+    // Keeps the port's UDP socket bound, silently dropping packets
+    _blackhole: Option<std::net::UdpSocket>,
+}
+
+/// What the server does with UDP on its port
+enum Udp {
+    Quic,
+    Blocked,
+}
+
+impl TestServer {
+    async fn start(udp: Udp) -> Self {
+        let certified = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        let certificate = certified.cert.pem().into_bytes();
+        let key = certified.key_pair.serialize_pem().into_bytes();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(
+            Server::builder()
+                .tls_config(ServerTlsConfig::new().identity(Identity::from_pem(&certificate, &key)))
+                .unwrap()
+                .add_service(RelayServer::new(AcceptingRelay))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let (quic, blackhole) = match udp {
+            Udp::Quic => (Some(serve_quic(&certificate, &key, address)), None),
+            Udp::Blocked => (None, Some(std::net::UdpSocket::bind(address).unwrap())),
+        };
+
+        Self {
+            endpoint: format!("https://{}", address),
+            address,
+            certificate,
+            key,
+            quic,
+            _blackhole: blackhole,
+        }
+    }
+
+    /// Close all QUIC connections and serve QUIC again with a fresh TLS
+    /// config, which has forgotten the sessions clients could resume
+    async fn restart_quic(&mut self) {
+        let quic = self.quic.take().unwrap();
+        quic.close(0u32.into(), b"restart");
+        quic.wait_idle().await;
+        drop(quic);
+
+        // The socket is released once the old endpoint has shut down
+        for _ in 0..50 {
+            if let Ok(socket) = std::net::UdpSocket::bind(self.address) {
+                drop(socket);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        self.quic = Some(serve_quic(&self.certificate, &self.key, self.address));
+    }
+
+    fn transport(&self, quic: bool) -> Transport {
+        Transport::new(TransportConfig {
+            quic,
+            quic_timeout: Duration::from_millis(300),
+            request_timeout: Duration::from_secs(5),
+            root_certificate: Some(self.certificate.clone()),
+        })
+        .unwrap()
+    }
+
+    async fn client(&self, transport: &Transport) -> NetworkClient {
+        let mut client = NetworkClient::with_transport(transport.clone());
+        client.connect_relay(&self.endpoint).await.unwrap();
+        client
+    }
+}
+
+fn serve_quic(certificate: &[u8], key: &[u8], address: std::net::SocketAddr) -> quinn::Endpoint {
+    let quic = quinn::Endpoint::server(quic_server_config(certificate, key).unwrap(), address).unwrap();
+    tokio::spawn(
+        Server::builder()
+            .add_service(RelayServer::new(AcceptingRelay))
+            .serve_with_incoming(quic_incoming(quic.clone())),
+    );
+    quic
+}
+
+/// Send a message and check that the relay accepted it
+async fn round_trip(client: &NetworkClient) {
+    let message = EncryptedMessage {
+        id: Uuid::new_v4(),
+        room_id: Uuid::new_v4(),
+        sender_device_id: Uuid::new_v4(),
+        payload: vec![1, 2, 3],
+        timestamp: chrono::Utc::now(),
+        recipient_device_ids: vec![Uuid::new_v4()],
+    };
+    let accepted = client.send_messages(vec![message.clone()]).await.unwrap();
+    assert_eq!(accepted, vec![message.id]);
+}
+
+#[tokio::test]
+async fn calls_over_quic_across_network_changes() {
+    let server = TestServer::start(Udp::Quic).await;
+    let client = server.client(&server.transport(true)).await;
+    assert_eq!(client.relay_transport(), Some(TransportKind::Quic { zero_rtt: false }));
+    round_trip(&client).await;
+
+    // The old socket is gone, so calls only work if the connection migrated
+    client.network_changed().unwrap();
+    round_trip(&client).await;
+    client.network_changed().unwrap();
+    round_trip(&client).await;
+}
+
+#[tokio::test]
+async fn resumes_with_zero_rtt() {
+    let server = TestServer::start(Udp::Quic).await;
+    let transport = server.transport(true);
+    let first = server.client(&transport).await;
+    round_trip(&first).await;
+
+    let second = server.client(&transport).await;
+    assert_eq!(second.relay_transport(), Some(TransportKind::Quic { zero_rtt: true }));
+    round_trip(&second).await;
+}
+
+#[tokio::test]
+async fn reconnects_when_early_data_is_rejected() {
+    let mut server = TestServer::start(Udp::Quic).await;
+    let transport = server.transport(true);
+    round_trip(&server.client(&transport).await).await;
+    let client = server.client(&transport).await;
+    assert_eq!(client.relay_transport(), Some(TransportKind::Quic { zero_rtt: true }));
+
+    // The client reconnects with early data the restarted server rejects
+    server.restart_quic().await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    round_trip(&client).await;
+}
+
+#[tokio::test]
+async fn falls_back_to_tls_when_udp_is_blocked() {
+    let server = TestServer::start(Udp::Blocked).await;
+    let client = server.client(&server.transport(true)).await;
+    assert_eq!(client.relay_transport(), Some(TransportKind::Tls { fallback: true }));
+    round_trip(&client).await;
+}
+
+#[tokio::test]
+async fn uses_tls_when_quic_is_disabled() {
+    let server = TestServer::start(Udp::Quic).await;
+    let client = server.client(&server.transport(false)).await;
+    assert_eq!(client.relay_transport(), Some(TransportKind::Tls { fallback: false }));
+    round_trip(&client).await;
+}
+
+#[tokio::test]
+async fn rejects_untrusted_certificates() {
+    let server = TestServer::start(Udp::Quic).await;
+    let transport = Transport::new(TransportConfig {
+        quic_timeout: Duration::from_millis(300),
+        ..TransportConfig::default()
+    })
+    .unwrap();
+
+    let mut client = NetworkClient::with_transport(transport);
+    assert!(client.connect_relay(&server.endpoint).await.is_err());
+    assert_eq!(client.relay_transport(), None);
+}
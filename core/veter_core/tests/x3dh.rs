@@ -0,0 +1,120 @@
+//! X3DH tests: agreement, bundle signatures and prekey lifetimes
+
+use uuid::Uuid;
+use veter_core::crypto::x3dh::{self, IdentityKeyPair, OneTimePreKey, PreKeyBundle, SignedPreKey};
+use veter_core::crypto::CryptoManager;
+use veter_core::VeterError;
+
+fn device() -> CryptoManager {
+    let (private_key, _) = CryptoManager::generate_identity_keypair().unwrap();
+    CryptoManager::new(private_key, Uuid::new_v4()).unwrap()
+}
+
+#[test]
+fn both_sides_derive_the_same_secret() {
+    let alice = IdentityKeyPair::generate();
+    let bob = IdentityKeyPair::generate();
+    let signed_prekey = SignedPreKey::generate(&bob);
+    let one_time_prekey = OneTimePreKey::generate();
+
+    for one_time in [Some(&one_time_prekey), None] {
+        let bundle = PreKeyBundle {
+            identity_key: bob.public_key(),
+            signed_prekey: signed_prekey.public_key(),
+            signed_prekey_signature: signed_prekey.signature(),
+            one_time_prekey: one_time.map(OneTimePreKey::public_key),
+        };
+        let (sent, message) = x3dh::initiate(&alice, &bundle).unwrap();
+        let received = x3dh::respond(&bob, &signed_prekey, one_time, &message).unwrap();
+        assert_eq!(sent.secret, received.secret);
+        assert_eq!(sent.associated_data, received.associated_data);
+        assert_eq!(message.identity_key, alice.public_key());
+
+        // Without the one-time prekey the message asks for, no secret
+        let other = OneTimePreKey::generate();
+        let mismatch = match one_time {
+            Some(_) => None,
+            None => Some(&other),
+        };
+        assert!(x3dh::respond(&bob, &signed_prekey, mismatch, &message).is_err());
+    }
+}
+
+#[test]
+fn rejects_bundles_with_a_bad_signed_prekey_signature() {
+    let alice = IdentityKeyPair::generate();
+    let bob = IdentityKeyPair::generate();
+    let mallory = IdentityKeyPair::generate();
+    let signed_prekey = SignedPreKey::generate(&bob);
+    let mut bundle = PreKeyBundle {
+        identity_key: bob.public_key(),
+        signed_prekey: signed_prekey.public_key(),
+        signed_prekey_signature: signed_prekey.signature(),
+        one_time_prekey: None,
+    };
+
+    bundle.signed_prekey_signature[0] ^= 1;
+    assert!(matches!(x3dh::initiate(&alice, &bundle), Err(VeterError::Crypto(_))));
+
+    // A prekey signed by someone else's identity key
+    let forged = SignedPreKey::generate(&mallory);
+    bundle.signed_prekey = forged.public_key();
+    bundle.signed_prekey_signature = forged.signature();
+    assert!(matches!(x3dh::initiate(&alice, &bundle), Err(VeterError::Crypto(_))));
+}
+
+#[test]
+fn one_time_prekeys_are_used_once() {
+    let alice = device();
+    let mut bob = device();
+    let bundle = bob.generate_key_material(2).unwrap();
+    assert_eq!(bob.one_time_prekey_count(), 2);
+
+    let (sent, message) = alice.initiate_key_agreement(&bundle).unwrap();
+    assert_eq!(bob.accept_key_agreement(&message).unwrap().secret, sent.secret);
+    assert_eq!(bob.one_time_prekey_count(), 1);
+    assert!(matches!(bob.accept_key_agreement(&message), Err(VeterError::KeyManagement(_))));
+}
+
+#[test]
+fn prekeys_survive_a_restart() {
+    let (private_key, _) = CryptoManager::generate_identity_keypair().unwrap();
+    let device_id = Uuid::new_v4();
+    let alice = device();
+    let mut bob = CryptoManager::new(private_key.clone(), device_id).unwrap();
+    let bundle = bob.generate_key_material(3).unwrap();
+    let state = bob.prekey_state().unwrap();
+    drop(bob);
+
+    let mut bob = CryptoManager::new(private_key, device_id).unwrap();
+    bob.init_prekey_state(&state).unwrap();
+    assert_eq!(bob.one_time_prekey_count(), 3);
+    let (sent, message) = alice.initiate_key_agreement(&bundle).unwrap();
+    assert_eq!(bob.accept_key_agreement(&message).unwrap().secret, sent.secret);
+    assert_eq!(bob.one_time_prekey_count(), 2);
+}
+
+#[test]
+fn accepts_the_replaced_signed_prekey_until_it_is_replaced_again() {
+    let (private_key, _) = CryptoManager::generate_identity_keypair().unwrap();
+    let device_id = Uuid::new_v4();
+    let alice = device();
+    let mut bob = CryptoManager::new(private_key.clone(), device_id).unwrap();
+    let first = bob.generate_key_material(2).unwrap();
+    let (early_secret, early) = alice.initiate_key_agreement(&first).unwrap();
+    let (_, late) = alice.initiate_key_agreement(&first).unwrap();
+
+    // Messages built from the bundle published before still arrive
+    let second = bob.generate_key_material(2).unwrap();
+    assert_ne!(first.signed_prekey, second.signed_prekey);
+    assert_eq!(bob.accept_key_agreement(&early).unwrap().secret, early_secret.secret);
+
+    // Also after a restart, until the next rotation
+    let state = bob.prekey_state().unwrap();
+    let mut bob = CryptoManager::new(private_key, device_id).unwrap();
+    bob.init_prekey_state(&state).unwrap();
+    bob.generate_key_material(0).unwrap();
+    assert!(matches!(bob.accept_key_agreement(&late), Err(VeterError::KeyManagement(_))));
+    let (current_secret, current) = alice.initiate_key_agreement(&second).unwrap();
+    assert_eq!(bob.accept_key_agreement(&current).unwrap().secret, current_secret.secret);
+}